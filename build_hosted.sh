@@ -0,0 +1,5 @@
+#!/bin/sh
+
+cargo build --release --features hosted --target x86_64-unknown-linux-gnu || exit 1
+cd hosted || exit 1
+make || exit 1
@@ -0,0 +1,553 @@
+//! Encoding of checkpoints of running applications.
+//!
+//! A checkpoint is laid out like a core dump: `MAGIC`, followed by sections
+//! made of a little-endian `u32` tag, a little-endian `u64` length and that
+//! many bytes of data. It holds the code along with the state of the
+//! interpreter, so restoring needs nothing else, and the names of the native
+//! invokes the code uses, whose ids have to be the same where it is restored.
+
+use prelude::{String, Vec};
+use core::fmt::Write;
+
+use resource::{IoOptions, ResourceSnapshot};
+
+pub const MAGIC: &'static [u8; 8] = b"CVCKPT01";
+
+/// Limits for the sections that don't have a configured one.
+const MAX_CODE_LEN: usize = 1048576 * 64;
+const MAX_TABLE_LEN: usize = 1048576;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Section {
+    /// The code as loaded, with its memory initializers
+    Code = 1,
+
+    /// Offset of the opcode to continue at, as `u64`
+    Ip = 2,
+
+    Memory = 3,
+
+    /// Arrays of `i64`. Only the live parts of the stacks are written.
+    Slots = 4,
+    Stack = 5,
+    CallStack = 6,
+
+    /// Resource records, see `encode_resources`
+    Resources = 7,
+
+    /// One `<id> <name>` line per native invoke the code uses
+    NativeInvokes = 8,
+
+    /// Number of arguments of the entry function, as `u64`
+    EntryArgs = 9,
+
+    /// Manifest of the application, if it has one
+    Manifest = 10
+}
+
+const RESOURCE_STDIO: u32 = 1;
+const RESOURCE_FILE: u32 = 2;
+
+const OPTION_NONBLOCK: u32 = 1;
+const OPTION_TIMEOUT: u32 = 2;
+
+/// State of a running application, for checkpoints.
+pub struct CheckpointState<'a> {
+    /// The code as it was loaded, with its memory initializers
+    pub code: &'a [u8],
+
+    /// Number of arguments the entry function was called with
+    pub n_entry_args: usize,
+
+    /// Offset of the opcode to continue at in the code
+    pub ip: usize,
+
+    pub memory: &'a [u8],
+    pub slots: &'a [i64],
+
+    /// The live parts of the stacks, bottom first
+    pub stack: &'a [i64],
+    pub call_stack: &'a [i64],
+
+    /// Ids of the registered native invokes the code uses
+    pub native_invokes: &'a [usize]
+}
+
+/// An open resource and how to open it again.
+#[derive(Clone, Debug)]
+pub struct ResourceEntry {
+    pub id: usize,
+    pub options: IoOptions,
+    pub snapshot: ResourceSnapshot
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    let v: [u8; 4] = unsafe { ::core::mem::transmute(v.to_le()) };
+    out.extend_from_slice(&v);
+}
+
+fn push_u64(out: &mut Vec<u8>, v: u64) {
+    let v: [u8; 8] = unsafe { ::core::mem::transmute(v.to_le()) };
+    out.extend_from_slice(&v);
+}
+
+fn le_u32(data: &[u8]) -> u32 {
+    data[0..4].iter().rev().fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+fn le_u64(data: &[u8]) -> u64 {
+    data[0..8].iter().rev().fold(0, |acc, b| (acc << 8) | *b as u64)
+}
+
+/// Takes `n` bytes from the front of `data`.
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if data.len() < n {
+        return None;
+    }
+
+    let (head, rest) = data.split_at(n);
+    *data = rest;
+    Some(head)
+}
+
+/// Encodes each resource as `u32` id, `u32` kind, `u32` option flags, `u64`
+/// timeout, `i64` stdio number or file offset, `u32` path length and path,
+/// all little-endian.
+pub fn encode_resources(entries: &[ResourceEntry]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+
+    for entry in entries {
+        let (kind, value, path) = match entry.snapshot {
+            ResourceSnapshot::Stdio(n) => (RESOURCE_STDIO, n as i64, ""),
+            ResourceSnapshot::File { ref path, offset } => (RESOURCE_FILE, offset, path.as_str())
+        };
+        let mut flags: u32 = 0;
+        if entry.options.nonblock {
+            flags |= OPTION_NONBLOCK;
+        }
+        if entry.options.timeout_ms.is_some() {
+            flags |= OPTION_TIMEOUT;
+        }
+
+        push_u32(&mut out, entry.id as u32);
+        push_u32(&mut out, kind);
+        push_u32(&mut out, flags);
+        push_u64(&mut out, entry.options.timeout_ms.unwrap_or(0));
+        push_u64(&mut out, value as u64);
+        push_u32(&mut out, path.len() as u32);
+        out.extend_from_slice(path.as_bytes());
+    }
+
+    out
+}
+
+fn decode_resources(mut data: &[u8]) -> Option<Vec<ResourceEntry>> {
+    let mut entries: Vec<ResourceEntry> = Vec::new();
+
+    while data.len() > 0 {
+        let header = take(&mut data, 32)?;
+        let path_len = le_u32(&header[28..]) as usize;
+        let path = ::core::str::from_utf8(take(&mut data, path_len)?).ok()?;
+
+        let flags = le_u32(&header[8..]);
+        let value = le_u64(&header[20..]) as i64;
+
+        entries.push(ResourceEntry {
+            id: le_u32(&header[0..]) as usize,
+            options: IoOptions {
+                nonblock: flags & OPTION_NONBLOCK != 0,
+                timeout_ms: if flags & OPTION_TIMEOUT != 0 { Some(le_u64(&header[12..])) } else { None }
+            },
+            snapshot: match le_u32(&header[4..]) {
+                RESOURCE_STDIO if value >= 0 && value <= 2 => ResourceSnapshot::Stdio(value as usize),
+                RESOURCE_FILE => ResourceSnapshot::File {
+                    path: path.into(),
+                    offset: value
+                },
+                _ => return None
+            }
+        });
+    }
+
+    Some(entries)
+}
+
+/// Where the sections of a checkpoint are written to, after the magic.
+pub trait SectionWriter {
+    fn write_tagged(&mut self, tag: u32, data: &[u8]);
+
+    /// Writes `data` as little-endian values.
+    fn write_i64_tagged(&mut self, tag: u32, data: &[i64]);
+}
+
+/// Writes the sections of a checkpoint of `state` with the given resources,
+/// native invoke names and manifest.
+pub fn write_sections<W: SectionWriter>(
+    w: &mut W,
+    state: &CheckpointState,
+    resources: &[ResourceEntry],
+    native_invokes: &[(usize, String)],
+    manifest: Option<&str>
+) {
+    let ip: [u8; 8] = unsafe { ::core::mem::transmute((state.ip as u64).to_le()) };
+    let n_entry_args: [u8; 8] = unsafe { ::core::mem::transmute((state.n_entry_args as u64).to_le()) };
+
+    let mut names = String::new();
+    for &(id, ref name) in native_invokes {
+        let _ = writeln!(names, "{} {}", id, name);
+    }
+
+    w.write_tagged(Section::Code as u32, state.code);
+    w.write_tagged(Section::EntryArgs as u32, &n_entry_args);
+    w.write_tagged(Section::Ip as u32, &ip);
+    w.write_tagged(Section::Memory as u32, state.memory);
+    w.write_i64_tagged(Section::Slots as u32, state.slots);
+    w.write_i64_tagged(Section::Stack as u32, state.stack);
+    w.write_i64_tagged(Section::CallStack as u32, state.call_stack);
+    w.write_tagged(Section::Resources as u32, &encode_resources(resources));
+    w.write_tagged(Section::NativeInvokes as u32, names.as_bytes());
+    if let Some(text) = manifest {
+        w.write_tagged(Section::Manifest as u32, text.as_bytes());
+    }
+}
+
+/// Where a checkpoint is read from, from the start.
+pub trait Reader {
+    type Error;
+
+    /// Fills as much of `out` as is left and returns how much that was.
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+impl<'a> Reader for &'a [u8] {
+    type Error = ();
+
+    fn read(&mut self, out: &mut [u8]) -> Result<usize, ()> {
+        let n = ::core::cmp::min(out.len(), self.len());
+        out[..n].copy_from_slice(&self[..n]);
+        *self = &self[n..];
+        Ok(n)
+    }
+}
+
+/// Sizes of the application a checkpoint is restored into.
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    pub memory_max_len: usize,
+    pub max_slots: usize,
+    pub stack_len: usize,
+    pub call_stack_len: usize
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LoadError<E> {
+    /// The reader failed.
+    Io(E),
+
+    /// Section `tag` is larger than the limits allow.
+    SectionTooLarge { tag: u32 },
+
+    /// The data isn't a checkpoint, or what is wrong with it.
+    Malformed(&'static str)
+}
+
+fn i64_array(data: &[u8]) -> Option<Vec<i64>> {
+    if data.len() % 8 != 0 {
+        return None;
+    }
+
+    Some(data.chunks(8).map(|v| le_u64(v) as i64).collect())
+}
+
+/// A checkpoint read back for restoring.
+pub struct Checkpoint {
+    pub code: Vec<u8>,
+    pub n_entry_args: usize,
+    pub ip: usize,
+    pub memory: Vec<u8>,
+    pub slots: Vec<i64>,
+    pub stack: Vec<i64>,
+    pub call_stack: Vec<i64>,
+    pub resources: Vec<ResourceEntry>,
+    pub native_invokes: Vec<(usize, String)>,
+    pub manifest: Option<String>
+}
+
+impl Checkpoint {
+    /// Reads a checkpoint from `r`.
+    ///
+    /// Sections larger than `limits` allow for the restored application are
+    /// rejected before they are read.
+    pub fn read<R: Reader>(r: &mut R, limits: &Limits) -> Result<Checkpoint, LoadError<R::Error>> {
+        let malformed = LoadError::Malformed;
+
+        let mut magic = [0u8; 8];
+        if r.read(&mut magic).map_err(LoadError::Io)? != magic.len() || &magic != MAGIC {
+            return Err(malformed("not a checkpoint"));
+        }
+
+        let mut cp = Checkpoint {
+            code: Vec::new(),
+            n_entry_args: 0,
+            ip: 0,
+            memory: Vec::new(),
+            slots: Vec::new(),
+            stack: Vec::new(),
+            call_stack: Vec::new(),
+            resources: Vec::new(),
+            native_invokes: Vec::new(),
+            manifest: None
+        };
+        let mut has_ip = false;
+
+        loop {
+            let mut header = [0u8; 12];
+            match r.read(&mut header).map_err(LoadError::Io)? {
+                0 => break,
+                12 => {},
+                _ => return Err(malformed("truncated section header"))
+            }
+
+            let tag = le_u32(&header);
+            let len = le_u64(&header[4..]);
+            let max_len = match tag {
+                1 => MAX_CODE_LEN,
+                2 | 9 => 8,
+                3 => limits.memory_max_len,
+                4 => limits.max_slots.saturating_mul(8),
+                5 => limits.stack_len.saturating_mul(8),
+                6 => limits.call_stack_len.saturating_mul(8),
+                7 | 8 | 10 => MAX_TABLE_LEN,
+                _ => return Err(malformed("unknown section"))
+            };
+            if len > max_len as u64 {
+                return Err(LoadError::SectionTooLarge { tag: tag });
+            }
+
+            let mut body = vec! [ 0u8; len as usize ];
+            if r.read(&mut body).map_err(LoadError::Io)? != body.len() {
+                return Err(malformed("truncated section"));
+            }
+
+            match tag {
+                1 => cp.code = body,
+                2 | 9 if body.len() != 8 => return Err(malformed("truncated value")),
+                2 => {
+                    cp.ip = le_u64(&body) as usize;
+                    has_ip = true;
+                },
+                9 => cp.n_entry_args = le_u64(&body) as usize,
+                3 => cp.memory = body,
+                4 | 5 | 6 => {
+                    let values = i64_array(&body).ok_or_else(|| malformed("truncated array"))?;
+                    match tag {
+                        4 => cp.slots = values,
+                        5 => cp.stack = values,
+                        _ => cp.call_stack = values
+                    }
+                },
+                7 => cp.resources = decode_resources(&body).ok_or_else(|| malformed("resources"))?,
+                8 => {
+                    let text = String::from_utf8(body).map_err(|_| malformed("native invokes"))?;
+                    for line in text.lines() {
+                        let mut parts = line.splitn(2, ' ');
+                        match (parts.next().and_then(|v| v.parse().ok()), parts.next()) {
+                            (Some(id), Some(name)) => cp.native_invokes.push((id, name.into())),
+                            _ => return Err(malformed("native invokes"))
+                        }
+                    }
+                },
+                _ => cp.manifest = Some(String::from_utf8(body).map_err(|_| malformed("manifest"))?)
+            }
+        }
+
+        if cp.code.len() == 0 || !has_ip {
+            return Err(malformed("code or offset missing"));
+        }
+
+        Ok(cp)
+    }
+
+    pub fn state(&self) -> CheckpointState {
+        CheckpointState {
+            code: &self.code,
+            n_entry_args: self.n_entry_args,
+            ip: self.ip,
+            memory: &self.memory,
+            slots: &self.slots,
+            stack: &self.stack,
+            call_stack: &self.call_stack,
+
+            // Only needed when writing one
+            native_invokes: &[]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes sections like the kernel module's `CoreDumpWriter`.
+    struct VecWriter(Vec<u8>);
+
+    impl SectionWriter for VecWriter {
+        fn write_tagged(&mut self, tag: u32, data: &[u8]) {
+            push_u32(&mut self.0, tag);
+            push_u64(&mut self.0, data.len() as u64);
+            self.0.extend_from_slice(data);
+        }
+
+        fn write_i64_tagged(&mut self, tag: u32, data: &[i64]) {
+            let mut bytes: Vec<u8> = Vec::new();
+            for v in data {
+                push_u64(&mut bytes, *v as u64);
+            }
+            self.write_tagged(tag, &bytes);
+        }
+    }
+
+    const LIMITS: Limits = Limits {
+        memory_max_len: 65536,
+        max_slots: 16,
+        stack_len: 64,
+        call_stack_len: 64
+    };
+
+    fn encode(state: &CheckpointState, resources: &[ResourceEntry], manifest: Option<&str>) -> Vec<u8> {
+        let mut w = VecWriter(MAGIC.to_vec());
+        write_sections(&mut w, state, resources, &[ (3, "log_write".into()) ], manifest);
+        w.0
+    }
+
+    fn state<'a>(code: &'a [u8], memory: &'a [u8], stack: &'a [i64]) -> CheckpointState<'a> {
+        CheckpointState {
+            code: code,
+            n_entry_args: 1,
+            ip: 42,
+            memory: memory,
+            slots: &[ 7 ],
+            stack: stack,
+            call_stack: &[ 5, 2, 0, -1 ],
+            native_invokes: &[ 3 ]
+        }
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let memory = [ 1u8, 2, 3, 4 ];
+        let resources = [ ResourceEntry {
+            id: 0,
+            options: IoOptions::default(),
+            snapshot: ResourceSnapshot::Stdio(1)
+        } ];
+        let data = encode(&state(&[ 9, 9 ], &memory, &[ 1, -2 ]), &resources, Some("invoke log_write\n"));
+
+        let cp = Checkpoint::read(&mut &data[..], &LIMITS).unwrap();
+        assert_eq!(cp.code, vec! [ 9, 9 ]);
+        assert_eq!(cp.n_entry_args, 1);
+        assert_eq!(cp.ip, 42);
+        assert_eq!(cp.memory, memory.to_vec());
+        assert_eq!(cp.slots, vec! [ 7 ]);
+        assert_eq!(cp.stack, vec! [ 1, -2 ]);
+        assert_eq!(cp.call_stack, vec! [ 5, 2, 0, -1 ]);
+        assert_eq!(cp.resources.len(), 1);
+        assert_eq!(cp.native_invokes, vec! [ (3, String::from("log_write")) ]);
+        assert_eq!(cp.manifest, Some(String::from("invoke log_write\n")));
+    }
+
+    #[test]
+    fn read_rejects_malformed_checkpoints() {
+        let data = encode(&state(&[ 9 ], &[], &[]), &[], None);
+        let read = |data: &[u8]| Checkpoint::read(&mut &data[..], &LIMITS).err();
+
+        assert_eq!(read(&data[..7]), Some(LoadError::Malformed("not a checkpoint")));
+        assert_eq!(read(&data[..12]), Some(LoadError::Malformed("truncated section header")));
+        assert_eq!(read(&data[..20]), Some(LoadError::Malformed("truncated section")));
+
+        // Only the code
+        assert_eq!(read(&data[..21]), Some(LoadError::Malformed("code or offset missing")));
+
+        let mut bad = data.clone();
+        bad[8] = 11;
+        assert_eq!(read(&bad), Some(LoadError::Malformed("unknown section")));
+    }
+
+    #[test]
+    fn read_enforces_limits() {
+        let stack = [ 0i64; 65 ];
+        let data = encode(&state(&[ 9 ], &[], &stack), &[], None);
+
+        assert_eq!(
+            Checkpoint::read(&mut &data[..], &LIMITS).err(),
+            Some(LoadError::SectionTooLarge { tag: Section::Stack as u32 })
+        );
+    }
+
+    #[test]
+    fn resources_round_trip() {
+        let entries = vec! [
+            ResourceEntry {
+                id: 0,
+                options: IoOptions::default(),
+                snapshot: ResourceSnapshot::Stdio(1)
+            },
+            ResourceEntry {
+                id: 3,
+                options: IoOptions {
+                    nonblock: true,
+                    timeout_ms: Some(250)
+                },
+                snapshot: ResourceSnapshot::File {
+                    path: "/tmp/data".into(),
+                    offset: 4096
+                }
+            }
+        ];
+
+        let decoded = decode_resources(&encode_resources(&entries)).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].id, 0);
+        assert_eq!(decoded[0].options, IoOptions::default());
+        match decoded[0].snapshot {
+            ResourceSnapshot::Stdio(1) => {},
+            ref v => panic!("unexpected snapshot: {:?}", v)
+        }
+        assert_eq!(decoded[1].id, 3);
+        assert_eq!(decoded[1].options, entries[1].options);
+        match decoded[1].snapshot {
+            ResourceSnapshot::File { ref path, offset: 4096 } if path == "/tmp/data" => {},
+            ref v => panic!("unexpected snapshot: {:?}", v)
+        }
+    }
+
+    #[test]
+    fn decode_rejects_malformed_resources() {
+        let data = encode_resources(&[ResourceEntry {
+            id: 1,
+            options: IoOptions::default(),
+            snapshot: ResourceSnapshot::File {
+                path: "/tmp/data".into(),
+                offset: 0
+            }
+        }]);
+
+        assert!(decode_resources(&data[..data.len() - 1]).is_none());
+        assert!(decode_resources(&data[..20]).is_none());
+
+        // Unknown kind
+        let mut bad = data.clone();
+        bad[4] = 9;
+        assert!(decode_resources(&bad).is_none());
+
+        // stdio beyond stderr
+        let mut bad = encode_resources(&[ResourceEntry {
+            id: 1,
+            options: IoOptions::default(),
+            snapshot: ResourceSnapshot::Stdio(2)
+        }]);
+        bad[20] = 3;
+        assert!(decode_resources(&bad).is_none());
+    }
+}
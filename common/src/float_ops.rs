@@ -0,0 +1,26 @@
+//! Ids of the floating point operations that the interpreter runs itself.
+//!
+//! hexagon-e has no floating point opcodes, so `cvctl` lowers them to native
+//! invokes with ids starting at `FLOAT_OP_BASE`. They are executed by
+//! `backend::float` in the kernel module.
+
+/// Id of the first operation. Must match `cvctl::float_ops`.
+pub const FLOAT_OP_BASE: usize = 0x7fff_0000;
+
+/// Operations are numbered as follows, relative to `FLOAT_OP_BASE`:
+///
+/// - 0..20: `f32` abs, neg, ceil, floor, trunc, nearest, sqrt, add, sub, mul,
+///   div, min, max, copysign, eq, ne, lt, gt, le, ge
+/// - 20..40: the same for `f64`
+/// - 40..48: `i32.trunc_s/f32`, `i32.trunc_u/f32`, `i32.trunc_s/f64`,
+///   `i32.trunc_u/f64`, then the same for `i64`
+/// - 48..56: `f32.convert_s/i32`, `f32.convert_u/i32`, `f32.convert_s/i64`,
+///   `f32.convert_u/i64`, then the same for `f64`
+/// - 56: `f32.demote/f64`, 57: `f64.promote/f32`
+pub fn n_args(id: usize) -> Option<usize> {
+    match id.checked_sub(FLOAT_OP_BASE)? {
+        op @ 0..=39 => Some(if op % 20 < 7 { 1 } else { 2 }),
+        40..=57 => Some(1),
+        _ => None
+    }
+}
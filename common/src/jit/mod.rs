@@ -0,0 +1,1787 @@
+//! Compiles verified hexagon-e code to x86-64 machine code.
+//!
+//! Each opcode becomes a fixed sequence of instructions that works on the
+//! same stacks, slots and memory as the interpreter, so a run can be
+//! checkpointed, dumped and resumed the same way. The code only needs the
+//! checks the interpreter makes at run time: stack bounds, locals, slots,
+//! memory bounds, division by zero and the targets of calls and returns.
+//!
+//! While the code runs, these registers hold its state:
+//!
+//! - `rbx`: the `JitState`
+//! - `r12`: top of the stack, growing upwards
+//! - `r13`: top of the call stack, growing upwards
+//! - `r14`, `r15`: base and length of linear memory
+//!
+//! The stack pointers are written back to the `JitState` before anything
+//! outside the code looks at it. Opcodes that need the backend, like native
+//! invokes, call the helpers in the `JitState`, which may change memory,
+//! slots and the stack.
+//!
+//! Every branch counts down `JitState::budget` and calls the `branch` helper
+//! when it runs out, with the state complete and the target of the branch.
+//! The helper takes care of fuel, ticks, profiling and checkpoints, and sets
+//! the next budget.
+
+pub mod x86;
+
+use prelude::Vec;
+
+use hexagon_e::module::Opcode;
+
+use verify::immediate_len;
+use self::x86::*;
+
+/// Called by the code with the state, the offset of an opcode or a branch
+/// target, and an argument. Returns 0 to continue.
+pub type Helper = unsafe extern "C" fn(state: *mut JitState, ip: usize, arg: u64) -> u64;
+
+/// The prologue at the start of the code. Runs the code from `start`, the
+/// address of an opcode, and returns `EXIT_HALT` or `EXIT_STOP`.
+pub type Entry = unsafe extern "C" fn(state: *mut JitState, start: *const u8) -> u64;
+
+/// The code ran into `Halt`.
+pub const EXIT_HALT: u64 = 0;
+
+/// The code trapped, with `JitState::trap` set, or a helper failed.
+pub const EXIT_STOP: u64 = 1;
+
+/// Errors raised by the code itself, in `JitState::trap`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u64)]
+pub enum Trap {
+    /// An access outside of the stacks, locals, slots or memory, or a call
+    /// or return to an offset that isn't an opcode
+    Bounds = 1,
+
+    /// Division by zero, `Unreachable` and `NotSupported`
+    Generic = 2
+}
+
+impl Trap {
+    pub fn from_code(code: u64) -> Option<Trap> {
+        match code {
+            1 => Some(Trap::Bounds),
+            2 => Some(Trap::Generic),
+            _ => None
+        }
+    }
+}
+
+/// Everything the code uses besides its registers. Pointers to values
+/// point to the first one, and `*_end` past the last one.
+#[repr(C)]
+pub struct JitState {
+    pub mem_base: *mut u8,
+    pub mem_len: usize,
+    pub slots_base: *mut i64,
+    pub slots_len: usize,
+
+    pub stack_base: *mut i64,
+    pub stack_end: *mut i64,
+    pub sp: *mut i64,
+    pub call_stack_base: *mut i64,
+    pub call_stack_end: *mut i64,
+    pub csp: *mut i64,
+
+    /// Branches left before `branch` is called
+    pub budget: u64,
+
+    /// Machine code of the opcodes, by their offset in the bytecode, from
+    /// `Compiled::targets`
+    pub code_base: *const u8,
+    pub targets: *const u32,
+    pub n_targets: usize,
+
+    /// Where the code stopped: the opcode that trapped, or the `Halt`
+    pub ip: usize,
+    pub trap: u64,
+
+    /// Stack pointer of the prologue, for leaving from anywhere
+    pub saved_rsp: usize,
+
+    /// Called with the target of a branch when the budget has run out.
+    /// Sets the next budget.
+    pub branch: Helper,
+
+    /// Called with the id of a native invoke. Pops its arguments and pushes
+    /// its result.
+    pub native_invoke: Helper,
+
+    /// Called with the number of bytes to add to memory, after the old
+    /// length has been pushed. Updates `mem_base` and `mem_len`.
+    pub grow_memory: Helper,
+
+    /// Called with the number of slots. Updates `slots_base` and `slots_len`.
+    pub reset_slots: Helper,
+
+    /// For the helpers
+    pub runtime: *mut u8
+}
+
+const MEM_BASE: i32 = 0;
+const MEM_LEN: i32 = 8;
+const SLOTS_BASE: i32 = 16;
+const SLOTS_LEN: i32 = 24;
+const STACK_BASE: i32 = 32;
+const STACK_END: i32 = 40;
+const SP: i32 = 48;
+const CALL_STACK_BASE: i32 = 56;
+const CALL_STACK_END: i32 = 64;
+const CSP: i32 = 72;
+const BUDGET: i32 = 80;
+const CODE_BASE: i32 = 88;
+const TARGETS: i32 = 96;
+const N_TARGETS: i32 = 104;
+const IP: i32 = 112;
+const TRAP: i32 = 120;
+const SAVED_RSP: i32 = 128;
+const HELPER_BRANCH: i32 = 136;
+const HELPER_NATIVE_INVOKE: i32 = 144;
+const HELPER_GROW_MEMORY: i32 = 152;
+const HELPER_RESET_SLOTS: i32 = 160;
+
+const STATE: Reg = Reg::Rbx;
+const VSP: Reg = Reg::R12;
+const CSP_REG: Reg = Reg::R13;
+const MEM: Reg = Reg::R14;
+const MEM_LEN_REG: Reg = Reg::R15;
+
+/// Immediates that scale to stack offsets are limited to this, so that
+/// the offsets fit into a displacement. Anything above can't fit into a
+/// stack that fits into memory.
+const MAX_SCALED_IMMEDIATE: usize = 0x0fff_0000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CompileError {
+    /// The code doesn't decode. It should have been verified.
+    InvalidCode { offset: usize },
+
+    /// The machine code would be 4 GiB or more.
+    TooLarge
+}
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+pub struct Compiled {
+    /// The prologue, which is the `Entry`, and the opcodes
+    pub code: Vec<u8>,
+
+    /// Offset of the machine code of each opcode in `code`, by its offset in
+    /// the bytecode, or 0 where no opcode starts
+    pub targets: Vec<u32>
+}
+
+impl Compiled {
+    /// Offset in `code` where a run that continues at the opcode at `ip`
+    /// starts, if there is an opcode there.
+    pub fn start_of(&self, ip: usize) -> Option<usize> {
+        match self.targets.get(ip) {
+            Some(&v) if v != 0 => Some(v as usize),
+            _ => None
+        }
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    read_u32(data) as u64 | (read_u32(&data[4..]) as u64) << 32
+}
+
+fn vs(disp: i32) -> Mem {
+    mem(VSP, disp)
+}
+
+fn state(field: i32) -> Mem {
+    mem(STATE, field)
+}
+
+/// Integer types of the arithmetic opcodes.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Int {
+    /// 32 bits, with results sign extended like the interpreter's `i32`s
+    I32,
+
+    /// 32 bits, with results zero extended like the interpreter's `u32`s
+    U32,
+    I64
+}
+
+impl Int {
+    fn size(self) -> Size {
+        match self {
+            Int::I64 => Size::S64,
+            _ => Size::S32
+        }
+    }
+}
+
+struct Compiler<'a> {
+    code: &'a [u8],
+    asm: Assembler,
+
+    /// Offsets and labels of the opcodes
+    offsets: Vec<usize>,
+    labels: Vec<Label>,
+
+    epilogue: Label,
+    stop: Label,
+    slow_branch: Label,
+    trap_exit: Label,
+
+    /// Code that is placed after the opcodes
+    traps: Vec<(Label, usize, Trap)>,
+    branch_stubs: Vec<(Label, usize, Label)>,
+    jump_tables: Vec<(Label, usize, usize)>,
+
+    /// Offset of the opcode being compiled
+    ip: usize,
+
+    /// Traps of the opcode being compiled, to share them between its checks
+    op_traps: Vec<(Trap, Label)>
+}
+
+/// Compiles `code`, which must have been verified.
+pub fn compile(code: &[u8]) -> CompileResult<Compiled> {
+    let mut asm = Assembler::new();
+    let mut offsets: Vec<usize> = Vec::new();
+    let mut ops: Vec<Opcode> = Vec::new();
+    let mut labels: Vec<Label> = Vec::new();
+
+    let mut pos: usize = 0;
+    while pos < code.len() {
+        let op = match Opcode::from_raw(code[pos]) {
+            Ok(v) => v,
+            Err(_) => return Err(CompileError::InvalidCode { offset: pos })
+        };
+        let end = pos + 1 + immediate_len(&op, &code[pos + 1..]);
+        if end > code.len() {
+            return Err(CompileError::InvalidCode { offset: pos });
+        }
+
+        offsets.push(pos);
+        ops.push(op);
+        labels.push(asm.new_label());
+        pos = end;
+    }
+
+    let mut c = Compiler {
+        code: code,
+        epilogue: asm.new_label(),
+        stop: asm.new_label(),
+        slow_branch: asm.new_label(),
+        trap_exit: asm.new_label(),
+        asm: asm,
+        offsets: offsets,
+        labels: labels,
+        traps: Vec::new(),
+        branch_stubs: Vec::new(),
+        jump_tables: Vec::new(),
+        ip: 0,
+        op_traps: Vec::new()
+    };
+
+    c.emit_runtime();
+
+    let mut native_offsets: Vec<usize> = Vec::with_capacity(ops.len());
+    for (i, op) in ops.iter().enumerate() {
+        c.ip = c.offsets[i];
+        c.op_traps.clear();
+
+        let label = c.labels[i];
+        c.asm.bind(label);
+        native_offsets.push(c.asm.offset());
+        c.emit_op(*op);
+    }
+
+    // The interpreter fails when it runs past the end of the code.
+    c.ip = code.len();
+    c.op_traps.clear();
+    let end = c.trap(Trap::Bounds);
+    c.asm.jmp(end);
+
+    c.emit_out_of_line();
+
+    let native = c.asm.finish();
+    if native.len() > ::core::u32::MAX as usize {
+        return Err(CompileError::TooLarge);
+    }
+
+    let mut targets = vec! [ 0u32; code.len() ];
+    for (offset, native_offset) in c.offsets.iter().zip(native_offsets.iter()) {
+        targets[*offset] = *native_offset as u32;
+    }
+
+    Ok(Compiled {
+        code: native,
+        targets: targets
+    })
+}
+
+impl<'a> Compiler<'a> {
+    fn imm_u32(&self, n: usize) -> u32 {
+        read_u32(&self.code[self.ip + 1 + n * 4..])
+    }
+
+    fn label_of(&self, offset: usize) -> Option<Label> {
+        self.offsets.binary_search(&offset).ok().map(|i| self.labels[i])
+    }
+
+    /// Returns a label that stops the run with `trap` at the current opcode.
+    fn trap(&mut self, trap: Trap) -> Label {
+        for &(t, label) in &self.op_traps {
+            if t == trap {
+                return label;
+            }
+        }
+
+        let label = self.asm.new_label();
+        self.op_traps.push((trap, label));
+        self.traps.push((label, self.ip, trap));
+        label
+    }
+
+    /// The entry point at offset 0 and the code shared by the opcodes.
+    fn emit_runtime(&mut self) {
+        let a = &mut self.asm;
+
+        // Entry: save the callee-saved registers and keep the stack aligned
+        // for calls to the helpers.
+        a.push(Reg::Rbp);
+        a.push(Reg::Rbx);
+        a.push(Reg::R12);
+        a.push(Reg::R13);
+        a.push(Reg::R14);
+        a.push(Reg::R15);
+        a.alu_imm(Alu::Sub, Size::S64, Reg::Rsp, 8);
+        a.mov(Size::S64, STATE, Reg::Rdi);
+        a.store(Size::S64, state(SAVED_RSP), Reg::Rsp);
+        a.load(Size::S64, VSP, state(SP));
+        a.load(Size::S64, CSP_REG, state(CSP));
+        a.load(Size::S64, MEM, state(MEM_BASE));
+        a.load(Size::S64, MEM_LEN_REG, state(MEM_LEN));
+        a.jmp_reg(Reg::Rsi);
+
+        // Result in rax
+        a.bind(self.epilogue);
+        a.load(Size::S64, Reg::Rsp, state(SAVED_RSP));
+        a.alu_imm(Alu::Add, Size::S64, Reg::Rsp, 8);
+        a.pop(Reg::R15);
+        a.pop(Reg::R14);
+        a.pop(Reg::R13);
+        a.pop(Reg::R12);
+        a.pop(Reg::Rbx);
+        a.pop(Reg::Rbp);
+        a.ret();
+
+        // A helper failed and has set up the state.
+        a.bind(self.stop);
+        a.mov_imm(Reg::Rax, EXIT_STOP);
+        a.jmp(self.epilogue);
+
+        // Trap at rsi, with the trap in rdi
+        a.bind(self.trap_exit);
+        a.store(Size::S64, state(IP), Reg::Rsi);
+        a.store(Size::S64, state(TRAP), Reg::Rdi);
+        a.store(Size::S64, state(SP), VSP);
+        a.store(Size::S64, state(CSP), CSP_REG);
+        a.jmp(self.stop);
+
+        // Called when the budget has run out, with the target in rsi.
+        // Keeps rax, the address of the target.
+        a.bind(self.slow_branch);
+        a.push(Reg::Rax);
+        a.store(Size::S64, state(SP), VSP);
+        a.store(Size::S64, state(CSP), CSP_REG);
+        a.mov(Size::S64, Reg::Rdi, STATE);
+        a.alu(Alu::Xor, Size::S32, Reg::Rdx, Reg::Rdx);
+        a.call_mem(state(HELPER_BRANCH));
+        a.test(Size::S64, Reg::Rax, Reg::Rax);
+        a.jcc(Cond::NE, self.stop);
+        a.pop(Reg::Rax);
+        a.ret();
+    }
+
+    fn emit_out_of_line(&mut self) {
+        let traps = ::core::mem::replace(&mut self.traps, Vec::new());
+        for (label, ip, trap) in traps {
+            self.asm.bind(label);
+            self.asm.mov_imm(Reg::Rsi, ip as u64);
+            self.asm.mov_imm(Reg::Rdi, trap as u64);
+            self.asm.jmp(self.trap_exit);
+        }
+
+        let stubs = ::core::mem::replace(&mut self.branch_stubs, Vec::new());
+        for (label, target, target_label) in stubs {
+            self.asm.bind(label);
+            self.asm.mov_imm(Reg::Rsi, target as u64);
+            self.asm.call(self.slow_branch);
+            self.asm.jmp(target_label);
+        }
+
+        self.asm.align(4);
+        let tables = ::core::mem::replace(&mut self.jump_tables, Vec::new());
+        for (label, start, len) in tables {
+            self.asm.bind(label);
+            for i in 0..len {
+                let target = read_u32(&self.code[start + i * 4..]);
+                self.asm.emit_u32(target);
+            }
+        }
+    }
+
+    /// Fails unless there are at least `n` values on the stack.
+    fn need(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let trap = self.trap(Trap::Bounds);
+        self.asm.lea(Reg::Rax, vs(-8 * n as i32));
+        self.asm.alu_load(Alu::Cmp, Size::S64, Reg::Rax, state(STACK_BASE));
+        self.asm.jcc(Cond::B, trap);
+    }
+
+    /// Fails unless the stack has room for another value.
+    fn room(&mut self) {
+        let trap = self.trap(Trap::Bounds);
+        self.asm.alu_load(Alu::Cmp, Size::S64, VSP, state(STACK_END));
+        self.asm.jcc(Cond::AE, trap);
+    }
+
+    /// Pushes rax, after `room`.
+    fn push_rax(&mut self) {
+        self.asm.store(Size::S64, vs(0), Reg::Rax);
+        self.asm.alu_imm(Alu::Add, Size::S64, VSP, 8);
+    }
+
+    fn drop_values(&mut self, n: usize) {
+        self.asm.alu_imm(Alu::Sub, Size::S64, VSP, 8 * n as i32);
+    }
+
+    /// Writes rax as the result of an operation on `int`.
+    fn extend_result(&mut self, int: Int) {
+        if int == Int::I32 {
+            self.asm.sign_extend_32(Reg::Rax, Reg::Rax);
+        }
+    }
+
+    /// Counts a branch to the code at `target`, whose address is in rax,
+    /// and jumps there. The target offset is in rsi.
+    fn dynamic_branch(&mut self) {
+        let counted = self.asm.new_label();
+        self.asm.alu_imm_mem(Alu::Sub, Size::S64, state(BUDGET), 1);
+        self.asm.jcc(Cond::AE, counted);
+        self.asm.call(self.slow_branch);
+        self.asm.bind(counted);
+        self.asm.jmp_reg(Reg::Rax);
+    }
+
+    /// Counts a branch to the opcode at `target` and jumps there.
+    fn branch(&mut self, target: usize) {
+        let target_label = match self.label_of(target) {
+            Some(v) => v,
+            None => {
+                let trap = self.trap(Trap::Bounds);
+                self.asm.jmp(trap);
+                return;
+            }
+        };
+
+        let stub = self.asm.new_label();
+        self.branch_stubs.push((stub, target, target_label));
+        self.asm.alu_imm_mem(Alu::Sub, Size::S64, state(BUDGET), 1);
+        self.asm.jcc(Cond::B, stub);
+        self.asm.jmp(target_label);
+    }
+
+    /// Looks up the code of the opcode at the offset in rsi into rax, and
+    /// fails if there is none.
+    fn lookup_target(&mut self) {
+        let trap = self.trap(Trap::Bounds);
+        self.asm.alu_load(Alu::Cmp, Size::S64, Reg::Rsi, state(N_TARGETS));
+        self.asm.jcc(Cond::AE, trap);
+        self.asm.load(Size::S64, Reg::Rax, state(TARGETS));
+        self.asm.load(Size::S32, Reg::Rax, mem_index(Reg::Rax, Reg::Rsi, 4, 0));
+        self.asm.test(Size::S32, Reg::Rax, Reg::Rax);
+        self.asm.jcc(Cond::E, trap);
+        self.asm.alu_load(Alu::Add, Size::S64, Reg::Rax, state(CODE_BASE));
+    }
+
+    /// Calls a helper with `arg` in rdx, and stops if it fails.
+    fn call_helper(&mut self, helper: i32) {
+        self.asm.store(Size::S64, state(SP), VSP);
+        self.asm.store(Size::S64, state(CSP), CSP_REG);
+        self.asm.mov(Size::S64, Reg::Rdi, STATE);
+        self.asm.mov_imm(Reg::Rsi, self.ip as u64);
+        self.asm.call_mem(state(helper));
+        self.asm.test(Size::S64, Reg::Rax, Reg::Rax);
+        self.asm.jcc(Cond::NE, self.stop);
+
+        // Helpers can change the stack and move memory.
+        self.asm.load(Size::S64, VSP, state(SP));
+        self.asm.load(Size::S64, CSP_REG, state(CSP));
+        self.asm.load(Size::S64, MEM, state(MEM_BASE));
+        self.asm.load(Size::S64, MEM_LEN_REG, state(MEM_LEN));
+    }
+
+    /// Puts the address of local `id` into rdx.
+    fn local_address(&mut self, id: usize) {
+        let trap = self.trap(Trap::Bounds);
+        if id > MAX_SCALED_IMMEDIATE {
+            self.asm.jmp(trap);
+            return;
+        }
+
+        // Frame from the top: return ip, number of locals, locals
+        self.asm.lea(Reg::Rax, mem(CSP_REG, -16));
+        self.asm.alu_load(Alu::Cmp, Size::S64, Reg::Rax, state(CALL_STACK_BASE));
+        self.asm.jcc(Cond::B, trap);
+        self.asm.load(Size::S64, Reg::Rcx, mem(CSP_REG, -16));
+        self.asm.alu_imm(Alu::Cmp, Size::S64, Reg::Rcx, id as i32);
+        self.asm.jcc(Cond::BE, trap);
+        self.asm.unary(Unary::Neg, Size::S64, Reg::Rcx);
+        self.asm.lea(Reg::Rdx, mem_index(CSP_REG, Reg::Rcx, 8, id as i32 * 8 - 16));
+    }
+
+    /// Puts the address of the slot in rcx into rdx.
+    fn slot_address(&mut self) {
+        let trap = self.trap(Trap::Bounds);
+        self.asm.alu_load(Alu::Cmp, Size::S64, Reg::Rcx, state(SLOTS_LEN));
+        self.asm.jcc(Cond::AE, trap);
+        self.asm.load(Size::S64, Reg::Rdx, state(SLOTS_BASE));
+        self.asm.lea(Reg::Rdx, mem_index(Reg::Rdx, Reg::Rcx, 8, 0));
+    }
+
+    /// Puts the memory address of a `size` byte access at the address in
+    /// the stack at `addr` into rax.
+    fn memory_address(&mut self, addr: i32, size: i32) {
+        let trap = self.trap(Trap::Bounds);
+        let offset = self.imm_u32(0);
+
+        self.asm.load(Size::S32, Reg::Rax, vs(addr));
+        if offset != 0 {
+            self.asm.mov_imm(Reg::Rcx, offset as u64);
+            self.asm.alu(Alu::Add, Size::S64, Reg::Rax, Reg::Rcx);
+        }
+        self.asm.lea(Reg::Rcx, mem(Reg::Rax, size));
+        self.asm.alu(Alu::Cmp, Size::S64, Reg::Rcx, MEM_LEN_REG);
+        self.asm.jcc(Cond::A, trap);
+    }
+
+    fn load(&mut self, size: i32, signed: bool) {
+        self.need(1);
+        self.memory_address(-8, size);
+
+        let m = mem_index(MEM, Reg::Rax, 1, 0);
+        match (size, signed) {
+            (1, false) => self.asm.load_u8(Reg::Rax, m),
+            (1, true) => self.asm.load_i8(Reg::Rax, m),
+            (2, false) => self.asm.load_u16(Reg::Rax, m),
+            (2, true) => self.asm.load_i16(Reg::Rax, m),
+            (4, false) => self.asm.load(Size::S32, Reg::Rax, m),
+            (4, true) => self.asm.load_i32(Reg::Rax, m),
+            _ => self.asm.load(Size::S64, Reg::Rax, m)
+        }
+        self.asm.store(Size::S64, vs(-8), Reg::Rax);
+    }
+
+    fn store(&mut self, size: i32) {
+        self.need(2);
+        self.memory_address(-16, size);
+
+        let width = match size {
+            1 => Size::S8,
+            2 => Size::S16,
+            4 => Size::S32,
+            _ => Size::S64
+        };
+        self.asm.load(Size::S64, Reg::Rcx, vs(-8));
+        self.asm.store(width, mem_index(MEM, Reg::Rax, 1, 0), Reg::Rcx);
+        self.drop_values(2);
+    }
+
+    /// An operation on the two values at the top, with the result in rax.
+    fn binop<F: FnOnce(&mut Assembler, Size)>(&mut self, int: Int, f: F) {
+        let size = int.size();
+        self.need(2);
+        self.asm.load(size, Reg::Rax, vs(-16));
+        f(&mut self.asm, size);
+        self.extend_result(int);
+        self.asm.store(Size::S64, vs(-16), Reg::Rax);
+        self.drop_values(1);
+    }
+
+    fn alu_op(&mut self, int: Int, op: Alu) {
+        self.binop(int, |a, size| a.alu_load(op, size, Reg::Rax, vs(-8)));
+    }
+
+    fn shift(&mut self, int: Int, op: Shift) {
+        self.binop(int, |a, size| {
+            a.load(Size::S32, Reg::Rcx, vs(-8));
+            a.shift_cl(op, size, Reg::Rax);
+        });
+    }
+
+    fn relop(&mut self, int: Int, cond: Cond) {
+        self.binop(Int::I64, |a, _| {
+            a.alu_load(Alu::Cmp, int.size(), Reg::Rax, vs(-8));
+            a.setcc(cond, Reg::Rax);
+            a.zero_extend_8(Reg::Rax, Reg::Rax);
+        });
+    }
+
+    /// Division and remainder, which trap on division by zero. Signed
+    /// division by -1 is done without `idiv`, which would fault on an
+    /// overflow; the interpreter wraps.
+    fn divide(&mut self, int: Int, signed: bool, rem: bool) {
+        let size = int.size();
+        let trap = self.trap(Trap::Generic);
+        self.need(2);
+
+        self.asm.load(size, Reg::Rcx, vs(-8));
+        self.asm.test(size, Reg::Rcx, Reg::Rcx);
+        self.asm.jcc(Cond::E, trap);
+        self.asm.load(size, Reg::Rax, vs(-16));
+
+        if signed {
+            let general = self.asm.new_label();
+            let done = self.asm.new_label();
+
+            self.asm.alu_imm(Alu::Cmp, size, Reg::Rcx, -1);
+            self.asm.jcc(Cond::NE, general);
+            if rem {
+                self.asm.alu(Alu::Xor, Size::S32, Reg::Rax, Reg::Rax);
+            } else {
+                self.asm.unary(Unary::Neg, size, Reg::Rax);
+            }
+            self.asm.jmp(done);
+
+            self.asm.bind(general);
+            self.asm.sign_extend_ax(size);
+            self.asm.unary(Unary::Idiv, size, Reg::Rcx);
+            if rem {
+                self.asm.mov(size, Reg::Rax, Reg::Rdx);
+            }
+            self.asm.bind(done);
+        } else {
+            self.asm.alu(Alu::Xor, Size::S32, Reg::Rdx, Reg::Rdx);
+            self.asm.unary(Unary::Div, size, Reg::Rcx);
+            if rem {
+                self.asm.mov(size, Reg::Rax, Reg::Rdx);
+            }
+        }
+
+        self.extend_result(int);
+        self.asm.store(Size::S64, vs(-16), Reg::Rax);
+        self.drop_values(1);
+    }
+
+    /// An operation on the value at the top, which it loads into rax itself.
+    fn unop<F: FnOnce(&mut Assembler)>(&mut self, f: F) {
+        self.need(1);
+        f(&mut self.asm);
+        self.asm.store(Size::S64, vs(-8), Reg::Rax);
+    }
+
+    fn clz(&mut self, int: Int) {
+        let size = int.size();
+        let bits: i32 = if size == Size::S64 { 63 } else { 31 };
+        self.unop(|a| {
+            a.load(size, Reg::Rax, vs(-8));
+            a.mov_imm(Reg::Rcx, !0);
+            a.bsr(size, Reg::Rax, Reg::Rax);
+            a.cmov(Cond::E, size, Reg::Rax, Reg::Rcx);
+            a.unary(Unary::Neg, size, Reg::Rax);
+            a.alu_imm(Alu::Add, size, Reg::Rax, bits);
+        });
+    }
+
+    fn ctz(&mut self, int: Int) {
+        let size = int.size();
+        let bits: u64 = if size == Size::S64 { 64 } else { 32 };
+        self.unop(|a| {
+            a.load(size, Reg::Rax, vs(-8));
+            a.mov_imm(Reg::Rcx, bits);
+            a.bsf(size, Reg::Rax, Reg::Rax);
+            a.cmov(Cond::E, size, Reg::Rax, Reg::Rcx);
+        });
+    }
+
+    /// Counts bits without `popcnt`, which older CPUs lack.
+    fn popcnt(&mut self, int: Int) {
+        let size = int.size();
+        self.unop(|a| {
+            a.load(size, Reg::Rax, vs(-8));
+
+            a.mov(Size::S64, Reg::Rdx, Reg::Rax);
+            a.shift_imm(Shift::Shr, Size::S64, Reg::Rdx, 1);
+            a.mov_imm(Reg::Rcx, 0x5555_5555_5555_5555);
+            a.alu(Alu::And, Size::S64, Reg::Rdx, Reg::Rcx);
+            a.alu(Alu::Sub, Size::S64, Reg::Rax, Reg::Rdx);
+
+            a.mov_imm(Reg::Rcx, 0x3333_3333_3333_3333);
+            a.mov(Size::S64, Reg::Rdx, Reg::Rax);
+            a.alu(Alu::And, Size::S64, Reg::Rax, Reg::Rcx);
+            a.shift_imm(Shift::Shr, Size::S64, Reg::Rdx, 2);
+            a.alu(Alu::And, Size::S64, Reg::Rdx, Reg::Rcx);
+            a.alu(Alu::Add, Size::S64, Reg::Rax, Reg::Rdx);
+
+            a.mov(Size::S64, Reg::Rdx, Reg::Rax);
+            a.shift_imm(Shift::Shr, Size::S64, Reg::Rdx, 4);
+            a.alu(Alu::Add, Size::S64, Reg::Rax, Reg::Rdx);
+            a.mov_imm(Reg::Rcx, 0x0f0f_0f0f_0f0f_0f0f);
+            a.alu(Alu::And, Size::S64, Reg::Rax, Reg::Rcx);
+
+            a.mov_imm(Reg::Rcx, 0x0101_0101_0101_0101);
+            a.imul(Size::S64, Reg::Rax, Reg::Rcx);
+            a.shift_imm(Shift::Shr, Size::S64, Reg::Rax, 56);
+        });
+    }
+
+    fn constant(&mut self, v: u64) {
+        self.room();
+        self.asm.mov_imm(Reg::Rax, v);
+        self.push_rax();
+    }
+
+    fn call(&mut self) {
+        let n_args = self.imm_u32(0) as usize;
+        let trap = self.trap(Trap::Bounds);
+        if n_args > MAX_SCALED_IMMEDIATE {
+            self.asm.jmp(trap);
+            return;
+        }
+        let args_disp = -16 - 8 * n_args as i32;
+
+        // Target and number of locals, then the arguments
+        self.need(n_args + 2);
+        self.asm.load(Size::S64, Reg::R8, vs(-8));
+        self.asm.load(Size::S64, Reg::Rsi, vs(-16));
+        self.lookup_target();
+
+        // Room for the frame
+        self.asm.load(Size::S64, Reg::Rdx, state(CALL_STACK_END));
+        self.asm.alu(Alu::Sub, Size::S64, Reg::Rdx, CSP_REG);
+        self.asm.shift_imm(Shift::Shr, Size::S64, Reg::Rdx, 3);
+        self.asm.alu_imm(Alu::Sub, Size::S64, Reg::Rdx, n_args as i32 + 2);
+        self.asm.jcc(Cond::B, trap);
+        self.asm.alu(Alu::Cmp, Size::S64, Reg::R8, Reg::Rdx);
+        self.asm.jcc(Cond::A, trap);
+
+        self.asm.mov(Size::S64, Reg::R9, Reg::Rsi);
+        self.asm.mov(Size::S64, Reg::R10, Reg::Rax);
+        self.asm.mov(Size::S64, Reg::Rdi, CSP_REG);
+
+        // Arguments, then zeroed locals
+        if n_args != 0 {
+            self.asm.lea(Reg::Rsi, vs(args_disp));
+            self.asm.mov_imm(Reg::Rcx, n_args as u64);
+            self.asm.rep_movsq();
+        }
+        self.asm.lea(VSP, vs(args_disp));
+        self.asm.alu(Alu::Xor, Size::S32, Reg::Rax, Reg::Rax);
+        self.asm.mov(Size::S64, Reg::Rcx, Reg::R8);
+        self.asm.rep_stosq();
+
+        // Number of locals and the return ip
+        self.asm.lea(Reg::Rax, mem(Reg::R8, n_args as i32));
+        self.asm.store(Size::S64, mem(Reg::Rdi, 0), Reg::Rax);
+        let return_ip = self.ip + 5;
+        self.asm.mov_imm(Reg::Rax, return_ip as u64);
+        self.asm.store(Size::S64, mem(Reg::Rdi, 8), Reg::Rax);
+        self.asm.lea(CSP_REG, mem(Reg::Rdi, 16));
+
+        self.asm.mov(Size::S64, Reg::Rsi, Reg::R9);
+        self.asm.mov(Size::S64, Reg::Rax, Reg::R10);
+        self.dynamic_branch();
+    }
+
+    fn ret(&mut self) {
+        let trap = self.trap(Trap::Bounds);
+
+        self.asm.lea(Reg::Rax, mem(CSP_REG, -16));
+        self.asm.alu_load(Alu::Sub, Size::S64, Reg::Rax, state(CALL_STACK_BASE));
+        self.asm.jcc(Cond::B, trap);
+        self.asm.load(Size::S64, Reg::Rsi, mem(CSP_REG, -8));
+        self.asm.load(Size::S64, Reg::Rcx, mem(CSP_REG, -16));
+        self.asm.shift_imm(Shift::Shr, Size::S64, Reg::Rax, 3);
+        self.asm.alu(Alu::Cmp, Size::S64, Reg::Rcx, Reg::Rax);
+        self.asm.jcc(Cond::A, trap);
+
+        self.asm.unary(Unary::Neg, Size::S64, Reg::Rcx);
+        self.asm.lea(CSP_REG, mem_index(CSP_REG, Reg::Rcx, 8, -16));
+
+        self.lookup_target();
+        self.dynamic_branch();
+    }
+
+    fn jmp_table(&mut self) {
+        let default = self.imm_u32(0) as usize;
+        let len = self.imm_u32(1) as usize;
+        let default_label = self.asm.new_label();
+
+        self.need(1);
+        self.drop_values(1);
+        if len != 0 {
+            let table = self.asm.new_label();
+            self.jump_tables.push((table, self.ip + 9, len));
+
+            self.asm.load(Size::S64, Reg::Rax, vs(0));
+            self.asm.mov_imm(Reg::Rcx, len as u64);
+            self.asm.alu(Alu::Cmp, Size::S64, Reg::Rax, Reg::Rcx);
+            self.asm.jcc(Cond::AE, default_label);
+            self.asm.lea_label(Reg::Rcx, table);
+            self.asm.load(Size::S32, Reg::Rsi, mem_index(Reg::Rcx, Reg::Rax, 4, 0));
+            self.lookup_target();
+            self.dynamic_branch();
+        }
+
+        self.asm.bind(default_label);
+        self.branch(default);
+    }
+
+    fn emit_op(&mut self, op: Opcode) {
+        match op {
+            Opcode::Drop => {
+                self.need(1);
+                self.drop_values(1);
+            },
+            Opcode::Dup => {
+                self.need(1);
+                self.room();
+                self.asm.load(Size::S64, Reg::Rax, vs(-8));
+                self.push_rax();
+            },
+            Opcode::Swap2 => {
+                self.need(2);
+                self.asm.load(Size::S64, Reg::Rax, vs(-16));
+                self.asm.load(Size::S64, Reg::Rcx, vs(-8));
+                self.asm.store(Size::S64, vs(-16), Reg::Rcx);
+                self.asm.store(Size::S64, vs(-8), Reg::Rax);
+            },
+            Opcode::Select => {
+                self.need(3);
+                self.asm.load(Size::S64, Reg::Rax, vs(-24));
+                self.asm.load(Size::S64, Reg::Rcx, vs(-16));
+                self.asm.alu_imm_mem(Alu::Cmp, Size::S64, vs(-8), 0);
+                self.asm.cmov(Cond::E, Size::S64, Reg::Rax, Reg::Rcx);
+                self.asm.store(Size::S64, vs(-24), Reg::Rax);
+                self.drop_values(2);
+            },
+
+            Opcode::Call => self.call(),
+            Opcode::Return => self.ret(),
+            Opcode::Halt => {
+                self.asm.store(Size::S64, state(SP), VSP);
+                self.asm.store(Size::S64, state(CSP), CSP_REG);
+                self.asm.mov_imm(Reg::Rax, self.ip as u64);
+                self.asm.store(Size::S64, state(IP), Reg::Rax);
+                self.asm.mov_imm(Reg::Rax, EXIT_HALT);
+                self.asm.jmp(self.epilogue);
+            },
+
+            Opcode::GetLocal => {
+                let id = self.imm_u32(0) as usize;
+                self.room();
+                self.local_address(id);
+                self.asm.load(Size::S64, Reg::Rax, mem(Reg::Rdx, 0));
+                self.push_rax();
+            },
+            Opcode::SetLocal => {
+                let id = self.imm_u32(0) as usize;
+                self.need(1);
+                self.local_address(id);
+                self.asm.load(Size::S64, Reg::Rax, vs(-8));
+                self.asm.store(Size::S64, mem(Reg::Rdx, 0), Reg::Rax);
+                self.drop_values(1);
+            },
+            Opcode::TeeLocal => {
+                let id = self.imm_u32(0) as usize;
+                self.need(1);
+                self.local_address(id);
+                self.asm.load(Size::S64, Reg::Rax, vs(-8));
+                self.asm.store(Size::S64, mem(Reg::Rdx, 0), Reg::Rax);
+            },
+
+            Opcode::GetSlotIndirect => {
+                self.need(1);
+                self.asm.load(Size::S64, Reg::Rcx, vs(-8));
+                self.slot_address();
+                self.asm.load(Size::S64, Reg::Rax, mem(Reg::Rdx, 0));
+                self.asm.store(Size::S64, vs(-8), Reg::Rax);
+            },
+            Opcode::GetSlot => {
+                let id = self.imm_u32(0);
+                self.room();
+                self.asm.mov_imm(Reg::Rcx, id as u64);
+                self.slot_address();
+                self.asm.load(Size::S64, Reg::Rax, mem(Reg::Rdx, 0));
+                self.push_rax();
+            },
+            Opcode::SetSlot => {
+                let id = self.imm_u32(0);
+                self.need(1);
+                self.asm.mov_imm(Reg::Rcx, id as u64);
+                self.slot_address();
+                self.asm.load(Size::S64, Reg::Rax, vs(-8));
+                self.asm.store(Size::S64, mem(Reg::Rdx, 0), Reg::Rax);
+                self.drop_values(1);
+            },
+            Opcode::ResetSlots => {
+                let n = self.imm_u32(0);
+                self.asm.mov_imm(Reg::Rdx, n as u64);
+                self.call_helper(HELPER_RESET_SLOTS);
+            },
+
+            Opcode::NativeInvoke => {
+                let id = self.imm_u32(0);
+                self.asm.mov_imm(Reg::Rdx, id as u64);
+                self.call_helper(HELPER_NATIVE_INVOKE);
+            },
+
+            Opcode::CurrentMemory => {
+                self.room();
+                self.asm.mov(Size::S64, Reg::Rax, MEM_LEN_REG);
+                self.push_rax();
+            },
+            Opcode::GrowMemory => {
+                self.need(1);
+                self.asm.load(Size::S64, Reg::Rdx, vs(-8));
+                self.asm.store(Size::S64, vs(-8), MEM_LEN_REG);
+                self.call_helper(HELPER_GROW_MEMORY);
+            },
+
+            Opcode::Nop => {},
+            Opcode::Unreachable | Opcode::NotSupported | Opcode::Never => {
+                let trap = self.trap(Trap::Generic);
+                self.asm.jmp(trap);
+            },
+
+            Opcode::Jmp => {
+                let target = self.imm_u32(0) as usize;
+                self.branch(target);
+            },
+            Opcode::JmpIf => {
+                let target = self.imm_u32(0) as usize;
+                let next = self.asm.new_label();
+                self.need(1);
+                self.drop_values(1);
+                self.asm.alu_imm_mem(Alu::Cmp, Size::S64, vs(0), 0);
+                self.asm.jcc(Cond::E, next);
+                self.branch(target);
+                self.asm.bind(next);
+            },
+            Opcode::JmpEither => {
+                let target_a = self.imm_u32(0) as usize;
+                let target_b = self.imm_u32(1) as usize;
+                let else_label = self.asm.new_label();
+                self.need(1);
+                self.drop_values(1);
+                self.asm.alu_imm_mem(Alu::Cmp, Size::S64, vs(0), 0);
+                self.asm.jcc(Cond::E, else_label);
+                self.branch(target_a);
+                self.asm.bind(else_label);
+                self.branch(target_b);
+            },
+            Opcode::JmpTable => self.jmp_table(),
+
+            Opcode::I32Load => self.load(4, false),
+            Opcode::I32Load8U => self.load(1, false),
+            Opcode::I32Load8S => self.load(1, true),
+            Opcode::I32Load16U => self.load(2, false),
+            Opcode::I32Load16S => self.load(2, true),
+            Opcode::I32Store => self.store(4),
+            Opcode::I32Store8 => self.store(1),
+            Opcode::I32Store16 => self.store(2),
+
+            Opcode::I32Const => {
+                let v = self.imm_u32(0);
+                self.constant(v as u64);
+            },
+            Opcode::I32Ctz => self.ctz(Int::I32),
+            Opcode::I32Clz => self.clz(Int::I32),
+            Opcode::I32Popcnt => self.popcnt(Int::I32),
+            Opcode::I32Add => self.alu_op(Int::I32, Alu::Add),
+            Opcode::I32Sub => self.alu_op(Int::I32, Alu::Sub),
+            Opcode::I32Mul => self.binop(Int::I32, |a, size| a.imul_load(size, Reg::Rax, vs(-8))),
+            Opcode::I32DivU => self.divide(Int::U32, false, false),
+            Opcode::I32DivS => self.divide(Int::I32, true, false),
+            Opcode::I32RemU => self.divide(Int::U32, false, true),
+            Opcode::I32RemS => self.divide(Int::I32, true, true),
+            Opcode::I32And => self.alu_op(Int::U32, Alu::And),
+            Opcode::I32Or => self.alu_op(Int::U32, Alu::Or),
+            Opcode::I32Xor => self.alu_op(Int::U32, Alu::Xor),
+            Opcode::I32Shl => self.shift(Int::U32, Shift::Shl),
+            Opcode::I32ShrU => self.shift(Int::U32, Shift::Shr),
+            Opcode::I32ShrS => self.shift(Int::I32, Shift::Sar),
+            Opcode::I32Rotl => self.shift(Int::U32, Shift::Rol),
+            Opcode::I32Rotr => self.shift(Int::U32, Shift::Ror),
+
+            Opcode::I32Eq => self.relop(Int::U32, Cond::E),
+            Opcode::I32Ne => self.relop(Int::U32, Cond::NE),
+            Opcode::I32LtU => self.relop(Int::U32, Cond::B),
+            Opcode::I32LtS => self.relop(Int::U32, Cond::L),
+            Opcode::I32LeU => self.relop(Int::U32, Cond::BE),
+            Opcode::I32LeS => self.relop(Int::U32, Cond::LE),
+            Opcode::I32GtU => self.relop(Int::U32, Cond::A),
+            Opcode::I32GtS => self.relop(Int::U32, Cond::G),
+            Opcode::I32GeU => self.relop(Int::U32, Cond::AE),
+            Opcode::I32GeS => self.relop(Int::U32, Cond::GE),
+
+            Opcode::I32WrapI64 | Opcode::I64ExtendI32U => self.unop(|a| {
+                a.load(Size::S32, Reg::Rax, vs(-8));
+            }),
+
+            Opcode::I64Load => self.load(8, false),
+            Opcode::I64Load8U => self.load(1, false),
+            Opcode::I64Load8S => self.load(1, true),
+            Opcode::I64Load16U => self.load(2, false),
+            Opcode::I64Load16S => self.load(2, true),
+            Opcode::I64Load32U => self.load(4, false),
+            Opcode::I64Load32S => self.load(4, true),
+            Opcode::I64Store => self.store(8),
+            Opcode::I64Store8 => self.store(1),
+            Opcode::I64Store16 => self.store(2),
+            Opcode::I64Store32 => self.store(4),
+
+            Opcode::I64Const => {
+                let v = read_u64(&self.code[self.ip + 1..]);
+                self.constant(v);
+            },
+            Opcode::I64Ctz => self.ctz(Int::I64),
+            Opcode::I64Clz => self.clz(Int::I64),
+            Opcode::I64Popcnt => self.popcnt(Int::I64),
+            Opcode::I64Add => self.alu_op(Int::I64, Alu::Add),
+            Opcode::I64Sub => self.alu_op(Int::I64, Alu::Sub),
+            Opcode::I64Mul => self.binop(Int::I64, |a, size| a.imul_load(size, Reg::Rax, vs(-8))),
+            Opcode::I64DivU => self.divide(Int::I64, false, false),
+            Opcode::I64DivS => self.divide(Int::I64, true, false),
+            Opcode::I64RemU => self.divide(Int::I64, false, true),
+            Opcode::I64RemS => self.divide(Int::I64, true, true),
+            Opcode::I64And => self.alu_op(Int::I64, Alu::And),
+            Opcode::I64Or => self.alu_op(Int::I64, Alu::Or),
+            Opcode::I64Xor => self.alu_op(Int::I64, Alu::Xor),
+            Opcode::I64Shl => self.shift(Int::I64, Shift::Shl),
+            Opcode::I64ShrU => self.shift(Int::I64, Shift::Shr),
+            Opcode::I64ShrS => self.shift(Int::I64, Shift::Sar),
+            Opcode::I64Rotl => self.shift(Int::I64, Shift::Rol),
+            Opcode::I64Rotr => self.shift(Int::I64, Shift::Ror),
+
+            Opcode::I64Eq => self.relop(Int::I64, Cond::E),
+            Opcode::I64Ne => self.relop(Int::I64, Cond::NE),
+            Opcode::I64LtU => self.relop(Int::I64, Cond::B),
+            Opcode::I64LtS => self.relop(Int::I64, Cond::L),
+            Opcode::I64LeU => self.relop(Int::I64, Cond::BE),
+            Opcode::I64LeS => self.relop(Int::I64, Cond::LE),
+            Opcode::I64GtU => self.relop(Int::I64, Cond::A),
+            Opcode::I64GtS => self.relop(Int::I64, Cond::G),
+            Opcode::I64GeU => self.relop(Int::I64, Cond::AE),
+            Opcode::I64GeS => self.relop(Int::I64, Cond::GE),
+
+            Opcode::I64ExtendI32S => self.unop(|a| {
+                a.load_i32(Reg::Rax, vs(-8));
+            })
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use hexagon_e::environment::Environment;
+    use hexagon_e::error::{ExecuteError, ExecuteResult};
+    use hexagon_e::module::Module;
+    use hexagon_e::tape::Tape;
+    use hexagon_e::vm::VirtualMachine;
+
+    const STACK_LEN: usize = 64;
+    const CALL_STACK_LEN: usize = 64;
+    const MEMORY_LEN: usize = 64;
+    const MAX_MEM: usize = 256;
+    const MAX_SLOTS: usize = 8;
+
+    /// Native invoke with two arguments and a result
+    const INVOKE: u32 = 3;
+
+    /// Native invoke without arguments or result
+    const INVOKE_NOTHING: u32 = 4;
+
+    fn invoke(a: i64, b: i64) -> i64 {
+        a.wrapping_mul(3).wrapping_add(b)
+    }
+
+    struct Asm {
+        code: Vec<u8>
+    }
+
+    impl Asm {
+        fn new() -> Asm {
+            Asm { code: Vec::new() }
+        }
+
+        fn op(&mut self, op: Opcode, imm: &[u32]) -> u32 {
+            let offset = self.code.len() as u32;
+            self.code.push(op as u8);
+            for v in imm {
+                for i in 0..4 {
+                    self.code.push((v >> (i * 8)) as u8);
+                }
+            }
+            offset
+        }
+
+        fn i64_const(&mut self, v: i64) {
+            self.op(Opcode::I64Const, &[v as u32, (v >> 32) as u32]);
+        }
+
+        fn patch(&mut self, offset: u32, n: usize, v: u32) {
+            for i in 0..4 {
+                self.code[offset as usize + 1 + n * 4 + i] = (v >> (i * 8)) as u8;
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Outcome {
+        Halted { stack: Vec<i64>, mem: Vec<u8>, slots: Vec<i64>, branches: u64 },
+        Trapped(Trap)
+    }
+
+    struct VmEnv<'a> {
+        mem: Vec<u8>,
+        slots: Vec<i64>,
+        stack: Tape<'a, Cell<i64>>,
+        call_stack: Tape<'a, Cell<i64>>,
+        compiled: &'a Compiled,
+        branches: Cell<u64>
+    }
+
+    impl<'a> Environment for VmEnv<'a> {
+        fn get_memory(&self) -> &[u8] {
+            &self.mem
+        }
+
+        fn get_memory_mut(&mut self) -> &mut [u8] {
+            &mut self.mem
+        }
+
+        fn grow_memory(&mut self, len_inc: usize) -> ExecuteResult<()> {
+            let new_len = self.mem.len() + len_inc;
+            if new_len < self.mem.len() || new_len > MAX_MEM {
+                return Err(ExecuteError::Generic);
+            }
+            self.mem.resize(new_len, 0);
+            Ok(())
+        }
+
+        fn get_slots(&self) -> &[i64] {
+            &self.slots
+        }
+
+        fn get_slots_mut(&mut self) -> &mut [i64] {
+            &mut self.slots
+        }
+
+        fn reset_slots(&mut self, len: usize) -> ExecuteResult<()> {
+            if len > MAX_SLOTS {
+                return Err(ExecuteError::Generic);
+            }
+            self.slots = vec! [ 0; len ];
+            Ok(())
+        }
+
+        fn get_stack(&self) -> &Tape<Cell<i64>> {
+            &self.stack
+        }
+
+        fn get_call_stack(&self) -> &Tape<Cell<i64>> {
+            &self.call_stack
+        }
+
+        fn do_native_invoke(&mut self, id: usize) -> ExecuteResult<Option<i64>> {
+            if id == INVOKE_NOTHING as usize {
+                return Ok(None);
+            }
+            let args = self.stack.prev_many(2)?;
+            Ok(Some(invoke(args[0].get(), args[1].get())))
+        }
+
+        fn trace_branch(&self, target: usize) -> ExecuteResult<()> {
+            // Like the backend, which checks the targets of calls.
+            if self.compiled.start_of(target).is_none() {
+                return Err(ExecuteError::Bounds);
+            }
+            self.branches.set(self.branches.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn run_vm(code: &[u8], compiled: &Compiled, args: &[i64]) -> Outcome {
+        let stack = vec! [ Cell::new(0i64); STACK_LEN ];
+        let call_stack = vec! [ Cell::new(0i64); CALL_STACK_LEN ];
+        let env = VmEnv {
+            mem: vec! [ 0; MEMORY_LEN ],
+            slots: Vec::new(),
+            stack: Tape::from(&stack[..]),
+            call_stack: Tape::from(&call_stack[..]),
+            compiled: compiled,
+            branches: Cell::new(0)
+        };
+        for arg in args {
+            env.stack.next().unwrap().set(*arg);
+        }
+
+        let m = Module {
+            memory_initializers: &[],
+            code: code
+        };
+        let mut vm = VirtualMachine::new(&m, env);
+        match vm.run() {
+            Ok(()) => Outcome::Halted {
+                stack: stack[..vm.env.stack.get_pos()].iter().map(|v| v.get()).collect(),
+                mem: vm.env.mem.clone(),
+                slots: vm.env.slots.clone(),
+                branches: vm.env.branches.get()
+            },
+            Err(ExecuteError::Bounds) => Outcome::Trapped(Trap::Bounds),
+            Err(_) => Outcome::Trapped(Trap::Generic)
+        }
+    }
+
+    struct Runtime {
+        mem: Vec<u8>,
+        slots: Vec<i64>,
+        slots_reset: bool,
+
+        /// Budget to give on each call to `branch`
+        grant: u64,
+        last_grant: u64,
+        branches: u64
+    }
+
+    unsafe fn runtime<'a>(state: *mut JitState) -> &'a mut Runtime {
+        &mut *((*state).runtime as *mut Runtime)
+    }
+
+    unsafe fn fail(state: *mut JitState, ip: usize, trap: Trap) -> u64 {
+        (*state).ip = ip;
+        (*state).trap = trap as u64;
+        1
+    }
+
+    unsafe extern "C" fn branch(state: *mut JitState, _target: usize, _: u64) -> u64 {
+        let rt = runtime(state);
+        rt.branches += rt.last_grant + 1;
+        rt.last_grant = rt.grant;
+        (*state).budget = rt.grant;
+        0
+    }
+
+    unsafe extern "C" fn native_invoke(state: *mut JitState, ip: usize, id: u64) -> u64 {
+        let s = &mut *state;
+        if id == INVOKE_NOTHING as u64 {
+            return 0;
+        }
+        if (s.sp as usize - s.stack_base as usize) < 16 {
+            return fail(state, ip, Trap::Bounds);
+        }
+        s.sp = s.sp.offset(-2);
+        *s.sp = invoke(*s.sp, *s.sp.offset(1));
+        s.sp = s.sp.offset(1);
+        0
+    }
+
+    unsafe extern "C" fn grow_memory(state: *mut JitState, ip: usize, len_inc: u64) -> u64 {
+        let rt = runtime(state);
+        let new_len = rt.mem.len().wrapping_add(len_inc as usize);
+        if new_len < rt.mem.len() || new_len > MAX_MEM {
+            return fail(state, ip, Trap::Generic);
+        }
+        rt.mem.resize(new_len, 0);
+        (*state).mem_base = rt.mem.as_mut_ptr();
+        (*state).mem_len = rt.mem.len();
+        0
+    }
+
+    unsafe extern "C" fn reset_slots(state: *mut JitState, ip: usize, n: u64) -> u64 {
+        let rt = runtime(state);
+        if rt.slots_reset || n as usize > MAX_SLOTS {
+            return fail(state, ip, Trap::Generic);
+        }
+        rt.slots_reset = true;
+        rt.slots = vec! [ 0; n as usize ];
+        (*state).slots_base = rt.slots.as_mut_ptr();
+        (*state).slots_len = rt.slots.len();
+        0
+    }
+
+    extern "C" {
+        fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+        fn munmap(addr: *mut u8, len: usize) -> i32;
+    }
+
+    const PROT_RWX: i32 = 7;
+    const MAP_PRIVATE_ANONYMOUS: i32 = 0x22;
+
+    fn run_jit(compiled: &Compiled, args: &[i64], grant: u64) -> Outcome {
+        let mut stack = vec! [ 0i64; STACK_LEN ];
+        let mut call_stack = vec! [ 0i64; CALL_STACK_LEN ];
+        stack[..args.len()].copy_from_slice(args);
+
+        let mut rt = Runtime {
+            mem: vec! [ 0; MEMORY_LEN ],
+            slots: Vec::new(),
+            slots_reset: false,
+            grant: grant,
+            last_grant: 0,
+            branches: 0
+        };
+
+        unsafe {
+            let len = compiled.code.len();
+            let exec = mmap(::std::ptr::null_mut(), len, PROT_RWX, MAP_PRIVATE_ANONYMOUS, -1, 0);
+            assert!(exec as isize != -1);
+            ::std::ptr::copy_nonoverlapping(compiled.code.as_ptr(), exec, len);
+
+            let mut state = JitState {
+                mem_base: rt.mem.as_mut_ptr(),
+                mem_len: rt.mem.len(),
+                slots_base: rt.slots.as_mut_ptr(),
+                slots_len: 0,
+                stack_base: stack.as_mut_ptr(),
+                stack_end: stack.as_mut_ptr().offset(STACK_LEN as isize),
+                sp: stack.as_mut_ptr().offset(args.len() as isize),
+                call_stack_base: call_stack.as_mut_ptr(),
+                call_stack_end: call_stack.as_mut_ptr().offset(CALL_STACK_LEN as isize),
+                csp: call_stack.as_mut_ptr(),
+                budget: 0,
+                code_base: exec,
+                targets: compiled.targets.as_ptr(),
+                n_targets: compiled.targets.len(),
+                ip: 0,
+                trap: 0,
+                saved_rsp: 0,
+                branch: branch,
+                native_invoke: native_invoke,
+                grow_memory: grow_memory,
+                reset_slots: reset_slots,
+                runtime: &mut rt as *mut Runtime as *mut u8
+            };
+
+            let entry: Entry = ::std::mem::transmute(exec);
+            let start = exec.offset(compiled.start_of(0).unwrap() as isize);
+            let result = entry(&mut state, start);
+            munmap(exec, len);
+
+            match result {
+                EXIT_HALT => {
+                    let n = (state.sp as usize - stack.as_ptr() as usize) / 8;
+                    Outcome::Halted {
+                        stack: stack[..n].to_vec(),
+                        mem: rt.mem.clone(),
+                        slots: rt.slots.clone(),
+                        branches: rt.branches + rt.last_grant - state.budget
+                    }
+                },
+                _ => Outcome::Trapped(Trap::from_code(state.trap).unwrap())
+            }
+        }
+    }
+
+    /// Runs `code` on both and checks that they end the same way.
+    fn check(code: &[u8], args: &[i64]) -> Outcome {
+        let compiled = compile(code).unwrap();
+        let expected = run_vm(code, &compiled, args);
+        for &grant in &[0, 1, 5, 1000] {
+            assert_eq!(run_jit(&compiled, args, grant), expected, "budget {}", grant);
+        }
+        expected
+    }
+
+    fn halted_with(outcome: Outcome) -> Vec<i64> {
+        match outcome {
+            Outcome::Halted { stack, .. } => stack,
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn state_layout_matches_offsets() {
+        let null = ::std::ptr::null_mut();
+        let state = JitState {
+            mem_base: null as *mut u8,
+            mem_len: 0,
+            slots_base: null,
+            slots_len: 0,
+            stack_base: null,
+            stack_end: null,
+            sp: null,
+            call_stack_base: null,
+            call_stack_end: null,
+            csp: null,
+            budget: 0,
+            code_base: null as *const u8,
+            targets: null as *const u32,
+            n_targets: 0,
+            ip: 0,
+            trap: 0,
+            saved_rsp: 0,
+            branch: branch,
+            native_invoke: native_invoke,
+            grow_memory: grow_memory,
+            reset_slots: reset_slots,
+            runtime: null as *mut u8
+        };
+        let base = &state as *const JitState as usize;
+        assert_eq!(&state.mem_len as *const _ as usize - base, MEM_LEN as usize);
+        assert_eq!(&state.slots_len as *const _ as usize - base, SLOTS_LEN as usize);
+        assert_eq!(&state.csp as *const _ as usize - base, CSP as usize);
+        assert_eq!(&state.budget as *const _ as usize - base, BUDGET as usize);
+        assert_eq!(&state.n_targets as *const _ as usize - base, N_TARGETS as usize);
+        assert_eq!(&state.saved_rsp as *const _ as usize - base, SAVED_RSP as usize);
+        assert_eq!(&state.reset_slots as *const _ as usize - base, HELPER_RESET_SLOTS as usize);
+        assert_eq!(&state.runtime as *const _ as usize - base, HELPER_RESET_SLOTS as usize + 8);
+    }
+
+    /// Random straight-line arithmetic, with the interesting values of both
+    /// widths.
+    #[test]
+    fn arithmetic_matches_interpreter() {
+        let values: &[i64] = &[
+            0, 1, 2, 7, 31, 32, 33, 63, 64, -1, -2, -7,
+            0x7fff_ffff, 0x8000_0000, 0xffff_ffff, 0x1_0000_0000, -0x8000_0000,
+            ::std::i64::MAX, ::std::i64::MIN, 0x1234_5678_9abc_def0
+        ];
+        let binops = [
+            Opcode::I32Add, Opcode::I32Sub, Opcode::I32Mul, Opcode::I32DivU, Opcode::I32DivS,
+            Opcode::I32RemU, Opcode::I32RemS, Opcode::I32And, Opcode::I32Or, Opcode::I32Xor,
+            Opcode::I32Shl, Opcode::I32ShrU, Opcode::I32ShrS, Opcode::I32Rotl, Opcode::I32Rotr,
+            Opcode::I32Eq, Opcode::I32Ne, Opcode::I32LtU, Opcode::I32LtS, Opcode::I32LeU,
+            Opcode::I32LeS, Opcode::I32GtU, Opcode::I32GtS, Opcode::I32GeU, Opcode::I32GeS,
+            Opcode::I64Add, Opcode::I64Sub, Opcode::I64Mul, Opcode::I64DivU, Opcode::I64DivS,
+            Opcode::I64RemU, Opcode::I64RemS, Opcode::I64And, Opcode::I64Or, Opcode::I64Xor,
+            Opcode::I64Shl, Opcode::I64ShrU, Opcode::I64ShrS, Opcode::I64Rotl, Opcode::I64Rotr,
+            Opcode::I64Eq, Opcode::I64Ne, Opcode::I64LtU, Opcode::I64LtS, Opcode::I64LeU,
+            Opcode::I64LeS, Opcode::I64GtU, Opcode::I64GtS, Opcode::I64GeU, Opcode::I64GeS
+        ];
+        let unops = [
+            Opcode::I32Ctz, Opcode::I32Clz, Opcode::I32Popcnt, Opcode::I32WrapI64,
+            Opcode::I64Ctz, Opcode::I64Clz, Opcode::I64Popcnt,
+            Opcode::I64ExtendI32U, Opcode::I64ExtendI32S,
+            Opcode::Dup, Opcode::Drop, Opcode::Nop
+        ];
+
+        // xorshift, for the same programs on every run
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+
+        let mut n_halted = 0;
+        for _ in 0..3000 {
+            let mut asm = Asm::new();
+            let mut height = 0;
+            for _ in 0..24 {
+                match next(4) {
+                    _ if height < 2 => {
+                        let v = values[next(values.len())];
+                        if next(2) == 0 {
+                            asm.op(Opcode::I32Const, &[v as u32]);
+                        } else {
+                            asm.i64_const(v);
+                        }
+                        height += 1;
+                    },
+                    0 | 1 => {
+                        asm.op(binops[next(binops.len())], &[]);
+                        height -= 1;
+                    },
+                    2 => {
+                        let op = unops[next(unops.len())];
+                        asm.op(op, &[]);
+                        match op {
+                            Opcode::Dup => height += 1,
+                            Opcode::Drop => height -= 1,
+                            _ => {}
+                        }
+                    },
+                    _ => {
+                        if next(2) == 0 {
+                            asm.op(Opcode::Swap2, &[]);
+                        } else if height >= 3 {
+                            asm.op(Opcode::Select, &[]);
+                            height -= 2;
+                        }
+                    }
+                }
+            }
+            asm.op(Opcode::Halt, &[]);
+
+            if let Outcome::Halted { .. } = check(&asm.code, &[]) {
+                n_halted += 1;
+            }
+        }
+
+        // Most programs shouldn't trap on a division.
+        assert!(n_halted > 2000);
+    }
+
+    #[test]
+    fn memory_matches_interpreter() {
+        let loads = [
+            (Opcode::I32Load, 4), (Opcode::I32Load8U, 1), (Opcode::I32Load8S, 1),
+            (Opcode::I32Load16U, 2), (Opcode::I32Load16S, 2),
+            (Opcode::I64Load, 8), (Opcode::I64Load8U, 1), (Opcode::I64Load8S, 1),
+            (Opcode::I64Load16U, 2), (Opcode::I64Load16S, 2),
+            (Opcode::I64Load32U, 4), (Opcode::I64Load32S, 4)
+        ];
+        let stores = [
+            (Opcode::I32Store, 4), (Opcode::I32Store8, 1), (Opcode::I32Store16, 2),
+            (Opcode::I64Store, 8), (Opcode::I64Store8, 1), (Opcode::I64Store16, 2),
+            (Opcode::I64Store32, 4)
+        ];
+        let addrs: &[i64] = &[0, 1, 56, 60, 62, 63, 64, -1, 0x1_0000_0000, 0x1_0000_0038];
+
+        for &(store, size) in &stores {
+            for &addr in addrs {
+                for &offset in &[0u32, 3, 0xffff_ffff] {
+                    let mut asm = Asm::new();
+                    asm.i64_const(addr);
+                    asm.i64_const(0x8182_8384_8586_8788u64 as i64);
+                    asm.op(store, &[offset]);
+                    for &(load, load_size) in &loads {
+                        if load_size <= size {
+                            asm.i64_const(addr);
+                            asm.op(load, &[offset]);
+                        }
+                    }
+                    asm.op(Opcode::Halt, &[]);
+                    check(&asm.code, &[]);
+                }
+            }
+        }
+
+        let mut asm = Asm::new();
+        asm.op(Opcode::CurrentMemory, &[]);
+        asm.op(Opcode::I32Const, &[100]);
+        asm.op(Opcode::GrowMemory, &[]);
+        asm.op(Opcode::CurrentMemory, &[]);
+        asm.op(Opcode::I32Const, &[150]);
+        asm.op(Opcode::I32Const, &[9]);
+        asm.op(Opcode::I32Store8, &[0]);
+        asm.op(Opcode::Halt, &[]);
+        assert_eq!(halted_with(check(&asm.code, &[])), [64, 64, 164]);
+
+        asm.code.pop();
+        asm.op(Opcode::I32Const, &[100]);
+        asm.op(Opcode::GrowMemory, &[]);
+        asm.op(Opcode::Halt, &[]);
+        assert_eq!(check(&asm.code, &[]), Outcome::Trapped(Trap::Generic));
+    }
+
+    #[test]
+    fn check_stack_bounds() {
+        for op in &[Opcode::Drop, Opcode::Dup, Opcode::Swap2, Opcode::Select, Opcode::I32Add, Opcode::I64Clz, Opcode::JmpIf] {
+            let mut asm = Asm::new();
+            asm.op(*op, &[0][..immediate_len(op, &[]) / 4]);
+            asm.op(Opcode::Halt, &[]);
+            assert_eq!(check(&asm.code, &[]), Outcome::Trapped(Trap::Bounds));
+        }
+
+        // Pushes until the stack is full.
+        let mut asm = Asm::new();
+        asm.op(Opcode::I32Const, &[1]);
+        asm.op(Opcode::Jmp, &[0]);
+        assert_eq!(check(&asm.code, &[]), Outcome::Trapped(Trap::Bounds));
+
+        // Runs off the end.
+        let mut asm = Asm::new();
+        asm.op(Opcode::Nop, &[]);
+        assert_eq!(check(&asm.code, &[]), Outcome::Trapped(Trap::Bounds));
+
+        let mut asm = Asm::new();
+        asm.op(Opcode::Unreachable, &[]);
+        assert_eq!(check(&asm.code, &[]), Outcome::Trapped(Trap::Generic));
+    }
+
+    #[test]
+    fn slots_and_native_invokes() {
+        let mut asm = Asm::new();
+        asm.op(Opcode::ResetSlots, &[4]);
+        asm.op(Opcode::I32Const, &[5]);
+        asm.op(Opcode::SetSlot, &[3]);
+        asm.op(Opcode::I32Const, &[3]);
+        asm.op(Opcode::GetSlotIndirect, &[]);
+        asm.op(Opcode::GetSlot, &[3]);
+        asm.op(Opcode::NativeInvoke, &[INVOKE]);
+        asm.op(Opcode::NativeInvoke, &[INVOKE_NOTHING]);
+        asm.op(Opcode::Halt, &[]);
+        assert_eq!(halted_with(check(&asm.code, &[])), [20]);
+
+        for bad in &[
+            &[(Opcode::GetSlot, 4)][..],
+            &[(Opcode::I32Const, 4), (Opcode::GetSlotIndirect, 0)],
+            &[(Opcode::I32Const, 1), (Opcode::SetSlot, 4)],
+            &[(Opcode::I32Const, 1), (Opcode::NativeInvoke, INVOKE)],
+            &[(Opcode::ResetSlots, 1)]
+        ] {
+            let mut asm = Asm::new();
+            asm.op(Opcode::ResetSlots, &[4]);
+            for &(op, imm) in *bad {
+                asm.op(op, &[imm][..immediate_len(&op, &[]) / 4]);
+            }
+            asm.op(Opcode::Halt, &[]);
+            let outcome = check(&asm.code, &[]);
+            assert!(outcome != Outcome::Halted { stack: vec! [], mem: vec! [], slots: vec! [], branches: 0 });
+            match outcome {
+                Outcome::Trapped(_) => {},
+                other => panic!("{:?}", other)
+            }
+        }
+    }
+
+    /// Recursive calls, locals, loops and a call through the table.
+    #[test]
+    fn calls_and_loops() {
+        let mut asm = Asm::new();
+        asm.op(Opcode::ResetSlots, &[1]);
+        let fib_const = asm.op(Opcode::I32Const, &[0]);
+        asm.op(Opcode::SetSlot, &[0]);
+
+        // Startup code: fib(arg) through the slot, then sum() of the result
+        asm.op(Opcode::GetSlot, &[0]);
+        asm.op(Opcode::I32Const, &[0]);
+        asm.op(Opcode::Call, &[1]);
+        asm.op(Opcode::Dup, &[]);
+        let sum_const = asm.op(Opcode::I32Const, &[0]);
+        asm.op(Opcode::I32Const, &[1]);
+        asm.op(Opcode::Call, &[1]);
+        asm.op(Opcode::Halt, &[]);
+
+        // fib(n) = n < 2 ? n : fib(n - 1) + fib(n - 2)
+        let fib = asm.op(Opcode::GetLocal, &[0]);
+        asm.op(Opcode::I32Const, &[2]);
+        asm.op(Opcode::I32LtS, &[]);
+        let small = asm.op(Opcode::JmpIf, &[0]);
+        asm.op(Opcode::GetLocal, &[0]);
+        asm.op(Opcode::I32Const, &[1]);
+        asm.op(Opcode::I32Sub, &[]);
+        asm.op(Opcode::I32Const, &[fib]);
+        asm.op(Opcode::I32Const, &[0]);
+        asm.op(Opcode::Call, &[1]);
+        asm.op(Opcode::GetLocal, &[0]);
+        asm.op(Opcode::I32Const, &[2]);
+        asm.op(Opcode::I32Sub, &[]);
+        asm.op(Opcode::I32Const, &[fib]);
+        asm.op(Opcode::I32Const, &[0]);
+        asm.op(Opcode::Call, &[1]);
+        asm.op(Opcode::I32Add, &[]);
+        asm.op(Opcode::Return, &[]);
+        let small_target = asm.op(Opcode::GetLocal, &[0]);
+        asm.op(Opcode::Return, &[]);
+        asm.patch(small, 0, small_target);
+
+        // sum(n): acc = 0; while n != 0 { acc += n; n -= 1 }
+        let sum = asm.op(Opcode::GetLocal, &[0]);
+        let done = asm.op(Opcode::JmpEither, &[0, 0]);
+        let body = asm.op(Opcode::GetLocal, &[1]);
+        asm.op(Opcode::GetLocal, &[0]);
+        asm.op(Opcode::I32Add, &[]);
+        asm.op(Opcode::SetLocal, &[1]);
+        asm.op(Opcode::GetLocal, &[0]);
+        asm.op(Opcode::I32Const, &[1]);
+        asm.op(Opcode::I32Sub, &[]);
+        asm.op(Opcode::TeeLocal, &[0]);
+        asm.op(Opcode::Drop, &[]);
+        asm.op(Opcode::Jmp, &[sum]);
+        let done_target = asm.op(Opcode::GetLocal, &[1]);
+        asm.op(Opcode::Return, &[]);
+        asm.patch(done, 0, body);
+        asm.patch(done, 1, done_target);
+
+        asm.patch(fib_const, 0, fib);
+        asm.patch(sum_const, 0, sum);
+
+        for &(n, fib_n) in &[(0, 0), (1, 1), (2, 1), (10, 55)] {
+            let stack = halted_with(check(&asm.code, &[n]));
+            assert_eq!(stack, [fib_n, fib_n * (fib_n + 1) / 2]);
+        }
+
+        // Deep enough to overflow the call stack
+        assert_eq!(check(&asm.code, &[40]), Outcome::Trapped(Trap::Bounds));
+
+        // The slot doesn't point to an opcode.
+        asm.patch(fib_const, 0, fib + 1);
+        assert_eq!(check(&asm.code, &[5]), Outcome::Trapped(Trap::Bounds));
+        asm.patch(fib_const, 0, asm.code.len() as u32 + 3);
+        assert_eq!(check(&asm.code, &[5]), Outcome::Trapped(Trap::Bounds));
+    }
+
+    #[test]
+    fn check_frames() {
+        // Locals and returns without a frame
+        for &(op, imm) in &[(Opcode::GetLocal, 0), (Opcode::Return, 0)] {
+            let mut asm = Asm::new();
+            asm.op(Opcode::I32Const, &[1]);
+            asm.op(op, &[imm][..immediate_len(&op, &[]) / 4]);
+            asm.op(Opcode::Halt, &[]);
+            assert_eq!(check(&asm.code, &[]), Outcome::Trapped(Trap::Bounds));
+        }
+
+        // A local past the ones of the frame, and more arguments than
+        // there are values
+        for &(n_args, local) in &[(1, 1), (3, 0)] {
+            let mut asm = Asm::new();
+            asm.op(Opcode::I32Const, &[1]);
+            let target = asm.op(Opcode::I32Const, &[0]);
+            asm.op(Opcode::I32Const, &[0]);
+            asm.op(Opcode::Call, &[n_args]);
+            asm.op(Opcode::Halt, &[]);
+            let function = asm.op(Opcode::GetLocal, &[local]);
+            asm.op(Opcode::Return, &[]);
+            asm.patch(target, 0, function);
+            assert_eq!(check(&asm.code, &[]), Outcome::Trapped(Trap::Bounds));
+        }
+
+        // More locals than fit into the call stack
+        let mut asm = Asm::new();
+        let target = asm.op(Opcode::I32Const, &[0]);
+        asm.i64_const(-1);
+        asm.op(Opcode::Call, &[0]);
+        asm.op(Opcode::Halt, &[]);
+        let function = asm.op(Opcode::Return, &[]);
+        asm.patch(target, 0, function);
+        assert_eq!(check(&asm.code, &[]), Outcome::Trapped(Trap::Bounds));
+    }
+
+    #[test]
+    fn jump_table() {
+        let mut asm = Asm::new();
+        let table = asm.op(Opcode::JmpTable, &[0, 3, 0, 0, 0]);
+        let targets: Vec<u32> = (0..4).map(|i| {
+            let target = asm.op(Opcode::I32Const, &[i]);
+            asm.op(Opcode::Halt, &[]);
+            target
+        }).collect();
+        asm.patch(table, 0, targets[3]);
+        for i in 0..3 {
+            asm.patch(table, 2 + i, targets[i]);
+        }
+
+        for &(cond, result) in &[(0, 0), (1, 1), (2, 2), (3, 3), (-1, 3), (0x1_0000_0000, 3)] {
+            assert_eq!(halted_with(check(&asm.code, &[cond])), [result]);
+        }
+
+        let mut asm = Asm::new();
+        asm.op(Opcode::JmpTable, &[9, 0]);
+        asm.op(Opcode::Halt, &[]);
+        assert_eq!(halted_with(check(&asm.code, &[7])), []);
+    }
+}
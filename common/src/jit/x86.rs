@@ -0,0 +1,549 @@
+//! Just enough of an x86-64 assembler for the JIT.
+//!
+//! Instructions are emitted in the order they are called. Jumps to labels
+//! always take a 32-bit displacement, which is filled in by `finish`.
+
+use prelude::Vec;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15
+}
+
+impl Reg {
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn high(self) -> u8 {
+        (self as u8 >> 3) & 1
+    }
+}
+
+/// A memory operand, `[base + index * scale + disp]`.
+#[derive(Copy, Clone, Debug)]
+pub struct Mem {
+    base: Reg,
+    index: Option<(Reg, u8)>,
+    disp: i32
+}
+
+pub fn mem(base: Reg, disp: i32) -> Mem {
+    Mem {
+        base: base,
+        index: None,
+        disp: disp
+    }
+}
+
+/// `scale` is 1, 2, 4 or 8. `index` can't be `rsp`.
+pub fn mem_index(base: Reg, index: Reg, scale: u8, disp: i32) -> Mem {
+    Mem {
+        base: base,
+        index: Some((index, scale)),
+        disp: disp
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Cond {
+    O = 0,
+    NO,
+    B,
+    AE,
+    E,
+    NE,
+    BE,
+    A,
+    S,
+    NS,
+    P,
+    NP,
+    L,
+    GE,
+    LE,
+    G
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Size {
+    S8,
+    S16,
+    S32,
+    S64
+}
+
+/// Arithmetic operations with the same encodings.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Shift {
+    Rol = 0,
+    Ror = 1,
+    Shl = 4,
+    Shr = 5,
+    Sar = 7
+}
+
+/// Operations of the `F7` group.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Unary {
+    Neg = 3,
+    Div = 6,
+    Idiv = 7
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Label(usize);
+
+#[derive(Copy, Clone)]
+enum Operand {
+    Reg(Reg),
+    Mem(Mem)
+}
+
+pub struct Assembler {
+    buf: Vec<u8>,
+    labels: Vec<Option<usize>>,
+
+    /// Positions of 32-bit displacements and the labels they refer to
+    fixups: Vec<(usize, Label)>
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            buf: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new()
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.buf.len());
+    }
+
+    /// Resolves the jumps and returns the code. Every label that is used
+    /// must have been bound.
+    pub fn finish(mut self) -> Vec<u8> {
+        for &(pos, label) in &self.fixups {
+            let target = self.labels[label.0].expect("unbound label");
+            let rel = (target as i64 - (pos as i64 + 4)) as i32;
+            write_u32(&mut self.buf[pos..pos + 4], rel as u32);
+        }
+        self.buf
+    }
+
+    pub fn emit_u32(&mut self, v: u32) {
+        for i in 0..4 {
+            self.buf.push((v >> (i * 8)) as u8);
+        }
+    }
+
+    fn emit_u64(&mut self, v: u64) {
+        self.emit_u32(v as u32);
+        self.emit_u32((v >> 32) as u32);
+    }
+
+    fn emit_rel32(&mut self, label: Label) {
+        let pos = self.buf.len();
+        self.fixups.push((pos, label));
+        self.emit_u32(0);
+    }
+
+    /// Pads with `int3` up to a multiple of `n`.
+    pub fn align(&mut self, n: usize) {
+        while self.buf.len() % n != 0 {
+            self.buf.push(0xcc);
+        }
+    }
+
+    /// Emits an instruction with a ModRM byte. `reg` is a register or an
+    /// opcode extension.
+    fn op_rm(&mut self, size: Size, opcode: &[u8], reg: u8, rm: Operand) {
+        if size == Size::S16 {
+            self.buf.push(0x66);
+        }
+
+        let mut rex: u8 = 0x40;
+        if size == Size::S64 {
+            rex |= 8;
+        }
+        rex |= ((reg >> 3) & 1) << 2;
+        let mut force_rex = false;
+        match rm {
+            Operand::Reg(r) => {
+                rex |= r.high();
+                // Without a prefix these would be ah, ch, dh and bh.
+                if size == Size::S8 && (r as u8) >= 4 && (r as u8) < 8 {
+                    force_rex = true;
+                }
+            },
+            Operand::Mem(m) => {
+                rex |= m.base.high();
+                if let Some((index, _)) = m.index {
+                    rex |= index.high() << 1;
+                }
+            }
+        }
+        if size == Size::S8 && reg >= 4 && reg < 8 {
+            force_rex = true;
+        }
+        if rex != 0x40 || force_rex {
+            self.buf.push(rex);
+        }
+
+        self.buf.extend_from_slice(opcode);
+
+        let reg = (reg & 7) << 3;
+        match rm {
+            Operand::Reg(r) => self.buf.push(0xc0 | reg | r.low()),
+            Operand::Mem(m) => {
+                // `rbp` and `r13` as the base always take a displacement.
+                let (mode, disp_len) = if m.disp == 0 && m.base.low() != 5 {
+                    (0x00, 0)
+                } else if m.disp >= -128 && m.disp <= 127 {
+                    (0x40, 1)
+                } else {
+                    (0x80, 4)
+                };
+
+                match m.index {
+                    None if m.base.low() != 4 => self.buf.push(mode | reg | m.base.low()),
+                    None => {
+                        self.buf.push(mode | reg | 4);
+                        self.buf.push(0x24);
+                    },
+                    Some((index, scale)) => {
+                        let scale_bits = match scale {
+                            1 => 0,
+                            2 => 1,
+                            4 => 2,
+                            _ => 3
+                        };
+                        self.buf.push(mode | reg | 4);
+                        self.buf.push(scale_bits << 6 | index.low() << 3 | m.base.low());
+                    }
+                }
+
+                match disp_len {
+                    0 => {},
+                    1 => self.buf.push(m.disp as u8),
+                    _ => self.emit_u32(m.disp as u32)
+                }
+            }
+        }
+    }
+
+    /// `mov dst, [m]` for 32 or 64 bits. 32-bit loads clear the upper half.
+    pub fn load(&mut self, size: Size, dst: Reg, m: Mem) {
+        self.op_rm(size, &[0x8b], dst as u8, Operand::Mem(m));
+    }
+
+    pub fn store(&mut self, size: Size, m: Mem, src: Reg) {
+        let opcode = if size == Size::S8 { 0x88 } else { 0x89 };
+        self.op_rm(size, &[opcode], src as u8, Operand::Mem(m));
+    }
+
+    pub fn mov(&mut self, size: Size, dst: Reg, src: Reg) {
+        self.op_rm(size, &[0x8b], dst as u8, Operand::Reg(src));
+    }
+
+    /// Loads `imm` into `dst` with the shortest encoding.
+    pub fn mov_imm(&mut self, dst: Reg, imm: u64) {
+        if imm <= ::core::u32::MAX as u64 {
+            if dst.high() != 0 {
+                self.buf.push(0x41);
+            }
+            self.buf.push(0xb8 + dst.low());
+            self.emit_u32(imm as u32);
+        } else if imm as i64 >= ::core::i32::MIN as i64 && (imm as i64) < 0 {
+            self.op_rm(Size::S64, &[0xc7], 0, Operand::Reg(dst));
+            self.emit_u32(imm as u32);
+        } else {
+            self.buf.push(0x48 | dst.high());
+            self.buf.push(0xb8 + dst.low());
+            self.emit_u64(imm);
+        }
+    }
+
+    /// `movzx dst, byte [m]`
+    pub fn load_u8(&mut self, dst: Reg, m: Mem) {
+        self.op_rm(Size::S32, &[0x0f, 0xb6], dst as u8, Operand::Mem(m));
+    }
+
+    /// `movzx dst, word [m]`
+    pub fn load_u16(&mut self, dst: Reg, m: Mem) {
+        self.op_rm(Size::S32, &[0x0f, 0xb7], dst as u8, Operand::Mem(m));
+    }
+
+    /// `movsx dst, byte [m]`, to 64 bits
+    pub fn load_i8(&mut self, dst: Reg, m: Mem) {
+        self.op_rm(Size::S64, &[0x0f, 0xbe], dst as u8, Operand::Mem(m));
+    }
+
+    /// `movsx dst, word [m]`, to 64 bits
+    pub fn load_i16(&mut self, dst: Reg, m: Mem) {
+        self.op_rm(Size::S64, &[0x0f, 0xbf], dst as u8, Operand::Mem(m));
+    }
+
+    /// `movsxd dst, dword [m]`
+    pub fn load_i32(&mut self, dst: Reg, m: Mem) {
+        self.op_rm(Size::S64, &[0x63], dst as u8, Operand::Mem(m));
+    }
+
+    /// `movsxd dst, src`
+    pub fn sign_extend_32(&mut self, dst: Reg, src: Reg) {
+        self.op_rm(Size::S64, &[0x63], dst as u8, Operand::Reg(src));
+    }
+
+    /// `movzx dst, src` from the low byte of `src`
+    pub fn zero_extend_8(&mut self, dst: Reg, src: Reg) {
+        self.op_rm(Size::S8, &[0x0f, 0xb6], dst as u8, Operand::Reg(src));
+    }
+
+    pub fn lea(&mut self, dst: Reg, m: Mem) {
+        self.op_rm(Size::S64, &[0x8d], dst as u8, Operand::Mem(m));
+    }
+
+    /// `lea dst, [rip + label]`
+    pub fn lea_label(&mut self, dst: Reg, label: Label) {
+        self.buf.push(0x48 | (dst.high() << 2));
+        self.buf.push(0x8d);
+        self.buf.push(0x05 | (dst.low() << 3));
+        self.emit_rel32(label);
+    }
+
+    /// `op dst, [m]`
+    pub fn alu_load(&mut self, op: Alu, size: Size, dst: Reg, m: Mem) {
+        self.op_rm(size, &[(op as u8) << 3 | 0x03], dst as u8, Operand::Mem(m));
+    }
+
+    /// `op dst, src`
+    pub fn alu(&mut self, op: Alu, size: Size, dst: Reg, src: Reg) {
+        self.op_rm(size, &[(op as u8) << 3 | 0x03], dst as u8, Operand::Reg(src));
+    }
+
+    fn alu_imm_rm(&mut self, op: Alu, size: Size, rm: Operand, imm: i32) {
+        if imm >= -128 && imm <= 127 {
+            self.op_rm(size, &[0x83], op as u8, rm);
+            self.buf.push(imm as u8);
+        } else {
+            self.op_rm(size, &[0x81], op as u8, rm);
+            self.emit_u32(imm as u32);
+        }
+    }
+
+    /// `op dst, imm`, with `imm` sign extended
+    pub fn alu_imm(&mut self, op: Alu, size: Size, dst: Reg, imm: i32) {
+        self.alu_imm_rm(op, size, Operand::Reg(dst), imm);
+    }
+
+    /// `op [m], imm`, with `imm` sign extended
+    pub fn alu_imm_mem(&mut self, op: Alu, size: Size, m: Mem, imm: i32) {
+        self.alu_imm_rm(op, size, Operand::Mem(m), imm);
+    }
+
+    /// `imul dst, [m]`
+    pub fn imul_load(&mut self, size: Size, dst: Reg, m: Mem) {
+        self.op_rm(size, &[0x0f, 0xaf], dst as u8, Operand::Mem(m));
+    }
+
+    pub fn imul(&mut self, size: Size, dst: Reg, src: Reg) {
+        self.op_rm(size, &[0x0f, 0xaf], dst as u8, Operand::Reg(src));
+    }
+
+    /// `op dst, cl`
+    pub fn shift_cl(&mut self, op: Shift, size: Size, dst: Reg) {
+        self.op_rm(size, &[0xd3], op as u8, Operand::Reg(dst));
+    }
+
+    pub fn shift_imm(&mut self, op: Shift, size: Size, dst: Reg, imm: u8) {
+        self.op_rm(size, &[0xc1], op as u8, Operand::Reg(dst));
+        self.buf.push(imm);
+    }
+
+    pub fn unary(&mut self, op: Unary, size: Size, reg: Reg) {
+        self.op_rm(size, &[0xf7], op as u8, Operand::Reg(reg));
+    }
+
+    /// Sign extends `eax` into `edx`, or `rax` into `rdx`.
+    pub fn sign_extend_ax(&mut self, size: Size) {
+        if size == Size::S64 {
+            self.buf.push(0x48);
+        }
+        self.buf.push(0x99);
+    }
+
+    pub fn setcc(&mut self, cond: Cond, dst: Reg) {
+        self.op_rm(Size::S8, &[0x0f, 0x90 + cond as u8], 0, Operand::Reg(dst));
+    }
+
+    pub fn cmov(&mut self, cond: Cond, size: Size, dst: Reg, src: Reg) {
+        self.op_rm(size, &[0x0f, 0x40 + cond as u8], dst as u8, Operand::Reg(src));
+    }
+
+    pub fn bsr(&mut self, size: Size, dst: Reg, src: Reg) {
+        self.op_rm(size, &[0x0f, 0xbd], dst as u8, Operand::Reg(src));
+    }
+
+    pub fn bsf(&mut self, size: Size, dst: Reg, src: Reg) {
+        self.op_rm(size, &[0x0f, 0xbc], dst as u8, Operand::Reg(src));
+    }
+
+    pub fn test(&mut self, size: Size, a: Reg, b: Reg) {
+        self.op_rm(size, &[0x85], b as u8, Operand::Reg(a));
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.buf.push(0xe9);
+        self.emit_rel32(label);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.buf.push(0x0f);
+        self.buf.push(0x80 + cond as u8);
+        self.emit_rel32(label);
+    }
+
+    pub fn call(&mut self, label: Label) {
+        self.buf.push(0xe8);
+        self.emit_rel32(label);
+    }
+
+    /// `call [m]`
+    pub fn call_mem(&mut self, m: Mem) {
+        self.op_rm(Size::S32, &[0xff], 2, Operand::Mem(m));
+    }
+
+    /// `jmp reg`
+    pub fn jmp_reg(&mut self, reg: Reg) {
+        self.op_rm(Size::S32, &[0xff], 4, Operand::Reg(reg));
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        if reg.high() != 0 {
+            self.buf.push(0x41);
+        }
+        self.buf.push(0x50 + reg.low());
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        if reg.high() != 0 {
+            self.buf.push(0x41);
+        }
+        self.buf.push(0x58 + reg.low());
+    }
+
+    pub fn ret(&mut self) {
+        self.buf.push(0xc3);
+    }
+
+    /// Copies `rcx` quadwords from `[rsi]` to `[rdi]`.
+    pub fn rep_movsq(&mut self) {
+        self.buf.extend_from_slice(&[0xf3, 0x48, 0xa5]);
+    }
+
+    /// Stores `rax` to `rcx` quadwords at `[rdi]`.
+    pub fn rep_stosq(&mut self) {
+        self.buf.extend_from_slice(&[0xf3, 0x48, 0xab]);
+    }
+}
+
+fn write_u32(out: &mut [u8], v: u32) {
+    for i in 0..4 {
+        out[i] = (v >> (i * 8)) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble<F: FnOnce(&mut Assembler)>(f: F) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        f(&mut asm);
+        asm.finish()
+    }
+
+    #[test]
+    fn encodings() {
+        // Checked against objdump.
+        assert_eq!(assemble(|a| a.load(Size::S64, Reg::Rax, mem(Reg::R12, -8))), [0x49, 0x8b, 0x44, 0x24, 0xf8]);
+        assert_eq!(assemble(|a| a.load(Size::S32, Reg::Rax, mem(Reg::R13, 0))), [0x41, 0x8b, 0x45, 0x00]);
+        assert_eq!(assemble(|a| a.store(Size::S64, mem(Reg::Rbx, 0x80), Reg::Rsp)), [0x48, 0x89, 0xa3, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(assemble(|a| a.store(Size::S8, mem_index(Reg::R14, Reg::Rax, 1, 0), Reg::Rcx)), [0x41, 0x88, 0x0c, 0x06]);
+        assert_eq!(assemble(|a| a.store(Size::S16, mem_index(Reg::R14, Reg::Rax, 1, 0), Reg::Rcx)), [0x66, 0x41, 0x89, 0x0c, 0x06]);
+        assert_eq!(assemble(|a| a.load_u8(Reg::Rax, mem_index(Reg::R14, Reg::Rax, 1, 0))), [0x41, 0x0f, 0xb6, 0x04, 0x06]);
+        assert_eq!(assemble(|a| a.load(Size::S64, Reg::Rax, mem_index(Reg::Rax, Reg::Rcx, 8, 0))), [0x48, 0x8b, 0x04, 0xc8]);
+        assert_eq!(assemble(|a| a.lea(Reg::Rdx, mem_index(Reg::R13, Reg::Rcx, 8, -16))), [0x49, 0x8d, 0x54, 0xcd, 0xf0]);
+        assert_eq!(assemble(|a| a.alu_imm_mem(Alu::Sub, Size::S64, mem(Reg::Rbx, 0x50), 1)), [0x48, 0x83, 0x6b, 0x50, 0x01]);
+        assert_eq!(assemble(|a| a.alu_imm(Alu::Cmp, Size::S32, Reg::Rcx, -1)), [0x83, 0xf9, 0xff]);
+        assert_eq!(assemble(|a| a.alu_imm(Alu::Add, Size::S64, Reg::R12, 8)), [0x49, 0x83, 0xc4, 0x08]);
+        assert_eq!(assemble(|a| a.alu_load(Alu::Cmp, Size::S64, Reg::Rax, mem(Reg::Rbx, 32))), [0x48, 0x3b, 0x43, 0x20]);
+        assert_eq!(assemble(|a| a.alu(Alu::Cmp, Size::S64, Reg::Rcx, Reg::R15)), [0x49, 0x3b, 0xcf]);
+        assert_eq!(assemble(|a| a.mov_imm(Reg::Rax, 5)), [0xb8, 0x05, 0x00, 0x00, 0x00]);
+        assert_eq!(assemble(|a| a.mov_imm(Reg::R9, 5)), [0x41, 0xb9, 0x05, 0x00, 0x00, 0x00]);
+        assert_eq!(assemble(|a| a.mov_imm(Reg::Rcx, !0)), [0x48, 0xc7, 0xc1, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(assemble(|a| a.mov_imm(Reg::Rcx, 1 << 40)), [0x48, 0xb9, 0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(assemble(|a| a.setcc(Cond::L, Reg::Rax)), [0x0f, 0x9c, 0xc0]);
+        assert_eq!(assemble(|a| a.zero_extend_8(Reg::Rax, Reg::Rax)), [0x0f, 0xb6, 0xc0]);
+        assert_eq!(assemble(|a| a.cmov(Cond::E, Size::S64, Reg::Rax, Reg::Rcx)), [0x48, 0x0f, 0x44, 0xc1]);
+        assert_eq!(assemble(|a| a.shift_cl(Shift::Sar, Size::S32, Reg::Rax)), [0xd3, 0xf8]);
+        assert_eq!(assemble(|a| a.unary(Unary::Idiv, Size::S64, Reg::Rcx)), [0x48, 0xf7, 0xf9]);
+        assert_eq!(assemble(|a| a.sign_extend_32(Reg::Rax, Reg::Rax)), [0x48, 0x63, 0xc0]);
+        assert_eq!(assemble(|a| a.call_mem(mem(Reg::Rbx, 136))), [0xff, 0x93, 0x88, 0x00, 0x00, 0x00]);
+        assert_eq!(assemble(|a| a.jmp_reg(Reg::Rax)), [0xff, 0xe0]);
+        assert_eq!(assemble(|a| { a.push(Reg::R15); a.pop(Reg::Rbx); }), [0x41, 0x57, 0x5b]);
+    }
+
+    #[test]
+    fn labels() {
+        let code = assemble(|a| {
+            let back = a.new_label();
+            let fwd = a.new_label();
+            a.bind(back);
+            a.jcc(Cond::NE, fwd);
+            a.jmp(back);
+            a.bind(fwd);
+            a.lea_label(Reg::Rcx, back);
+        });
+        assert_eq!(code, [
+            0x0f, 0x85, 0x05, 0x00, 0x00, 0x00,
+            0xe9, 0xf5, 0xff, 0xff, 0xff,
+            0x48, 0x8d, 0x0d, 0xee, 0xff, 0xff, 0xff
+        ]);
+    }
+}
@@ -0,0 +1,40 @@
+//! Parts of the kernel module that don't depend on the kernel.
+//!
+//! The kernel module links this crate like any other dependency. Tests
+//! build it against the standard library instead, so that they run with
+//! `cargo test` on the host.
+
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), feature(alloc))]
+
+#[cfg(not(test))]
+#[macro_use]
+extern crate alloc;
+
+// `::core` paths resolve without this in `no_std` crates only.
+#[cfg(test)]
+extern crate core;
+
+extern crate hexagon_e;
+
+/// Collections from `alloc`, or from `std` for tests.
+mod prelude {
+    #[cfg(not(test))]
+    pub use alloc::{Vec, String, BTreeMap};
+
+    #[cfg(test)]
+    pub use std::vec::Vec;
+    #[cfg(test)]
+    pub use std::string::String;
+    #[cfg(test)]
+    pub use std::collections::BTreeMap;
+}
+
+pub mod float_ops;
+pub mod verify;
+pub mod wasm;
+pub mod trans;
+pub mod manifest;
+pub mod resource;
+pub mod checkpoint;
+pub mod jit;
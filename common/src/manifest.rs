@@ -0,0 +1,289 @@
+//! Which native invokes and URLs an application may use.
+//!
+//! A manifest is text with one rule per line:
+//!
+//! ```text
+//! invoke log_write
+//! url file:///tmp/
+//! url ipc-broadcast://status
+//! ```
+//!
+//! Empty lines and lines starting with `#` are ignored. Applications
+//! without a manifest may use everything.
+
+use prelude::{String, Vec};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParseError<'a> {
+    /// An `invoke` rule names a native invoke that isn't registered.
+    UnknownInvoke(&'a str),
+
+    /// A `url` rule without a scheme.
+    MalformedUrl(&'a str),
+
+    /// The line isn't a rule.
+    MalformedLine(&'a str)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    /// Indexed by native invoke id
+    invokes: Vec<bool>,
+
+    /// Schemes and path prefixes
+    urls: Vec<(String, String)>
+}
+
+impl Manifest {
+    /// Parses `text`, with native invoke names resolved by `map_invoke`.
+    pub fn parse_with<'a, F: Fn(&str) -> Option<u32>>(text: &'a str, map_invoke: F) -> Result<Manifest, ParseError<'a>> {
+        let mut manifest = Manifest::default();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("invoke"), Some(name), None) => {
+                    let id = match map_invoke(name) {
+                        Some(v) => v as usize,
+                        None => return Err(ParseError::UnknownInvoke(name))
+                    };
+                    if manifest.invokes.len() <= id {
+                        manifest.invokes.resize(id + 1, false);
+                    }
+                    manifest.invokes[id] = true;
+                },
+                (Some("url"), Some(prefix), None) => {
+                    let mut parts = prefix.splitn(2, "://");
+                    match (parts.next(), parts.next()) {
+                        (Some(scheme), Some(path)) if scheme.len() > 0 => {
+                            manifest.urls.push((scheme.into(), path.into()));
+                        },
+                        _ => return Err(ParseError::MalformedUrl(prefix))
+                    }
+                },
+                _ => return Err(ParseError::MalformedLine(line))
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Formats the rules as text that `parse_with` reads back, naming native
+    /// invokes with `name_of`. Invokes without a name are left out.
+    pub fn format<F: Fn(usize) -> Option<String>>(&self, name_of: F) -> String {
+        let mut text = String::new();
+
+        for (id, allowed) in self.invokes.iter().enumerate() {
+            if let (true, Some(name)) = (*allowed, name_of(id)) {
+                text.push_str("invoke ");
+                text.push_str(&name);
+                text.push('\n');
+            }
+        }
+
+        for &(ref scheme, ref path) in &self.urls {
+            text.push_str("url ");
+            text.push_str(scheme);
+            text.push_str("://");
+            text.push_str(path);
+            text.push('\n');
+        }
+
+        text
+    }
+
+    pub fn allows_invoke(&self, id: usize) -> bool {
+        self.invokes.get(id).map(|v| *v).unwrap_or(false)
+    }
+
+    pub fn allows_url(&self, scheme: &str, path: &str) -> bool {
+        // Prefixes would not mean much otherwise.
+        if path.split('/').any(|v| v == "..") {
+            return false;
+        }
+
+        self.urls.iter().any(|&(ref s, ref prefix)| {
+            scheme == s.as_str() && path_has_prefix(path, prefix)
+        })
+    }
+
+    /// Whether a file opened as `path`, which is `resolved` once symlinks
+    /// are followed, may be used.
+    ///
+    /// A symlink below an allowed prefix can point anywhere, so the
+    /// resolved path has to be allowed as well.
+    pub fn allows_file(&self, path: &str, resolved: &str) -> bool {
+        self.allows_url("file", path) && self.allows_url("file", resolved)
+    }
+}
+
+/// Whether `prefix` names `path` or one of its parent directories.
+///
+/// Prefixes are matched by whole path components, so `/tmp/a` covers
+/// `/tmp/a` and `/tmp/a/b` but not `/tmp/ab`. A prefix ending in `/` covers
+/// everything below it, and an empty prefix covers the whole scheme.
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    if !path.starts_with(prefix) {
+        return false;
+    }
+
+    prefix.len() == 0
+        || prefix.ends_with("/")
+        || path.len() == prefix.len()
+        || path[prefix.len()..].starts_with("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_invoke(name: &str) -> Option<u32> {
+        match name {
+            "log_write" => Some(1),
+            "resource_open" => Some(3),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let m = Manifest::parse_with(
+            "# comment\n\ninvoke log_write\n  invoke resource_open  \nurl file:///tmp/\n",
+            map_invoke
+        ).unwrap();
+
+        assert!(m.allows_invoke(1));
+        assert!(m.allows_invoke(3));
+        assert!(!m.allows_invoke(0));
+        assert!(!m.allows_invoke(2));
+        assert!(!m.allows_invoke(100));
+        assert_eq!(m.urls, vec! [ ("file".into(), "/tmp/".into()) ]);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_lines() {
+        assert_eq!(Manifest::parse_with("invoke", map_invoke).err(), Some(ParseError::MalformedLine("invoke")));
+        assert_eq!(Manifest::parse_with("invoke log_write extra", map_invoke).err(), Some(ParseError::MalformedLine("invoke log_write extra")));
+        assert_eq!(Manifest::parse_with("invoke unknown_invoke", map_invoke).err(), Some(ParseError::UnknownInvoke("unknown_invoke")));
+        assert_eq!(Manifest::parse_with("url", map_invoke).err(), Some(ParseError::MalformedLine("url")));
+        assert_eq!(Manifest::parse_with("url /tmp", map_invoke).err(), Some(ParseError::MalformedUrl("/tmp")));
+        assert_eq!(Manifest::parse_with("url :///tmp", map_invoke).err(), Some(ParseError::MalformedUrl(":///tmp")));
+        assert_eq!(Manifest::parse_with("allow everything", map_invoke).err(), Some(ParseError::MalformedLine("allow everything")));
+    }
+
+    #[test]
+    fn format_parses_back() {
+        let m = Manifest::parse_with(
+            "invoke resource_open\nurl file:///tmp/\nurl ipc-broadcast://\n",
+            map_invoke
+        ).unwrap();
+        let text = m.format(|id| match id {
+            1 => Some("log_write".into()),
+            3 => Some("resource_open".into()),
+            _ => None
+        });
+
+        assert_eq!(text, "invoke resource_open\nurl file:///tmp/\nurl ipc-broadcast://\n");
+
+        let m2 = Manifest::parse_with(&text, map_invoke).unwrap();
+        assert_eq!(m2.invokes, m.invokes);
+        assert_eq!(m2.urls, m.urls);
+    }
+
+    #[test]
+    fn allows_listed_urls() {
+        let m = Manifest::parse_with(
+            "url file:///tmp/\nurl file:///var/data\nurl ipc-broadcast://status\n",
+            map_invoke
+        ).unwrap();
+
+        assert!(m.allows_url("file", "/tmp/a"));
+        assert!(m.allows_url("file", "/tmp/a/b"));
+        assert!(m.allows_url("file", "/var/data"));
+        assert!(m.allows_url("file", "/var/data/x"));
+        assert!(m.allows_url("ipc-broadcast", "status"));
+        assert!(m.allows_url("ipc-broadcast", "status/sub"));
+    }
+
+    #[test]
+    fn denies_other_urls() {
+        let m = Manifest::parse_with(
+            "url file:///tmp/\nurl file:///var/data\nurl ipc-broadcast://status\n",
+            map_invoke
+        ).unwrap();
+
+        assert!(!m.allows_url("file", "/tmp"));
+        assert!(!m.allows_url("file", "/etc/passwd"));
+        assert!(!m.allows_url("file", "/var/database"));
+        assert!(!m.allows_url("file", "/var/dat"));
+        assert!(!m.allows_url("ipc-broadcast", "statusx"));
+        assert!(!m.allows_url("ipc-broadcast", "/tmp/a"));
+        assert!(!m.allows_url("http", "/tmp/a"));
+    }
+
+    #[test]
+    fn denies_parent_components() {
+        let m = Manifest::parse_with("url file:///tmp/\n", map_invoke).unwrap();
+
+        assert!(!m.allows_url("file", "/tmp/../etc/passwd"));
+        assert!(!m.allows_url("file", "/tmp/a/../../etc"));
+        assert!(!m.allows_url("file", "/tmp/.."));
+        assert!(m.allows_url("file", "/tmp/..a"));
+    }
+
+    #[test]
+    fn empty_prefix_allows_whole_scheme() {
+        let m = Manifest::parse_with("url ipc-broadcast://\n", map_invoke).unwrap();
+
+        assert!(m.allows_url("ipc-broadcast", "anything"));
+        assert!(!m.allows_url("file", "/tmp/a"));
+    }
+
+    #[test]
+    fn no_rules_allow_nothing() {
+        let m = Manifest::parse_with("# nothing\n", map_invoke).unwrap();
+
+        assert!(!m.allows_invoke(1));
+        assert!(!m.allows_url("file", "/tmp/a"));
+    }
+
+    #[test]
+    fn denies_symlinks_out_of_prefix() {
+        use std::fs;
+        use std::os::unix::fs::symlink;
+
+        let base = ::std::env::temp_dir().join(format!("cervus-manifest-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("allowed")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        fs::write(base.join("allowed/a"), b"a").unwrap();
+        fs::write(base.join("outside/secret"), b"secret").unwrap();
+        symlink(base.join("outside"), base.join("allowed/x")).unwrap();
+
+        let base = fs::canonicalize(&base).unwrap();
+        let m = Manifest::parse_with(
+            &format!("url file://{}/allowed/\n", base.display()),
+            map_invoke
+        ).unwrap();
+
+        let check = |rel: &str| {
+            let path = base.join(rel);
+            let resolved = fs::canonicalize(&path).unwrap();
+            m.allows_file(path.to_str().unwrap(), resolved.to_str().unwrap())
+        };
+        let allows_link = m.allows_url("file", base.join("allowed/x/secret").to_str().unwrap());
+        let allowed = check("allowed/a");
+        let through_link = check("allowed/x/secret");
+        fs::remove_dir_all(&base).unwrap();
+
+        assert!(allowed);
+        assert!(allows_link);
+        assert!(!through_link);
+    }
+}
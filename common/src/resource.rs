@@ -0,0 +1,34 @@
+//! State of resources that is kept in checkpoints.
+
+use prelude::String;
+
+/// How `resource_read` and `resource_write` wait for a resource.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct IoOptions {
+    /// Fail with `WouldBlock` instead of waiting
+    pub nonblock: bool,
+
+    /// Fail with `TimedOut` after waiting this long
+    pub timeout_ms: Option<u64>
+}
+
+impl IoOptions {
+    /// How long to wait for the resource to become ready, `None` for blocking calls.
+    pub fn wait_ms(&self) -> Option<u64> {
+        if self.nonblock {
+            Some(0)
+        } else {
+            self.timeout_ms
+        }
+    }
+}
+
+/// What is needed to open a resource again when a checkpoint is restored.
+#[derive(Clone, Debug)]
+pub enum ResourceSnapshot {
+    /// stdin (0), stdout (1) or stderr (2) of the instance
+    Stdio(usize),
+
+    /// A file opened by path, and the offset of the next read or write
+    File { path: String, offset: i64 }
+}
@@ -0,0 +1,781 @@
+//! Translates WebAssembly binaries to hexagon-e code, so that a loader can
+//! hand over the wasm binary itself.
+//!
+//! This is a port of the wasm-core translator that `cvctl` uses, and gives
+//! the same code for the same module:
+//!
+//! - floating point operations become native invokes starting at
+//!   `FLOAT_OP_BASE`, as `cvctl::float_ops` lowers them
+//! - imports from `cwa` are resolved through the native invoke registry;
+//!   imports from other modules need `cvctl --link` and are rejected
+//! - blocks are translated to jumps in a single pass, with the targets of
+//!   forward jumps filled in at the `end` of their block
+//!
+//! Unlike wasm-core, malformed input fails with an error instead of a panic.
+//! The result goes through `verify` like any other code.
+
+use prelude::{Vec, String};
+
+use hexagon_e::module::Opcode;
+
+use float_ops::FLOAT_OP_BASE;
+use wasm::{Module, Function, Body, Reader, ValType};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TransError {
+    /// The binary can't be decoded.
+    Malformed { offset: usize },
+
+    /// The module uses something the translator doesn't support.
+    Unsupported { offset: usize },
+
+    /// The byte at `offset` isn't a WebAssembly 1.0 instruction.
+    UnknownOpcode { offset: usize, byte: u8 },
+
+    /// A function, type, local, global, table or label doesn't exist.
+    IndexBounds { offset: usize },
+
+    /// An `else` or `end` without a block to close.
+    UnbalancedBlocks { offset: usize },
+
+    /// The table is larger than its limit or than the slots allow.
+    TableBounds { offset: usize },
+
+    /// The table and the globals need more slots than the configuration allows.
+    TooManySlots { n_slots: usize },
+
+    /// The entry function isn't exported.
+    EntryNotFound,
+
+    /// The entry function takes floating point arguments.
+    EntrySignature,
+
+    /// The entry function takes a different number of arguments.
+    EntryArgs { expected: usize, given: usize },
+
+    /// Imports that aren't registered native invokes, as `(module, field)`
+    UnresolvedImports(Vec<(String, String)>)
+}
+
+pub type TransResult<T> = Result<T, TransError>;
+
+pub struct Translated {
+    pub code: Vec<u8>,
+
+    /// The arguments of the entry function, with those of 32-bit
+    /// parameters zero-extended as `cvctl` passes them
+    pub entry_args: Vec<i64>
+}
+
+/// What a 32-bit immediate is relocated to.
+#[derive(Copy, Clone)]
+enum Reloc {
+    /// Start of the function
+    Function(usize),
+
+    /// Offset in the function it is in, which is held by the immediate
+    Local
+}
+
+#[derive(Default)]
+struct Writer {
+    code: Vec<u8>,
+    relocs: Vec<(usize, Reloc)>
+}
+
+impl Writer {
+    fn op(&mut self, op: Opcode) {
+        self.code.push(op as u8);
+    }
+
+    fn u32(&mut self, v: u32) {
+        let v: [u8; 4] = unsafe { ::core::mem::transmute(v.to_le()) };
+        self.code.extend_from_slice(&v);
+    }
+
+    fn u64(&mut self, v: u64) {
+        let v: [u8; 8] = unsafe { ::core::mem::transmute(v.to_le()) };
+        self.code.extend_from_slice(&v);
+    }
+
+    fn op_u32(&mut self, op: Opcode, v: u32) {
+        self.op(op);
+        self.u32(v);
+    }
+
+    fn op_u64(&mut self, op: Opcode, v: u64) {
+        self.op(op);
+        self.u64(v);
+    }
+
+    /// Writes a jump target to be filled in by `patch`, returning its position.
+    fn jump_target(&mut self) -> usize {
+        let pos = self.code.len();
+        self.relocs.push((pos, Reloc::Local));
+        self.u32(::core::u32::MAX);
+        pos
+    }
+
+    fn jump(&mut self, op: Opcode) -> usize {
+        self.op(op);
+        self.jump_target()
+    }
+
+    fn patch(&mut self, pos: usize, v: usize) {
+        let v: [u8; 4] = unsafe { ::core::mem::transmute((v as u32).to_le()) };
+        self.code[pos..pos + 4].copy_from_slice(&v);
+    }
+
+    fn read(&self, pos: usize) -> usize {
+        self.code[pos..pos + 4].iter().rev().fold(0, |acc, b| (acc << 8) | *b as usize)
+    }
+
+    /// Calls `target` with the arguments on the stack.
+    fn call(&mut self, m: &Module, target: usize) {
+        let f = &m.functions[target];
+
+        self.op(Opcode::I32Const);
+        let pos = self.code.len();
+        self.relocs.push((pos, Reloc::Function(target)));
+        self.u32(::core::u32::MAX);
+
+        self.op_u32(Opcode::I32Const, f.n_locals);
+        self.op_u32(Opcode::Call, m.func_type(f).params.len() as u32);
+    }
+
+    /// Appends `other`, moving its jump targets along with its code.
+    fn append(&mut self, other: &Writer) {
+        let base = self.code.len();
+        self.code.extend_from_slice(&other.code);
+        for &(pos, reloc) in &other.relocs {
+            let pos = base + pos;
+            if let Reloc::Local = reloc {
+                let target = self.read(pos);
+                self.patch(pos, base + target);
+            } else {
+                self.relocs.push((pos, reloc));
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum LabelKind {
+    Block,
+
+    /// Start of the loop
+    Loop(usize),
+
+    /// The jump to the `else` branch, taken if the condition is 0
+    If(usize),
+
+    Else
+}
+
+/// A block that can be branched to.
+struct Label {
+    kind: LabelKind,
+
+    /// Jump targets that branch to the label
+    branches: Vec<usize>
+}
+
+impl Label {
+    fn new(kind: LabelKind) -> Label {
+        Label {
+            kind: kind,
+            branches: Vec::new()
+        }
+    }
+}
+
+fn label_at(labels: &mut [Label], depth: u32, offset: usize) -> TransResult<&mut Label> {
+    let n = labels.len();
+    if depth as usize >= n {
+        return Err(TransError::IndexBounds { offset: offset });
+    }
+    Ok(&mut labels[n - 1 - depth as usize])
+}
+
+/// Opcodes that translate to a single hexagon-e opcode without immediates.
+fn simple_op(byte: u8) -> Option<Opcode> {
+    Some(match byte {
+        0x00 => Opcode::Unreachable,
+        0x0f => Opcode::Return,
+        0x1a => Opcode::Drop,
+        0x1b => Opcode::Select,
+
+        0x46 => Opcode::I32Eq,
+        0x47 => Opcode::I32Ne,
+        0x48 => Opcode::I32LtS,
+        0x49 => Opcode::I32LtU,
+        0x4a => Opcode::I32GtS,
+        0x4b => Opcode::I32GtU,
+        0x4c => Opcode::I32LeS,
+        0x4d => Opcode::I32LeU,
+        0x4e => Opcode::I32GeS,
+        0x4f => Opcode::I32GeU,
+
+        0x51 => Opcode::I64Eq,
+        0x52 => Opcode::I64Ne,
+        0x53 => Opcode::I64LtS,
+        0x54 => Opcode::I64LtU,
+        0x55 => Opcode::I64GtS,
+        0x56 => Opcode::I64GtU,
+        0x57 => Opcode::I64LeS,
+        0x58 => Opcode::I64LeU,
+        0x59 => Opcode::I64GeS,
+        0x5a => Opcode::I64GeU,
+
+        0x67 => Opcode::I32Clz,
+        0x68 => Opcode::I32Ctz,
+        0x69 => Opcode::I32Popcnt,
+        0x6a => Opcode::I32Add,
+        0x6b => Opcode::I32Sub,
+        0x6c => Opcode::I32Mul,
+        0x6d => Opcode::I32DivS,
+        0x6e => Opcode::I32DivU,
+        0x6f => Opcode::I32RemS,
+        0x70 => Opcode::I32RemU,
+        0x71 => Opcode::I32And,
+        0x72 => Opcode::I32Or,
+        0x73 => Opcode::I32Xor,
+        0x74 => Opcode::I32Shl,
+        0x75 => Opcode::I32ShrS,
+        0x76 => Opcode::I32ShrU,
+        0x77 => Opcode::I32Rotl,
+        0x78 => Opcode::I32Rotr,
+
+        0x79 => Opcode::I64Clz,
+        0x7a => Opcode::I64Ctz,
+        0x7b => Opcode::I64Popcnt,
+        0x7c => Opcode::I64Add,
+        0x7d => Opcode::I64Sub,
+        0x7e => Opcode::I64Mul,
+        0x7f => Opcode::I64DivS,
+        0x80 => Opcode::I64DivU,
+        0x81 => Opcode::I64RemS,
+        0x82 => Opcode::I64RemU,
+        0x83 => Opcode::I64And,
+        0x84 => Opcode::I64Or,
+        0x85 => Opcode::I64Xor,
+        0x86 => Opcode::I64Shl,
+        0x87 => Opcode::I64ShrS,
+        0x88 => Opcode::I64ShrU,
+        0x89 => Opcode::I64Rotl,
+        0x8a => Opcode::I64Rotr,
+
+        0xa7 => Opcode::I32WrapI64,
+        0xac => Opcode::I64ExtendI32S,
+        0xad => Opcode::I64ExtendI32U,
+
+        _ => return None
+    })
+}
+
+/// Loads and stores, which take the offset as their immediate. Floating
+/// point values are loaded and stored by their bit patterns.
+fn memory_op(byte: u8) -> Option<Opcode> {
+    Some(match byte {
+        0x28 | 0x2a => Opcode::I32Load,
+        0x29 | 0x2b => Opcode::I64Load,
+        0x2c => Opcode::I32Load8S,
+        0x2d => Opcode::I32Load8U,
+        0x2e => Opcode::I32Load16S,
+        0x2f => Opcode::I32Load16U,
+        0x30 => Opcode::I64Load8S,
+        0x31 => Opcode::I64Load8U,
+        0x32 => Opcode::I64Load16S,
+        0x33 => Opcode::I64Load16U,
+        0x34 => Opcode::I64Load32S,
+        0x35 => Opcode::I64Load32U,
+        0x36 | 0x38 => Opcode::I32Store,
+        0x37 | 0x39 => Opcode::I64Store,
+        0x3a => Opcode::I32Store8,
+        0x3b => Opcode::I32Store16,
+        0x3c => Opcode::I64Store8,
+        0x3d => Opcode::I64Store16,
+        0x3e => Opcode::I64Store32,
+        _ => return None
+    })
+}
+
+/// Operation number of a floating point opcode relative to `FLOAT_OP_BASE`.
+/// Must match `cvctl::float_ops`.
+fn float_op(byte: u8) -> Option<u32> {
+    let byte = byte as u32;
+    Some(match byte {
+        // abs, neg, ceil, floor, trunc, nearest, sqrt, add, sub, mul, div,
+        // min, max and copysign, then eq, ne, lt, gt, le and ge
+        0x8b..=0x98 => byte - 0x8b,
+        0x5b..=0x60 => 14 + byte - 0x5b,
+        0x99..=0xa6 => 20 + byte - 0x99,
+        0x61..=0x66 => 34 + byte - 0x61,
+
+        // i32 and i64 from f32 and f64, signed and unsigned
+        0xa8..=0xab => 40 + byte - 0xa8,
+        0xae..=0xb1 => 44 + byte - 0xae,
+
+        // f32 and f64 from i32 and i64, signed and unsigned
+        0xb2..=0xb5 => 48 + byte - 0xb2,
+        0xb7..=0xba => 52 + byte - 0xb7,
+
+        0xb6 => 56,
+        0xbb => 57,
+        _ => return None
+    })
+}
+
+fn block_type(r: &mut Reader) -> TransResult<()> {
+    let offset = r.offset();
+    match r.u8()? {
+        0x40 | 0x7f | 0x7e | 0x7d | 0x7c => Ok(()),
+        _ => Err(TransError::Unsupported { offset: offset })
+    }
+}
+
+/// Reads the reserved byte of `call_indirect`, `current_memory` and `grow_memory`.
+fn reserved(r: &mut Reader) -> TransResult<()> {
+    let offset = r.offset();
+    match r.u8()? {
+        0 => Ok(()),
+        _ => Err(TransError::Malformed { offset: offset })
+    }
+}
+
+/// Translates the body of `f`, reading the native invoke ids of imports
+/// from `native_invokes`.
+fn translate_function(
+    m: &Module,
+    f: &Function,
+    globals_slot_offset: usize,
+    native_invokes: &[u32]
+) -> TransResult<Writer> {
+    let mut w = Writer::default();
+    let n_params = m.func_type(f).params.len();
+
+    let mut r = match f.body {
+        Body::Import(i) => {
+            for p in 0..n_params {
+                w.op_u32(Opcode::GetLocal, p as u32);
+            }
+            w.op_u32(Opcode::NativeInvoke, native_invokes[i]);
+            w.op(Opcode::Return);
+            return Ok(w);
+        },
+        Body::Code(ref r) => r.clone()
+    };
+    let n_all_locals = n_params as u64 + f.n_locals as u64;
+
+    // The body itself is a block, which branches to its `end` return.
+    let mut labels: Vec<Label> = vec! [ Label::new(LabelKind::Block) ];
+
+    loop {
+        let offset = r.offset();
+        let byte = r.u8()?;
+
+        if let Some(op) = simple_op(byte) {
+            w.op(op);
+            continue;
+        }
+        if let Some(op) = memory_op(byte) {
+            r.var_u32()?; // alignment
+            w.op_u32(op, r.var_u32()?);
+            continue;
+        }
+        if let Some(n) = float_op(byte) {
+            w.op_u32(Opcode::NativeInvoke, FLOAT_OP_BASE as u32 + n);
+            continue;
+        }
+
+        match byte {
+            // nop
+            0x01 => {},
+            0x02 => {
+                block_type(&mut r)?;
+                labels.push(Label::new(LabelKind::Block));
+            },
+            0x03 => {
+                block_type(&mut r)?;
+                labels.push(Label::new(LabelKind::Loop(w.code.len())));
+            },
+            0x04 => {
+                block_type(&mut r)?;
+                let then = w.jump(Opcode::JmpIf);
+                let otherwise = w.jump(Opcode::Jmp);
+                let len = w.code.len();
+                w.patch(then, len);
+                labels.push(Label::new(LabelKind::If(otherwise)));
+            },
+            0x05 => {
+                let label = match labels.pop() {
+                    Some(Label { kind: LabelKind::If(otherwise), branches }) => {
+                        let mut label = Label::new(LabelKind::Else);
+                        label.branches = branches;
+
+                        // The `then` branch continues after the `else` one.
+                        label.branches.push(w.jump(Opcode::Jmp));
+                        let len = w.code.len();
+                        w.patch(otherwise, len);
+                        label
+                    },
+                    _ => return Err(TransError::UnbalancedBlocks { offset: offset })
+                };
+                labels.push(label);
+            },
+            0x0b => {
+                let label = match labels.pop() {
+                    Some(v) => v,
+                    None => return Err(TransError::UnbalancedBlocks { offset: offset })
+                };
+
+                let len = w.code.len();
+                if let LabelKind::If(otherwise) = label.kind {
+                    w.patch(otherwise, len);
+                }
+                let target = match label.kind {
+                    LabelKind::Loop(start) => start,
+                    _ => len
+                };
+                for pos in label.branches {
+                    w.patch(pos, target);
+                }
+
+                if labels.len() == 0 {
+                    if !r.is_empty() {
+                        return Err(TransError::Malformed { offset: r.offset() });
+                    }
+                    w.op(Opcode::Return);
+                    return Ok(w);
+                }
+            },
+            0x0c | 0x0d => {
+                let depth = r.var_u32()?;
+                let pos = w.jump(if byte == 0x0c { Opcode::Jmp } else { Opcode::JmpIf });
+                label_at(&mut labels, depth, offset)?.branches.push(pos);
+            },
+            0x0e => {
+                w.op(Opcode::JmpTable);
+                let otherwise = w.jump_target();
+
+                let n = r.var_u32()?;
+                w.u32(n);
+                for _ in 0..n {
+                    let depth = r.var_u32()?;
+                    let pos = w.jump_target();
+                    label_at(&mut labels, depth, offset)?.branches.push(pos);
+                }
+
+                let depth = r.var_u32()?;
+                label_at(&mut labels, depth, offset)?.branches.push(otherwise);
+            },
+            0x10 => {
+                let target = r.var_u32()? as usize;
+                if target >= m.functions.len() {
+                    return Err(TransError::IndexBounds { offset: offset });
+                }
+                w.call(m, target);
+            },
+            0x11 => {
+                let n_args = match m.types.get(r.var_u32()? as usize) {
+                    Some(v) => v.params.len(),
+                    None => return Err(TransError::IndexBounds { offset: offset })
+                };
+                reserved(&mut r)?;
+
+                // The table starts at slot 0. Each slot holds the target in
+                // its lower 32 bits and the number of locals in the upper ones.
+                w.op_u32(Opcode::I32Const, 0);
+                w.op(Opcode::I32Add);
+                w.op(Opcode::GetSlotIndirect);
+
+                w.op(Opcode::Dup);
+                w.op_u64(Opcode::I64Const, 0xffffffff);
+                w.op(Opcode::I64And);
+
+                w.op(Opcode::Swap2);
+                w.op_u64(Opcode::I64Const, 0xffffffff << 32);
+                w.op(Opcode::I64And);
+                w.op_u64(Opcode::I64Const, 32);
+                w.op(Opcode::I64ShrU);
+
+                w.op_u32(Opcode::Call, n_args as u32);
+            },
+            0x20 | 0x21 | 0x22 => {
+                let id = r.var_u32()?;
+                if id as u64 >= n_all_locals {
+                    return Err(TransError::IndexBounds { offset: offset });
+                }
+                w.op_u32(match byte {
+                    0x20 => Opcode::GetLocal,
+                    0x21 => Opcode::SetLocal,
+                    _ => Opcode::TeeLocal
+                }, id);
+            },
+            0x23 | 0x24 => {
+                let id = r.var_u32()? as usize;
+                if id >= m.globals.len() {
+                    return Err(TransError::IndexBounds { offset: offset });
+                }
+                w.op_u32(
+                    if byte == 0x23 { Opcode::GetSlot } else { Opcode::SetSlot },
+                    (globals_slot_offset + id) as u32
+                );
+            },
+            0x3f => {
+                // current_memory is in pages.
+                reserved(&mut r)?;
+                w.op(Opcode::CurrentMemory);
+                w.op_u32(Opcode::I32Const, 65536);
+                w.op(Opcode::I32DivU);
+            },
+            0x40 => {
+                reserved(&mut r)?;
+                w.op_u32(Opcode::I32Const, 65536);
+                w.op(Opcode::I32Mul);
+                w.op(Opcode::GrowMemory);
+                w.op_u32(Opcode::I32Const, 65536);
+                w.op(Opcode::I32DivU);
+            },
+            0x41 => w.op_u32(Opcode::I32Const, r.var_i32()? as u32),
+            0x42 => w.op_u64(Opcode::I64Const, r.var_i64()? as u64),
+            0x43 => w.op_u32(Opcode::I32Const, r.le(4)? as u32),
+            0x44 => w.op_u64(Opcode::I64Const, r.le(8)?),
+            0x45 => {
+                w.op_u32(Opcode::I32Const, 0);
+                w.op(Opcode::I32Eq);
+            },
+            0x50 => {
+                w.op_u64(Opcode::I64Const, 0);
+                w.op(Opcode::I64Eq);
+            },
+
+            // Reinterpretations don't change the bits.
+            0xbc..=0xbf => {},
+
+            _ => return Err(TransError::UnknownOpcode { offset: offset, byte: byte })
+        }
+    }
+}
+
+/// Translates `wasm` to code that calls its export `entry` with `args`.
+///
+/// Imports from `cwa` are looked up with `map_import`. Slots are limited
+/// to `max_slots`.
+pub fn translate<F: FnMut(&str) -> Option<u32>>(
+    wasm: &[u8],
+    entry: &str,
+    args: &[i64],
+    max_slots: usize,
+    mut map_import: F
+) -> TransResult<Translated> {
+    let m = Module::parse(wasm, max_slots)?;
+
+    let entry_fn = match m.exports.get(entry) {
+        Some(v) if (*v as usize) < m.functions.len() => *v as usize,
+        _ => return Err(TransError::EntryNotFound)
+    };
+    let params = &m.func_type(&m.functions[entry_fn]).params;
+    if params.iter().any(|t| *t != ValType::I32 && *t != ValType::I64) {
+        return Err(TransError::EntrySignature);
+    }
+    if args.len() != params.len() {
+        return Err(TransError::EntryArgs { expected: params.len(), given: args.len() });
+    }
+    let entry_args: Vec<i64> = args.iter().zip(params.iter())
+        .map(|(v, t)| if *t == ValType::I32 { *v as u32 as i64 } else { *v })
+        .collect();
+
+    // The table comes first in the slots, then the globals.
+    let n_slots = m.table.len() + m.globals.len();
+    if n_slots > max_slots {
+        return Err(TransError::TooManySlots { n_slots: n_slots });
+    }
+    let globals_slot_offset = m.table.len();
+
+    let mut native_invokes: Vec<u32> = Vec::with_capacity(m.imports.len());
+    let mut unresolved: Vec<(String, String)> = Vec::new();
+    for import in &m.imports {
+        let id = if import.module == "cwa" {
+            map_import(import.field)
+        } else {
+            None
+        };
+        native_invokes.push(id.unwrap_or_else(|| {
+            unresolved.push((String::from(import.module), String::from(import.field)));
+            0
+        }));
+    }
+    if unresolved.len() > 0 {
+        return Err(TransError::UnresolvedImports(unresolved));
+    }
+
+    let mut functions: Vec<Writer> = Vec::with_capacity(m.functions.len());
+    for f in &m.functions {
+        functions.push(translate_function(&m, f, globals_slot_offset, &native_invokes)?);
+    }
+
+    // Memory initializers, as `(addr, len, data)`, with their total length first
+    let mut initializers = Writer::default();
+    for ds in &m.data {
+        initializers.u32(ds.offset);
+        initializers.u32(ds.data.len() as u32);
+        initializers.code.extend_from_slice(ds.data);
+    }
+    let mut out = Writer::default();
+    out.u32(initializers.code.len() as u32);
+    out.code.extend_from_slice(&initializers.code);
+
+    let mut exec = Writer::default();
+    exec.op_u32(Opcode::ResetSlots, n_slots as u32);
+
+    // Table slots are relocated to the start of their function.
+    for (i, elem) in m.table.iter().enumerate() {
+        let value = match *elem {
+            Some(f) => ((m.functions[f as usize].n_locals as u64) << 32) | ::core::u32::MAX as u64,
+            None => ::core::u64::MAX
+        };
+        exec.op(Opcode::I64Const);
+        if let Some(f) = *elem {
+            let pos = exec.code.len();
+            exec.relocs.push((pos, Reloc::Function(f as usize)));
+        }
+        exec.u64(value);
+        exec.op_u32(Opcode::SetSlot, i as u32);
+    }
+    for (i, value) in m.globals.iter().enumerate() {
+        exec.op_u64(Opcode::I64Const, *value as u64);
+        exec.op_u32(Opcode::SetSlot, (globals_slot_offset + i) as u32);
+    }
+
+    exec.call(&m, entry_fn);
+    exec.op(Opcode::Halt);
+
+    let mut starts: Vec<usize> = Vec::with_capacity(functions.len());
+    for f in &functions {
+        starts.push(exec.code.len());
+        exec.append(f);
+    }
+    for &(pos, reloc) in &exec.relocs {
+        if let Reloc::Function(id) = reloc {
+            let target = starts[id];
+            let v: [u8; 4] = unsafe { ::core::mem::transmute((target as u32).to_le()) };
+            exec.code[pos..pos + 4].copy_from_slice(&v);
+        }
+    }
+
+    out.code.extend_from_slice(&exec.code);
+
+    Ok(Translated {
+        code: out.code,
+        entry_args: entry_args
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use verify::{self, OpcodeMap, VerifyResult};
+    use float_ops;
+
+    /// Id that `cwa.log_write` is registered as.
+    const LOG_WRITE: u32 = 3;
+
+    /// Offset of the body passed to `module` in the binary.
+    const BODY_OFFSET: usize = 59;
+
+    fn map_import(name: &str) -> Option<u32> {
+        if name == "log_write" { Some(LOG_WRITE) } else { None }
+    }
+
+    fn section(m: &mut Vec<u8>, id: u8, payload: &[u8]) {
+        m.push(id);
+        m.push(payload.len() as u8);
+        m.extend_from_slice(payload);
+    }
+
+    /// A module that imports `cwa.log_write` and exports `main`, which
+    /// takes an i32, returns an i32 and runs `body`.
+    fn module(body: &[u8]) -> Vec<u8> {
+        let mut m = b"\0asm\x01\0\0\0".to_vec();
+        section(&mut m, 1, b"\x02\x60\x01\x7f\x01\x7f\x60\x02\x7f\x7f\x00");
+        section(&mut m, 2, b"\x01\x03cwa\x09log_write\x00\x01");
+        section(&mut m, 3, b"\x01\x00");
+        section(&mut m, 7, b"\x01\x04main\x00\x01");
+
+        let mut code = vec! [ 1, body.len() as u8 + 1, 0 ];
+        code.extend_from_slice(body);
+        section(&mut m, 10, &code);
+        m
+    }
+
+    fn check(t: &Translated) -> VerifyResult<OpcodeMap> {
+        let m = ::hexagon_e::module::Module::from_raw(&t.code).unwrap();
+        verify::verify(&m, t.entry_args.len(), 64, 16, 4, |id| {
+            float_ops::n_args(id).or_else(|| if id == LOG_WRITE as usize { Some(2) } else { None })
+        })
+    }
+
+    fn error(body: &[u8]) -> Option<TransError> {
+        translate(&module(body), "main", &[0], 4, map_import).err()
+    }
+
+    #[test]
+    fn translate_branches() {
+        // if (result i32) i32.const 1 else i32.const 2 end
+        let t = translate(&module(b"\x20\x00\x04\x7f\x41\x01\x05\x41\x02\x0b\x0b"), "main", &[-1], 4, map_import).unwrap();
+
+        assert_eq!(t.entry_args, vec! [ 0xffffffff ]);
+        assert!(check(&t).is_ok());
+    }
+
+    #[test]
+    fn lower_imports_and_float_ops() {
+        // log_write(f32.add(1.0, 2.0) as i32, local 0)
+        let body = b"\x43\x00\x00\x80\x3f\x43\x00\x00\x00\x40\x92\xbc\x20\x00\x10\x00\x20\x00\x0b";
+        let t = translate(&module(body), "main", &[0], 4, map_import).unwrap();
+
+        let map = check(&t).unwrap();
+        assert_eq!(map.native_invokes(), &[ LOG_WRITE as usize, FLOAT_OP_BASE + 7 ]);
+    }
+
+    #[test]
+    fn reject_unresolved_imports() {
+        match translate(&module(b"\x20\x00\x0b"), "main", &[0], 4, |_| None) {
+            Err(TransError::UnresolvedImports(v)) => {
+                assert_eq!(v, vec! [ (String::from("cwa"), String::from("log_write")) ]);
+            },
+            _ => panic!("unresolved import accepted")
+        }
+    }
+
+    #[test]
+    fn reject_bad_entry() {
+        let m = module(b"\x20\x00\x0b");
+
+        assert_eq!(translate(&m, "start", &[0], 4, map_import).err(), Some(TransError::EntryNotFound));
+        assert_eq!(
+            translate(&m, "main", &[], 4, map_import).err(),
+            Some(TransError::EntryArgs { expected: 1, given: 0 })
+        );
+    }
+
+    #[test]
+    fn reject_malformed_bodies() {
+        assert_eq!(error(b"\x05\x0b"), Some(TransError::UnbalancedBlocks { offset: BODY_OFFSET }));
+        assert_eq!(error(b"\x0c\x01\x0b"), Some(TransError::IndexBounds { offset: BODY_OFFSET }));
+        assert_eq!(error(b"\x20\x01\x0b"), Some(TransError::IndexBounds { offset: BODY_OFFSET }));
+        assert_eq!(error(b"\x10\x02\x0b"), Some(TransError::IndexBounds { offset: BODY_OFFSET }));
+        assert_eq!(error(b"\xfc\x0b"), Some(TransError::UnknownOpcode { offset: BODY_OFFSET, byte: 0xfc }));
+        assert_eq!(error(b"\x0b\x01"), Some(TransError::Malformed { offset: BODY_OFFSET + 1 }));
+        assert_eq!(error(b"\x02\x40\x0b"), Some(TransError::Malformed { offset: BODY_OFFSET + 3 }));
+    }
+
+    #[test]
+    fn reject_truncated_modules() {
+        let m = module(b"\x20\x00\x04\x7f\x41\x01\x05\x41\x02\x0b\x0b");
+        for len in 0..m.len() {
+            assert!(translate(&m[..len], "main", &[0], 4, map_import).is_err());
+        }
+    }
+}
@@ -0,0 +1,996 @@
+//! Checks translated code before the interpreter runs it.
+//!
+//! Code comes from userspace, so everything the interpreter would otherwise
+//! find out halfway through a run is checked up front:
+//!
+//! - every opcode is known and has all of its immediates
+//! - jump targets and the targets of direct calls start an opcode
+//! - the code starts with its only `ResetSlots`, and `GetSlot` and `SetSlot`
+//!   stay below the slot count it sets
+//! - every native invoke id exists
+//! - no function pops values that it can't have pushed
+//! - memory initializers are well-formed and fit into the initial memory
+//!
+//! `verify_resume` also checks the state a checkpoint continues with, so
+//! that a resumed run returns only to the opcodes after calls and its
+//! stacks fit what the code can have at that point.
+//!
+//! Calls through the table jump to a value read from a slot, so their
+//! targets are only known at run time. `OpcodeMap` lets the interpreter
+//! check that they start an opcode, which keeps execution on the opcodes
+//! checked here.
+//!
+//! The stack check follows the maximum height each opcode can see, since
+//! native invokes may or may not push a result. It rejects code that pops
+//! a value its function never pushed on any path, like a `Drop` at the start
+//! of a function or a call that takes more arguments than are there. Reads
+//! below the stack are still caught by the interpreter.
+
+use prelude::{Vec, BTreeMap};
+
+use hexagon_e::module::{Module, Opcode};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VerifyError {
+    /// A memory initializer record is cut off.
+    TruncatedInitializer,
+
+    /// A memory initializer writes outside of the initial memory.
+    InitializerBounds { addr: usize, len: usize },
+
+    /// The byte at `offset` isn't an opcode.
+    IllegalOpcode { offset: usize, byte: u8 },
+
+    /// The code ends within the immediates of the opcode at `offset`.
+    TruncatedOpcode { offset: usize },
+
+    /// The code doesn't start with `ResetSlots`.
+    MissingResetSlots,
+
+    /// Another `ResetSlots`, which the interpreter refuses to run.
+    ResetSlotsAgain { offset: usize },
+
+    /// `ResetSlots` asks for more slots than the configuration allows.
+    TooManySlots { n_slots: usize },
+
+    /// `GetSlot` or `SetSlot` uses a slot that doesn't exist.
+    SlotBounds { offset: usize, slot: usize },
+
+    /// A jump doesn't land on an opcode.
+    JumpTarget { offset: usize, target: usize },
+
+    /// A direct call doesn't land on an opcode.
+    CallTarget { offset: usize, target: usize },
+
+    /// The native invoke id isn't registered.
+    NativeInvoke { offset: usize, id: usize },
+
+    /// The opcode pops more values than its function has pushed.
+    StackUnderflow { offset: usize },
+
+    /// Execution continues past the end of the code.
+    RunsOffEnd { offset: usize },
+
+    /// The opcode can be reached from more than one function.
+    SharedCode { offset: usize },
+
+    /// A resumed run continues at an offset the code can't be at.
+    ResumeOffset { offset: usize },
+
+    /// A resumed run has another number of slots than `ResetSlots` sets up.
+    ResumeSlots { n_slots: usize },
+
+    /// Call frame `depth` of a resumed run, counted from the top, doesn't
+    /// return to right after a call that can have pushed it.
+    ResumeFrame { depth: usize },
+
+    /// A resumed run has more values on the stack than the code can push.
+    ResumeStack { len: usize }
+}
+
+pub type VerifyResult<T> = Result<T, VerifyError>;
+
+/// Length of the immediates that follow `op`, which start at `imm`.
+pub fn immediate_len(op: &Opcode, imm: &[u8]) -> usize {
+    match *op {
+        Opcode::Call
+            | Opcode::GetLocal | Opcode::SetLocal | Opcode::TeeLocal
+            | Opcode::GetSlot | Opcode::SetSlot | Opcode::ResetSlots
+            | Opcode::NativeInvoke
+            | Opcode::Jmp | Opcode::JmpIf
+            | Opcode::I32Load | Opcode::I32Load8U | Opcode::I32Load8S
+            | Opcode::I32Load16U | Opcode::I32Load16S
+            | Opcode::I32Store | Opcode::I32Store8 | Opcode::I32Store16
+            | Opcode::I64Load | Opcode::I64Load8U | Opcode::I64Load8S
+            | Opcode::I64Load16U | Opcode::I64Load16S
+            | Opcode::I64Load32U | Opcode::I64Load32S
+            | Opcode::I64Store | Opcode::I64Store8 | Opcode::I64Store16 | Opcode::I64Store32
+            | Opcode::I32Const => 4,
+        Opcode::JmpEither | Opcode::I64Const => 8,
+
+        // Default target, table length and the table. A truncated table
+        // makes the interpreter fail before it moves on.
+        Opcode::JmpTable => if imm.len() >= 8 {
+            8 + read_u32(&imm[4..]) as usize * 4
+        } else {
+            8
+        },
+        _ => 0
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
+
+/// Start offsets of the opcodes in the code.
+pub struct OpcodeMap {
+    bits: Vec<u8>,
+
+    /// Ids of the native invokes the code uses, float operations included
+    native_invokes: Vec<usize>
+}
+
+impl OpcodeMap {
+    pub fn contains(&self, offset: usize) -> bool {
+        match self.bits.get(offset / 8) {
+            Some(b) => b & (1 << (offset % 8)) != 0,
+            None => false
+        }
+    }
+
+    pub fn native_invokes(&self) -> &[usize] {
+        &self.native_invokes
+    }
+}
+
+const NO_FUNCTION: u32 = ::core::u32::MAX;
+const UNREACHED: i64 = ::core::i64::MIN;
+
+/// Updates of the stack height of an opcode before it is set to the limit.
+/// Keeps loops that push on every iteration from being followed until the
+/// stack is full.
+const MAX_HEIGHT_UPDATES: u8 = 3;
+
+struct Code<'a> {
+    code: &'a [u8],
+    offsets: Vec<u32>,
+    ops: Vec<Opcode>,
+
+    /// Whether a jump lands on the opcode.
+    jump_targets: Vec<bool>
+}
+
+impl<'a> Code<'a> {
+    fn decode(code: &'a [u8]) -> VerifyResult<Code<'a>> {
+        let mut offsets: Vec<u32> = Vec::new();
+        let mut ops: Vec<Opcode> = Vec::new();
+        let mut pos: usize = 0;
+
+        while pos < code.len() {
+            let op = match Opcode::from_raw(code[pos]) {
+                Ok(v) => v,
+                Err(_) => return Err(VerifyError::IllegalOpcode { offset: pos, byte: code[pos] })
+            };
+            let end = pos + 1 + immediate_len(&op, &code[pos + 1..]);
+            if end > code.len() {
+                return Err(VerifyError::TruncatedOpcode { offset: pos });
+            }
+
+            offsets.push(pos as u32);
+            ops.push(op);
+            pos = end;
+        }
+
+        let n_ops = ops.len();
+        Ok(Code {
+            code: code,
+            offsets: offsets,
+            ops: ops,
+            jump_targets: vec! [ false; n_ops ]
+        })
+    }
+
+    fn offset(&self, i: usize) -> usize {
+        self.offsets[i] as usize
+    }
+
+    fn imm_u32(&self, i: usize, n: usize) -> usize {
+        read_u32(&self.code[self.offset(i) + 1 + n * 4..]) as usize
+    }
+
+    /// Index of the opcode that starts at `offset`.
+    fn index_of(&self, offset: usize) -> Option<usize> {
+        if offset > ::core::u32::MAX as usize {
+            return None;
+        }
+        self.offsets.binary_search(&(offset as u32)).ok()
+    }
+
+    /// Static jump targets of opcode `i`, as offsets.
+    fn jumps(&self, i: usize) -> Vec<usize> {
+        match self.ops[i] {
+            Opcode::Jmp | Opcode::JmpIf => vec! [ self.imm_u32(i, 0) ],
+            Opcode::JmpEither => vec! [ self.imm_u32(i, 0), self.imm_u32(i, 1) ],
+            Opcode::JmpTable => {
+                let mut targets = vec! [ self.imm_u32(i, 0) ];
+                targets.extend((0..self.imm_u32(i, 1)).map(|n| self.imm_u32(i, 2 + n)));
+                targets
+            },
+            _ => vec! []
+        }
+    }
+
+    /// Whether execution can continue with the next opcode after `i`.
+    fn falls_through(&self, i: usize) -> bool {
+        match self.ops[i] {
+            Opcode::Return | Opcode::Halt | Opcode::Unreachable | Opcode::NotSupported
+                | Opcode::Jmp | Opcode::JmpEither | Opcode::JmpTable => false,
+            _ => true
+        }
+    }
+
+    /// Offset of the function called by the `Call` at `i`, if it is the
+    /// `I32Const <target>`, `I32Const <n_locals>`, `Call` sequence the
+    /// translator emits for direct calls.
+    fn direct_call_target(&self, i: usize) -> Option<usize> {
+        if i < 2 || self.jump_targets[i] || self.jump_targets[i - 1] {
+            return None;
+        }
+        match (self.ops[i - 2], self.ops[i - 1]) {
+            (Opcode::I32Const, Opcode::I32Const) => Some(self.imm_u32(i - 2, 0)),
+            _ => None
+        }
+    }
+
+    /// Indices of the opcodes that can run after `i`.
+    fn successors(&self, i: usize) -> VerifyResult<Vec<usize>> {
+        let mut out: Vec<usize> = self.jumps(i).iter()
+            .map(|t| self.index_of(*t).unwrap())
+            .collect();
+
+        if self.falls_through(i) {
+            if i + 1 == self.ops.len() {
+                return Err(VerifyError::RunsOffEnd { offset: self.offset(i) });
+            }
+            out.push(i + 1);
+        }
+
+        Ok(out)
+    }
+}
+
+/// Values popped and pushed by opcodes other than `Call`, `Return` and
+/// `NativeInvoke`.
+fn stack_effect(op: &Opcode) -> (i32, i32) {
+    match *op {
+        Opcode::Drop | Opcode::SetLocal | Opcode::SetSlot
+            | Opcode::JmpIf | Opcode::JmpEither | Opcode::JmpTable => (1, 0),
+        Opcode::Dup => (1, 2),
+        Opcode::Swap2 => (2, 2),
+        Opcode::Select => (3, 1),
+
+        Opcode::GetLocal | Opcode::GetSlot | Opcode::CurrentMemory
+            | Opcode::I32Const | Opcode::I64Const => (0, 1),
+        Opcode::TeeLocal | Opcode::GetSlotIndirect | Opcode::GrowMemory
+            | Opcode::I32Ctz | Opcode::I32Clz | Opcode::I32Popcnt | Opcode::I32WrapI64
+            | Opcode::I64Ctz | Opcode::I64Clz | Opcode::I64Popcnt
+            | Opcode::I64ExtendI32U | Opcode::I64ExtendI32S => (1, 1),
+
+        Opcode::I32Load | Opcode::I32Load8U | Opcode::I32Load8S
+            | Opcode::I32Load16U | Opcode::I32Load16S
+            | Opcode::I64Load | Opcode::I64Load8U | Opcode::I64Load8S
+            | Opcode::I64Load16U | Opcode::I64Load16S
+            | Opcode::I64Load32U | Opcode::I64Load32S => (1, 1),
+        Opcode::I32Store | Opcode::I32Store8 | Opcode::I32Store16
+            | Opcode::I64Store | Opcode::I64Store8 | Opcode::I64Store16 | Opcode::I64Store32 => (2, 0),
+
+        Opcode::Call | Opcode::Return | Opcode::Halt
+            | Opcode::ResetSlots | Opcode::NativeInvoke
+            | Opcode::Nop | Opcode::Unreachable | Opcode::NotSupported
+            | Opcode::Jmp | Opcode::Never => (0, 0),
+
+        // Binary operators and comparisons
+        _ => (2, 1)
+    }
+}
+
+fn verify_initializers(mut init: &[u8], mem_len: usize) -> VerifyResult<()> {
+    while init.len() > 0 {
+        if init.len() < 8 {
+            return Err(VerifyError::TruncatedInitializer);
+        }
+        let addr = read_u32(init) as usize;
+        let len = read_u32(&init[4..]) as usize;
+        init = &init[8..];
+
+        if init.len() < len {
+            return Err(VerifyError::TruncatedInitializer);
+        }
+        if addr >= mem_len || addr + len > mem_len {
+            return Err(VerifyError::InitializerBounds { addr: addr, len: len });
+        }
+        init = &init[len..];
+    }
+
+    Ok(())
+}
+
+/// Checks the opcodes one at a time and returns the number of arguments of
+/// every native invoke they use.
+fn verify_opcodes<F: Fn(usize) -> Option<usize>>(
+    code: &mut Code,
+    max_slots: usize,
+    native_invoke_args: F
+) -> VerifyResult<BTreeMap<usize, usize>> {
+    let n_slots = match code.ops.first() {
+        Some(&Opcode::ResetSlots) => code.imm_u32(0, 0),
+        _ => return Err(VerifyError::MissingResetSlots)
+    };
+    if n_slots > max_slots {
+        return Err(VerifyError::TooManySlots { n_slots: n_slots });
+    }
+
+    let mut native_invokes: BTreeMap<usize, usize> = BTreeMap::new();
+
+    for i in 0..code.ops.len() {
+        let offset = code.offset(i);
+
+        match code.ops[i] {
+            Opcode::ResetSlots => if i != 0 {
+                return Err(VerifyError::ResetSlotsAgain { offset: offset });
+            },
+            Opcode::GetSlot | Opcode::SetSlot => {
+                let slot = code.imm_u32(i, 0);
+                if slot >= n_slots {
+                    return Err(VerifyError::SlotBounds { offset: offset, slot: slot });
+                }
+            },
+            Opcode::NativeInvoke => {
+                let id = code.imm_u32(i, 0);
+                if !native_invokes.contains_key(&id) {
+                    match native_invoke_args(id) {
+                        Some(n) => native_invokes.insert(id, n),
+                        None => return Err(VerifyError::NativeInvoke { offset: offset, id: id })
+                    };
+                }
+            },
+            _ => {}
+        }
+
+        for target in code.jumps(i) {
+            match code.index_of(target) {
+                Some(t) => code.jump_targets[t] = true,
+                None => return Err(VerifyError::JumpTarget { offset: offset, target: target })
+            }
+        }
+    }
+
+    Ok(native_invokes)
+}
+
+/// Functions reachable from the startup code, which is function 0.
+struct Functions {
+    /// First opcode of each function
+    entries: Vec<usize>,
+
+    /// Function that starts at an opcode
+    ids: BTreeMap<usize, usize>,
+
+    /// Functions called directly by each function
+    callees: Vec<Vec<usize>>,
+
+    /// Function that each opcode belongs to
+    owner: Vec<u32>
+}
+
+impl Functions {
+    /// Function called by the `Call` at `i`, if it is a direct call.
+    fn callee(&self, code: &Code, i: usize) -> Option<usize> {
+        code.direct_call_target(i)
+            .and_then(|t| code.index_of(t))
+            .and_then(|t| self.ids.get(&t).cloned())
+    }
+}
+
+/// Assigns the opcodes reachable from the startup code to functions, which
+/// start at the startup code and at the targets of direct calls.
+fn find_functions(code: &Code) -> VerifyResult<Functions> {
+    let mut fns = Functions {
+        entries: vec! [ 0 ],
+        ids: BTreeMap::new(),
+        callees: Vec::new(),
+        owner: vec! [ NO_FUNCTION; code.ops.len() ]
+    };
+    fns.ids.insert(0, 0);
+
+    let mut f = 0;
+    while f < fns.entries.len() {
+        let mut callees: Vec<usize> = Vec::new();
+        let mut pending: Vec<usize> = vec! [ fns.entries[f] ];
+
+        while let Some(i) = pending.pop() {
+            if fns.owner[i] == f as u32 {
+                continue;
+            }
+            if fns.owner[i] != NO_FUNCTION {
+                return Err(VerifyError::SharedCode { offset: code.offset(i) });
+            }
+            fns.owner[i] = f as u32;
+
+            if let Opcode::Call = code.ops[i] {
+                if let Some(target) = code.direct_call_target(i) {
+                    let t = match code.index_of(target) {
+                        Some(v) => v,
+                        None => return Err(VerifyError::CallTarget { offset: code.offset(i), target: target })
+                    };
+                    let callee = match fns.ids.get(&t).cloned() {
+                        Some(v) => v,
+                        None => {
+                            fns.entries.push(t);
+                            fns.ids.insert(t, fns.entries.len() - 1);
+                            fns.entries.len() - 1
+                        }
+                    };
+                    if !callees.contains(&callee) {
+                        callees.push(callee);
+                    }
+                }
+            }
+
+            pending.extend(code.successors(i)?);
+        }
+
+        fns.callees.push(callees);
+        f += 1;
+    }
+
+    Ok(fns)
+}
+
+/// Orders functions so that callees come before their callers, except
+/// within cycles of recursive calls.
+fn callees_first(callees: &[Vec<usize>]) -> Vec<usize> {
+    let mut order: Vec<usize> = Vec::with_capacity(callees.len());
+    let mut visited = vec! [ false; callees.len() ];
+    let mut path: Vec<(usize, usize)> = vec! [ (0, 0) ];
+    visited[0] = true;
+
+    while let Some((f, next)) = path.pop() {
+        if next < callees[f].len() {
+            path.push((f, next + 1));
+            let callee = callees[f][next];
+            if !visited[callee] {
+                visited[callee] = true;
+                path.push((callee, 0));
+            }
+        } else {
+            order.push(f);
+        }
+    }
+
+    order
+}
+
+/// Values popped by opcode `i`, and the values it pushes before execution
+/// moves on, or `None` if it doesn't.
+fn stack_effect_at(
+    code: &Code,
+    fns: &Functions,
+    results: &[Option<Option<i64>>],
+    native_invokes: &BTreeMap<usize, usize>,
+    limit: i64,
+    i: usize
+) -> (i64, Option<i64>) {
+    match code.ops[i] {
+        Opcode::Call => {
+            let pop = 2 + code.imm_u32(i, 0) as i64;
+            match fns.callee(code, i).map(|c| results[c]) {
+                Some(Some(v)) => (pop, v),
+                _ => (pop, Some(limit))
+            }
+        },
+        Opcode::NativeInvoke => (native_invokes[&code.imm_u32(i, 0)] as i64, Some(1)),
+        Opcode::Return => (0, None),
+        ref op => {
+            let (pop, push) = stack_effect(op);
+            (pop as i64, Some(push as i64))
+        }
+    }
+}
+
+/// Follows the maximum stack height, relative to the start of the function,
+/// through every function, callees first.
+fn verify_stack(
+    code: &Code,
+    fns: &Functions,
+    native_invokes: &BTreeMap<usize, usize>,
+    n_entry_args: usize,
+    stack_len: usize
+) -> VerifyResult<Vec<i64>> {
+    let limit = stack_len as i64;
+
+    // Maximum height at the start of each opcode, and the number of times
+    // it went up.
+    let mut heights = vec! [ UNREACHED; code.ops.len() ];
+    let mut updates = vec! [ 0u8; code.ops.len() ];
+
+    // Maximum number of values each function leaves on the stack, `None`
+    // if it never returns. Functions that haven't been followed yet could
+    // leave anything.
+    let mut results: Vec<Option<Option<i64>>> = vec! [ None; fns.entries.len() ];
+
+    for f in callees_first(&fns.callees) {
+        let entry = fns.entries[f];
+        heights[entry] = if f == 0 {
+            ::core::cmp::min(n_entry_args as i64, limit)
+        } else {
+            0
+        };
+
+        let mut reached: Vec<usize> = vec! [ entry ];
+        let mut pending: Vec<usize> = vec! [ entry ];
+
+        while let Some(i) = pending.pop() {
+            let next_height = match stack_effect_at(code, fns, &results, native_invokes, limit, i) {
+                (pop, Some(push)) => ::core::cmp::min(heights[i] - pop + push, limit),
+                (_, None) => continue
+            };
+
+            for s in code.successors(i)? {
+                if fns.owner[s] != f as u32 || heights[s] >= next_height {
+                    continue;
+                }
+                if heights[s] == UNREACHED {
+                    reached.push(s);
+                }
+                updates[s] += 1;
+                heights[s] = if updates[s] >= MAX_HEIGHT_UPDATES { limit } else { next_height };
+                pending.push(s);
+            }
+        }
+
+        // Heights are only final once every path has been followed.
+        let mut result: Option<i64> = None;
+        for i in reached {
+            let (pop, _) = stack_effect_at(code, fns, &results, native_invokes, limit, i);
+            if heights[i] < pop {
+                return Err(VerifyError::StackUnderflow { offset: code.offset(i) });
+            }
+            if let Opcode::Return = code.ops[i] {
+                result = Some(::core::cmp::max(result.unwrap_or(0), heights[i]));
+            }
+        }
+        results[f] = Some(result);
+    }
+
+    Ok(heights)
+}
+
+/// Where a checkpointed run continues.
+pub struct ResumePoint<'a> {
+    /// Offset of the opcode to continue at
+    pub ip: usize,
+
+    pub n_slots: usize,
+
+    /// Number of values on the stack
+    pub stack_len: usize,
+
+    /// The live part of the call stack, bottom first
+    pub call_stack: &'a [i64]
+}
+
+/// Checks that `resume` is a state the code can be in: every call frame
+/// returns to right after a `Call` in the function that the frame below it
+/// called, and the stack holds at most as many values as the functions on
+/// the call stack can have pushed.
+///
+/// Functions that are only called through the table weren't followed, so
+/// they are taken to be able to be anywhere and to push anything.
+fn verify_resume_point(
+    code: &Code,
+    fns: &Functions,
+    heights: &[i64],
+    n_slots: usize,
+    resume: &ResumePoint
+) -> VerifyResult<()> {
+    if resume.n_slots != n_slots {
+        return Err(VerifyError::ResumeSlots { n_slots: resume.n_slots });
+    }
+
+    let ip = match code.index_of(resume.ip) {
+        Some(v) => v,
+        None => return Err(VerifyError::ResumeOffset { offset: resume.ip })
+    };
+
+    // Frame layout from the top: return ip, number of locals, locals.
+    let cs = resume.call_stack;
+    let mut calls: Vec<usize> = Vec::new();
+    let mut pos = cs.len();
+
+    while pos > 0 {
+        let bad_frame = VerifyError::ResumeFrame { depth: calls.len() };
+        if pos < 2 || cs[pos - 2] < 0 || cs[pos - 2] as u64 > (pos - 2) as u64 || cs[pos - 1] < 0 {
+            return Err(bad_frame);
+        }
+        let n_all_locals = cs[pos - 2] as usize;
+
+        let call = match code.index_of(cs[pos - 1] as u64 as usize) {
+            Some(r) if r > 0 => match code.ops[r - 1] {
+                Opcode::Call => r - 1,
+                _ => return Err(bad_frame)
+            },
+            _ => return Err(bad_frame)
+        };
+
+        // Direct calls give their number of locals.
+        let n_args = code.imm_u32(call, 0);
+        let locals_match = match code.direct_call_target(call) {
+            Some(_) => n_all_locals == n_args + code.imm_u32(call - 1, 0),
+            None => n_all_locals >= n_args
+        };
+        if !locals_match {
+            return Err(bad_frame);
+        }
+
+        calls.push(call);
+        pos -= 2 + n_all_locals;
+    }
+
+    // Follow the calls from the startup code, which is function 0.
+    let mut expected: Option<usize> = Some(0);
+    let mut max_len: i64 = 0;
+    let mut bounded = true;
+
+    for (n, &call) in calls.iter().rev().enumerate() {
+        let bad_frame = VerifyError::ResumeFrame { depth: calls.len() - 1 - n };
+        let owner = fns.owner[call];
+
+        if expected.map(|f| owner != f as u32).unwrap_or(false) {
+            return Err(bad_frame);
+        }
+        if owner == NO_FUNCTION {
+            bounded = false;
+        } else if heights[call] == UNREACHED {
+            return Err(bad_frame);
+        } else {
+            max_len += heights[call] - 2 - code.imm_u32(call, 0) as i64;
+        }
+
+        expected = fns.callee(code, call);
+    }
+
+    let owner = fns.owner[ip];
+    if expected.map(|f| owner != f as u32).unwrap_or(false) || (owner != NO_FUNCTION && heights[ip] == UNREACHED) {
+        return Err(VerifyError::ResumeOffset { offset: resume.ip });
+    }
+    if owner == NO_FUNCTION {
+        bounded = false;
+    } else {
+        max_len += heights[ip];
+    }
+
+    if bounded && resume.stack_len as i64 > max_len {
+        return Err(VerifyError::ResumeStack { len: resume.stack_len });
+    }
+
+    Ok(())
+}
+
+/// Verifies `m` for a run that starts with `n_entry_args` values on a stack
+/// of `stack_len` values and with `mem_len` bytes of memory.
+/// `native_invoke_args` returns the number of arguments of a native invoke,
+/// or `None` if the id isn't registered.
+pub fn verify<F: Fn(usize) -> Option<usize>>(
+    m: &Module,
+    n_entry_args: usize,
+    stack_len: usize,
+    mem_len: usize,
+    max_slots: usize,
+    native_invoke_args: F
+) -> VerifyResult<OpcodeMap> {
+    verify_with(m, n_entry_args, stack_len, mem_len, max_slots, native_invoke_args, None)
+}
+
+/// Like `verify`, for a run that continues at `resume` instead of the start.
+pub fn verify_resume<F: Fn(usize) -> Option<usize>>(
+    m: &Module,
+    n_entry_args: usize,
+    stack_len: usize,
+    mem_len: usize,
+    max_slots: usize,
+    native_invoke_args: F,
+    resume: &ResumePoint
+) -> VerifyResult<OpcodeMap> {
+    verify_with(m, n_entry_args, stack_len, mem_len, max_slots, native_invoke_args, Some(resume))
+}
+
+fn verify_with<F: Fn(usize) -> Option<usize>>(
+    m: &Module,
+    n_entry_args: usize,
+    stack_len: usize,
+    mem_len: usize,
+    max_slots: usize,
+    native_invoke_args: F,
+    resume: Option<&ResumePoint>
+) -> VerifyResult<OpcodeMap> {
+    verify_initializers(m.memory_initializers, mem_len)?;
+
+    let mut code = Code::decode(m.code)?;
+    let native_invokes = verify_opcodes(&mut code, max_slots, native_invoke_args)?;
+
+    let fns = find_functions(&code)?;
+    let heights = verify_stack(&code, &fns, &native_invokes, n_entry_args, stack_len)?;
+
+    if let Some(resume) = resume {
+        let n_slots = code.imm_u32(0, 0);
+        verify_resume_point(&code, &fns, &heights, n_slots, resume)?;
+    }
+
+    let mut bits = vec! [ 0u8; (m.code.len() + 7) / 8 ];
+    for offset in &code.offsets {
+        let offset = *offset as usize;
+        bits[offset / 8] |= 1 << (offset % 8);
+    }
+
+    Ok(OpcodeMap {
+        bits: bits,
+        native_invokes: native_invokes.keys().cloned().collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use float_ops::{self, FLOAT_OP_BASE};
+
+    /// Id of a native invoke with two arguments.
+    const INVOKE: u32 = 3;
+
+    struct Asm {
+        code: Vec<u8>
+    }
+
+    impl Asm {
+        /// Startup code that sets up one slot and calls the function that
+        /// follows it with one argument.
+        fn new() -> Asm {
+            let mut asm = Asm { code: Vec::new() };
+            asm.op(Opcode::ResetSlots, &[1]);
+            asm.op(Opcode::I64Const, &[7, 0]);
+            asm.op(Opcode::SetSlot, &[0]);
+            let entry = asm.code.len() as u32 + 16;
+            asm.op(Opcode::I32Const, &[entry]);
+            asm.op(Opcode::I32Const, &[0]);
+            asm.op(Opcode::Call, &[1]);
+            asm.op(Opcode::Halt, &[]);
+            asm
+        }
+
+        fn op(&mut self, op: Opcode, imm: &[u32]) -> u32 {
+            let offset = self.code.len() as u32;
+            self.code.push(op as u8);
+            for v in imm {
+                for i in 0..4 {
+                    self.code.push((v >> (i * 8)) as u8);
+                }
+            }
+            offset
+        }
+
+        /// Sets the first immediate of the opcode at `offset`.
+        fn patch(&mut self, offset: u32, v: u32) {
+            for i in 0..4 {
+                self.code[offset as usize + 1 + i] = (v >> (i * 8)) as u8;
+            }
+        }
+    }
+
+    fn check(init: &[u8], code: &[u8], n_entry_args: usize) -> VerifyResult<OpcodeMap> {
+        let m = Module {
+            memory_initializers: init,
+            code: code
+        };
+        verify(&m, n_entry_args, 64, 16, 4, |id| {
+            float_ops::n_args(id).or_else(|| if id == INVOKE as usize { Some(2) } else { None })
+        })
+    }
+
+    fn check_body(f: &Fn(&mut Asm)) -> Option<VerifyError> {
+        let mut asm = Asm::new();
+        f(&mut asm);
+        check(&[], &asm.code, 1).err()
+    }
+
+    #[test]
+    fn accept_translated_code() {
+        let mut asm = Asm::new();
+        let entry = asm.op(Opcode::GetLocal, &[0]);
+        let jmp = asm.op(Opcode::JmpIf, &[0]);
+        asm.op(Opcode::GetSlot, &[0]);
+        asm.op(Opcode::Return, &[]);
+        let float_op = asm.op(Opcode::I32Const, &[0x3f80_0000]);
+        asm.op(Opcode::NativeInvoke, &[FLOAT_OP_BASE as u32 + 6]);
+        asm.op(Opcode::I32Const, &[1]);
+        asm.op(Opcode::NativeInvoke, &[INVOKE]);
+
+        // Recursive call
+        asm.op(Opcode::I32Const, &[entry]);
+        asm.op(Opcode::I32Const, &[0]);
+        asm.op(Opcode::Call, &[1]);
+
+        // Loop that grows the stack forever
+        let lp = asm.op(Opcode::I32Const, &[1]);
+        asm.op(Opcode::Jmp, &[lp]);
+        asm.patch(jmp, float_op);
+
+        let opcodes = check(&[ 0, 0, 0, 0, 2, 0, 0, 0, 1, 2 ], &asm.code, 1).unwrap();
+        assert!(opcodes.contains(0));
+        assert!(opcodes.contains(entry as usize));
+        assert!(!opcodes.contains(1));
+        assert!(!opcodes.contains(entry as usize + 1));
+        assert!(!opcodes.contains(asm.code.len()));
+        assert_eq!(opcodes.native_invokes(), &[ INVOKE as usize, FLOAT_OP_BASE + 6 ]);
+    }
+
+    #[test]
+    fn reject_malformed_opcodes() {
+        let mut asm = Asm::new();
+        let end = asm.code.len();
+        asm.code.push(Opcode::Never as u8);
+        assert_eq!(check(&[], &asm.code, 1).err(), Some(VerifyError::IllegalOpcode { offset: end, byte: Opcode::Never as u8 }));
+
+        asm.code[end] = Opcode::I32Const as u8;
+        asm.code.extend_from_slice(&[ 0, 0 ]);
+        assert_eq!(check(&[], &asm.code, 1).err(), Some(VerifyError::TruncatedOpcode { offset: end }));
+
+        asm.code.truncate(end);
+        asm.op(Opcode::Nop, &[]);
+        assert_eq!(check(&[], &asm.code, 1).err(), Some(VerifyError::RunsOffEnd { offset: end }));
+    }
+
+    #[test]
+    fn reject_bad_targets() {
+        assert_eq!(check_body(&|asm| {
+            let jmp = asm.op(Opcode::Jmp, &[0]);
+            asm.patch(jmp, jmp + 1);
+        }), Some(VerifyError::JumpTarget { offset: 35, target: 36 }));
+
+        assert_eq!(check_body(&|asm| {
+            asm.op(Opcode::I32Const, &[30]);
+            asm.op(Opcode::I32Const, &[0]);
+            asm.op(Opcode::Call, &[0]);
+            asm.op(Opcode::Return, &[]);
+        }), Some(VerifyError::CallTarget { offset: 45, target: 30 }));
+    }
+
+    #[test]
+    fn reject_bad_slots() {
+        assert_eq!(check(&[], &[ Opcode::Halt as u8 ], 0).err(), Some(VerifyError::MissingResetSlots));
+
+        let mut asm = Asm::new();
+        asm.patch(0, 5);
+        assert_eq!(check(&[], &asm.code, 1).err(), Some(VerifyError::TooManySlots { n_slots: 5 }));
+
+        assert_eq!(check_body(&|asm| {
+            asm.op(Opcode::GetSlot, &[1]);
+            asm.op(Opcode::Return, &[]);
+        }), Some(VerifyError::SlotBounds { offset: 35, slot: 1 }));
+
+        assert_eq!(check_body(&|asm| {
+            asm.op(Opcode::ResetSlots, &[1]);
+            asm.op(Opcode::Return, &[]);
+        }), Some(VerifyError::ResetSlotsAgain { offset: 35 }));
+    }
+
+    #[test]
+    fn check_native_invoke_ids() {
+        let invoke = |id: u32| check_body(&|asm| {
+            asm.op(Opcode::GetLocal, &[0]);
+            asm.op(Opcode::NativeInvoke, &[id]);
+            asm.op(Opcode::Return, &[]);
+        });
+
+        assert_eq!(invoke(FLOAT_OP_BASE as u32), None);
+        assert_eq!(invoke(FLOAT_OP_BASE as u32 + 0x39), None);
+        assert_eq!(invoke(FLOAT_OP_BASE as u32 + 0x3a), Some(VerifyError::NativeInvoke { offset: 40, id: 0x7fff_003a }));
+        assert_eq!(invoke(INVOKE + 1), Some(VerifyError::NativeInvoke { offset: 40, id: 4 }));
+    }
+
+    #[test]
+    fn reject_stack_underflow() {
+        assert_eq!(check_body(&|asm| {
+            asm.op(Opcode::Drop, &[]);
+            asm.op(Opcode::Return, &[]);
+        }), Some(VerifyError::StackUnderflow { offset: 35 }));
+
+        // The function takes its argument from the caller's stack only.
+        assert_eq!(check_body(&|asm| {
+            asm.op(Opcode::I32Const, &[1]);
+            asm.op(Opcode::NativeInvoke, &[INVOKE]);
+            asm.op(Opcode::Return, &[]);
+        }), Some(VerifyError::StackUnderflow { offset: 40 }));
+
+        // Underflow on only one of two paths into an opcode is allowed.
+        assert_eq!(check_body(&|asm| {
+            asm.op(Opcode::GetLocal, &[0]);
+            let jmp = asm.op(Opcode::JmpIf, &[0]);
+            asm.op(Opcode::I32Const, &[1]);
+            let drop = asm.op(Opcode::Drop, &[]);
+            asm.op(Opcode::Return, &[]);
+            asm.patch(jmp, drop);
+        }), None);
+
+        // The entry function takes one more argument than there is.
+        let asm = Asm::new();
+        let mut code = asm.code.clone();
+        code.extend_from_slice(&[ Opcode::Return as u8 ]);
+        assert_eq!(check(&[], &code, 0).err(), Some(VerifyError::StackUnderflow { offset: 29 }));
+    }
+
+    #[test]
+    fn reject_bad_initializers() {
+        let mut asm = Asm::new();
+        asm.op(Opcode::Return, &[]);
+
+        assert_eq!(check(&[ 12, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0 ], &asm.code, 1).err(),
+            Some(VerifyError::InitializerBounds { addr: 12, len: 8 }));
+        assert_eq!(check(&[ 16, 0, 0, 0, 0, 0, 0, 0 ], &asm.code, 1).err(),
+            Some(VerifyError::InitializerBounds { addr: 16, len: 0 }));
+        assert_eq!(check(&[ 0, 0, 0, 0, 4, 0, 0, 0, 1 ], &asm.code, 1).err(),
+            Some(VerifyError::TruncatedInitializer));
+        assert_eq!(check(&[ 0, 0, 0 ], &asm.code, 1).err(),
+            Some(VerifyError::TruncatedInitializer));
+    }
+
+    #[test]
+    fn check_resume_points() {
+        // The entry function at 35 calls a native invoke and returns at 50.
+        // Its frame returns to the `Halt` at 34.
+        let mut asm = Asm::new();
+        asm.op(Opcode::GetLocal, &[0]);
+        asm.op(Opcode::I32Const, &[2]);
+        asm.op(Opcode::NativeInvoke, &[INVOKE]);
+        asm.op(Opcode::Return, &[]);
+
+        let resume = |ip: usize, n_slots: usize, stack_len: usize, call_stack: &[i64]| {
+            let m = Module {
+                memory_initializers: &[],
+                code: &asm.code
+            };
+            let point = ResumePoint {
+                ip: ip,
+                n_slots: n_slots,
+                stack_len: stack_len,
+                call_stack: call_stack
+            };
+            verify_resume(&m, 1, 64, 16, 4, |id| if id == INVOKE as usize { Some(2) } else { None }, &point).err()
+        };
+
+        assert_eq!(resume(50, 1, 1, &[ 7, 1, 34 ]), None);
+        assert_eq!(resume(34, 1, 1, &[]), None);
+
+        assert_eq!(resume(50, 2, 1, &[ 7, 1, 34 ]), Some(VerifyError::ResumeSlots { n_slots: 2 }));
+        assert_eq!(resume(50, 1, 2, &[ 7, 1, 34 ]), Some(VerifyError::ResumeStack { len: 2 }));
+
+        // Not an opcode, or not in the called function
+        assert_eq!(resume(51, 1, 1, &[ 7, 1, 34 ]), Some(VerifyError::ResumeOffset { offset: 51 }));
+        assert_eq!(resume(34, 1, 1, &[ 7, 1, 34 ]), Some(VerifyError::ResumeOffset { offset: 34 }));
+        assert_eq!(resume(50, 1, 1, &[]), Some(VerifyError::ResumeOffset { offset: 50 }));
+
+        // Returns into the middle of the call, to the call itself, and
+        // after something other than a call
+        assert_eq!(resume(50, 1, 1, &[ 7, 1, 33 ]), Some(VerifyError::ResumeFrame { depth: 0 }));
+        assert_eq!(resume(50, 1, 1, &[ 7, 1, 29 ]), Some(VerifyError::ResumeFrame { depth: 0 }));
+        assert_eq!(resume(50, 1, 1, &[ 7, 1, 40 ]), Some(VerifyError::ResumeFrame { depth: 0 }));
+
+        // Locals that don't match the call, or aren't there
+        assert_eq!(resume(50, 1, 1, &[ 7, 0, 2, 34 ]), Some(VerifyError::ResumeFrame { depth: 0 }));
+        assert_eq!(resume(50, 1, 1, &[ 1, 34 ]), Some(VerifyError::ResumeFrame { depth: 0 }));
+        assert_eq!(resume(50, 1, 1, &[ 34 ]), Some(VerifyError::ResumeFrame { depth: 0 }));
+
+        // A second frame below the first one, which the startup code can't have pushed
+        assert_eq!(resume(50, 1, 1, &[ 7, 1, 34, 7, 1, 34 ]), Some(VerifyError::ResumeFrame { depth: 0 }));
+    }
+}
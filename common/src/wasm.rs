@@ -0,0 +1,462 @@
+//! Reads WebAssembly binaries for `trans`.
+//!
+//! Only the parts of a module that the translator uses are kept. Function
+//! bodies stay undecoded; the translator reads their instructions as it
+//! translates them. Offsets in errors are offsets into the binary.
+
+use prelude::{Vec, BTreeMap};
+
+use trans::{TransError, TransResult};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64
+}
+
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub result: Option<ValType>
+}
+
+/// An imported function.
+pub struct Import<'a> {
+    pub module: &'a str,
+    pub field: &'a str
+}
+
+pub enum Body<'a> {
+    /// Imported function, with its index in `Module::imports`
+    Import(usize),
+
+    /// Instructions, up to and including the final `end`
+    Code(Reader<'a>)
+}
+
+pub struct Function<'a> {
+    pub typeidx: u32,
+
+    /// Number of locals besides the parameters
+    pub n_locals: u32,
+
+    pub body: Body<'a>
+}
+
+pub struct DataSegment<'a> {
+    pub offset: u32,
+    pub data: &'a [u8]
+}
+
+/// The table and its limit, if the module has one.
+struct Table {
+    elements: Vec<Option<u32>>,
+    max: Option<u32>
+}
+
+pub struct Module<'a> {
+    pub types: Vec<FuncType>,
+
+    /// Imported functions, which come first in `functions`
+    pub imports: Vec<Import<'a>>,
+    pub functions: Vec<Function<'a>>,
+
+    /// Functions in the table, `None` for empty elements
+    pub table: Vec<Option<u32>>,
+
+    /// Initial values of the globals, by their bit patterns
+    pub globals: Vec<i64>,
+
+    /// Exported functions
+    pub exports: BTreeMap<&'a str, u32>,
+
+    pub data: Vec<DataSegment<'a>>
+}
+
+#[derive(Clone)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+
+    /// Offset of `data` in the binary
+    base: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader {
+            data: data,
+            pos: 0,
+            base: 0
+        }
+    }
+
+    /// Offset of the next byte in the binary.
+    pub fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn malformed(&self) -> TransError {
+        TransError::Malformed { offset: self.offset() }
+    }
+
+    pub fn u8(&mut self) -> TransResult<u8> {
+        match self.data.get(self.pos) {
+            Some(v) => {
+                self.pos += 1;
+                Ok(*v)
+            },
+            None => Err(self.malformed())
+        }
+    }
+
+    pub fn bytes(&mut self, n: usize) -> TransResult<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(self.malformed());
+        }
+        let v = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(v)
+    }
+
+    /// Splits off the next `n` bytes.
+    pub fn sub(&mut self, n: usize) -> TransResult<Reader<'a>> {
+        let base = self.offset();
+        Ok(Reader {
+            data: self.bytes(n)?,
+            pos: 0,
+            base: base
+        })
+    }
+
+    /// Reads a little endian value of `n` bytes.
+    pub fn le(&mut self, n: usize) -> TransResult<u64> {
+        Ok(self.bytes(n)?.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    fn leb128(&mut self, max_bits: u32, signed: bool) -> TransResult<u64> {
+        let offset = self.offset();
+        let mut result: u64 = 0;
+        let mut shift: u32 = 0;
+
+        loop {
+            let b = self.u8()?;
+            if shift >= max_bits {
+                return Err(TransError::Malformed { offset: offset });
+            }
+            result |= ((b & 0x7f) as u64) << shift;
+            shift += 7;
+
+            if b & 0x80 == 0 {
+                if signed && shift < 64 && b & 0x40 != 0 {
+                    result |= !0u64 << shift;
+                }
+                return Ok(result);
+            }
+        }
+    }
+
+    pub fn var_u32(&mut self) -> TransResult<u32> {
+        Ok(self.leb128(32, false)? as u32)
+    }
+
+    pub fn var_i32(&mut self) -> TransResult<i32> {
+        Ok(self.leb128(32, true)? as u32 as i32)
+    }
+
+    pub fn var_i64(&mut self) -> TransResult<i64> {
+        Ok(self.leb128(64, true)? as i64)
+    }
+
+    fn name(&mut self) -> TransResult<&'a str> {
+        let offset = self.offset();
+        let len = self.var_u32()? as usize;
+        ::core::str::from_utf8(self.bytes(len)?).map_err(|_| TransError::Malformed { offset: offset })
+    }
+
+    fn val_type(&mut self) -> TransResult<ValType> {
+        let offset = self.offset();
+        Ok(match self.u8()? {
+            0x7f => ValType::I32,
+            0x7e => ValType::I64,
+            0x7d => ValType::F32,
+            0x7c => ValType::F64,
+            _ => return Err(TransError::Malformed { offset: offset })
+        })
+    }
+
+    /// Reads resizable limits as `(initial, maximum)`.
+    fn limits(&mut self) -> TransResult<(u32, Option<u32>)> {
+        let offset = self.offset();
+        match self.u8()? {
+            0 => Ok((self.var_u32()?, None)),
+            1 => Ok((self.var_u32()?, Some(self.var_u32()?))),
+            _ => Err(TransError::Malformed { offset: offset })
+        }
+    }
+
+    fn table_type(&mut self, max_len: usize) -> TransResult<Table> {
+        let offset = self.offset();
+        if self.u8()? != 0x70 {
+            return Err(TransError::Malformed { offset: offset });
+        }
+
+        let (min, max) = self.limits()?;
+        if min as usize > max_len {
+            return Err(TransError::TableBounds { offset: offset });
+        }
+
+        Ok(Table {
+            elements: vec! [ None; min as usize ],
+            max: max
+        })
+    }
+
+    /// Evaluates a constant expression, which may read the globals
+    /// defined before it.
+    fn init_expr(&mut self, globals: &[i64]) -> TransResult<i64> {
+        let offset = self.offset();
+        let value = match self.u8()? {
+            0x41 => self.var_i32()? as u32 as i64,
+            0x42 => self.var_i64()?,
+            0x43 => self.le(4)? as i64,
+            0x44 => self.le(8)? as i64,
+            0x23 => match globals.get(self.var_u32()? as usize) {
+                Some(v) => *v,
+                None => return Err(TransError::IndexBounds { offset: offset })
+            },
+            _ => return Err(TransError::Unsupported { offset: offset })
+        };
+
+        if self.u8()? != 0x0b {
+            return Err(TransError::Unsupported { offset: offset });
+        }
+        Ok(value)
+    }
+}
+
+impl<'a> Module<'a> {
+    /// Parses `wasm`, whose table may have at most `max_table_len` elements.
+    pub fn parse(wasm: &'a [u8], max_table_len: usize) -> TransResult<Module<'a>> {
+        let mut r = Reader::new(wasm);
+        if r.bytes(4)? != b"\0asm" || r.le(4)? != 1 {
+            return Err(TransError::Malformed { offset: 0 });
+        }
+
+        let mut m = Module {
+            types: Vec::new(),
+            imports: Vec::new(),
+            functions: Vec::new(),
+            table: Vec::new(),
+            globals: Vec::new(),
+            exports: BTreeMap::new(),
+            data: Vec::new()
+        };
+        let mut table: Option<Table> = None;
+        let mut defined_types: Vec<u32> = Vec::new();
+        let mut bodies: Vec<Function<'a>> = Vec::new();
+
+        // Ids of the known sections must increase. Elements are applied when
+        // the functions are known.
+        let mut last_id: u8 = 0;
+        let mut elements: Vec<(usize, u32, Vec<u32>)> = Vec::new();
+
+        while !r.is_empty() {
+            let offset = r.offset();
+            let id = r.u8()?;
+            let len = r.var_u32()? as usize;
+            let mut s = r.sub(len)?;
+
+            if id == 0 {
+                continue;
+            }
+            if id <= last_id || id > 11 {
+                return Err(TransError::Malformed { offset: offset });
+            }
+            last_id = id;
+
+            // The start function is ignored, as in wasm-core.
+            if id == 8 {
+                continue;
+            }
+
+            let n = s.var_u32()?;
+            for _ in 0..n {
+                let offset = s.offset();
+                match id {
+                    1 => {
+                        if s.u8()? != 0x60 {
+                            return Err(TransError::Malformed { offset: offset });
+                        }
+                        let mut params: Vec<ValType> = Vec::new();
+                        for _ in 0..s.var_u32()? {
+                            params.push(s.val_type()?);
+                        }
+                        let result = match s.var_u32()? {
+                            0 => None,
+                            1 => Some(s.val_type()?),
+                            _ => return Err(TransError::Unsupported { offset: offset })
+                        };
+                        m.types.push(FuncType {
+                            params: params,
+                            result: result
+                        });
+                    },
+                    2 => {
+                        let module = s.name()?;
+                        let field = s.name()?;
+                        match s.u8()? {
+                            0 => {
+                                let typeidx = s.var_u32()?;
+                                if typeidx as usize >= m.types.len() {
+                                    return Err(TransError::IndexBounds { offset: offset });
+                                }
+                                m.functions.push(Function {
+                                    typeidx: typeidx,
+                                    n_locals: 0,
+                                    body: Body::Import(m.imports.len())
+                                });
+                                m.imports.push(Import {
+                                    module: module,
+                                    field: field
+                                });
+                            },
+                            1 => {
+                                if table.is_some() {
+                                    return Err(TransError::Unsupported { offset: offset });
+                                }
+                                table = Some(s.table_type(max_table_len)?);
+                            },
+                            2 => {
+                                s.limits()?;
+                            },
+                            3 => {
+                                // Imported globals start out as 0, as in wasm-core.
+                                s.val_type()?;
+                                s.u8()?;
+                                m.globals.push(0);
+                            },
+                            _ => return Err(TransError::Malformed { offset: offset })
+                        }
+                    },
+                    3 => {
+                        let typeidx = s.var_u32()?;
+                        if typeidx as usize >= m.types.len() {
+                            return Err(TransError::IndexBounds { offset: offset });
+                        }
+                        defined_types.push(typeidx);
+                    },
+                    4 => {
+                        if table.is_some() {
+                            return Err(TransError::Unsupported { offset: offset });
+                        }
+                        table = Some(s.table_type(max_table_len)?);
+                    },
+                    5 => {
+                        s.limits()?;
+                    },
+                    6 => {
+                        s.val_type()?;
+                        s.u8()?;
+                        let value = s.init_expr(&m.globals)?;
+                        m.globals.push(value);
+                    },
+                    7 => {
+                        let name = s.name()?;
+                        let kind = s.u8()?;
+                        let index = s.var_u32()?;
+                        if kind == 0 {
+                            m.exports.insert(name, index);
+                        }
+                    },
+                    9 => {
+                        if s.var_u32()? != 0 {
+                            return Err(TransError::IndexBounds { offset: offset });
+                        }
+                        let start = s.init_expr(&m.globals)? as u32;
+                        let mut members: Vec<u32> = Vec::new();
+                        for _ in 0..s.var_u32()? {
+                            members.push(s.var_u32()?);
+                        }
+                        elements.push((offset, start, members));
+                    },
+                    10 => {
+                        let len = s.var_u32()? as usize;
+                        let mut body = s.sub(len)?;
+
+                        let mut n_locals: u64 = 0;
+                        for _ in 0..body.var_u32()? {
+                            n_locals += body.var_u32()? as u64;
+                            body.val_type()?;
+                        }
+                        if n_locals > ::core::u32::MAX as u64 {
+                            return Err(TransError::Unsupported { offset: offset });
+                        }
+
+                        bodies.push(Function {
+                            typeidx: 0,
+                            n_locals: n_locals as u32,
+                            body: Body::Code(body)
+                        });
+                    },
+                    11 => {
+                        if s.var_u32()? != 0 {
+                            return Err(TransError::IndexBounds { offset: offset });
+                        }
+                        let start = s.init_expr(&m.globals)? as u32;
+                        let len = s.var_u32()? as usize;
+                        m.data.push(DataSegment {
+                            offset: start,
+                            data: s.bytes(len)?
+                        });
+                    },
+                    _ => return Err(TransError::Malformed { offset: offset })
+                }
+            }
+        }
+
+        if defined_types.len() != bodies.len() {
+            return Err(TransError::Malformed { offset: r.offset() });
+        }
+        for (mut f, typeidx) in bodies.into_iter().zip(defined_types) {
+            f.typeidx = typeidx;
+            m.functions.push(f);
+        }
+
+        for (offset, start, members) in elements {
+            let t = match table {
+                Some(ref mut v) => v,
+                None => return Err(TransError::IndexBounds { offset: offset })
+            };
+
+            let end = start as usize + members.len();
+            if end > max_table_len || t.max.map(|max| end > max as usize).unwrap_or(false) {
+                return Err(TransError::TableBounds { offset: offset });
+            }
+            if end > t.elements.len() {
+                t.elements.resize(end, None);
+            }
+
+            for (i, f) in members.into_iter().enumerate() {
+                if f as usize >= m.functions.len() {
+                    return Err(TransError::IndexBounds { offset: offset });
+                }
+                t.elements[start as usize + i] = Some(f);
+            }
+        }
+        if let Some(t) = table {
+            m.table = t.elements;
+        }
+
+        Ok(m)
+    }
+
+    pub fn func_type(&self, f: &Function) -> &FuncType {
+        &self.types[f.typeidx as usize]
+    }
+}
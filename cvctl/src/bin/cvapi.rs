@@ -0,0 +1,28 @@
+extern crate cvctl;
+
+use std::env;
+
+use cvctl::hosted::HostedContext;
+use cvctl::service::ServiceContext;
+
+fn main() {
+    let mut args = env::args();
+    args.next().unwrap();
+
+    let hosted = match args.next() {
+        Some(ref v) if v == "--hosted" => true,
+        Some(v) => panic!("Unknown option: {}", v),
+        None => false
+    };
+
+    let invokes = if hosted {
+        HostedContext::connect().unwrap().list_native_invokes().unwrap()
+    } else {
+        ServiceContext::connect().unwrap().list_native_invokes().unwrap()
+    };
+
+    println!("{:>6} {:>6}  {}", "ID", "ARGS", "NAME");
+    for ni in &invokes {
+        println!("{:>6} {:>6}  {}", ni.id, ni.n_args, ni.name);
+    }
+}
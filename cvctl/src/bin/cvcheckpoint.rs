@@ -0,0 +1,67 @@
+extern crate libc;
+extern crate cvctl;
+
+use std::fs::File;
+use std::env;
+use std::process;
+
+use cvctl::service::{ServiceContext, ServiceError};
+
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("    cvcheckpoint [--stop] <pid> <path>");
+    eprintln!("    cvcheckpoint --hosted [--stop] <pid>");
+    process::exit(1);
+}
+
+fn main() {
+    let mut args = env::args();
+    args.next().unwrap();
+
+    let mut hosted = false;
+    let mut stop = false;
+    let mut pid: Option<i32> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hosted" => hosted = true,
+            "--stop" => stop = true,
+            _ => {
+                pid = Some(arg.parse().unwrap_or_else(|_| panic!("Invalid pid: {}", arg)));
+                break;
+            }
+        }
+    }
+
+    let pid = pid.unwrap_or_else(|| usage());
+
+    // The hosted runner writes to the file given to `cvrun --checkpoint`.
+    if hosted {
+        let sig = if stop { ::libc::SIGUSR2 } else { ::libc::SIGUSR1 };
+        if unsafe { ::libc::kill(pid, sig) } != 0 {
+            eprintln!("Error: {}", ::std::io::Error::last_os_error());
+            process::exit(1);
+        }
+        return;
+    }
+
+    let path = args.next().unwrap_or_else(|| usage());
+    let out = File::create(&path).unwrap();
+    let ctx = ServiceContext::connect().unwrap();
+
+    match ctx.checkpoint(pid, &out, stop) {
+        Ok(()) => {},
+        Err(ServiceError::NotCheckpointable { resource, kind }) => {
+            eprintln!("Error: resource {} ({}) can't be checkpointed", resource, kind);
+            process::exit(1);
+        },
+        Err(ServiceError::Io(e)) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+extern crate wasm_core;
+extern crate cvctl;
+
+use std::fs::File;
+use std::env;
+use std::io::Read;
+
+use wasm_core::trans::config::ModuleConfig;
+
+use cvctl::core_dump::CoreDump;
+use cvctl::float_ops;
+use cvctl::link::{self, Library};
+use cvctl::symbols::SymbolMap;
+use cvctl::termination::backend_error_name;
+
+/// Builds the symbols of the application, linked and lowered like `cvrun` does.
+fn load_symbols(path: &str, libs: Vec<Library>) -> SymbolMap {
+    let mut code: Vec<u8> = Vec::new();
+    File::open(path).unwrap().read_to_end(&mut code).unwrap();
+
+    let module = wasm_core::trans::translate_module_raw(&code, ModuleConfig::default());
+    let mut module = link::link(module, libs).unwrap_or_else(|e| panic!("Unable to link: {}", e));
+    float_ops::lower(&mut module);
+
+    SymbolMap::new(&module)
+}
+
+/// Prints `values`, most recent first.
+fn print_entries(title: &str, values: &[i64]) {
+    println!("{} ({} entries):", title, values.len());
+
+    for (i, v) in values.iter().enumerate().rev() {
+        println!("    #{:<6} 0x{:016x}  {}", i, *v as u64, v);
+    }
+}
+
+fn main() {
+    let mut args = env::args();
+    args.next().unwrap();
+
+    let mut libs: Vec<Library> = Vec::new();
+    let mut path: Option<String> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--link" => libs.push(Library::load(&args.next().expect("Library required")).unwrap()),
+            _ => {
+                path = Some(arg);
+                break;
+            }
+        }
+    }
+
+    let path = path.expect("Path required");
+    let dump = CoreDump::open(&path).unwrap();
+
+    // Without the application, code offsets are printed as they are.
+    let symbols = match args.next() {
+        Some(app) => load_symbols(&app, libs),
+        None => SymbolMap::default()
+    };
+
+    println!("Error: {}", backend_error_name(dump.error as i32));
+    println!("Trapped at: {}", symbols.describe(dump.ip as usize));
+    println!("Linear memory: {} bytes", dump.memory.len());
+    println!("");
+
+    // Frame N returns into the function of frame N + 1.
+    let (frames, rest) = dump.frames();
+    println!("Backtrace:");
+    println!("    #0     {}", symbols.describe(dump.ip as usize));
+    for (i, &(return_ip, locals)) in frames.iter().enumerate() {
+        for (j, v) in locals.iter().enumerate() {
+            println!("               local {}: 0x{:016x}  {}", j, *v as u64, v);
+        }
+        println!("    #{:<5} {}", i + 1, symbols.describe(return_ip as usize));
+    }
+    if rest.len() > 0 {
+        print_entries("Incomplete frame", rest);
+    }
+    println!("");
+
+    print_entries("Operand stack", &dump.stack);
+    print_entries("Slots", &dump.slots);
+    println!("");
+
+    println!("Open resources:");
+    for &(id, ref kind) in &dump.resources {
+        println!("    {:<6} {}", id, kind);
+    }
+}
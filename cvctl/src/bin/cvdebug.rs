@@ -0,0 +1,81 @@
+extern crate libc;
+extern crate cvctl;
+
+use std::env;
+use std::net::TcpListener;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use cvctl::gdb::Session;
+use cvctl::service::{DebugCommand, DebugStop, ServiceContext, ServiceError, ServiceResult};
+
+const DEFAULT_LISTEN_ADDR: &'static str = "127.0.0.1:1234";
+
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("    cvdebug [--listen <addr>] <pid>");
+    process::exit(1);
+}
+
+fn attach(ctx: &ServiceContext, pid: i32) -> ServiceResult<DebugStop> {
+    match ctx.debug(pid, DebugCommand::Attach, 0) {
+        // A debugger that went away while the application was running
+        // leaves its continue behind, which an interrupt completes.
+        Err(ServiceError::Io(ref e)) if e.raw_os_error() == Some(::libc::EBUSY) => {
+            ctx.debug(pid, DebugCommand::Interrupt, 0)?;
+            thread::sleep(Duration::from_millis(100));
+            ctx.debug(pid, DebugCommand::Attach, 0)
+        },
+        other => other
+    }
+}
+
+fn main() {
+    let mut args = env::args();
+    args.next().unwrap();
+
+    let mut listen_addr = DEFAULT_LISTEN_ADDR.to_string();
+    let mut pid: Option<i32> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => listen_addr = args.next().unwrap_or_else(|| usage()),
+            _ => {
+                pid = Some(arg.parse().unwrap_or_else(|_| panic!("Invalid pid: {}", arg)));
+                break;
+            }
+        }
+    }
+
+    let pid = pid.unwrap_or_else(|| usage());
+    let ctx = Arc::new(ServiceContext::connect().unwrap());
+
+    let stop = match attach(&ctx, pid) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Unable to attach to {}: {}", pid, e);
+            process::exit(1);
+        }
+    };
+    eprintln!(
+        "Attached to {}: {:?} at ip {}",
+        pid,
+        stop.reason(),
+        stop.ip
+    );
+
+    let listener = TcpListener::bind(&listen_addr).unwrap();
+    eprintln!("Waiting for gdb: target remote {}", listen_addr);
+
+    let (stream, peer) = listener.accept().unwrap();
+    eprintln!("Connection from {}", peer);
+    stream.set_nodelay(true).unwrap();
+
+    let mut session = Session::new(ctx, pid, stream, stop);
+    if let Err(e) = session.serve() {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
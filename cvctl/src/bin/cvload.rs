@@ -4,17 +4,32 @@ extern crate cvctl;
 use std::fs::File;
 use std::env;
 use std::io::Read;
+use std::process;
 
 use wasm_core::trans::config::ModuleConfig;
 use wasm_core::hetrans::translate_module;
 
-use cvctl::cwa_trans::Mapper;
+use cvctl::cwa_trans::{Mapper, DEFAULT_ENTRY, check_imports};
+use cvctl::float_ops;
+use cvctl::link::{self, Library};
 
 fn main() {
     let mut args = env::args();
     args.next().unwrap();
 
-    let path = args.next().expect("Path required");
+    let mut entry = DEFAULT_ENTRY.to_string();
+    let mut libs: Vec<Library> = Vec::new();
+    let mut path = args.next().expect("Path required");
+
+    loop {
+        match path.as_str() {
+            "--entry" => entry = args.next().expect("Entry function name required"),
+            "--link" => libs.push(Library::load(&args.next().expect("Library required")).unwrap()),
+            _ => break
+        }
+        path = args.next().expect("Path required");
+    }
+
     let mut f = File::open(&path).unwrap();
     let mut code: Vec<u8> = Vec::new();
 
@@ -22,11 +37,26 @@ fn main() {
 
     f.read_to_end(&mut code).unwrap();
     let module = wasm_core::trans::translate_module_raw(code.as_slice(), cfg);
-    let entry_fn = module.lookup_exported_func("__app_main").expect("Entry function `__cv_main` not found");
+    let mut module = link::link(module, libs).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    let entry_fn = module.lookup_exported_func(&entry)
+        .unwrap_or_else(|| panic!("Entry function `{}` not found", entry));
 
     let mut ctx = cvctl::service::ServiceContext::connect().unwrap();
 
-    let result = translate_module(&module, entry_fn, &mut Mapper::new(&ctx));
+    let mut mapper = Mapper::new(&ctx);
+    if let Err(unresolved) = check_imports(&mut mapper, &module) {
+        for &(ref module, ref field) in &unresolved {
+            eprintln!("Error: Unresolved import: {} {}", module, field);
+        }
+        eprintln!("{} unresolved import(s)", unresolved.len());
+        process::exit(1);
+    }
+    float_ops::lower(&mut module);
+
+    let result = translate_module(&module, entry_fn, &mut mapper);
 
     ctx.load_code(&result, cvctl::service::Backend::HexagonE).unwrap();
 
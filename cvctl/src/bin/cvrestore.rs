@@ -0,0 +1,127 @@
+extern crate cvctl;
+
+use std::env;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+
+use cvctl::hosted::HostedContext;
+use cvctl::files;
+use cvctl::service::{Backend, ExecEnv, ServiceContext};
+use cvctl::termination::Termination;
+
+fn next_value<T: FromStr>(args: &mut env::Args, what: &str) -> T {
+    let v = args.next().unwrap_or_else(|| panic!("{} required", what));
+    v.parse().unwrap_or_else(|_| panic!("Invalid {}: {}", what, v))
+}
+
+fn main() {
+    let mut args = env::args();
+    args.next().unwrap();
+
+    let mut hosted = false;
+    let mut backend = Backend::HexagonE;
+    let mut fuel: Option<u64> = None;
+    let mut timeout_ms: Option<u64> = None;
+    let mut cpu_time_ms: Option<u64> = None;
+    let mut mem_max_len: Option<usize> = None;
+    let mut max_slots: Option<usize> = None;
+    let mut stack_len: Option<usize> = None;
+    let mut call_stack_len: Option<usize> = None;
+    let mut nice: Option<i32> = None;
+    let mut core_path: Option<String> = None;
+    let mut manifest_path: Option<String> = None;
+    let mut checkpoint_path: Option<String> = None;
+    let mut debug = false;
+    let mut path: Option<String> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hosted" => hosted = true,
+            "--backend" => {
+                let name = args.next().expect("Backend name required");
+                backend = Backend::from_name(&name).unwrap_or_else(|| panic!("Unknown backend: {}", name));
+            },
+            "--fuel" => {
+                // 0 means unlimited to the kernel module.
+                let v: u64 = next_value(&mut args, "fuel");
+                if v == 0 {
+                    panic!("Fuel must be at least 1");
+                }
+                fuel = Some(v);
+            },
+            "--timeout" => timeout_ms = Some(next_value(&mut args, "timeout")),
+            "--cpu-time" => cpu_time_ms = Some(next_value(&mut args, "CPU time limit")),
+            "--max-memory" => mem_max_len = Some(next_value(&mut args, "maximum memory size")),
+            "--max-slots" => max_slots = Some(next_value(&mut args, "maximum slot count")),
+            "--stack-len" => stack_len = Some(next_value(&mut args, "stack length")),
+            "--call-stack-len" => call_stack_len = Some(next_value(&mut args, "call stack length")),
+            "--nice" => nice = Some(next_value(&mut args, "niceness increment")),
+            "--core" => core_path = Some(args.next().expect("Core dump path required")),
+            "--manifest" => manifest_path = Some(args.next().expect("Manifest path required")),
+            "--checkpoint" => checkpoint_path = Some(args.next().expect("Checkpoint path required")),
+            "--debug" => debug = true,
+            _ => {
+                path = Some(arg);
+                break;
+            }
+        }
+    }
+
+    let path = path.expect("Checkpoint path required");
+    let restore_file = files::open_inheritable(&path).unwrap();
+
+    let mut target_args: Vec<String> = Vec::new();
+    target_args.push(path.clone());
+    target_args.extend(args);
+    let arg_refs: Vec<&str> = target_args.iter().map(|v| v.as_str()).collect();
+
+    let core_file = core_path.as_ref().map(|p| files::create_inheritable(p).unwrap());
+
+    // Without one, the manifest stored in the checkpoint applies.
+    let manifest_file = manifest_path.as_ref().map(|p| files::open_inheritable(p).unwrap());
+
+    if checkpoint_path.is_some() && !hosted {
+        panic!("--checkpoint requires --hosted");
+    }
+    let checkpoint_file = checkpoint_path.as_ref().map(|p| files::create_inheritable(p).unwrap());
+
+    // Debuggers attach through the kernel module, and compiled code doesn't
+    // stop between opcodes.
+    if debug && hosted {
+        panic!("--debug cannot be used with --hosted");
+    }
+    if debug && backend != Backend::HexagonE {
+        panic!("--debug requires the hexagon-e backend");
+    }
+
+    let exec_env = ExecEnv {
+        args: &arg_refs,
+        fuel: fuel,
+        timeout_ms: timeout_ms,
+        cpu_time_ms: cpu_time_ms,
+        mem_max_len: mem_max_len,
+        max_slots: max_slots,
+        stack_len: stack_len,
+        call_stack_len: call_stack_len,
+        nice: nice,
+        core_fd: core_file.as_ref().map(|v| v.as_raw_fd()),
+        manifest_fd: manifest_file.as_ref().map(|v| v.as_raw_fd()),
+        restore_fd: Some(restore_file.as_raw_fd()),
+        checkpoint_fd: checkpoint_file.as_ref().map(|v| v.as_raw_fd()),
+        debug: debug,
+        ..ExecEnv::empty()
+    };
+
+    let termination = if hosted {
+        HostedContext::connect().unwrap().run_code(&[], backend, exec_env).unwrap()
+    } else {
+        ServiceContext::connect().unwrap().run_code_in_child(&[], backend, exec_env).unwrap()
+    };
+
+    match termination {
+        Termination::Exited(_) => {},
+        ref t => eprintln!("Application {}", t)
+    }
+
+    ::std::process::exit(termination.exit_code());
+}
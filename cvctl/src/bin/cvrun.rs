@@ -4,41 +4,297 @@ extern crate cvctl;
 use std::fs::File;
 use std::env;
 use std::io::Read;
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::str::FromStr;
 
+use wasm_core::module::Module;
 use wasm_core::trans::config::ModuleConfig;
 use wasm_core::hetrans::translate_module;
 
-use cvctl::cwa_trans::Mapper;
+use cvctl::cwa_trans::{Mapper, MapCwaApi, EntrySignature, DEFAULT_ENTRY, check_imports};
+use cvctl::float_ops;
+use cvctl::link::{self, Library};
+use cvctl::hosted::HostedContext;
+use cvctl::files;
+use cvctl::manifest;
+use cvctl::profile::ProfileFile;
+use cvctl::symbols::SymbolMap;
+use cvctl::service::{Backend, ExecEnv};
+use cvctl::termination::Termination;
+
+/// Translated application with what is needed to run it and report on it.
+struct Translated {
+    code: Vec<u8>,
+    symbols: SymbolMap,
+    signature: EntrySignature,
+    entry_args: Vec<i64>
+}
+
+/// Looks up the entry function and parses its arguments.
+fn resolve_entry(module: &Module, entry: &str, entry_args: &[String]) -> (usize, EntrySignature, Vec<i64>) {
+    let entry_fn = module.lookup_exported_func(entry)
+        .unwrap_or_else(|| panic!("Entry function `{}` not found", entry));
+    let signature = EntrySignature::of(module, entry_fn).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    let entry_args = signature.parse_args(entry_args).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+
+    (entry_fn, signature, entry_args)
+}
+
+fn translate(code: &[u8], entry: &str, entry_args: &[String], libs: Vec<Library>, api: &MapCwaApi) -> Translated {
+    let cfg: ModuleConfig = ModuleConfig::default();
+
+    let module = wasm_core::trans::translate_module_raw(code, cfg);
+    let mut module = link::link(module, libs).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    let (entry_fn, signature, entry_args) = resolve_entry(&module, entry, entry_args);
+
+    let mut mapper = Mapper::new(api);
+    if let Err(unresolved) = check_imports(&mut mapper, &module) {
+        for &(ref module, ref field) in &unresolved {
+            eprintln!("Error: Unresolved import: {} {}", module, field);
+        }
+        eprintln!("{} unresolved import(s)", unresolved.len());
+        process::exit(1);
+    }
+    float_ops::lower(&mut module);
+
+    Translated {
+        code: translate_module(&module, entry_fn, &mut mapper),
+        symbols: SymbolMap::new(&module),
+        signature: signature,
+        entry_args: entry_args
+    }
+}
+
+/// Like `translate`, but leaves the translation to the kernel, which is
+/// handed the binary as it is. The module is still parsed here to check the
+/// entry arguments and to report on the result.
+fn prepare_for_kernel(code: &[u8], entry: &str, entry_args: &[String]) -> Translated {
+    let module = wasm_core::trans::translate_module_raw(code, ModuleConfig::default());
+    let (_, signature, entry_args) = resolve_entry(&module, entry, entry_args);
+
+    Translated {
+        code: code.to_vec(),
+        symbols: SymbolMap::new(&module),
+        signature: signature,
+        entry_args: entry_args
+    }
+}
+
+/// Parses a byte size with an optional `K`, `M` or `G` suffix.
+fn parse_size(v: &str) -> Option<usize> {
+    let (num, unit) = match v.chars().last() {
+        Some('K') | Some('k') => (&v[..v.len() - 1], 1024),
+        Some('M') | Some('m') => (&v[..v.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&v[..v.len() - 1], 1024 * 1024 * 1024),
+        _ => (v, 1)
+    };
+
+    num.parse::<usize>().ok().and_then(|n| n.checked_mul(unit))
+}
+
+fn next_value<T: FromStr>(args: &mut env::Args, what: &str) -> T {
+    let v = args.next().unwrap_or_else(|| panic!("{} required", what));
+    v.parse().unwrap_or_else(|_| panic!("Invalid {}: {}", what, v))
+}
+
+fn next_size(args: &mut env::Args, what: &str) -> usize {
+    let v = args.next().unwrap_or_else(|| panic!("{} required", what));
+    parse_size(&v).unwrap_or_else(|| panic!("Invalid {}: {}", what, v))
+}
 
 fn main() {
     let mut args = env::args();
     args.next().unwrap();
 
-    let path = args.next().expect("Path required");
+    let mut hosted = false;
+    let mut backend = Backend::HexagonE;
+    let mut kernel_translate = false;
+    let mut entry = DEFAULT_ENTRY.to_string();
+    let mut libs: Vec<Library> = Vec::new();
+    let mut entry_args: Vec<String> = Vec::new();
+    let mut fuel: Option<u64> = None;
+    let mut timeout_ms: Option<u64> = None;
+    let mut cpu_time_ms: Option<u64> = None;
+    let mut mem_default_len: Option<usize> = None;
+    let mut mem_max_len: Option<usize> = None;
+    let mut max_slots: Option<usize> = None;
+    let mut stack_len: Option<usize> = None;
+    let mut call_stack_len: Option<usize> = None;
+    let mut profile_out: Option<String> = None;
+    let mut profile_interval: Option<u32> = None;
+    let mut nice: Option<i32> = None;
+    let mut core_path: Option<String> = None;
+    let mut record_path: Option<String> = None;
+    let mut replay_path: Option<String> = None;
+    let mut manifest_path: Option<String> = None;
+    let mut checkpoint_path: Option<String> = None;
+    let mut debug = false;
+    let mut path: Option<String> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hosted" => hosted = true,
+            "--backend" => {
+                let name = args.next().expect("Backend name required");
+                backend = Backend::from_name(&name).unwrap_or_else(|| panic!("Unknown backend: {}", name));
+            },
+            "--kernel-translate" => kernel_translate = true,
+            "--entry" => entry = args.next().expect("Entry function name required"),
+            "--arg" => entry_args.push(args.next().expect("Entry function argument required")),
+            "--link" => libs.push(Library::load(&args.next().expect("Library required")).unwrap()),
+            "--fuel" => {
+                // 0 means unlimited to the kernel module.
+                let v: u64 = next_value(&mut args, "fuel");
+                if v == 0 {
+                    panic!("Fuel must be at least 1");
+                }
+                fuel = Some(v);
+            },
+            "--timeout" => timeout_ms = Some(next_value(&mut args, "timeout")),
+            "--cpu-time" => cpu_time_ms = Some(next_value(&mut args, "CPU time limit")),
+            "--memory" => mem_default_len = Some(next_size(&mut args, "memory size")),
+            "--max-memory" => mem_max_len = Some(next_size(&mut args, "maximum memory size")),
+            "--max-slots" => max_slots = Some(next_value(&mut args, "maximum slot count")),
+            "--stack-len" => stack_len = Some(next_value(&mut args, "stack length")),
+            "--call-stack-len" => call_stack_len = Some(next_value(&mut args, "call stack length")),
+            "--profile" => profile_out = Some(args.next().expect("Profile output path required")),
+            "--profile-interval" => profile_interval = Some(next_value(&mut args, "profile interval")),
+            "--nice" => nice = Some(next_value(&mut args, "niceness increment")),
+            "--core" => core_path = Some(args.next().expect("Core dump path required")),
+            "--record" => record_path = Some(args.next().expect("Recording path required")),
+            "--replay" => replay_path = Some(args.next().expect("Recording path required")),
+            "--manifest" => manifest_path = Some(args.next().expect("Manifest path required")),
+            "--checkpoint" => checkpoint_path = Some(args.next().expect("Checkpoint path required")),
+            "--debug" => debug = true,
+            _ => {
+                path = Some(arg);
+                break;
+            }
+        }
+    }
+
+    let path = path.expect("Path required");
     let mut f = File::open(&path).unwrap();
     let mut code: Vec<u8> = Vec::new();
     f.read_to_end(&mut code).unwrap();
 
-    let mut ctx = cvctl::service::ServiceContext::connect().unwrap();
+    let mut target_args: Vec<String> = Vec::new();
+    target_args.push(path.clone());
+    target_args.extend(args);
 
-    let result = {
-        let cfg: ModuleConfig = ModuleConfig::default();
+    let arg_refs: Vec<&str> = target_args.iter().map(|v| v.as_str()).collect();
+    let profile_file = profile_out.as_ref().map(|_| ProfileFile::new().unwrap());
+    let core_file = core_path.as_ref().map(|p| files::create_inheritable(p).unwrap());
 
-        let module = wasm_core::trans::translate_module_raw(code.as_slice(), cfg);
-        let entry_fn = module.lookup_exported_func("__app_main").expect("Entry function `__cv_main` not found");
+    if record_path.is_some() && replay_path.is_some() {
+        panic!("Cannot record and replay at the same time");
+    }
+    let record_file = record_path.as_ref().map(|p| files::create_inheritable(p).unwrap());
+    let replay_file = replay_path.as_ref().map(|p| files::open_inheritable(p).unwrap());
 
-        translate_module(&module, entry_fn, &mut Mapper::new(&ctx))
+    // The kernel module takes checkpoint requests from `cvcheckpoint` instead.
+    if checkpoint_path.is_some() && !hosted {
+        panic!("--checkpoint requires --hosted");
+    }
+    let checkpoint_file = checkpoint_path.as_ref().map(|p| files::create_inheritable(p).unwrap());
+
+    // Debuggers attach through the kernel module, and compiled code doesn't
+    // stop between opcodes.
+    if debug && hosted {
+        panic!("--debug cannot be used with --hosted");
+    }
+    if debug && backend != Backend::HexagonE {
+        panic!("--debug requires the hexagon-e backend");
+    }
+
+    // Libraries are linked during the translation here, which the kernel can't do.
+    if kernel_translate && libs.len() > 0 {
+        panic!("--link cannot be used with --kernel-translate");
+    }
+
+    // A manifest given on the command line takes precedence over the one embedded in the app.
+    let manifest_file = match manifest_path {
+        Some(ref p) => Some(files::open_inheritable(p).unwrap()),
+        None => manifest::find_custom_section(&code, manifest::SECTION_NAME)
+            .map(|v| manifest::write_temp(v).unwrap())
     };
 
-    let mut target_args: Vec<String> = Vec::new();
-    target_args.push(path.clone());
-    target_args.extend(args);
+    // Set once the application is translated, when the entry function is known.
+    let translated: Translated;
 
-    let arg_refs: Vec<&str> = target_args.iter().map(|v| v.as_str()).collect();
+    let mut exec_env = ExecEnv {
+        args: &arg_refs,
+        entry_args: &[],
+        fuel: fuel,
+        timeout_ms: timeout_ms,
+        cpu_time_ms: cpu_time_ms,
+        mem_default_len: mem_default_len,
+        mem_max_len: mem_max_len,
+        max_slots: max_slots,
+        stack_len: stack_len,
+        call_stack_len: call_stack_len,
+        report_fd: None,
+        profile_fd: profile_file.as_ref().map(|v| v.fd()),
+        profile_interval: profile_interval,
+        nice: nice,
+        core_fd: core_file.as_ref().map(|v| v.as_raw_fd()),
+        record_fd: record_file.as_ref().map(|v| v.as_raw_fd()),
+        replay_fd: replay_file.as_ref().map(|v| v.as_raw_fd()),
+        manifest_fd: manifest_file.as_ref().map(|v| v.as_raw_fd()),
+        trace_fd: None,
+        restore_fd: None,
+        wasm_entry: if kernel_translate { Some(&entry) } else { None },
+        checkpoint_fd: checkpoint_file.as_ref().map(|v| v.as_raw_fd()),
+        debug: debug
+    };
 
-    let ret = ctx.run_code(&result, cvctl::service::Backend::HexagonE, &arg_refs).unwrap();
+    let termination = if hosted {
+        let mut ctx = HostedContext::connect().unwrap();
+        translated = if kernel_translate {
+            prepare_for_kernel(&code, &entry, &entry_args)
+        } else {
+            translate(&code, &entry, &entry_args, libs, &ctx)
+        };
+        exec_env.entry_args = &translated.entry_args;
 
-    if ret != 0 {
-        eprintln!("Application exited with code {}", ret);
+        ctx.run_code(&translated.code, backend, exec_env).unwrap()
+    } else {
+        let mut ctx = cvctl::service::ServiceContext::connect().unwrap();
+        translated = if kernel_translate {
+            prepare_for_kernel(&code, &entry, &entry_args)
+        } else {
+            translate(&code, &entry, &entry_args, libs, &ctx)
+        };
+        exec_env.entry_args = &translated.entry_args;
+
+        ctx.run_code_in_child(&translated.code, backend, exec_env).unwrap()
+    };
+
+    match termination {
+        Termination::Exited(_) => {},
+        Termination::Returned(v) => eprintln!("Application returned {}", translated.signature.format_result(v)),
+        ref t => eprintln!("Application {}", t)
     }
+
+    if let (Some(out), Some(pf)) = (profile_out, profile_file) {
+        match pf.finish() {
+            Ok(profile) => {
+                let mut f = File::create(&out).unwrap();
+                profile.write_report(&mut f, &translated.symbols).unwrap();
+            },
+            Err(e) => eprintln!("Unable to read profile: {}", e)
+        }
+    }
+
+    ::std::process::exit(termination.exit_code());
 }
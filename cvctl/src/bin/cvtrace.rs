@@ -0,0 +1,126 @@
+extern crate wasm_core;
+extern crate cvctl;
+
+use std::fs::File;
+use std::env;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::process;
+
+use wasm_core::trans::config::ModuleConfig;
+use wasm_core::hetrans::translate_module;
+
+use cvctl::cwa_trans::{Mapper, MapCwaApi, DEFAULT_ENTRY, check_imports};
+use cvctl::float_ops;
+use cvctl::link::{self, Library};
+use cvctl::hosted::HostedContext;
+use cvctl::manifest;
+use cvctl::service::{Backend, ExecEnv};
+use cvctl::termination::Termination;
+use cvctl::trace::TracePipe;
+
+fn translate(code: &[u8], entry: &str, libs: Vec<Library>, api: &MapCwaApi) -> Vec<u8> {
+    let cfg: ModuleConfig = ModuleConfig::default();
+
+    let module = wasm_core::trans::translate_module_raw(code, cfg);
+    let mut module = link::link(module, libs).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
+    let entry_fn = module.lookup_exported_func(entry)
+        .unwrap_or_else(|| panic!("Entry function `{}` not found", entry));
+
+    let mut mapper = Mapper::new(api);
+    if let Err(unresolved) = check_imports(&mut mapper, &module) {
+        for &(ref module, ref field) in &unresolved {
+            eprintln!("Error: Unresolved import: {} {}", module, field);
+        }
+        eprintln!("{} unresolved import(s)", unresolved.len());
+        process::exit(1);
+    }
+    float_ops::lower(&mut module);
+
+    translate_module(&module, entry_fn, &mut mapper)
+}
+
+fn main() {
+    let mut args = env::args();
+    args.next().unwrap();
+
+    let mut hosted = false;
+    let mut entry = DEFAULT_ENTRY.to_string();
+    let mut libs: Vec<Library> = Vec::new();
+    let mut output: Option<String> = None;
+    let mut path: Option<String> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--hosted" => hosted = true,
+            "--entry" => entry = args.next().expect("Entry function name required"),
+            "--link" => libs.push(Library::load(&args.next().expect("Library required")).unwrap()),
+            "--output" => output = Some(args.next().expect("Output path required")),
+            _ => {
+                path = Some(arg);
+                break;
+            }
+        }
+    }
+
+    let path = path.expect("Path required");
+    let mut code: Vec<u8> = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut code).unwrap();
+
+    let mut target_args: Vec<String> = Vec::new();
+    target_args.push(path.clone());
+    target_args.extend(args);
+
+    let arg_refs: Vec<&str> = target_args.iter().map(|v| v.as_str()).collect();
+
+    // The app is traced under the same restrictions it would run with.
+    let manifest_file = manifest::find_custom_section(&code, manifest::SECTION_NAME)
+        .map(|v| manifest::write_temp(v).unwrap());
+
+    let out: Box<Write + Send> = match output {
+        Some(ref p) => Box::new(File::create(p).unwrap()),
+        None => Box::new(io::stderr())
+    };
+
+    let mut exec_env = ExecEnv::empty();
+    exec_env.args = &arg_refs[..];
+    exec_env.manifest_fd = manifest_file.as_ref().map(|v| v.as_raw_fd());
+
+    // The pipe is created only after connecting, so that the API server of the
+    // hosted runner doesn't inherit the write end and keep the trace open.
+    let termination = if hosted {
+        let mut ctx = HostedContext::connect().unwrap();
+        let result = translate(&code, &entry, libs, &ctx);
+
+        let mut pipe = TracePipe::new().unwrap();
+        exec_env.trace_fd = Some(pipe.write_fd());
+        let streamer = pipe.stream(out);
+
+        let termination = ctx.run_code(&result, Backend::HexagonE, exec_env).unwrap();
+        pipe.close_write();
+        streamer.join().unwrap().unwrap();
+        termination
+    } else {
+        let mut ctx = cvctl::service::ServiceContext::connect().unwrap();
+        let result = translate(&code, &entry, libs, &ctx);
+
+        let mut pipe = TracePipe::new().unwrap();
+        exec_env.trace_fd = Some(pipe.write_fd());
+        let streamer = pipe.stream(out);
+
+        let termination = ctx.run_code_in_child(&result, Backend::HexagonE, exec_env).unwrap();
+        pipe.close_write();
+        streamer.join().unwrap().unwrap();
+        termination
+    };
+
+    match termination {
+        Termination::Exited(_) | Termination::Returned(_) => {},
+        ref t => eprintln!("Application {}", t)
+    }
+
+    ::std::process::exit(termination.exit_code());
+}
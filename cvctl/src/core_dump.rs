@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+/// Must match `core_dump::MAGIC` in the kernel module.
+const MAGIC: &'static [u8; 8] = b"CVCORE02";
+
+#[derive(Clone, Debug, Default)]
+pub struct CoreDump {
+    /// `BackendError` discriminant
+    pub error: u32,
+
+    /// Offset of the opcode that trapped in the hexagon-e code
+    pub ip: u64,
+
+    pub memory: Vec<u8>,
+    pub slots: Vec<i64>,
+
+    /// The live parts of the stacks, bottom first
+    pub stack: Vec<i64>,
+    pub call_stack: Vec<i64>,
+
+    /// Ids and kinds of the resources that were open
+    pub resources: Vec<(usize, String)>
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn le_u32(data: &[u8]) -> u32 {
+    data[0..4].iter().rev().fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+fn le_u64(data: &[u8]) -> u64 {
+    data[0..8].iter().rev().fold(0, |acc, b| (acc << 8) | *b as u64)
+}
+
+fn i64_array(data: &[u8]) -> Vec<i64> {
+    data.chunks(8)
+        .filter(|v| v.len() == 8)
+        .map(|v| le_u64(v) as i64)
+        .collect()
+}
+
+impl CoreDump {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CoreDump> {
+        let mut data: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        CoreDump::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<CoreDump> {
+        if data.len() < MAGIC.len() || &data[0..MAGIC.len()] != &MAGIC[..] {
+            return Err(invalid_data("Not a core dump"));
+        }
+
+        let mut dump = CoreDump::default();
+        let mut rest = &data[MAGIC.len()..];
+
+        while rest.len() > 0 {
+            if rest.len() < 12 {
+                return Err(invalid_data("Truncated section header"));
+            }
+
+            let tag = le_u32(rest);
+            let len = le_u64(&rest[4..]) as usize;
+            rest = &rest[12..];
+
+            if rest.len() < len {
+                return Err(invalid_data("Truncated section"));
+            }
+            let (body, next) = rest.split_at(len);
+            rest = next;
+
+            match tag {
+                1 if len >= 4 => dump.error = le_u32(body),
+                2 if len >= 8 => dump.ip = le_u64(body),
+                3 => dump.memory = body.to_vec(),
+                4 => dump.slots = i64_array(body),
+                5 => dump.stack = i64_array(body),
+                6 => dump.call_stack = i64_array(body),
+                7 => dump.resources = String::from_utf8_lossy(body)
+                    .lines()
+                    .filter_map(|line| {
+                        let mut parts = line.splitn(2, ' ');
+                        let id = parts.next()?.parse().ok()?;
+                        Some((id, parts.next().unwrap_or("unknown").to_string()))
+                    })
+                    .collect(),
+                _ => {} // Unknown sections are skipped.
+            }
+        }
+
+        Ok(dump)
+    }
+
+    /// Splits the call stack into frames, innermost first.
+    ///
+    /// Returns the frames as `(return address, locals)` and the entries
+    /// at the bottom that don't form a complete frame.
+    pub fn frames(&self) -> (Vec<(u64, &[i64])>, &[i64]) {
+        // Each frame is its locals, their count and the return address.
+        let mut frames: Vec<(u64, &[i64])> = Vec::new();
+        let mut top = self.call_stack.len();
+
+        while top >= 2 {
+            let n_locals = self.call_stack[top - 2];
+            if n_locals < 0 || n_locals as u64 > (top - 2) as u64 {
+                break;
+            }
+
+            let bottom = top - 2 - n_locals as usize;
+            frames.push((self.call_stack[top - 1] as u64, &self.call_stack[bottom..top - 2]));
+            top = bottom;
+        }
+
+        (frames, &self.call_stack[..top])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_call_stack() {
+        let mut dump = CoreDump::default();
+        dump.call_stack = vec! [ 7, 0, 20, 1, 2, 2, 40 ];
+
+        let (frames, rest) = dump.frames();
+        assert_eq!(frames, vec! [ (40, &[ 1, 2 ][..]), (20, &[][..]) ]);
+        assert_eq!(rest, &[ 7 ]);
+    }
+}
@@ -1,15 +1,107 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use wasm_core::hetrans::MapNativeInvoke;
-use service::ServiceContext;
+use wasm_core::module::{Module, Type, ValType};
+
+use float_ops;
+
+/// Export that is called when no other entry is selected.
+pub const DEFAULT_ENTRY: &'static str = "__app_main";
+
+/// Parameter and result types of an entry function. Only integers can be
+/// passed to entry functions.
+#[derive(Clone, Debug)]
+pub struct EntrySignature {
+    pub params: Vec<ValType>,
+    pub result: Option<ValType>
+}
+
+impl EntrySignature {
+    pub fn of(module: &Module, entry_fn: usize) -> Result<EntrySignature, String> {
+        let Type::Func(ref params, ref results) = module.types[module.functions[entry_fn].typeidx as usize];
+
+        if params.iter().any(|t| *t != ValType::I32 && *t != ValType::I64) {
+            return Err("entry functions may only take i32 and i64 arguments".to_string());
+        }
+
+        Ok(EntrySignature {
+            params: params.clone(),
+            result: results.get(0).cloned()
+        })
+    }
+
+    /// Parses `args` as the arguments of the entry function.
+    pub fn parse_args<S: AsRef<str>>(&self, args: &[S]) -> Result<Vec<i64>, String> {
+        if args.len() != self.params.len() {
+            return Err(format!("entry function takes {} argument(s), {} given", self.params.len(), args.len()));
+        }
+
+        args.iter().zip(self.params.iter())
+            .map(|(arg, t)| {
+                let arg = arg.as_ref();
+                match *t {
+                    ValType::I32 => arg.parse::<i32>().map(|v| v as u32 as i64)
+                        .or_else(|_| arg.parse::<u32>().map(|v| v as i64)),
+                    _ => arg.parse::<i64>()
+                        .or_else(|_| arg.parse::<u64>().map(|v| v as i64))
+                }.map_err(|_| format!("invalid {:?} argument: {}", t, arg))
+            })
+            .collect()
+    }
+
+    /// Formats a value returned by the entry function. The runtime doesn't
+    /// know its type, so the upper half of 32-bit values is undefined.
+    pub fn format_result(&self, value: i64) -> String {
+        match self.result {
+            Some(ValType::I32) => (value as i32).to_string(),
+            Some(ValType::F32) => f32::from_bits(value as u32).to_string(),
+            Some(ValType::F64) => f64::from_bits(value as u64).to_string(),
+            _ => value.to_string()
+        }
+    }
+}
+
+/// Something that resolves CommonWA API names to native invoke ids.
+pub trait MapCwaApi {
+    fn map_cwa_api(&self, name: &str) -> Option<u32>;
+
+    /// Resolves a whole import table, in the same order as `names`.
+    fn map_cwa_apis(&self, names: &[&str]) -> Vec<Option<u32>> {
+        names.iter().map(|v| self.map_cwa_api(v)).collect()
+    }
+}
+
+/// A registered native invoke, as listed by the runtime.
+#[derive(Clone, Debug)]
+pub struct NativeInvokeInfo {
+    pub id: u32,
+    pub name: String,
+    pub n_args: usize
+}
+
+impl NativeInvokeInfo {
+    /// Parses the `<id> <n_args> <name>` lines the runtime lists native invokes as.
+    pub fn parse_list(text: &str) -> Option<Vec<NativeInvokeInfo>> {
+        text.lines()
+            .map(|line| {
+                let mut parts = line.splitn(3, ' ');
+                Some(NativeInvokeInfo {
+                    id: parts.next()?.parse().ok()?,
+                    n_args: parts.next()?.parse().ok()?,
+                    name: parts.next()?.to_string()
+                })
+            })
+            .collect()
+    }
+}
 
 pub struct Mapper<'a> {
-    ctx: &'a ServiceContext,
+    ctx: &'a MapCwaApi,
     cache: HashMap<String, u32>
 }
 
 impl<'a> Mapper<'a> {
-    pub fn new(ctx: &'a ServiceContext) -> Mapper<'a> {
+    pub fn new(ctx: &'a MapCwaApi) -> Mapper<'a> {
         Mapper {
             ctx: ctx,
             cache: HashMap::new()
@@ -17,9 +109,60 @@ impl<'a> Mapper<'a> {
     }
 }
 
+impl<'a> Mapper<'a> {
+    /// Resolves all imports of `module` in one go, so that translation doesn't
+    /// need to ask for them one by one.
+    ///
+    /// Returns the imports that could not be resolved.
+    pub fn resolve_imports(&mut self, module: &Module) -> Vec<(String, String)> {
+        let mut unresolved: Vec<(String, String)> = Vec::new();
+        let mut names: Vec<&str> = Vec::new();
+        let mut seen: HashSet<&str> = HashSet::new();
+
+        for n in &module.natives {
+            if n.module != "cwa" {
+                unresolved.push((n.module.clone(), n.field.clone()));
+            } else if !self.cache.contains_key(&n.field) && seen.insert(&n.field) {
+                names.push(&n.field);
+            }
+        }
+
+        for (name, id) in names.iter().zip(self.ctx.map_cwa_apis(&names)) {
+            match id {
+                Some(id) => {
+                    self.cache.insert(name.to_string(), id);
+                },
+                None => unresolved.push(("cwa".to_string(), name.to_string()))
+            }
+        }
+
+        unresolved
+    }
+}
+
+/// Resolves the imports of `module`, failing with the `(module, field)`
+/// pairs of those that can't be.
+pub fn check_imports(mapper: &mut Mapper, module: &Module) -> Result<(), Vec<(String, String)>> {
+    let unresolved = mapper.resolve_imports(module);
+    if unresolved.len() == 0 {
+        Ok(())
+    } else {
+        Err(unresolved)
+    }
+}
+
 impl<'a> MapNativeInvoke for Mapper<'a> {
     fn map_native_invoke(&mut self, module: &str, field: &str) -> Option<u32> {
+        // Added by `float_ops::lower` and resolved by the translator.
+        if module == float_ops::RAW_INVOKE_MODULE {
+            return None;
+        }
+
+        // Imports from other wasm modules are turned into calls by
+        // `link::link` before translation. Any that are left weren't given
+        // as a library.
         if module != "cwa" {
+            eprintln!("Error: Unable to import {} from module {}: not a `cwa` import or a library given with --link", field, module);
             return None;
         }
 
@@ -36,3 +179,44 @@ impl<'a> MapNativeInvoke for Mapper<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_native_invoke_list() {
+        let list = NativeInvokeInfo::parse_list("1 2 log_write\n7 0 runtime_yield\n").unwrap();
+
+        assert_eq!(list.len(), 2);
+        assert_eq!((list[0].id, list[0].n_args, list[0].name.as_str()), (1, 2, "log_write"));
+        assert_eq!((list[1].id, list[1].n_args, list[1].name.as_str()), (7, 0, "runtime_yield"));
+    }
+
+    #[test]
+    fn parse_entry_args() {
+        let sig = EntrySignature {
+            params: vec! [ ValType::I32, ValType::I64 ],
+            result: Some(ValType::I32)
+        };
+
+        assert_eq!(sig.parse_args(&[ "-1", "-1" ]).unwrap(), vec! [ 0xffffffff, -1 ]);
+        assert_eq!(sig.parse_args(&[ "4294967295", "18446744073709551615" ]).unwrap(), vec! [ 0xffffffff, -1 ]);
+        assert!(sig.parse_args(&[ "1" ]).is_err());
+        assert!(sig.parse_args(&[ "4294967296", "1" ]).is_err());
+        assert_eq!(sig.format_result(0x1_ffff_ffff), "-1");
+    }
+
+    #[test]
+    fn parse_empty_native_invoke_list() {
+        assert_eq!(NativeInvokeInfo::parse_list("").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn reject_malformed_native_invoke_list() {
+        assert!(NativeInvokeInfo::parse_list("1 2\n").is_none());
+        assert!(NativeInvokeInfo::parse_list("x 2 log_write\n").is_none());
+        assert!(NativeInvokeInfo::parse_list("1 -2 log_write\n").is_none());
+        assert!(NativeInvokeInfo::parse_list("1 2 log_write\ngarbage\n").is_none());
+    }
+}
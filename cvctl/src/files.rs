@@ -0,0 +1,76 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const MAX_TEMP_ATTEMPTS: usize = 16;
+
+/// Keeps `file` open across `exec`, so that its descriptor can be passed on to the runner.
+///
+/// The standard library opens everything close-on-exec.
+pub fn set_inheritable(file: &File) -> io::Result<()> {
+    if unsafe { ::libc::fcntl(file.as_raw_fd(), ::libc::F_SETFD, 0) } != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Creates or truncates a file for the runtime to write to.
+pub fn create_inheritable<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    set_inheritable(&file)?;
+
+    Ok(file)
+}
+
+/// Opens an existing file for the runtime to read from.
+pub fn open_inheritable<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    let file = File::open(path)?;
+    set_inheritable(&file)?;
+
+    Ok(file)
+}
+
+/// Creates an unlinked temporary file for the runtime to read from or write to.
+///
+/// The file is created with `O_EXCL`, so an existing file or symlink at the
+/// chosen path is never opened; another name is tried instead.
+pub fn temp_inheritable(prefix: &str) -> io::Result<File> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..MAX_TEMP_ATTEMPTS {
+        let path = env::temp_dir().join(format!(
+            "{}-{}-{}",
+            prefix,
+            ::std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path) {
+            Ok(v) => v,
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e)
+        };
+        fs::remove_file(&path)?;
+        set_inheritable(&file)?;
+
+        return Ok(file);
+    }
+
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "unable to create a temporary file"))
+}
+
+/// A path the runner can open to reach `file` through its inherited descriptor.
+pub fn inherited_path(file: &File) -> String {
+    format!("/proc/self/fd/{}", file.as_raw_fd())
+}
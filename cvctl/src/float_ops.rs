@@ -0,0 +1,202 @@
+use wasm_core::module::{Module, Native, Type, ValType};
+use wasm_core::opcode::Opcode;
+
+/// Import module that the hexagon-e translator maps to raw native invoke
+/// ids, given as `syscall_<id>` fields.
+pub const RAW_INVOKE_MODULE: &'static str = "hexagon_e";
+
+/// Id of the first floating point operation executed by the kernel's
+/// interpreter backend. Must match `backend::float` in the kernel module.
+pub const FLOAT_OP_BASE: u32 = 0x7fff_0000;
+
+/// Returns the operation number of `op` relative to `FLOAT_OP_BASE`, with
+/// its argument and result types, if it is a floating point operation that
+/// the translator doesn't handle itself.
+fn float_op(op: &Opcode) -> Option<(u32, &'static [ValType], ValType)> {
+    use self::ValType::*;
+
+    const F32_1: &'static [ValType] = &[F32];
+    const F32_2: &'static [ValType] = &[F32, F32];
+    const F64_1: &'static [ValType] = &[F64];
+    const F64_2: &'static [ValType] = &[F64, F64];
+    const I32_1: &'static [ValType] = &[I32];
+    const I64_1: &'static [ValType] = &[I64];
+
+    Some(match *op {
+        Opcode::F32Abs => (0, F32_1, F32),
+        Opcode::F32Neg => (1, F32_1, F32),
+        Opcode::F32Ceil => (2, F32_1, F32),
+        Opcode::F32Floor => (3, F32_1, F32),
+        Opcode::F32Trunc => (4, F32_1, F32),
+        Opcode::F32Nearest => (5, F32_1, F32),
+        Opcode::F32Sqrt => (6, F32_1, F32),
+        Opcode::F32Add => (7, F32_2, F32),
+        Opcode::F32Sub => (8, F32_2, F32),
+        Opcode::F32Mul => (9, F32_2, F32),
+        Opcode::F32Div => (10, F32_2, F32),
+        Opcode::F32Min => (11, F32_2, F32),
+        Opcode::F32Max => (12, F32_2, F32),
+        Opcode::F32Copysign => (13, F32_2, F32),
+        Opcode::F32Eq => (14, F32_2, I32),
+        Opcode::F32Ne => (15, F32_2, I32),
+        Opcode::F32Lt => (16, F32_2, I32),
+        Opcode::F32Gt => (17, F32_2, I32),
+        Opcode::F32Le => (18, F32_2, I32),
+        Opcode::F32Ge => (19, F32_2, I32),
+
+        Opcode::F64Abs => (20, F64_1, F64),
+        Opcode::F64Neg => (21, F64_1, F64),
+        Opcode::F64Ceil => (22, F64_1, F64),
+        Opcode::F64Floor => (23, F64_1, F64),
+        Opcode::F64Trunc => (24, F64_1, F64),
+        Opcode::F64Nearest => (25, F64_1, F64),
+        Opcode::F64Sqrt => (26, F64_1, F64),
+        Opcode::F64Add => (27, F64_2, F64),
+        Opcode::F64Sub => (28, F64_2, F64),
+        Opcode::F64Mul => (29, F64_2, F64),
+        Opcode::F64Div => (30, F64_2, F64),
+        Opcode::F64Min => (31, F64_2, F64),
+        Opcode::F64Max => (32, F64_2, F64),
+        Opcode::F64Copysign => (33, F64_2, F64),
+        Opcode::F64Eq => (34, F64_2, I32),
+        Opcode::F64Ne => (35, F64_2, I32),
+        Opcode::F64Lt => (36, F64_2, I32),
+        Opcode::F64Gt => (37, F64_2, I32),
+        Opcode::F64Le => (38, F64_2, I32),
+        Opcode::F64Ge => (39, F64_2, I32),
+
+        Opcode::I32TruncSF32 => (40, F32_1, I32),
+        Opcode::I32TruncUF32 => (41, F32_1, I32),
+        Opcode::I32TruncSF64 => (42, F64_1, I32),
+        Opcode::I32TruncUF64 => (43, F64_1, I32),
+        Opcode::I64TruncSF32 => (44, F32_1, I64),
+        Opcode::I64TruncUF32 => (45, F32_1, I64),
+        Opcode::I64TruncSF64 => (46, F64_1, I64),
+        Opcode::I64TruncUF64 => (47, F64_1, I64),
+
+        Opcode::F32ConvertSI32 => (48, I32_1, F32),
+        Opcode::F32ConvertUI32 => (49, I32_1, F32),
+        Opcode::F32ConvertSI64 => (50, I64_1, F32),
+        Opcode::F32ConvertUI64 => (51, I64_1, F32),
+        Opcode::F64ConvertSI32 => (52, I32_1, F64),
+        Opcode::F64ConvertUI32 => (53, I32_1, F64),
+        Opcode::F64ConvertSI64 => (54, I64_1, F64),
+        Opcode::F64ConvertUI64 => (55, I64_1, F64),
+
+        Opcode::F32DemoteF64 => (56, F64_1, F32),
+        Opcode::F64PromoteF32 => (57, F32_1, F64),
+
+        _ => return None
+    })
+}
+
+/// Replaces the floating point opcodes of `module` with native invokes that
+/// the kernel executes in software, since hexagon-e has no floating point
+/// instructions. Must run after imports are checked, as the added imports
+/// are resolved by the translator itself.
+pub fn lower(module: &mut Module) {
+    let mut natives: Vec<Option<u32>> = Vec::new();
+
+    for i in 0..module.functions.len() {
+        for j in 0..module.functions[i].body.opcodes.len() {
+            let (n, args, ret) = match float_op(&module.functions[i].body.opcodes[j]) {
+                Some(v) => v,
+                None => continue
+            };
+
+            if natives.len() <= n as usize {
+                natives.resize(n as usize + 1, None);
+            }
+
+            let native_id = match natives[n as usize] {
+                Some(v) => v,
+                None => {
+                    module.types.push(Type::Func(args.to_vec(), vec! [ ret ]));
+                    module.natives.push(Native {
+                        module: RAW_INVOKE_MODULE.to_string(),
+                        field: format!("syscall_{}", FLOAT_OP_BASE + n),
+                        typeidx: (module.types.len() - 1) as u32
+                    });
+
+                    let id = (module.natives.len() - 1) as u32;
+                    natives[n as usize] = Some(id);
+                    id
+                }
+            };
+
+            module.functions[i].body.opcodes[j] = Opcode::NativeInvoke(native_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_core::module::{Function, FunctionBody};
+
+    fn module_with_body(opcodes: Vec<Opcode>) -> Module {
+        Module {
+            types: vec! [ Type::Func(vec! [], vec! []) ],
+            functions: vec! [
+                Function {
+                    name: None,
+                    typeidx: 0,
+                    locals: vec! [],
+                    body: FunctionBody { opcodes: opcodes }
+                }
+            ],
+            data_segments: vec! [],
+            exports: Default::default(),
+            tables: vec! [],
+            globals: vec! [],
+            natives: vec! [],
+            start_function: None
+        }
+    }
+
+    #[test]
+    fn lower_float_opcodes() {
+        let mut m = module_with_body(vec! [
+            Opcode::F32Const(0),
+            Opcode::F32Const(0),
+            Opcode::F32Add,
+            Opcode::F64PromoteF32,
+            Opcode::F64Sqrt,
+            Opcode::Drop,
+            Opcode::F32Const(0),
+            Opcode::F32Const(0),
+            Opcode::F32Add,
+            Opcode::Drop
+        ]);
+        lower(&mut m);
+
+        assert_eq!(m.natives.len(), 3);
+        let fields: Vec<&str> = m.natives.iter().map(|n| n.field.as_str()).collect();
+        assert_eq!(fields, vec! [ "syscall_2147418119", "syscall_2147418169", "syscall_2147418138" ]);
+        assert!(m.natives.iter().all(|n| n.module == RAW_INVOKE_MODULE));
+
+        let invokes: Vec<u32> = m.functions[0].body.opcodes.iter()
+            .filter_map(|op| match *op {
+                Opcode::NativeInvoke(id) => Some(id),
+                _ => None
+            })
+            .collect();
+        assert_eq!(invokes, vec! [ 0, 1, 2, 0 ]);
+
+        let Type::Func(ref args, ref rets) = m.types[m.natives[0].typeidx as usize];
+        assert_eq!((args.len(), rets.len()), (2, 1));
+    }
+
+    #[test]
+    fn keep_other_opcodes() {
+        let mut m = module_with_body(vec! [
+            Opcode::F32Const(0),
+            Opcode::I32ReinterpretF32,
+            Opcode::Drop
+        ]);
+        lower(&mut m);
+
+        assert_eq!(m.natives.len(), 0);
+        assert_eq!(m.types.len(), 1);
+    }
+}
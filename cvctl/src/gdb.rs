@@ -0,0 +1,512 @@
+//! A GDB remote serial protocol server for applications running in debug
+//! mode, as served by `cvdebug`.
+//!
+//! hexagon-e keeps code, linear memory, slots and stacks apart, so they are
+//! laid out in one address space for the debugger. Linear memory starts at 0,
+//! so that pointers of the application can be read as they are, and the
+//! others start at the `*_BASE` addresses. Slots and stacks read as
+//! little-endian 64-bit values, bottom of the stack first.
+//!
+//! The registers are `pc`, the address of the next opcode in the code, and
+//! the heights of the operand stack, the call stack and the slots. The
+//! `monitor` commands print the same state for clients that don't know the
+//! registers.
+
+use std::io::{self, Read, Write, BufReader};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use service::{ServiceContext, ServiceError, ServiceResult, DebugCommand, DebugArea, DebugStop, StopReason};
+
+pub const CODE_BASE: u64 = 0x1_0000_0000;
+pub const SLOTS_BASE: u64 = 0x2_0000_0000;
+pub const STACK_BASE: u64 = 0x3_0000_0000;
+pub const CALL_STACK_BASE: u64 = 0x4_0000_0000;
+
+/// Size of the address range of each area
+const AREA_LEN: u64 = 0x1_0000_0000;
+
+/// Hex digits in the largest packet accepted, as advertised in `qSupported`
+const PACKET_SIZE: usize = 0x4000;
+
+/// How often a running application is checked for a stop while waiting for
+/// an interrupt from the client.
+const POLL_INTERVAL_MS: u64 = 100;
+
+const TARGET_XML: &'static str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.cervus.hexagon-e">
+    <reg name="pc" bitsize="64" type="code_ptr" regnum="0"/>
+    <reg name="sp" bitsize="64" type="uint64"/>
+    <reg name="csp" bitsize="64" type="uint64"/>
+    <reg name="nslots" bitsize="64" type="uint64"/>
+  </feature>
+</target>
+"#;
+
+const MONITOR_HELP: &'static str = "\
+state       Where the application stopped
+stack       Values on the operand stack, bottom first
+call-stack  Values on the call stack, bottom first
+slots       Values of the slots
+";
+
+/// Maps an address to the area it belongs to and the offset in it.
+fn area_of(addr: u64) -> (DebugArea, u64) {
+    match addr / AREA_LEN {
+        0 => (DebugArea::Memory, addr),
+        1 => (DebugArea::Code, addr - CODE_BASE),
+        2 => (DebugArea::Slots, addr - SLOTS_BASE),
+        3 => (DebugArea::Stack, addr - STACK_BASE),
+        _ => (DebugArea::CallStack, addr - CALL_STACK_BASE)
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len() / 2)
+        .map(|i| text.get(i * 2..i * 2 + 2).and_then(|v| u8::from_str_radix(v, 16).ok()))
+        .collect()
+}
+
+fn le_hex(v: u64) -> String {
+    (0..8).map(|i| format!("{:02x}", (v >> (i * 8)) as u8)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+/// Parses the `addr,len` of `m` and `Z` packets.
+fn parse_addr_len(text: &str) -> Option<(u64, u64)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?.split(';').next()?)?;
+    Some((addr, len))
+}
+
+fn stop_reply(stop: &DebugStop) -> String {
+    match stop.reason() {
+        // The exit status of a trapped application is negative, like the
+        // process exit code it becomes.
+        Some(StopReason::Exited(status)) => format!("W{:02x}", status as u8),
+        Some(StopReason::Interrupted) => "T02thread:1;".to_string(),
+        _ => "T05thread:1;".to_string()
+    }
+}
+
+/// What the client sent
+enum Input {
+    Packet(String),
+    Interrupt
+}
+
+/// A connection from a client to an application stopped for it.
+pub struct Session {
+    service: Arc<ServiceContext>,
+    pid: i32,
+    conn: BufReader<TcpStream>,
+    stop: DebugStop
+}
+
+impl Session {
+    /// Serves the client connected on `stream`. `stop` is where the
+    /// application stopped when the debugger attached.
+    pub fn new(service: Arc<ServiceContext>, pid: i32, stream: TcpStream, stop: DebugStop) -> Session {
+        Session {
+            service: service,
+            pid: pid,
+            conn: BufReader::new(stream),
+            stop: stop
+        }
+    }
+
+    /// Serves requests until the client detaches, kills the application or
+    /// it exits. The application is detached if the client goes away.
+    pub fn serve(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.read_input()? {
+                Some(Input::Packet(v)) => v,
+
+                // Stopped already
+                Some(Input::Interrupt) => continue,
+
+                None => {
+                    eprintln!("Connection closed, detaching");
+                    self.detach();
+                    return Ok(());
+                }
+            };
+
+            if !self.handle(&packet)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handles a packet and returns whether the session goes on.
+    fn handle(&mut self, packet: &str) -> io::Result<bool> {
+        let (kind, rest) = packet.split_at(if packet.len() > 0 { 1 } else { 0 });
+
+        match kind {
+            "?" => {
+                let reply = stop_reply(&self.stop);
+                self.send(&reply)?;
+            },
+            "g" => {
+                let reply = self.registers().iter().map(|v| le_hex(*v)).collect::<String>();
+                self.send(&reply)?;
+            },
+            "p" => {
+                let reply = match parse_hex(rest).and_then(|n| self.registers().get(n as usize).cloned()) {
+                    Some(v) => le_hex(v),
+                    None => "E00".to_string()
+                };
+                self.send(&reply)?;
+            },
+            "m" => {
+                let reply = match parse_addr_len(rest) {
+                    Some((addr, len)) => match self.read(addr, len as usize) {
+                        Ok(ref data) if data.len() > 0 => to_hex(data),
+                        _ => "E14".to_string()
+                    },
+                    None => "E00".to_string()
+                };
+                self.send(&reply)?;
+            },
+
+            // Everything is read-only.
+            "G" | "P" | "M" | "X" => self.send("E01")?,
+
+            "Z" | "z" => {
+                let reply = if rest.starts_with("0,") {
+                    let cmd = if kind == "Z" { DebugCommand::SetBreakpoint } else { DebugCommand::ClearBreakpoint };
+                    match parse_addr_len(&rest[2..]) {
+                        Some((addr, _)) if addr >= CODE_BASE && addr < CODE_BASE + AREA_LEN => {
+                            match self.service.debug(self.pid, cmd, addr - CODE_BASE) {
+                                Ok(_) => "OK".to_string(),
+                                Err(_) => "E16".to_string()
+                            }
+                        },
+                        _ => "E16".to_string()
+                    }
+                } else {
+                    // Only software breakpoints
+                    String::new()
+                };
+                self.send(&reply)?;
+            },
+            "c" => return self.resume(DebugCommand::Continue),
+            "s" => return self.resume(DebugCommand::Step),
+            "v" => return self.handle_v(packet),
+            "q" => self.handle_query(packet)?,
+            "H" | "T" => self.send("OK")?,
+            "D" => {
+                self.detach();
+                self.send("OK")?;
+                return Ok(false);
+            },
+            "k" => {
+                unsafe { ::libc::kill(self.pid, ::libc::SIGKILL); }
+                return Ok(false);
+            },
+            _ => self.send("")?
+        }
+
+        Ok(true)
+    }
+
+    fn handle_v(&mut self, packet: &str) -> io::Result<bool> {
+        if packet == "vCont?" {
+            self.send("vCont;c;s;t")?;
+        } else if packet.starts_with("vCont;c") {
+            return self.resume(DebugCommand::Continue);
+        } else if packet.starts_with("vCont;s") {
+            return self.resume(DebugCommand::Step);
+        } else if packet.starts_with("vCont;t") {
+            // Stopped already
+            let reply = stop_reply(&self.stop);
+            self.send(&reply)?;
+        } else {
+            self.send("")?;
+        }
+
+        Ok(true)
+    }
+
+    fn handle_query(&mut self, packet: &str) -> io::Result<()> {
+        if packet.starts_with("qSupported") {
+            self.send(&format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE))
+        } else if packet == "qAttached" {
+            self.send("1")
+        } else if packet == "qC" {
+            self.send("QC1")
+        } else if packet == "qfThreadInfo" {
+            self.send("m1")
+        } else if packet == "qsThreadInfo" {
+            self.send("l")
+        } else if packet.starts_with("qXfer:features:read:target.xml:") {
+            let reply = match parse_addr_len(&packet["qXfer:features:read:target.xml:".len()..]) {
+                Some((offset, len)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = ::std::cmp::min(offset as usize, xml.len());
+                    let end = ::std::cmp::min(start.saturating_add(len as usize), xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    format!("{}{}", more, String::from_utf8_lossy(&xml[start..end]))
+                },
+                None => "E00".to_string()
+            };
+            self.send(&reply)
+        } else if packet.starts_with("qRcmd,") {
+            match from_hex(&packet["qRcmd,".len()..]).and_then(|v| String::from_utf8(v).ok()) {
+                Some(cmd) => self.monitor(cmd.trim()),
+                None => self.send("E00")
+            }
+        } else {
+            self.send("")
+        }
+    }
+
+    /// Serves a `monitor` command, whose output goes to the console of the client.
+    fn monitor(&mut self, cmd: &str) -> io::Result<()> {
+        let text = match cmd {
+            "state" => {
+                let stop = self.stop;
+                format!(
+                    "{:?} at ip {} (pc 0x{:x})\nstack: {} values, call stack: {} values, slots: {}\nmemory: {} bytes, code: {} bytes\n",
+                    stop.reason(),
+                    stop.ip,
+                    CODE_BASE + stop.ip as u64,
+                    stop.stack_len,
+                    stop.call_stack_len,
+                    stop.n_slots,
+                    stop.memory_len,
+                    stop.code_len
+                )
+            },
+            "stack" => self.format_values(DebugArea::Stack, self.stop.stack_len),
+            "call-stack" => self.format_values(DebugArea::CallStack, self.stop.call_stack_len),
+            "slots" => self.format_values(DebugArea::Slots, self.stop.n_slots),
+            _ => MONITOR_HELP.to_string()
+        };
+
+        for chunk in text.as_bytes().chunks(PACKET_SIZE / 4) {
+            self.send(&format!("O{}", to_hex(chunk)))?;
+        }
+        self.send("OK")
+    }
+
+    fn format_values(&self, area: DebugArea, n: i64) -> String {
+        let data = match self.read_all(area, n as usize * 8) {
+            Ok(v) => v,
+            Err(e) => return format!("Unable to read: {}\n", e)
+        };
+
+        let mut text = String::new();
+        for (i, v) in data.chunks(8).enumerate() {
+            let v = v.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64) as i64;
+            text.push_str(&format!("[{}] {} (0x{:x})\n", i, v, v));
+        }
+        if text.len() == 0 {
+            text.push_str("(empty)\n");
+        }
+        text
+    }
+
+    fn registers(&self) -> [u64; 4] {
+        [
+            CODE_BASE + self.stop.ip as u64,
+            self.stop.stack_len as u64,
+            self.stop.call_stack_len as u64,
+            self.stop.n_slots as u64
+        ]
+    }
+
+    /// Reads at most `len` bytes at `addr`, which may end early at the end of an area.
+    fn read(&self, addr: u64, len: usize) -> ServiceResult<Vec<u8>> {
+        let (area, offset) = area_of(addr);
+        let mut buf = vec! [ 0; ::std::cmp::min(len, PACKET_SIZE / 2) ];
+        let (_, n) = self.service.debug_read(self.pid, area, offset, &mut buf)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    /// Reads the first `len` bytes of `area`.
+    fn read_all(&self, area: DebugArea, len: usize) -> ServiceResult<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::with_capacity(len);
+        let mut buf = vec! [ 0; ::service::DEBUG_MAX_READ_LEN ];
+
+        while data.len() < len {
+            let want = ::std::cmp::min(len - data.len(), buf.len());
+            let (_, n) = self.service.debug_read(self.pid, area, data.len() as u64, &mut buf[..want])?;
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+
+        Ok(data)
+    }
+
+    /// Continues or steps the application, and reports where it stops. The
+    /// client may interrupt it meanwhile.
+    fn resume(&mut self, cmd: DebugCommand) -> io::Result<bool> {
+        let (tx, rx) = mpsc::channel();
+        let service = self.service.clone();
+        let pid = self.pid;
+        thread::spawn(move || {
+            let _ = tx.send(service.debug(pid, cmd, 0));
+        });
+
+        let mut closed = false;
+        self.conn.get_ref().set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+        let result = loop {
+            match rx.try_recv() {
+                Ok(v) => break v,
+                Err(TryRecvError::Empty) => {},
+                Err(TryRecvError::Disconnected) => break Err(ServiceError::Rejected)
+            }
+
+            if closed {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                continue;
+            }
+
+            let mut b = [0u8; 1];
+            match self.conn.read(&mut b) {
+                // The connection is gone, so the application is stopped to
+                // be detached.
+                Ok(0) => {
+                    closed = true;
+                    let _ = self.service.debug(self.pid, DebugCommand::Interrupt, 0);
+                },
+                Ok(_) => if b[0] == 0x03 {
+                    let _ = self.service.debug(self.pid, DebugCommand::Interrupt, 0);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) => return Err(e)
+            }
+        };
+        self.conn.get_ref().set_read_timeout(None)?;
+
+        let stop = match result {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Unable to resume application: {}", e);
+                return Ok(false);
+            }
+        };
+        self.stop = stop;
+
+        if let Some(StopReason::Exited(status)) = stop.reason() {
+            eprintln!("Application exited with status {}", status);
+            if !closed {
+                self.send(&stop_reply(&stop))?;
+            }
+            return Ok(false);
+        }
+
+        if closed {
+            eprintln!("Connection closed, detaching");
+            self.detach();
+            return Ok(false);
+        }
+
+        self.send(&stop_reply(&stop))?;
+        Ok(true)
+    }
+
+    fn detach(&self) {
+        if let Err(e) = self.service.debug(self.pid, DebugCommand::Detach, 0) {
+            eprintln!("Unable to detach: {}", e);
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut b = [0u8; 1];
+        match self.conn.read(&mut b)? {
+            0 => Ok(None),
+            _ => Ok(Some(b[0]))
+        }
+    }
+
+    /// Reads the next packet or interrupt, acknowledging packets. Returns
+    /// `None` once the connection is closed.
+    fn read_input(&mut self) -> io::Result<Option<Input>> {
+        loop {
+            // Acknowledgements are not checked.
+            match self.read_byte()? {
+                Some(b'$') => {},
+                Some(0x03) => return Ok(Some(Input::Interrupt)),
+                Some(_) => continue,
+                None => return Ok(None)
+            }
+
+            let mut data: Vec<u8> = Vec::new();
+            let mut sum: u8 = 0;
+            let mut escaped = false;
+            loop {
+                let b = match self.read_byte()? {
+                    Some(v) => v,
+                    None => return Ok(None)
+                };
+                if b == b'#' && !escaped {
+                    break;
+                }
+                sum = sum.wrapping_add(b);
+
+                if escaped {
+                    data.push(b ^ 0x20);
+                    escaped = false;
+                } else if b == b'}' {
+                    escaped = true;
+                } else {
+                    data.push(b);
+                }
+            }
+
+            let mut checksum = [0u8; 2];
+            self.conn.read_exact(&mut checksum)?;
+            let expected = ::std::str::from_utf8(&checksum).ok().and_then(|v| u8::from_str_radix(v, 16).ok());
+
+            if expected != Some(sum) {
+                self.conn.get_mut().write_all(b"-")?;
+                continue;
+            }
+
+            self.conn.get_mut().write_all(b"+")?;
+            return Ok(Some(Input::Packet(String::from_utf8_lossy(&data).into_owned())));
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut body: Vec<u8> = Vec::with_capacity(data.len());
+        for b in data.bytes() {
+            match b {
+                b'#' | b'$' | b'}' | b'*' => {
+                    body.push(b'}');
+                    body.push(b ^ 0x20);
+                },
+                _ => body.push(b)
+            }
+        }
+        let sum = body.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+
+        let stream = self.conn.get_mut();
+        stream.write_all(b"$")?;
+        stream.write_all(&body)?;
+        stream.write_all(format!("#{:02x}", sum).as_bytes())?;
+        stream.flush()
+    }
+}
@@ -0,0 +1,222 @@
+use std::cell::RefCell;
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use cwa_trans::{MapCwaApi, NativeInvokeInfo};
+use files;
+use service::{Backend, ExecEnv, ServiceError, ServiceResult};
+use termination::{ReportPipe, Termination};
+
+const DEFAULT_RUNNER: &'static str = "cervus-hosted";
+
+/// Must match `MAX_NAME_LEN` in `hosted.c`. Longer lines would be split by the runner.
+const MAX_NAME_LEN: usize = 255;
+
+/// Number of names written to the runner before reading back their ids.
+const MAP_BATCH_SIZE: usize = 256;
+
+struct ApiServer {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>
+}
+
+impl ApiServer {
+    fn map(&mut self, name: &str) -> Option<u32> {
+        self.map_all(&[name]).pop().unwrap_or(None)
+    }
+
+    /// Writes a batch of names before reading back any of their ids.
+    fn map_all(&mut self, names: &[&str]) -> Vec<Option<u32>> {
+        let valid = |name: &str| name.len() > 0 && name.len() <= MAX_NAME_LEN && !name.contains('\n');
+        let mut ret: Vec<Option<u32>> = Vec::with_capacity(names.len());
+
+        for chunk in names.chunks(MAP_BATCH_SIZE) {
+            let mut failed = false;
+            for name in chunk.iter().filter(|v| valid(v)) {
+                if writeln!(self.stdin, "{}", name).is_err() {
+                    failed = true;
+                    break;
+                }
+            }
+            if failed || self.stdin.flush().is_err() {
+                ret.resize(names.len(), None);
+                return ret;
+            }
+
+            for name in chunk {
+                if !valid(name) {
+                    ret.push(None);
+                    continue;
+                }
+
+                let mut line = String::new();
+                if self.stdout.read_line(&mut line).is_err() {
+                    ret.resize(names.len(), None);
+                    return ret;
+                }
+
+                ret.push(match line.trim().parse::<i32>() {
+                    Ok(v) if v >= 0 => Some(v as u32),
+                    _ => None
+                });
+            }
+        }
+
+        ret
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Runs applications in an ordinary process through the `cervus-hosted` runner
+/// instead of the kernel module.
+///
+/// The runner is looked up from `CERVUS_HOSTED` and falls back to `cervus-hosted` in `PATH`.
+pub struct HostedContext {
+    runner: PathBuf,
+    api: RefCell<ApiServer>
+}
+
+impl HostedContext {
+    pub fn connect() -> ServiceResult<HostedContext> {
+        let runner: PathBuf = env::var_os("CERVUS_HOSTED")
+            .map(|v| v.into())
+            .unwrap_or_else(|| DEFAULT_RUNNER.into());
+
+        let mut child = Command::new(&runner)
+            .arg("--map-api")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        Ok(HostedContext {
+            runner: runner,
+            api: RefCell::new(ApiServer {
+                child: child,
+                stdin: stdin,
+                stdout: stdout
+            })
+        })
+    }
+
+    /// Lists every native invoke the runner provides.
+    pub fn list_native_invokes(&self) -> ServiceResult<Vec<NativeInvokeInfo>> {
+        let output = Command::new(&self.runner)
+            .arg("--list-api")
+            .stderr(Stdio::inherit())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(ServiceError::Rejected);
+        }
+
+        let text = String::from_utf8(output.stdout).map_err(|_| ServiceError::Rejected)?;
+        NativeInvokeInfo::parse_list(&text).ok_or(ServiceError::Rejected)
+    }
+
+    /// Runs translated code to completion, or continues a checkpoint if
+    /// `exec_env.restore_fd` is set and `code` is empty. `code` is a
+    /// WebAssembly binary instead if `exec_env.wasm_entry` is set.
+    pub fn run_code<'a>(&mut self, code: &[u8], backend: Backend, exec_env: ExecEnv<'a>) -> ServiceResult<Termination> {
+        if (code.len() == 0) != exec_env.restore_fd.is_some() {
+            return Err(ServiceError::InvalidInput);
+        }
+
+        // Debuggers attach through the kernel module.
+        if exec_env.debug {
+            return Err(ServiceError::InvalidInput);
+        }
+
+        // The runner reads the code through its inherited descriptor, so the
+        // file never has a name another user could race on.
+        let code_file = if code.len() > 0 {
+            let mut f = files::temp_inheritable("cvrun")?;
+            f.write_all(code)?;
+            Some(f)
+        } else {
+            None
+        };
+
+        let mut pipe = ReportPipe::new()?;
+
+        let mut cmd = Command::new(&self.runner);
+        cmd.arg("--run");
+        cmd.arg("--report-fd").arg(pipe.write_fd().to_string());
+        cmd.arg("--backend").arg(backend.name());
+
+        {
+            let mut opt = |name: &str, value: Option<u64>| if let Some(v) = value {
+                cmd.arg(name).arg(v.to_string());
+            };
+
+            opt("--fuel", exec_env.fuel);
+            opt("--timeout", exec_env.timeout_ms);
+            opt("--cpu-time", exec_env.cpu_time_ms);
+            opt("--memory", exec_env.mem_default_len.map(|v| v as u64));
+            opt("--max-memory", exec_env.mem_max_len.map(|v| v as u64));
+            opt("--max-slots", exec_env.max_slots.map(|v| v as u64));
+            opt("--stack-len", exec_env.stack_len.map(|v| v as u64));
+            opt("--call-stack-len", exec_env.call_stack_len.map(|v| v as u64));
+            opt("--profile-fd", exec_env.profile_fd.map(|v| v as u64));
+            opt("--profile-interval", exec_env.profile_interval.map(|v| v as u64));
+            opt("--core-fd", exec_env.core_fd.map(|v| v as u64));
+            opt("--record-fd", exec_env.record_fd.map(|v| v as u64));
+            opt("--replay-fd", exec_env.replay_fd.map(|v| v as u64));
+            opt("--manifest-fd", exec_env.manifest_fd.map(|v| v as u64));
+            opt("--trace-fd", exec_env.trace_fd.map(|v| v as u64));
+            opt("--restore-fd", exec_env.restore_fd.map(|v| v as u64));
+            opt("--checkpoint-fd", exec_env.checkpoint_fd.map(|v| v as u64));
+        }
+
+        if let Some(v) = exec_env.nice {
+            cmd.arg("--nice").arg(v.to_string());
+        }
+
+        for v in exec_env.entry_args {
+            cmd.arg("--entry-arg").arg(v.to_string());
+        }
+
+        if let Some(v) = exec_env.wasm_entry {
+            cmd.arg("--wasm-entry").arg(v);
+        }
+
+        if let Some(ref f) = code_file {
+            cmd.arg(files::inherited_path(f));
+        }
+        let status = cmd
+            .args(exec_env.args)
+            .status();
+
+        pipe.close_write();
+        drop(code_file);
+
+        let status = status?;
+
+        Ok(pipe.finish(match status.code() {
+            Some(v) => v,
+            None => 128 + status.signal().unwrap_or(0)
+        }))
+    }
+}
+
+impl MapCwaApi for HostedContext {
+    fn map_cwa_api(&self, name: &str) -> Option<u32> {
+        self.api.borrow_mut().map(name)
+    }
+
+    fn map_cwa_apis(&self, names: &[&str]) -> Vec<Option<u32>> {
+        self.api.borrow_mut().map_all(names)
+    }
+}
@@ -3,3 +3,14 @@ extern crate wasm_core;
 
 pub mod service;
 pub mod cwa_trans;
+pub mod float_ops;
+pub mod link;
+pub mod hosted;
+pub mod termination;
+pub mod profile;
+pub mod core_dump;
+pub mod symbols;
+pub mod files;
+pub mod manifest;
+pub mod trace;
+pub mod gdb;
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+
+use wasm_core::module::{Module, Export};
+use wasm_core::opcode::Opcode;
+use wasm_core::trans::config::ModuleConfig;
+
+/// A wasm module whose exported functions can be imported by other modules
+/// under `name`.
+pub struct Library {
+    pub name: String,
+    pub module: Module
+}
+
+impl Library {
+    /// Loads a library given on the command line as `<name>=<path>`.
+    pub fn load(spec: &str) -> io::Result<Library> {
+        let mut parts = spec.splitn(2, '=');
+        let (name, path) = match (parts.next(), parts.next()) {
+            (Some(name), Some(path)) if name.len() > 0 => (name, path),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "expected <name>=<path>"))
+        };
+
+        let mut code: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut code)?;
+
+        Ok(Library {
+            name: name.to_string(),
+            module: ::wasm_core::trans::translate_module_raw(&code, ModuleConfig::default())
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum LinkError {
+    /// Two libraries were given the same name.
+    DuplicateName(String),
+
+    /// The library and the modules before it both fill their function
+    /// tables. Indices into the table are plain values in wasm code, so
+    /// one of them would have to be relocated.
+    TableConflict(String),
+
+    /// The library's data segments overlap those of the modules before it.
+    DataOverlap(String),
+
+    /// The library has a start function, which the translator doesn't run.
+    StartFunction(String),
+
+    /// An import resolved to an export with a different signature.
+    SignatureMismatch(String, String)
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LinkError::DuplicateName(ref lib) => write!(f, "library name `{}` given more than once", lib),
+            LinkError::TableConflict(ref lib) => write!(f, "library `{}` and the modules before it both use a function table", lib),
+            LinkError::DataOverlap(ref lib) => write!(f, "data of library `{}` overlaps the data of the modules before it", lib),
+            LinkError::StartFunction(ref lib) => write!(f, "library `{}` has a start function, which is not supported", lib),
+            LinkError::SignatureMismatch(ref module, ref field) => write!(f, "import {} {} doesn't match the signature of the export", module, field)
+        }
+    }
+}
+
+struct Offsets {
+    types: u32,
+    functions: u32,
+    globals: u32,
+    natives: u32
+}
+
+fn relocate(op: &mut Opcode, offsets: &Offsets) {
+    match *op {
+        Opcode::Call(ref mut id) => *id += offsets.functions,
+        Opcode::CallIndirect(ref mut typeidx) => *typeidx += offsets.types,
+        Opcode::GetGlobal(ref mut id) | Opcode::SetGlobal(ref mut id) => *id += offsets.globals,
+        Opcode::NativeInvoke(ref mut id) => *id += offsets.natives,
+        _ => {}
+    }
+}
+
+fn uses_table(m: &Module) -> bool {
+    m.tables.iter().any(|t| t.elements.iter().any(|e| e.is_some()))
+}
+
+/// Appends the functions, types, globals, imports, table and data of `lib`
+/// to `target`, and returns where its exports ended up.
+fn append(target: &mut Module, lib: Library) -> Result<Vec<(String, u32)>, LinkError> {
+    let Library { name, module: lib } = lib;
+
+    if lib.start_function.is_some() {
+        return Err(LinkError::StartFunction(name));
+    }
+
+    for seg in &lib.data_segments {
+        let (begin, end) = (seg.offset as u64, seg.offset as u64 + seg.data.len() as u64);
+        let overlaps = target.data_segments.iter().any(|other| {
+            let (other_begin, other_end) = (other.offset as u64, other.offset as u64 + other.data.len() as u64);
+            begin < other_end && other_begin < end
+        });
+        if overlaps {
+            return Err(LinkError::DataOverlap(name));
+        }
+    }
+
+    let lib_uses_table = uses_table(&lib);
+    if lib_uses_table && uses_table(target) {
+        return Err(LinkError::TableConflict(name));
+    }
+
+    let offsets = Offsets {
+        types: target.types.len() as u32,
+        functions: target.functions.len() as u32,
+        globals: target.globals.len() as u32,
+        natives: target.natives.len() as u32
+    };
+
+    if lib_uses_table {
+        target.tables = lib.tables;
+        for t in &mut target.tables {
+            for e in &mut t.elements {
+                if let Some(ref mut id) = *e {
+                    *id += offsets.functions;
+                }
+            }
+        }
+    }
+
+    target.types.extend(lib.types);
+    target.globals.extend(lib.globals);
+    target.data_segments.extend(lib.data_segments);
+    target.natives.extend(lib.natives.into_iter().map(|mut n| {
+        n.typeidx += offsets.types;
+        n
+    }));
+    target.functions.extend(lib.functions.into_iter().map(|mut f| {
+        f.typeidx += offsets.types;
+        for op in &mut f.body.opcodes {
+            relocate(op, &offsets);
+        }
+        f
+    }));
+
+    Ok(lib.exports.into_iter()
+        .map(|(field, e)| match e {
+            Export::Function(id) => (field, id + offsets.functions)
+        })
+        .collect())
+}
+
+/// Links `libs` into `main`. Imports of any of the modules from a library
+/// name become direct calls to that library's exports; the remaining imports
+/// are left for `cwa_trans::check_imports`.
+///
+/// Modules keep their data at the addresses they were built for, so
+/// libraries must be built to use memory that doesn't overlap that of the
+/// application. Imported globals aren't linked.
+pub fn link(main: Module, libs: Vec<Library>) -> Result<Module, LinkError> {
+    let mut m = main;
+    let mut exports: HashMap<(String, String), u32> = HashMap::new();
+    let mut names: Vec<String> = Vec::new();
+
+    for lib in libs {
+        if names.contains(&lib.name) {
+            return Err(LinkError::DuplicateName(lib.name));
+        }
+        names.push(lib.name.clone());
+
+        let name = lib.name.clone();
+        for (field, id) in append(&mut m, lib)? {
+            exports.insert((name.clone(), field), id);
+        }
+    }
+
+    // Resolved imports become calls, the others are renumbered.
+    let mut resolved: Vec<Option<u32>> = Vec::with_capacity(m.natives.len());
+    let mut renumbered: Vec<u32> = Vec::with_capacity(m.natives.len());
+    let mut natives = Vec::new();
+
+    for n in ::std::mem::replace(&mut m.natives, Vec::new()) {
+        match exports.get(&(n.module.clone(), n.field.clone())) {
+            Some(&id) => {
+                if m.types[n.typeidx as usize] != m.types[m.functions[id as usize].typeidx as usize] {
+                    return Err(LinkError::SignatureMismatch(n.module, n.field));
+                }
+                resolved.push(Some(id));
+                renumbered.push(0);
+            },
+            None => {
+                resolved.push(None);
+                renumbered.push(natives.len() as u32);
+                natives.push(n);
+            }
+        }
+    }
+    m.natives = natives;
+
+    for f in &mut m.functions {
+        for op in &mut f.body.opcodes {
+            if let Opcode::NativeInvoke(id) = *op {
+                *op = match resolved[id as usize] {
+                    Some(target) => Opcode::Call(target),
+                    None => Opcode::NativeInvoke(renumbered[id as usize])
+                };
+            }
+        }
+    }
+
+    Ok(m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_core::module::{Function, FunctionBody, Native, Type, ValType};
+
+    fn module_with_body(opcodes: Vec<Opcode>) -> Module {
+        Module {
+            types: vec! [ Type::Func(vec! [], vec! [ ValType::I32 ]) ],
+            functions: vec! [
+                Function {
+                    name: None,
+                    typeidx: 0,
+                    locals: vec! [],
+                    body: FunctionBody { opcodes: opcodes }
+                }
+            ],
+            data_segments: vec! [],
+            exports: Default::default(),
+            tables: vec! [],
+            globals: vec! [],
+            natives: vec! [],
+            start_function: None
+        }
+    }
+
+    fn import(m: &mut Module, module: &str, field: &str) {
+        m.natives.push(Native {
+            module: module.to_string(),
+            field: field.to_string(),
+            typeidx: 0
+        });
+    }
+
+    fn library(name: &str) -> Library {
+        let mut m = module_with_body(vec! [ Opcode::NativeInvoke(0), Opcode::Return ]);
+        import(&mut m, "env", "get");
+        m.exports.insert("f".to_string(), Export::Function(0));
+
+        Library {
+            name: name.to_string(),
+            module: m
+        }
+    }
+
+    #[test]
+    fn resolve_library_imports() {
+        let mut m = module_with_body(vec! [ Opcode::NativeInvoke(0), Opcode::NativeInvoke(1), Opcode::Return ]);
+        import(&mut m, "env", "put");
+        import(&mut m, "lib", "f");
+
+        let m = link(m, vec! [ library("lib") ]).unwrap();
+
+        let targets: Vec<(bool, u32)> = m.functions.iter()
+            .flat_map(|f| f.body.opcodes.iter())
+            .filter_map(|op| match *op {
+                Opcode::NativeInvoke(id) => Some((false, id)),
+                Opcode::Call(id) => Some((true, id)),
+                _ => None
+            })
+            .collect();
+        assert_eq!(targets, vec! [ (false, 0), (true, 1), (false, 1) ]);
+
+        let fields: Vec<&str> = m.natives.iter().map(|n| n.field.as_str()).collect();
+        assert_eq!(fields, vec! [ "put", "get" ]);
+    }
+
+    #[test]
+    fn reject_bad_libraries() {
+        let mut m = module_with_body(vec! [ Opcode::Return ]);
+        m.types[0] = Type::Func(vec! [], vec! []);
+        import(&mut m, "lib", "f");
+
+        match link(m, vec! [ library("lib") ]) {
+            Err(LinkError::SignatureMismatch(ref module, ref field)) => assert_eq!((module.as_str(), field.as_str()), ("lib", "f")),
+            _ => panic!("signature mismatch not detected")
+        }
+
+        match link(module_with_body(vec! []), vec! [ library("lib"), library("lib") ]) {
+            Err(LinkError::DuplicateName(_)) => {},
+            _ => panic!("duplicate name not detected")
+        }
+    }
+}
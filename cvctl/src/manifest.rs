@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+/// Custom section that applications can embed their manifest in.
+pub const SECTION_NAME: &'static str = "cervus.manifest";
+
+/// Reads an unsigned LEB128 number, returning it and the number of bytes used.
+fn read_leb_u32(data: &[u8]) -> Option<(u32, usize)> {
+    let mut value: u32 = 0;
+
+    for (i, b) in data.iter().enumerate().take(5) {
+        value |= ((*b & 0x7f) as u32) << (i * 7);
+        if *b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+
+    None
+}
+
+/// Finds the contents of the custom section `name` in a wasm module.
+pub fn find_custom_section<'a>(wasm: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    if wasm.len() < 8 || &wasm[0..4] != b"\0asm" {
+        return None;
+    }
+
+    let mut rest = &wasm[8..];
+
+    while rest.len() > 0 {
+        let id = rest[0];
+        let (len, n) = read_leb_u32(&rest[1..])?;
+        let begin = 1 + n;
+        let end = begin.checked_add(len as usize)?;
+        if end > rest.len() {
+            return None;
+        }
+
+        let body = &rest[begin..end];
+        rest = &rest[end..];
+
+        if id == 0 {
+            let (name_len, n) = read_leb_u32(body)?;
+            let name_end = n.checked_add(name_len as usize)?;
+            if name_end > body.len() {
+                return None;
+            }
+
+            if &body[n..name_end] == name.as_bytes() {
+                return Some(&body[name_end..]);
+            }
+        }
+    }
+
+    None
+}
+
+/// Writes a manifest to a file that can be passed to the runtime.
+pub fn write_temp(manifest: &[u8]) -> io::Result<File> {
+    let mut file = ::files::temp_inheritable("cvmanifest")?;
+    file.write_all(manifest)?;
+
+    Ok(file)
+}
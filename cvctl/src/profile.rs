@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use symbols::SymbolMap;
+
+/// Default number of branches between two profiler samples.
+pub const DEFAULT_INTERVAL: u32 = 64;
+
+/// An unlinked temporary file that the runtime writes the profile to.
+pub struct ProfileFile {
+    file: File
+}
+
+impl ProfileFile {
+    pub fn new() -> io::Result<ProfileFile> {
+        Ok(ProfileFile {
+            file: ::files::temp_inheritable("cvprof")?
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    /// Reads the profile after the application has terminated.
+    pub fn finish(mut self) -> io::Result<Profile> {
+        let mut text = String::new();
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_string(&mut text)?;
+
+        Profile::parse(&text).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed or missing profile"
+        ))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub interval: u32,
+
+    /// Samples that were lost because too many distinct branch targets were seen.
+    pub dropped: u64,
+
+    /// Sampled branch targets (offsets into the hexagon-e code) and their sample counts.
+    pub branches: Vec<(usize, u64)>,
+
+    /// Native invoke names and how many times they were called.
+    pub invokes: Vec<(String, u64)>
+}
+
+impl Profile {
+    pub fn parse(text: &str) -> Option<Profile> {
+        let mut lines = text.lines();
+        if lines.next() != Some("cervus-profile 1") {
+            return None;
+        }
+
+        let mut profile = Profile::default();
+
+        for line in lines {
+            let mut parts = line.split(' ');
+
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("interval"), Some(v), None) => profile.interval = v.parse().ok()?,
+                (Some("dropped"), Some(v), None) => profile.dropped = v.parse().ok()?,
+                (Some("branch"), Some(target), Some(count)) => profile.branches.push(
+                    (target.parse().ok()?, count.parse().ok()?)
+                ),
+                (Some("invoke"), Some(name), Some(count)) => profile.invokes.push(
+                    (name.to_string(), count.parse().ok()?)
+                ),
+                _ => return None
+            }
+        }
+
+        Some(profile)
+    }
+
+    /// Writes a human-readable report with the hottest entries first.
+    ///
+    /// `symbols` maps branch targets to the functions of the application.
+    pub fn write_report<W: Write>(&self, w: &mut W, symbols: &SymbolMap) -> io::Result<()> {
+        let mut branches = self.branches.clone();
+        branches.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let mut functions: Vec<(String, u64)> = Vec::new();
+        for &(target, count) in &branches {
+            let name = symbols.lookup(target).map(|v| v.0).unwrap_or("<startup>");
+            match functions.iter().position(|v| v.0 == name) {
+                Some(i) => functions[i].1 += count,
+                None => functions.push((name.to_string(), count))
+            }
+        }
+        functions.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let mut invokes = self.invokes.clone();
+        invokes.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let total: u64 = branches.iter().map(|v| v.1).sum::<u64>() + self.dropped;
+
+        writeln!(w, "Branch samples (1 every {} branches, {} total, {} dropped):", self.interval, total, self.dropped)?;
+        for &(ref name, count) in &functions {
+            writeln!(w, "    {:>12} {:>6.2}%  {}", count, percentage(count, total), name)?;
+        }
+
+        writeln!(w, "")?;
+        writeln!(w, "Branch targets:")?;
+        for &(target, count) in &branches {
+            writeln!(w, "    {:>12} {:>6.2}%  {}", count, percentage(count, total), symbols.describe(target))?;
+        }
+
+        writeln!(w, "")?;
+        writeln!(w, "Native invokes:")?;
+        for &(ref name, count) in &invokes {
+            writeln!(w, "    {:>12}  {}", count, name)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn percentage(n: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n as f64 * 100.0 / total as f64
+    }
+}
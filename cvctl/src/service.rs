@@ -1,7 +1,13 @@
 use std::fs::File;
 use std::io;
 use std::error::Error;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use cwa_trans::{MapCwaApi, NativeInvokeInfo};
+use termination::{ReportPipe, Termination, wait_status_to_exit_code};
+
+/// Number of names resolved per `MapCwaApiBatch` request.
+const MAP_BATCH_SIZE: usize = 4096;
 
 macro_rules! impl_debug_display {
     ($target:ident) => {
@@ -19,23 +25,158 @@ pub struct UserString {
     data: *const u8
 }
 
+impl UserString {
+    fn new(v: &str) -> UserString {
+        let v = v.as_bytes();
+        UserString {
+            len: v.len(),
+            data: if v.len() == 0 {
+                ::std::ptr::null()
+            } else {
+                &v[0]
+            }
+        }
+    }
+}
+
 #[repr(i32)]
 pub enum Command {
     LoadCode = 0x1001,
-    RunCode = 0x1002,
-    MapCwaApi = 0x1003
+
+    // Takes the size-prefixed options. 0x1002 is the original `RunCode`, which
+    // only accepts the code and its arguments.
+    RunCode = 0x1006,
+    MapCwaApi = 0x1003,
+    MapCwaApiBatch = 0x1004,
+    ListNativeInvokes = 0x1005,
+    Checkpoint = 0x1007,
+    Debug = 0x1008
+}
+
+/// Must match `CHECKPOINT_KIND_LEN` in the kernel module.
+const CHECKPOINT_KIND_LEN: usize = 32;
+
+/// Must match `DEBUG_MAX_READ_LEN` in the kernel module.
+pub const DEBUG_MAX_READ_LEN: usize = 65536;
+
+/// Requests of `ServiceContext::debug`. Must match `DEBUG_CMD_*` in the
+/// kernel module.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DebugCommand {
+    /// Stops the application and reports where
+    Attach = 1,
+
+    /// Removes the breakpoints and continues without a debugger
+    Detach = 2,
+
+    /// Continues until the next breakpoint or interrupt and reports it
+    Continue = 3,
+
+    /// Runs one opcode and reports where the application stops
+    Step = 4,
+
+    /// Stops a running application, whose `Continue` then returns
+    Interrupt = 5,
+
+    /// Takes the offset of an opcode in the code
+    SetBreakpoint = 6,
+    ClearBreakpoint = 7
+}
+
+/// What `ServiceContext::debug_read` reads. Slots and stacks are read as
+/// little-endian 64-bit values. Must match `DEBUG_AREA_*`.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DebugArea {
+    Memory = 0,
+    Slots = 1,
+    Stack = 2,
+    CallStack = 3,
+    Code = 4
+}
+
+/// `DEBUG_CMD_READ`, which is not a `DebugCommand` as it takes an area.
+const DEBUG_CMD_READ: u32 = 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StopReason {
+    /// Waiting for a debugger before the first opcode
+    Started,
+    Attached,
+    Breakpoint,
+    Step,
+    Interrupted,
+
+    /// Terminated with this exit status
+    Exited(i32)
+}
+
+/// Where an application running in debug mode is. Must match `struct debug_stop`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DebugStop {
+    reason: i64,
+
+    /// Offset of the next opcode in the code
+    pub ip: i64,
+
+    pub stack_len: i64,
+    pub call_stack_len: i64,
+    pub n_slots: i64,
+    pub memory_len: i64,
+    pub code_len: i64
+}
+
+impl DebugStop {
+    pub fn reason(&self) -> Option<StopReason> {
+        Some(match self.reason {
+            1 => StopReason::Started,
+            2 => StopReason::Attached,
+            3 => StopReason::Breakpoint,
+            4 => StopReason::Step,
+            5 => StopReason::Interrupted,
+            6 => StopReason::Exited(self.ip as i32),
+            _ => return None
+        })
+    }
 }
 
 #[repr(i32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Backend {
-    HexagonE = 0x01
+    /// The interpreter
+    HexagonE = 0x01,
+
+    /// Compiles the code to machine code before running it
+    Jit = 0x02
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "hexagon-e" => Some(Backend::HexagonE),
+            "jit" => Some(Backend::Jit),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Backend::HexagonE => "hexagon-e",
+            Backend::Jit => "jit"
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ServiceError {
     Io(io::Error),
     InvalidInput,
-    Rejected
+    Rejected,
+
+    /// A checkpoint was refused because this resource can't be checkpointed.
+    NotCheckpointable { resource: i64, kind: String }
 }
 
 pub type ServiceResult<T> = Result<T, ServiceError>;
@@ -55,13 +196,101 @@ impl From<io::Error> for ServiceError {
 }
 
 pub struct ExecEnv<'a> {
-    pub args: &'a [&'a str]
+    pub args: &'a [&'a str],
+
+    /// Arguments of the entry function
+    pub entry_args: &'a [i64],
+
+    /// Maximum number of branches the app may take, `None` for unlimited.
+    pub fuel: Option<u64>,
+
+    /// Wall-clock time limit in milliseconds
+    pub timeout_ms: Option<u64>,
+
+    /// CPU time limit in milliseconds
+    pub cpu_time_ms: Option<u64>,
+
+    /// Initial linear memory size in bytes
+    pub mem_default_len: Option<usize>,
+
+    /// Maximum linear memory size in bytes
+    pub mem_max_len: Option<usize>,
+
+    pub max_slots: Option<usize>,
+    pub stack_len: Option<usize>,
+    pub call_stack_len: Option<usize>,
+
+    /// File descriptor that receives the termination report
+    pub report_fd: Option<RawFd>,
+
+    /// File descriptor that receives the profile. Profiling is off if `None`.
+    pub profile_fd: Option<RawFd>,
+
+    /// Branches between two profiler samples
+    pub profile_interval: Option<u32>,
+
+    /// Added to the niceness of the process running the app. Negative values need privileges.
+    pub nice: Option<i32>,
+
+    /// File descriptor that receives a core dump if the app traps
+    pub core_fd: Option<RawFd>,
+
+    /// File descriptor that native invoke results are recorded to
+    pub record_fd: Option<RawFd>,
+
+    /// File descriptor of a recording to replay instead of calling native invokes
+    pub replay_fd: Option<RawFd>,
+
+    /// File descriptor of the capability manifest. Everything is allowed if `None`.
+    pub manifest_fd: Option<RawFd>,
+
+    /// File descriptor that every native invoke the app makes is traced to
+    pub trace_fd: Option<RawFd>,
+
+    /// File descriptor of a checkpoint to continue from. The checkpoint holds
+    /// the code, so none is passed along with it.
+    pub restore_fd: Option<RawFd>,
+
+    /// Export to call if the code is a WebAssembly binary for the kernel to
+    /// translate. `None` if the code is translated already.
+    pub wasm_entry: Option<&'a str>,
+
+    /// File descriptor that the hosted runner writes a checkpoint to on
+    /// `SIGUSR1`, or `SIGUSR2` to stop afterwards. The kernel module takes
+    /// requests through `ServiceContext::checkpoint` instead.
+    pub checkpoint_fd: Option<RawFd>,
+
+    /// Stops the app before its first opcode until a debugger continues it
+    /// through `ServiceContext::debug`. Only the interpreter supports it.
+    pub debug: bool
 }
 
 impl<'a> ExecEnv<'a> {
     pub fn empty() -> ExecEnv<'a> {
         ExecEnv {
-            args: &[]
+            args: &[],
+            entry_args: &[],
+            fuel: None,
+            timeout_ms: None,
+            cpu_time_ms: None,
+            mem_default_len: None,
+            mem_max_len: None,
+            max_slots: None,
+            stack_len: None,
+            call_stack_len: None,
+            report_fd: None,
+            profile_fd: None,
+            profile_interval: None,
+            nice: None,
+            core_fd: None,
+            record_fd: None,
+            replay_fd: None,
+            manifest_fd: None,
+            trace_fd: None,
+            restore_fd: None,
+            wasm_entry: None,
+            checkpoint_fd: None,
+            debug: false
         }
     }
 }
@@ -84,39 +313,75 @@ impl ServiceContext {
         cmd: Command,
         exec_env: ExecEnv<'a>
     ) -> ServiceResult<i32> {
-        if code.len() == 0 {
+        if (code.len() == 0) != exec_env.restore_fd.is_some() {
             return Err(ServiceError::InvalidInput);
         }
 
         #[repr(C)]
         struct LoadCodeOptions {
+            size: u32,
             executor: i32,
             n_args: i32,
             args: *const UserString,
             len: usize,
-            addr: *const u8
+            addr: *const u8,
+            fuel: u64,
+            timeout_ms: u64,
+            cpu_time_ms: u64,
+            mem_default_len: usize,
+            mem_max_len: usize,
+            max_slots: usize,
+            stack_len: usize,
+            call_stack_len: usize,
+            report_fd: i32,
+            profile_fd: i32,
+            profile_interval: u32,
+            nice: i32,
+            core_fd: i32,
+            record_fd: i32,
+            replay_fd: i32,
+            manifest_fd: i32,
+            trace_fd: i32,
+            n_entry_args: u32,
+            entry_args: *const i64,
+            restore_fd: i32,
+            wasm_entry: UserString,
+            debug: i32
         }
 
         let args: Vec<UserString> = exec_env.args.iter()
-            .map(|v| {
-                let v = v.as_bytes();
-                UserString {
-                    len: v.len(),
-                    data: if v.len() == 0 {
-                        ::std::ptr::null()
-                    } else {
-                        &v[0]
-                    }
-                }
-            })
+            .map(|v| UserString::new(v))
             .collect();
 
         let opts = LoadCodeOptions {
+            size: ::std::mem::size_of::<LoadCodeOptions>() as u32,
             executor: backend as i32,
             n_args: args.len() as i32,
             args: if args.len() > 0 { &args[0] } else { ::std::ptr::null() },
             len: code.len(),
-            addr: &code[0]
+            addr: code.as_ptr(),
+            fuel: exec_env.fuel.unwrap_or(0),
+            timeout_ms: exec_env.timeout_ms.unwrap_or(0),
+            cpu_time_ms: exec_env.cpu_time_ms.unwrap_or(0),
+            mem_default_len: exec_env.mem_default_len.unwrap_or(0),
+            mem_max_len: exec_env.mem_max_len.unwrap_or(0),
+            max_slots: exec_env.max_slots.unwrap_or(0),
+            stack_len: exec_env.stack_len.unwrap_or(0),
+            call_stack_len: exec_env.call_stack_len.unwrap_or(0),
+            report_fd: exec_env.report_fd.unwrap_or(-1),
+            profile_fd: exec_env.profile_fd.unwrap_or(-1),
+            profile_interval: exec_env.profile_interval.unwrap_or(::profile::DEFAULT_INTERVAL),
+            nice: exec_env.nice.unwrap_or(0),
+            core_fd: exec_env.core_fd.unwrap_or(-1),
+            record_fd: exec_env.record_fd.unwrap_or(-1),
+            replay_fd: exec_env.replay_fd.unwrap_or(-1),
+            manifest_fd: exec_env.manifest_fd.unwrap_or(-1),
+            trace_fd: exec_env.trace_fd.unwrap_or(-1),
+            n_entry_args: exec_env.entry_args.len() as u32,
+            entry_args: exec_env.entry_args.as_ptr(),
+            restore_fd: exec_env.restore_fd.unwrap_or(-1),
+            wasm_entry: UserString::new(exec_env.wasm_entry.unwrap_or("")),
+            debug: exec_env.debug as i32
         };
 
         match cmd {
@@ -151,18 +416,267 @@ impl ServiceContext {
         }
     }
 
-    pub fn run_code(
+    pub fn run_code<'a>(
         &mut self,
         code: &[u8],
         backend: Backend,
-        args: &[&str]
+        exec_env: ExecEnv<'a>
     ) -> ServiceResult<i32> {
-        self.submit_code(code, backend, Command::RunCode, ExecEnv {
-            args: args
-        })
+        self.submit_code(code, backend, Command::RunCode, exec_env)
     }
 
-    pub fn map_cwa_api(&self, name: &str) -> Option<u32> {
+    /// Runs code in a child process and waits for it to terminate.
+    ///
+    /// `run_code` replaces the calling process with the application, so this is
+    /// what loaders that want to report the result need.
+    pub fn run_code_in_child<'a>(
+        &mut self,
+        code: &[u8],
+        backend: Backend,
+        mut exec_env: ExecEnv<'a>
+    ) -> ServiceResult<Termination> {
+        let mut pipe = ReportPipe::new()?;
+        exec_env.report_fd = Some(pipe.write_fd());
+        let debug = exec_env.debug;
+
+        let pid = unsafe { ::libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        if pid == 0 {
+            // Only returns on failure
+            let ret = self.run_code(code, backend, exec_env);
+            eprintln!("Unable to run code: {:?}", ret);
+            unsafe { ::libc::_exit(1); }
+        }
+
+        pipe.close_write();
+
+        if debug {
+            eprintln!("Application {} is waiting for a debugger: cvdebug {}", pid, pid);
+        }
+
+        let mut status: ::libc::c_int = 0;
+        if unsafe { ::libc::waitpid(pid, &mut status, 0) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(pipe.finish(wait_status_to_exit_code(status)))
+    }
+}
+
+impl ServiceContext {
+    /// Asks the application running in process `pid` to write a checkpoint
+    /// to `out`, and waits until it is written. With `stop`, the application
+    /// terminates afterwards.
+    pub fn checkpoint(&self, pid: i32, out: &File, stop: bool) -> ServiceResult<()> {
+        #[repr(C)]
+        struct Request {
+            pid: i32,
+            fd: i32,
+            flags: u32,
+            resource: i64,
+            resource_kind: [u8; CHECKPOINT_KIND_LEN]
+        }
+
+        let mut req = Request {
+            pid: pid,
+            fd: out.as_raw_fd(),
+            flags: if stop { 1 } else { 0 },
+            resource: -1,
+            resource_kind: [0; CHECKPOINT_KIND_LEN]
+        };
+        let fd = self.dev.as_raw_fd();
+        let ret = unsafe {
+            ::libc::ioctl(
+                fd,
+                Command::Checkpoint as i32 as ::libc::c_ulong,
+                &mut req as *mut Request as ::libc::c_ulong
+            )
+        };
+
+        if ret == 0 {
+            return Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(::libc::EOPNOTSUPP) {
+            let len = req.resource_kind.iter().position(|v| *v == 0).unwrap_or(CHECKPOINT_KIND_LEN);
+            return Err(ServiceError::NotCheckpointable {
+                resource: req.resource,
+                kind: String::from_utf8_lossy(&req.resource_kind[..len]).into_owned()
+            });
+        }
+
+        Err(err.into())
+    }
+
+    /// Sends a debugger request to the application running in debug mode in
+    /// process `pid`, and returns where it is afterwards. `arg` is the
+    /// breakpoint for the requests that take one.
+    pub fn debug(&self, pid: i32, cmd: DebugCommand, arg: u64) -> ServiceResult<DebugStop> {
+        self.debug_request(pid, cmd as u32, 0, arg, &mut []).map(|(stop, _)| stop)
+    }
+
+    /// Reads at most `DEBUG_MAX_READ_LEN` bytes at `offset` of `area` of a
+    /// stopped application to `buf`, and returns where the application is
+    /// with the number of bytes read. Fails with `EINVAL` if `offset` is past
+    /// the end of the area.
+    pub fn debug_read(&self, pid: i32, area: DebugArea, offset: u64, buf: &mut [u8]) -> ServiceResult<(DebugStop, usize)> {
+        let len = ::std::cmp::min(buf.len(), DEBUG_MAX_READ_LEN);
+        self.debug_request(pid, DEBUG_CMD_READ, area as u32, offset, &mut buf[..len])
+    }
+
+    fn debug_request(&self, pid: i32, cmd: u32, area: u32, arg: u64, buf: &mut [u8]) -> ServiceResult<(DebugStop, usize)> {
+        #[repr(C)]
+        struct Request {
+            pid: i32,
+            cmd: u32,
+            area: u32,
+            arg: u64,
+            buf: *mut u8,
+            len: u64,
+            stop: DebugStop
+        }
+
+        let mut req = Request {
+            pid: pid,
+            cmd: cmd,
+            area: area,
+            arg: arg,
+            buf: buf.as_mut_ptr(),
+            len: buf.len() as u64,
+            stop: DebugStop::default()
+        };
+        let fd = self.dev.as_raw_fd();
+        let ret = unsafe {
+            ::libc::ioctl(
+                fd,
+                Command::Debug as i32 as ::libc::c_ulong,
+                &mut req as *mut Request as ::libc::c_ulong
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok((req.stop, ret as usize))
+    }
+
+    /// Lists every native invoke registered in the kernel module, including
+    /// those provided by other modules.
+    pub fn list_native_invokes(&self) -> ServiceResult<Vec<NativeInvokeInfo>> {
+        #[repr(C)]
+        struct Request {
+            buf: *mut u8,
+            len: usize
+        }
+
+        let fd = self.dev.as_raw_fd();
+        let mut buf: Vec<u8> = Vec::new();
+
+        // The list may grow between the two calls.
+        loop {
+            let req = Request {
+                buf: buf.as_mut_ptr(),
+                len: buf.len()
+            };
+            let ret = unsafe {
+                ::libc::ioctl(
+                    fd,
+                    Command::ListNativeInvokes as i32 as ::libc::c_ulong,
+                    &req as *const Request as ::libc::c_ulong
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let len = ret as usize;
+            if len <= buf.len() {
+                buf.truncate(len);
+                break;
+            }
+            buf = vec! [ 0; len ];
+        }
+
+        let text = String::from_utf8(buf).map_err(|_| ServiceError::Rejected)?;
+        NativeInvokeInfo::parse_list(&text).ok_or(ServiceError::Rejected)
+    }
+
+    fn map_cwa_api_batch(&self, names: &[&str]) -> Option<Vec<Option<u32>>> {
+        #[repr(C)]
+        struct Request {
+            names: *const u8,
+            names_len: usize,
+            ids: *mut i32,
+            n_names: usize
+        }
+
+        let mut table: Vec<u8> = Vec::new();
+        for name in names {
+            table.extend_from_slice(name.as_bytes());
+            table.push(0);
+        }
+        let mut ids: Vec<i32> = vec! [ -1; names.len() ];
+
+        let req = Request {
+            names: table.as_ptr(),
+            names_len: table.len(),
+            ids: ids.as_mut_ptr(),
+            n_names: ids.len()
+        };
+        let fd = self.dev.as_raw_fd();
+        let ret = unsafe {
+            ::libc::ioctl(
+                fd,
+                Command::MapCwaApiBatch as i32 as ::libc::c_ulong,
+                &req as *const Request as ::libc::c_ulong
+            )
+        };
+
+        if ret < 0 {
+            None
+        } else {
+            Some(ids.into_iter().map(|v| if v < 0 { None } else { Some(v as u32) }).collect())
+        }
+    }
+}
+
+impl MapCwaApi for ServiceContext {
+    fn map_cwa_apis(&self, names: &[&str]) -> Vec<Option<u32>> {
+        let mut ret: Vec<Option<u32>> = Vec::with_capacity(names.len());
+
+        for chunk in names.chunks(MAP_BATCH_SIZE) {
+            // Names the kernel can't tell apart in a table are never registered.
+            let valid: Vec<&str> = chunk.iter()
+                .cloned()
+                .filter(|v| v.len() > 0 && !v.contains('\0'))
+                .collect();
+
+            // Older modules only resolve one name at a time.
+            let ids = match self.map_cwa_api_batch(&valid) {
+                Some(v) => v,
+                None => valid.iter().map(|v| self.map_cwa_api(v)).collect()
+            };
+
+            let mut ids = ids.into_iter();
+            for name in chunk {
+                ret.push(if name.len() > 0 && !name.contains('\0') {
+                    ids.next().unwrap_or(None)
+                } else {
+                    None
+                });
+            }
+        }
+
+        ret
+    }
+
+
+    fn map_cwa_api(&self, name: &str) -> Option<u32> {
         #[repr(C)]
         struct Request {
             name: *const u8,
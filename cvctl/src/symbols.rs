@@ -0,0 +1,161 @@
+use std::fmt::Write;
+
+use wasm_core::hetrans::{translate_module, MapNativeInvoke};
+use wasm_core::module::{Module, Table};
+
+/// Length of the `ResetSlots <u32>` opcode that starts translated code.
+const RESET_SLOTS_LEN: usize = 5;
+
+/// Length of the `I64Const <u64>`, `SetSlot <u32>` pair that initializes a slot.
+const SLOT_INIT_LEN: usize = 14;
+
+/// Maps every import to the same id. Ids don't change the layout of the
+/// translated code.
+struct AnyNativeInvoke;
+
+impl MapNativeInvoke for AnyNativeInvoke {
+    fn map_native_invoke(&mut self, _module: &str, _field: &str) -> Option<u32> {
+        Some(0)
+    }
+}
+
+fn le_u32(data: &[u8]) -> u32 {
+    data[0..4].iter().rev().fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+/// Maps offsets into the translated hexagon-e code of a module back to the
+/// wasm functions they belong to.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolMap {
+    /// Start offsets and names of functions, sorted by offset
+    functions: Vec<(usize, String)>,
+
+    /// Length of the translated code
+    code_len: usize
+}
+
+impl SymbolMap {
+    /// Builds the map for `m` as `translate_module` would translate it.
+    ///
+    /// `translate_module` doesn't report where it puts functions, so the
+    /// module is translated once more with every function added to its
+    /// table: the slots that hold the table then contain the offsets. Each
+    /// added slot moves the code by the length of its initializer, which
+    /// is subtracted again.
+    pub fn new(m: &Module) -> SymbolMap {
+        if m.functions.len() == 0 {
+            return SymbolMap::default();
+        }
+
+        let mut probe = m.clone();
+        if probe.tables.len() == 0 {
+            probe.tables.push(Table {
+                min: 0,
+                max: None,
+                elements: Vec::new()
+            });
+        }
+        let first_slot = probe.tables[0].elements.len();
+        probe.tables[0].elements.extend((0..m.functions.len()).map(|i| Some(i as u32)));
+
+        let code = translate_module(&probe, 0, &mut AnyNativeInvoke);
+        let code = &code[4 + le_u32(&code) as usize..];
+        let shift = m.functions.len() * SLOT_INIT_LEN;
+
+        let mut functions: Vec<(usize, String)> = m.functions.iter()
+            .enumerate()
+            .map(|(i, f)| {
+                // The low 32 bits of the slot, after the `I64Const` opcode.
+                let init = RESET_SLOTS_LEN + (first_slot + i) * SLOT_INIT_LEN + 1;
+                let offset = le_u32(&code[init..]) as usize - shift;
+                let name = f.name.clone().unwrap_or_else(|| format!("func{}", i));
+                (offset, name)
+            })
+            .collect();
+        functions.sort_by_key(|v| v.0);
+
+        SymbolMap {
+            functions: functions,
+            code_len: code.len() - shift
+        }
+    }
+
+    /// Returns the name of the function that contains `offset` and the
+    /// offset relative to its start, or `None` for the startup code.
+    pub fn lookup(&self, offset: usize) -> Option<(&str, usize)> {
+        if offset >= self.code_len {
+            return None;
+        }
+
+        let i = match self.functions.binary_search_by_key(&offset, |v| v.0) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1
+        };
+        let (start, ref name) = self.functions[i];
+
+        Some((name.as_str(), offset - start))
+    }
+
+    /// Formats `offset` as `0x<offset> <function>+0x<offset in function>`.
+    pub fn describe(&self, offset: usize) -> String {
+        let mut out = format!("0x{:x}", offset);
+
+        if let Some((name, rel)) = self.lookup(offset) {
+            let _ = write!(out, " {}+0x{:x}", name, rel);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_core::module::{Function, FunctionBody, Type};
+    use wasm_core::opcode::Opcode;
+
+    fn function(name: &str, opcodes: Vec<Opcode>) -> Function {
+        Function {
+            name: Some(name.to_string()),
+            typeidx: 0,
+            locals: vec! [],
+            body: FunctionBody { opcodes: opcodes }
+        }
+    }
+
+    #[test]
+    fn find_function_offsets() {
+        let m = Module {
+            types: vec! [ Type::Func(vec! [], vec! []) ],
+            functions: vec! [
+                function("a", vec! [ Opcode::Nop, Opcode::Return ]),
+                function("b", vec! [ Opcode::Call(0), Opcode::Return ])
+            ],
+            data_segments: vec! [],
+            exports: Default::default(),
+            tables: vec! [
+                Table {
+                    min: 1,
+                    max: None,
+                    elements: vec! [ Some(1) ]
+                }
+            ],
+            globals: vec! [],
+            natives: vec! [],
+            start_function: None
+        };
+        let symbols = SymbolMap::new(&m);
+
+        // The table slot of the actual translation holds the offset of `b`.
+        let code = translate_module(&m, 0, &mut AnyNativeInvoke);
+        let code = &code[4 + le_u32(&code) as usize..];
+        let b = le_u32(&code[RESET_SLOTS_LEN + 1..]) as usize;
+
+        assert_eq!(symbols.lookup(b), Some(("b", 0)));
+        assert_eq!(symbols.lookup(b + 1), Some(("b", 1)));
+        assert_eq!(symbols.lookup(b - 1).map(|v| v.0), Some("a"));
+        assert_eq!(symbols.lookup(0), None);
+        assert_eq!(symbols.lookup(code.len()), None);
+    }
+}
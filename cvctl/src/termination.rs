@@ -0,0 +1,160 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// Names of the kernel's `BackendError` variants, indexed by discriminant - 1.
+static BACKEND_ERRORS: &'static [&'static str] = &[
+    "Generic",
+    "Bounds",
+    "InvalidNativeInvoke",
+    "NotFound",
+    "InvalidInput",
+    "FatalSignal",
+    "Unsupported",
+    "OutOfFuel",
+    "Timeout",
+    "CpuTimeout",
+    "ReplayMismatch",
+    "Exit",
+    "Checkpointed"
+];
+
+pub fn backend_error_name(code: i32) -> &'static str {
+    if code <= 0 {
+        return "Unknown";
+    }
+
+    BACKEND_ERRORS.get((code - 1) as usize).map(|v| *v).unwrap_or("Unknown")
+}
+
+/// Must match `backend::common::TerminationReport` in the kernel module.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct TerminationReport {
+    kind: i32,
+    code: i32,
+    has_value: i32,
+    value: i64
+}
+
+#[derive(Clone, Debug)]
+pub enum Termination {
+    /// The application returned from its entry or called `runtime_exit`.
+    Exited(i32),
+
+    /// The entry function returned this value.
+    Returned(i64),
+
+    /// The application was stopped because of an error in its code.
+    Trapped(&'static str),
+
+    /// The application ran out of fuel or time.
+    ResourceExhausted(&'static str),
+
+    /// The application was killed by a signal.
+    Killed,
+
+    /// The application was stopped after writing a checkpoint.
+    Checkpointed,
+
+    /// No report was received. Holds the exit status of the runner process.
+    Unreported(i32)
+}
+
+impl Termination {
+    fn from_report(report: TerminationReport) -> Termination {
+        match report.kind {
+            0 if report.has_value != 0 => Termination::Returned(report.value),
+            0 => Termination::Exited(report.code),
+            1 => Termination::Trapped(backend_error_name(report.code)),
+            2 => Termination::ResourceExhausted(backend_error_name(report.code)),
+            3 => Termination::Killed,
+            4 => Termination::Checkpointed,
+            _ => Termination::Trapped("Unknown")
+        }
+    }
+
+    /// The exit code a loader should exit with to pass this termination on.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Termination::Exited(code) => code,
+            Termination::Returned(_) => 0,
+            Termination::Trapped(_) => 134,
+            Termination::ResourceExhausted(_) => 152,
+            Termination::Killed => 137,
+            Termination::Checkpointed => 0,
+            Termination::Unreported(status) => status
+        }
+    }
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Termination::Exited(code) => write!(f, "exited with code {}", code),
+            Termination::Returned(value) => write!(f, "returned {}", value),
+            Termination::Trapped(reason) => write!(f, "trapped: {}", reason),
+            Termination::ResourceExhausted(reason) => write!(f, "ran out of resources: {}", reason),
+            Termination::Killed => write!(f, "was killed"),
+            Termination::Checkpointed => write!(f, "stopped after a checkpoint"),
+            Termination::Unreported(status) => write!(f, "failed to start (status {})", status)
+        }
+    }
+}
+
+/// A pipe through which the runtime reports how an application terminated.
+pub struct ReportPipe {
+    read: File,
+    write: Option<File>
+}
+
+impl ReportPipe {
+    pub fn new() -> io::Result<ReportPipe> {
+        let mut fds: [::libc::c_int; 2] = [0; 2];
+
+        // Not close-on-exec: the write end is passed on to the runner.
+        if unsafe { ::libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ReportPipe {
+            read: unsafe { File::from_raw_fd(fds[0]) },
+            write: Some(unsafe { File::from_raw_fd(fds[1]) })
+        })
+    }
+
+    pub fn write_fd(&self) -> RawFd {
+        self.write.as_ref().map(|v| v.as_raw_fd()).unwrap_or(-1)
+    }
+
+    /// Closes our copy of the write end so that reading stops once the runner exits.
+    pub fn close_write(&mut self) {
+        self.write = None;
+    }
+
+    /// Reads the report after the runner has exited with `status`.
+    pub fn finish(mut self, status: i32) -> Termination {
+        self.close_write();
+
+        let mut buf = [0u8; 24];
+        if self.read.read_exact(&mut buf).is_err() {
+            return Termination::Unreported(status);
+        }
+
+        let report: TerminationReport = unsafe { ::std::mem::transmute(buf) };
+        Termination::from_report(report)
+    }
+}
+
+/// Converts a status from `waitpid` into an exit code like a shell does.
+pub fn wait_status_to_exit_code(status: ::libc::c_int) -> i32 {
+    if ::libc::WIFEXITED(status) {
+        ::libc::WEXITSTATUS(status)
+    } else if ::libc::WIFSIGNALED(status) {
+        128 + ::libc::WTERMSIG(status)
+    } else {
+        1
+    }
+}
@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::thread::{self, JoinHandle};
+
+/// A pipe through which the runtime traces native invokes while the application runs.
+pub struct TracePipe {
+    read: Option<File>,
+    write: Option<File>
+}
+
+impl TracePipe {
+    pub fn new() -> io::Result<TracePipe> {
+        let mut fds: [::libc::c_int; 2] = [0; 2];
+
+        if unsafe { ::libc::pipe2(fds.as_mut_ptr(), ::libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let read = unsafe { File::from_raw_fd(fds[0]) };
+        let write = unsafe { File::from_raw_fd(fds[1]) };
+
+        // Only the write end is passed on to the runner. If the runner held the
+        // read end too, the trace would never be seen to have no reader.
+        ::files::set_inheritable(&write)?;
+
+        Ok(TracePipe {
+            read: Some(read),
+            write: Some(write)
+        })
+    }
+
+    pub fn write_fd(&self) -> RawFd {
+        self.write.as_ref().map(|v| v.as_raw_fd()).unwrap_or(-1)
+    }
+
+    /// Copies the trace to `out` as it is written, until the runner exits
+    /// and `close_write` has been called.
+    pub fn stream<W: Write + Send + 'static>(&mut self, mut out: W) -> JoinHandle<io::Result<u64>> {
+        let mut read = self.read.take().expect("Trace is already being streamed");
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut total: u64 = 0;
+
+            loop {
+                let n = match io::Read::read(&mut read, &mut buf) {
+                    Ok(0) => return Ok(total),
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e)
+                };
+
+                // Flushed for every chunk, so that the trace can be followed live.
+                out.write_all(&buf[..n])?;
+                out.flush()?;
+                total += n as u64;
+            }
+        })
+    }
+
+    /// Closes our copy of the write end so that streaming stops once the runner exits.
+    pub fn close_write(&mut self) {
+        self.write = None;
+    }
+}
@@ -7,8 +7,20 @@
 #include <linux/sched/signal.h>
 #include <linux/semaphore.h>
 #include <linux/uaccess.h>
+#include <linux/ktime.h>
+#include <linux/poll.h>
+#include <linux/wait.h>
+#include <linux/list.h>
+#include <linux/kthread.h>
+#include <linux/completion.h>
+#include <linux/sched/task.h>
+#include <linux/cred.h>
+#include <linux/fs_struct.h>
+#include <linux/vmalloc.h>
+#include <asm/cacheflush.h>
 
 #include "kctx.h"
+#include "cervus.h"
 
 // FIXME: Return -1 on SIGKILL only
 #define CHK_FATAL_SIGNAL() \
@@ -47,14 +59,53 @@ void lapi_kfree(unsigned char *ptr) {
     vfree((char *) ptr);
 }
 
+// Copies `len` bytes of machine code to executable memory, which is made
+// read-only once the code is in place. Returns NULL on failure.
+unsigned char * lapi_exec_map(const unsigned char *code, size_t len) {
+    int n_pages = DIV_ROUND_UP(len, PAGE_SIZE);
+    unsigned char *mem = __vmalloc(n_pages * PAGE_SIZE, GFP_KERNEL, PAGE_KERNEL_EXEC);
+
+    if(mem == NULL) {
+        return NULL;
+    }
+
+    memcpy(mem, code, len);
+    set_memory_ro((unsigned long) mem, n_pages);
+    return mem;
+}
+
+void lapi_exec_unmap(unsigned char *mem, size_t len) {
+    set_memory_rw((unsigned long) mem, DIV_ROUND_UP(len, PAGE_SIZE));
+    vfree(mem);
+}
+
 void lapi_bug(void) {
     panic("Cervus has panicked unexpectedly. This is a bug.\n");
 }
 
+// Pins the module providing an external native invoke or scheme. Returns 0
+// if it is being unloaded.
+int lapi_module_get(struct module *module) {
+    return try_module_get(module) ? 1 : 0;
+}
+
+void lapi_module_put(struct module *module) {
+    module_put(module);
+}
+
 unsigned long lapi_get_total_ram_bytes(void) {
     return totalram_pages * PAGE_SIZE;
 }
 
+unsigned long long lapi_get_monotonic_ns(void) {
+    return ktime_get_ns();
+}
+
+unsigned long long lapi_get_cpu_time_ns(void) {
+    // Updated by the scheduler, so this is only accurate to a tick.
+    return current -> se.sum_exec_runtime;
+}
+
 void lapi_oom_score_adj_current(short score) {
     unsigned long irq_flags;
 
@@ -130,6 +181,36 @@ struct file * lapi_env_get_stderr(void *raw_kctx) {
     return kctx -> stderr;
 }
 
+struct file * lapi_env_get_profile_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> profile;
+}
+
+struct file * lapi_env_get_core_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> core;
+}
+
+struct file * lapi_env_get_record_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> record;
+}
+
+struct file * lapi_env_get_replay_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> replay;
+}
+
+struct file * lapi_env_get_manifest_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> manifest;
+}
+
+struct file * lapi_env_get_restore_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> restore;
+}
+
 struct file * lapi_env_open_file(
     void *kctx,
     const char *name_base,
@@ -184,6 +265,23 @@ void lapi_env_close_file(struct file *file) {
     filp_close(file, 0);
 }
 
+// Writes the absolute path of `file`, with symlinks resolved, to `out`.
+// Returns its length, or a negative error if it doesn't fit.
+ssize_t lapi_env_get_file_path(struct file *file, char *out, size_t len) {
+    char *path;
+    size_t path_len;
+
+    path = d_path(&file -> f_path, out, len);
+    if(IS_ERR(path)) {
+        return PTR_ERR(path);
+    }
+
+    // d_path() builds the path at the end of the buffer.
+    path_len = strlen(path);
+    memmove(out, path, path_len);
+    return path_len;
+}
+
 ssize_t lapi_env_write_file(
     void *kctx,
     struct file *file,
@@ -245,12 +343,109 @@ int lapi_env_msleep(void *kctx, unsigned int ms) {
     return 0;
 }
 
+// Checked by the interpreter on every branch, so it must stay cheap.
+int lapi_env_need_resched(void) {
+    return need_resched() || fatal_signal_pending(current);
+}
+
+// Called periodically from the interpreter. Only gives up the CPU when the
+// scheduler has asked for it, so this is cheap on an idle system.
 int lapi_env_reschedule(void *kctx) {
-    schedule();
+    if(need_resched()) {
+        schedule();
+    }
     CHK_FATAL_SIGNAL();
     return 0;
 }
 
+// A thread running a spawned application instance.
+struct cervus_thread {
+    struct task_struct *task;
+    struct completion done;
+    void (*entry)(void *);
+    void *arg;
+
+    // Of the task that spawned the thread
+    const struct cred *cred;
+    struct fs_struct *fs;
+};
+
+static int cervus_thread_main(void *raw) {
+    struct cervus_thread *t = raw;
+    struct fs_struct *kthread_fs;
+    const struct cred *old_cred;
+
+    // Files are opened with the credentials, root and working directory of
+    // the spawning task, as if the instance ran there. The reference to `fs`
+    // is dropped by do_exit().
+    task_lock(current);
+    kthread_fs = current -> fs;
+    current -> fs = t -> fs;
+    task_unlock(current);
+
+    spin_lock(&kthread_fs -> lock);
+    kthread_fs -> users--;
+    spin_unlock(&kthread_fs -> lock);
+
+    old_cred = override_creds(t -> cred);
+
+    // Lets lapi_thread_kill() make blocking calls fail like a fatal signal
+    // does for the task that started the application.
+    allow_signal(SIGKILL);
+
+    t -> entry(t -> arg);
+
+    revert_creds(old_cred);
+    complete_and_exit(&t -> done, 0);
+}
+
+// Runs `entry(arg)` on a new kernel thread. Returns NULL on failure.
+struct cervus_thread * lapi_thread_spawn(void (*entry)(void *), void *arg) {
+    struct cervus_thread *t = kmalloc(sizeof(struct cervus_thread), GFP_KERNEL);
+    if(!t) return NULL;
+
+    init_completion(&t -> done);
+    t -> entry = entry;
+    t -> arg = arg;
+    t -> cred = get_current_cred();
+
+    // Shared like CLONE_FS does
+    t -> fs = current -> fs;
+    spin_lock(&t -> fs -> lock);
+    t -> fs -> users++;
+    spin_unlock(&t -> fs -> lock);
+
+    t -> task = kthread_create(cervus_thread_main, t, "cervus/%d", task_pid_nr(current));
+    if(IS_ERR(t -> task)) {
+        // Can't be the last reference, since the spawning task holds one.
+        spin_lock(&t -> fs -> lock);
+        t -> fs -> users--;
+        spin_unlock(&t -> fs -> lock);
+
+        put_cred(t -> cred);
+        kfree(t);
+        return NULL;
+    }
+
+    // Kept until the thread is joined, so that it can be killed after it exited.
+    get_task_struct(t -> task);
+    wake_up_process(t -> task);
+
+    return t;
+}
+
+void lapi_thread_kill(struct cervus_thread *t) {
+    send_sig(SIGKILL, t -> task, 1);
+}
+
+// Waits for the thread to return from `entry` and frees it.
+void lapi_thread_join(struct cervus_thread *t) {
+    wait_for_completion(&t -> done);
+    put_task_struct(t -> task);
+    put_cred(t -> cred);
+    kfree(t);
+}
+
 struct semaphore * lapi_semaphore_new(void) {
     struct semaphore *sem = kmalloc(sizeof(struct semaphore), GFP_KERNEL);
     if(!sem) return NULL;
@@ -281,6 +476,136 @@ int lapi_semaphore_down(struct semaphore *sem) {
     return 0;
 }
 
+wait_queue_head_t * lapi_wait_queue_new(void) {
+    wait_queue_head_t *wq = kmalloc(sizeof(wait_queue_head_t), GFP_KERNEL);
+    if(!wq) return NULL;
+
+    init_waitqueue_head(wq);
+    return wq;
+}
+
+void lapi_wait_queue_destroy(wait_queue_head_t *wq) {
+    kfree(wq);
+}
+
+void lapi_wait_queue_wake(wait_queue_head_t *wq) {
+    wake_up_all(wq);
+}
+
+struct cervus_poll;
+
+struct cervus_poll_entry {
+    struct list_head list;
+    wait_queue_head_t *head;
+    wait_queue_entry_t wait;
+    struct cervus_poll *poll;
+};
+
+// Like `struct poll_wqueues` of select(2), but also usable with wait queues
+// that don't belong to a file.
+struct cervus_poll {
+    poll_table pt;
+    struct task_struct *task;
+    struct list_head entries;
+    int triggered;
+};
+
+static int cervus_poll_wake(wait_queue_entry_t *wait, unsigned mode, int sync, void *key) {
+    struct cervus_poll_entry *entry = container_of(wait, struct cervus_poll_entry, wait);
+
+    WRITE_ONCE(entry -> poll -> triggered, 1);
+    return wake_up_process(entry -> poll -> task);
+}
+
+static void cervus_poll_add(struct cervus_poll *poll, wait_queue_head_t *head) {
+    struct cervus_poll_entry *entry = kmalloc(sizeof(struct cervus_poll_entry), GFP_KERNEL);
+
+    // Nothing would wake us up, so don't sleep at all.
+    if(!entry) {
+        poll -> triggered = 1;
+        return;
+    }
+
+    entry -> head = head;
+    entry -> poll = poll;
+    init_waitqueue_func_entry(&entry -> wait, cervus_poll_wake);
+    add_wait_queue(head, &entry -> wait);
+    list_add_tail(&entry -> list, &poll -> entries);
+}
+
+static void cervus_poll_queue_proc(struct file *file, wait_queue_head_t *head, poll_table *pt) {
+    cervus_poll_add(container_of(pt, struct cervus_poll, pt), head);
+}
+
+struct cervus_poll * lapi_poll_new(void) {
+    struct cervus_poll *poll = kmalloc(sizeof(struct cervus_poll), GFP_KERNEL);
+    if(!poll) return NULL;
+
+    init_poll_funcptr(&poll -> pt, cervus_poll_queue_proc);
+    poll -> task = current;
+    INIT_LIST_HEAD(&poll -> entries);
+    poll -> triggered = 0;
+
+    return poll;
+}
+
+void lapi_poll_destroy(struct cervus_poll *poll) {
+    struct cervus_poll_entry *entry, *tmp;
+
+    list_for_each_entry_safe(entry, tmp, &poll -> entries, list) {
+        remove_wait_queue(entry -> head, &entry -> wait);
+        kfree(entry);
+    }
+    kfree(poll);
+}
+
+unsigned int lapi_poll_file(struct cervus_poll *poll, struct file *file, unsigned int interest) {
+    unsigned int mask, ready = 0;
+
+    // Files without a poll method never block.
+    if(!file -> f_op -> poll) {
+        return interest;
+    }
+
+    mask = file -> f_op -> poll(file, &poll -> pt);
+
+    if(mask & (POLLIN | POLLRDNORM | POLLHUP)) ready |= CERVUS_POLL_READ;
+    if(mask & (POLLOUT | POLLWRNORM)) ready |= CERVUS_POLL_WRITE;
+
+    return (ready & interest) | (mask & POLLERR ? CERVUS_POLL_ERROR : 0);
+}
+
+void lapi_poll_wait_queue(struct cervus_poll *poll, wait_queue_head_t *wq) {
+    if(poll -> pt._qproc) {
+        cervus_poll_add(poll, wq);
+    }
+}
+
+// Sleeps until a registered wait queue is woken up or `timeout_ms` passes,
+// forever if it is negative. Stops registering further wait queues.
+int lapi_poll_wait(struct cervus_poll *poll, long long timeout_ms) {
+    long timeout;
+
+    if(timeout_ms < 0 || timeout_ms > INT_MAX) {
+        timeout = MAX_SCHEDULE_TIMEOUT;
+    } else {
+        timeout = msecs_to_jiffies((unsigned int) timeout_ms);
+    }
+
+    poll -> pt._qproc = NULL;
+
+    CHK_FATAL_SIGNAL();
+    set_current_state(TASK_INTERRUPTIBLE);
+    if(!READ_ONCE(poll -> triggered)) {
+        schedule_timeout(timeout);
+    }
+    __set_current_state(TASK_RUNNING);
+    poll -> triggered = 0;
+    CHK_FATAL_SIGNAL();
+
+    return 0;
+}
+
 int __init init_module(void) {
     int ret;
 
@@ -0,0 +1,90 @@
+#ifndef _CERVUS_H_
+#define _CERVUS_H_
+
+// Interface for other kernel modules to extend cervus with native invokes
+// and URL schemes.
+//
+// Callbacks may be called from any task running an application, in process
+// context. A provider stays loaded while one of its callbacks is running or
+// one of its resources is open, so `priv` and the structures passed to the
+// register functions must stay valid until the module is unloaded.
+
+#include <linux/module.h>
+#include <linux/wait.h>
+
+// Statuses returned to applications, same as `CwaError`
+#define CERVUS_ERR_UNKNOWN -1
+#define CERVUS_ERR_INVALID_ARGUMENT -2
+#define CERVUS_ERR_PERMISSION_DENIED -3
+#define CERVUS_ERR_NOT_FOUND -4
+#define CERVUS_ERR_WOULD_BLOCK -5
+#define CERVUS_ERR_TIMED_OUT -6
+
+// Readiness flags, same as `resource::POLL_*`
+#define CERVUS_POLL_READ 1
+#define CERVUS_POLL_WRITE 2
+#define CERVUS_POLL_ERROR 4
+
+struct cervus_poll;
+
+struct cervus_native_invoke {
+    const char *name;
+    unsigned int n_args;
+
+    // Indices of the arguments holding the base and length of the linear
+    // memory region the call writes to, or -1. Needed to record and replay it.
+    int output_base_arg;
+    int output_len_arg;
+
+    // `args` holds `n_args` values. `mem` is the linear memory of the
+    // application, which the call has to bounds-check accesses to.
+    // Returns the value passed back to the application.
+    long long (*call)(void *priv, const long long *args, unsigned char *mem, size_t mem_len);
+
+    void *priv;
+    struct module *owner;
+};
+
+struct cervus_resource_ops {
+    // Return the number of bytes transferred or a negative `CERVUS_ERR_*`.
+    long long (*read)(void *res, unsigned char *out, size_t len);
+    long long (*write)(void *res, const unsigned char *data, size_t len);
+
+    // Optional. Returns the ready `CERVUS_POLL_*` flags among `interest`, after
+    // registering the wait queues to be woken up on with `cervus_poll_wait_queue`.
+    unsigned int (*poll)(void *res, struct cervus_poll *poll, unsigned int interest);
+
+    // Called when the application closes the resource or terminates.
+    void (*release)(void *res);
+};
+
+struct cervus_scheme {
+    // Handles URLs starting with `<name>://`
+    const char *name;
+
+    // `path` and `args` are the parts of the URL before and after `?`.
+    // Returns the resource, or NULL after setting `*err` to a `CERVUS_ERR_*`.
+    void * (*open)(void *priv, const char *path, size_t path_len, const char *args, size_t args_len, int *err);
+
+    const struct cervus_resource_ops *ops;
+    void *priv;
+    struct module *owner;
+};
+
+// Returns the id of the native invoke, or -EEXIST if the name is taken.
+// Applications refer to it by name like to the built-in ones.
+int cervus_register_native_invoke(const struct cervus_native_invoke *ni);
+
+// Applications calling the native invoke afterwards fail with `InvalidNativeInvoke`.
+// Ids are not reused.
+int cervus_unregister_native_invoke(int id);
+
+// Returns -EEXIST if the name is taken, including by the built-in schemes.
+int cervus_register_scheme(const struct cervus_scheme *scheme);
+
+// Resources that are already open stay usable until they are closed.
+int cervus_unregister_scheme(const char *name);
+
+void cervus_poll_wait_queue(struct cervus_poll *poll, wait_queue_head_t *wq);
+
+#endif
@@ -0,0 +1,227 @@
+#include <linux/module.h>
+#include <linux/slab.h>
+#include <linux/sched.h>
+#include <linux/spinlock.h>
+#include <linux/list.h>
+#include <linux/kref.h>
+#include <linux/completion.h>
+#include <linux/cred.h>
+#include <linux/capability.h>
+#include <linux/fs.h>
+#include <linux/file.h>
+
+#include "kctx.h"
+#include "checkpoint.h"
+
+// Applications started through the UAPI. An entry stays valid while
+// `running_apps_lock` is held.
+static LIST_HEAD(running_apps);
+static DEFINE_SPINLOCK(running_apps_lock);
+
+// Shared by the requester and the application, which may exit before the
+// request is taken.
+struct checkpoint_request {
+    struct kref ref;
+    struct completion done;
+    struct file *file;
+    unsigned int flags;
+
+    // Valid once `done` is completed
+    int ret;
+    long long resource;
+    char kind[CHECKPOINT_KIND_LEN];
+};
+
+static void release_request(struct kref *ref) {
+    struct checkpoint_request *req = container_of(ref, struct checkpoint_request, ref);
+
+    fput(req -> file);
+    kfree(req);
+}
+
+static void complete_request(struct checkpoint_request *req, int ret) {
+    req -> ret = ret;
+    complete(&req -> done);
+    kref_put(&req -> ref, release_request);
+}
+
+void cv_checkpoint_register(struct kernel_context *kctx) {
+    spin_lock_init(&kctx -> checkpoint_lock);
+    kctx -> checkpoint_pending = NULL;
+    kctx -> checkpoint_active = NULL;
+    kctx -> task = current;
+
+    spin_lock(&running_apps_lock);
+    list_add(&kctx -> checkpoint_entry, &running_apps);
+    spin_unlock(&running_apps_lock);
+}
+
+void cv_checkpoint_unregister(struct kernel_context *kctx) {
+    struct checkpoint_request *pending, *active;
+
+    spin_lock(&running_apps_lock);
+    list_del(&kctx -> checkpoint_entry);
+    spin_lock(&kctx -> checkpoint_lock);
+    pending = kctx -> checkpoint_pending;
+    active = kctx -> checkpoint_active;
+    kctx -> checkpoint_pending = NULL;
+    kctx -> checkpoint_active = NULL;
+    spin_unlock(&kctx -> checkpoint_lock);
+    spin_unlock(&running_apps_lock);
+
+    if(pending) {
+        complete_request(pending, -ESRCH);
+    }
+
+    // Terminated while writing the checkpoint
+    if(active) {
+        complete_request(active, -EIO);
+    }
+}
+
+
+// Withdraws `req` if the application hasn't taken it yet.
+static void withdraw_request(struct checkpoint_request *req) {
+    struct kernel_context *kctx;
+    bool withdrawn = false;
+
+    spin_lock(&running_apps_lock);
+    list_for_each_entry(kctx, &running_apps, checkpoint_entry) {
+        spin_lock(&kctx -> checkpoint_lock);
+        if(kctx -> checkpoint_pending == req) {
+            kctx -> checkpoint_pending = NULL;
+            withdrawn = true;
+        }
+        spin_unlock(&kctx -> checkpoint_lock);
+    }
+    spin_unlock(&running_apps_lock);
+
+    if(withdrawn) {
+        kref_put(&req -> ref, release_request);
+    }
+}
+
+int cv_checkpoint_request(
+    pid_t pid,
+    struct file *file,
+    unsigned int flags,
+    long long *resource,
+    char *kind
+) {
+    struct checkpoint_request *req;
+    struct kernel_context *kctx;
+    int ret = -ESRCH;
+
+    // Only the owner of the application may read its state. The capability is
+    // checked up front, as it can't be while holding the lock.
+    uid_t euid = current_cred() -> euid.val;
+    bool admin = has_capability_noaudit(current, CAP_SYS_ADMIN);
+
+    req = kzalloc(sizeof(*req), GFP_KERNEL);
+    if(!req) {
+        return -ENOMEM;
+    }
+
+    kref_init(&req -> ref);
+    init_completion(&req -> done);
+    req -> file = get_file(file);
+    req -> flags = flags;
+    req -> resource = -1;
+
+    spin_lock(&running_apps_lock);
+    list_for_each_entry(kctx, &running_apps, checkpoint_entry) {
+        if(task_pid_vnr(kctx -> task) != pid) {
+            continue;
+        }
+
+        if(kctx -> euid != euid && !admin) {
+            ret = -EPERM;
+            break;
+        }
+
+        spin_lock(&kctx -> checkpoint_lock);
+        if(kctx -> checkpoint_pending || kctx -> checkpoint_active) {
+            ret = -EBUSY;
+        } else {
+            // Reference of the application
+            kref_get(&req -> ref);
+            kctx -> checkpoint_pending = req;
+            ret = 0;
+        }
+        spin_unlock(&kctx -> checkpoint_lock);
+        break;
+    }
+    spin_unlock(&running_apps_lock);
+
+    if(ret == 0) {
+        if(wait_for_completion_interruptible(&req -> done) < 0) {
+            withdraw_request(req);
+            ret = -EINTR;
+        } else {
+            ret = req -> ret;
+            *resource = req -> resource;
+            memcpy(kind, req -> kind, CHECKPOINT_KIND_LEN);
+        }
+    }
+
+    kref_put(&req -> ref, release_request);
+    return ret;
+}
+
+int lapi_env_checkpoint_requested(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return READ_ONCE(kctx -> checkpoint_pending) != NULL;
+}
+
+struct file * lapi_env_take_checkpoint_request(void *raw_kctx, unsigned int *flags) {
+    struct kernel_context *kctx = raw_kctx;
+    struct checkpoint_request *req;
+
+    spin_lock(&kctx -> checkpoint_lock);
+    req = kctx -> checkpoint_pending;
+    kctx -> checkpoint_pending = NULL;
+    kctx -> checkpoint_active = req;
+    spin_unlock(&kctx -> checkpoint_lock);
+
+    if(!req) {
+        return NULL;
+    }
+
+    *flags = req -> flags;
+    return req -> file;
+}
+
+void lapi_env_finish_checkpoint(
+    void *raw_kctx,
+    int status,
+    long long resource,
+    const char *kind_base,
+    size_t kind_len
+) {
+    struct kernel_context *kctx = raw_kctx;
+    struct checkpoint_request *req = kctx -> checkpoint_active;
+
+    if(!req) {
+        return;
+    }
+
+    spin_lock(&kctx -> checkpoint_lock);
+    kctx -> checkpoint_active = NULL;
+    spin_unlock(&kctx -> checkpoint_lock);
+
+    req -> resource = resource;
+    if(kind_base) {
+        memcpy(req -> kind, kind_base, min_t(size_t, kind_len, CHECKPOINT_KIND_LEN - 1));
+    }
+
+    switch(status) {
+        case CHECKPOINT_OK:
+            complete_request(req, 0);
+            break;
+        case CHECKPOINT_UNSUPPORTED:
+            complete_request(req, -EOPNOTSUPP);
+            break;
+        default:
+            complete_request(req, -EIO);
+    }
+}
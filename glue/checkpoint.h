@@ -0,0 +1,34 @@
+#ifndef _CV_CHECKPOINT_H_
+#define _CV_CHECKPOINT_H_
+
+#include "kctx.h"
+
+// Stops the application once the checkpoint is written
+#define CHECKPOINT_STOP 1
+
+// Statuses passed to lapi_env_finish_checkpoint, same as `checkpoint::STATUS_*`
+#define CHECKPOINT_OK 0
+#define CHECKPOINT_IO_ERROR -1
+#define CHECKPOINT_UNSUPPORTED -2
+
+#define CHECKPOINT_KIND_LEN 32
+
+// Makes the application running with `kctx` in the current task available
+// to checkpoint requests until it is unregistered.
+void cv_checkpoint_register(struct kernel_context *kctx);
+void cv_checkpoint_unregister(struct kernel_context *kctx);
+
+// Asks the application running in task `pid` to write a checkpoint to `file`
+// at its next branch and waits until it is written.
+//
+// Returns 0 or a negative errno. On -EOPNOTSUPP, `resource` and `kind`
+// receive the id and kind of a resource that can't be checkpointed.
+int cv_checkpoint_request(
+    pid_t pid,
+    struct file *file,
+    unsigned int flags,
+    long long *resource,
+    char *kind
+);
+
+#endif
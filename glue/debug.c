@@ -0,0 +1,284 @@
+#include <linux/module.h>
+#include <linux/slab.h>
+#include <linux/vmalloc.h>
+#include <linux/sched.h>
+#include <linux/spinlock.h>
+#include <linux/list.h>
+#include <linux/kref.h>
+#include <linux/completion.h>
+#include <linux/cred.h>
+#include <linux/capability.h>
+#include <linux/wait.h>
+#include <linux/atomic.h>
+#include <linux/uaccess.h>
+
+#include "kctx.h"
+#include "debug.h"
+
+// Applications running in debug mode. An entry stays valid while
+// `debugged_apps_lock` is held.
+static LIST_HEAD(debugged_apps);
+static DEFINE_SPINLOCK(debugged_apps_lock);
+
+// Shared by the requester and the application, which may exit before the
+// request is served.
+struct debug_request {
+    struct kref ref;
+    struct completion done;
+    struct debug_request_args args;
+
+    // Receives up to `args.len` bytes for DEBUG_CMD_READ, or NULL
+    unsigned char *data;
+
+    // Valid once `done` is completed
+    int ret;
+    struct debug_stop stop;
+};
+
+static void release_request(struct kref *ref) {
+    struct debug_request *req = container_of(ref, struct debug_request, ref);
+
+    vfree(req -> data);
+    kfree(req);
+}
+
+static void complete_request(struct debug_request *req, int ret) {
+    req -> ret = ret;
+    complete(&req -> done);
+    kref_put(&req -> ref, release_request);
+}
+
+// Whether the request is only served when the application stops again.
+static bool waits_for_stop(struct debug_request *req) {
+    return req -> args.cmd == DEBUG_CMD_CONTINUE || req -> args.cmd == DEBUG_CMD_STEP;
+}
+
+void cv_debug_register(struct kernel_context *kctx) {
+    spin_lock_init(&kctx -> debug_lock);
+    init_waitqueue_head(&kctx -> debug_wait);
+    atomic_set(&kctx -> debug_interrupt, 0);
+    kctx -> debug_pending = NULL;
+    kctx -> debug_active = NULL;
+
+    spin_lock(&debugged_apps_lock);
+    list_add(&kctx -> debug_entry, &debugged_apps);
+    spin_unlock(&debugged_apps_lock);
+}
+
+void cv_debug_unregister(struct kernel_context *kctx, int status) {
+    struct debug_request *pending, *active;
+
+    spin_lock(&debugged_apps_lock);
+    list_del(&kctx -> debug_entry);
+    spin_lock(&kctx -> debug_lock);
+    pending = kctx -> debug_pending;
+    active = kctx -> debug_active;
+    kctx -> debug_pending = NULL;
+    kctx -> debug_active = NULL;
+    spin_unlock(&kctx -> debug_lock);
+    spin_unlock(&debugged_apps_lock);
+
+    if(pending) {
+        complete_request(pending, -ESRCH);
+    }
+
+    // A continue or step ends with the exit of the application.
+    if(active) {
+        memset(&active -> stop, 0, sizeof(active -> stop));
+        active -> stop.reason = DEBUG_STOP_EXITED;
+        active -> stop.ip = status;
+        complete_request(active, waits_for_stop(active) ? 0 : -ESRCH);
+    }
+}
+
+// Withdraws `req` if the application hasn't taken it yet.
+static void withdraw_request(struct debug_request *req) {
+    struct kernel_context *kctx;
+    bool withdrawn = false;
+
+    spin_lock(&debugged_apps_lock);
+    list_for_each_entry(kctx, &debugged_apps, debug_entry) {
+        spin_lock(&kctx -> debug_lock);
+        if(kctx -> debug_pending == req) {
+            kctx -> debug_pending = NULL;
+            withdrawn = true;
+        }
+        spin_unlock(&kctx -> debug_lock);
+    }
+    spin_unlock(&debugged_apps_lock);
+
+    if(withdrawn) {
+        kref_put(&req -> ref, release_request);
+    }
+}
+
+int cv_debug_request(
+    pid_t pid,
+    const struct debug_request_args *args,
+    void __user *buf,
+    struct debug_stop *stop
+) {
+    struct debug_request *req;
+    struct kernel_context *kctx;
+    bool queued = false;
+    int ret = -ESRCH;
+
+    // Only the owner of the application may debug it. The capability is
+    // checked up front, as it can't be while holding the lock.
+    uid_t euid = current_cred() -> euid.val;
+    bool admin = has_capability_noaudit(current, CAP_SYS_ADMIN);
+
+    req = kzalloc(sizeof(*req), GFP_KERNEL);
+    if(!req) {
+        return -ENOMEM;
+    }
+
+    kref_init(&req -> ref);
+    init_completion(&req -> done);
+    req -> args = *args;
+
+    if(args -> cmd == DEBUG_CMD_READ && args -> len > 0) {
+        req -> data = vmalloc(args -> len);
+        if(!req -> data) {
+            kfree(req);
+            return -ENOMEM;
+        }
+    }
+
+    spin_lock(&debugged_apps_lock);
+    list_for_each_entry(kctx, &debugged_apps, debug_entry) {
+        if(task_pid_vnr(kctx -> task) != pid) {
+            continue;
+        }
+
+        if(kctx -> euid != euid && !admin) {
+            ret = -EPERM;
+            break;
+        }
+
+        // The application checks for interrupts before every opcode, even
+        // while a continue is waiting for it to stop.
+        if(args -> cmd == DEBUG_CMD_INTERRUPT) {
+            atomic_set(&kctx -> debug_interrupt, 1);
+            wake_up(&kctx -> debug_wait);
+            ret = 0;
+            break;
+        }
+
+        spin_lock(&kctx -> debug_lock);
+        if(kctx -> debug_pending || kctx -> debug_active) {
+            ret = -EBUSY;
+        } else {
+            // Reference of the application
+            kref_get(&req -> ref);
+            kctx -> debug_pending = req;
+            queued = true;
+            ret = 0;
+        }
+        spin_unlock(&kctx -> debug_lock);
+
+        if(queued) {
+            wake_up(&kctx -> debug_wait);
+        }
+        break;
+    }
+    spin_unlock(&debugged_apps_lock);
+
+    if(queued) {
+        if(wait_for_completion_interruptible(&req -> done) < 0) {
+            withdraw_request(req);
+            ret = -EINTR;
+        } else {
+            ret = req -> ret;
+            *stop = req -> stop;
+            if(ret > 0 && copy_to_user(buf, req -> data, ret)) {
+                ret = -EFAULT;
+            }
+        }
+    }
+
+    kref_put(&req -> ref, release_request);
+    return ret;
+}
+
+int lapi_env_debug_enabled(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> debug;
+}
+
+int lapi_env_debug_pending(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return READ_ONCE(kctx -> debug_pending) != NULL || atomic_read(&kctx -> debug_interrupt);
+}
+
+// Takes the next request, waiting for one if `wait` is set. An interrupt is
+// taken as a DEBUG_CMD_INTERRUPT request, which doesn't need to be finished.
+//
+// Returns 1 if a request was taken, 0 if there is none, or -EINTR if the
+// application was killed while waiting.
+int lapi_env_take_debug_request(void *raw_kctx, int wait, struct debug_request_args *out) {
+    struct kernel_context *kctx = raw_kctx;
+    struct debug_request *req;
+
+    if(wait && wait_event_killable(kctx -> debug_wait, lapi_env_debug_pending(kctx))) {
+        return -EINTR;
+    }
+
+    if(atomic_xchg(&kctx -> debug_interrupt, 0)) {
+        memset(out, 0, sizeof(*out));
+        out -> cmd = DEBUG_CMD_INTERRUPT;
+        return 1;
+    }
+
+    spin_lock(&kctx -> debug_lock);
+    req = kctx -> debug_pending;
+    if(req) {
+        kctx -> debug_pending = NULL;
+        kctx -> debug_active = req;
+    }
+    spin_unlock(&kctx -> debug_lock);
+
+    if(!req) {
+        return 0;
+    }
+
+    *out = req -> args;
+    return 1;
+}
+
+// Completes the request taken last. `stop` may be NULL if the request was
+// invalid, and `data` is what a DEBUG_CMD_READ read.
+void lapi_env_finish_debug_request(
+    void *raw_kctx,
+    int status,
+    const struct debug_stop *stop,
+    const unsigned char *data_base,
+    size_t data_len
+) {
+    struct kernel_context *kctx = raw_kctx;
+    struct debug_request *req;
+
+    spin_lock(&kctx -> debug_lock);
+    req = kctx -> debug_active;
+    kctx -> debug_active = NULL;
+    spin_unlock(&kctx -> debug_lock);
+
+    if(!req) {
+        return;
+    }
+
+    if(stop) {
+        req -> stop = *stop;
+    }
+
+    if(status == DEBUG_STATUS_INVALID) {
+        complete_request(req, -EINVAL);
+        return;
+    }
+
+    data_len = min_t(size_t, data_len, req -> data ? req -> args.len : 0);
+    if(data_len > 0) {
+        memcpy(req -> data, data_base, data_len);
+    }
+    complete_request(req, data_len);
+}
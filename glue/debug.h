@@ -0,0 +1,79 @@
+#ifndef _CV_DEBUG_H_
+#define _CV_DEBUG_H_
+
+#include "kctx.h"
+
+// Commands of CERVUS_DEBUG, same as `debug::CMD_*`
+#define DEBUG_CMD_ATTACH 1
+#define DEBUG_CMD_DETACH 2
+#define DEBUG_CMD_CONTINUE 3
+#define DEBUG_CMD_STEP 4
+#define DEBUG_CMD_INTERRUPT 5
+#define DEBUG_CMD_SET_BREAKPOINT 6
+#define DEBUG_CMD_CLEAR_BREAKPOINT 7
+#define DEBUG_CMD_READ 8
+
+// What DEBUG_CMD_READ reads from, same as `debug::AREA_*`
+#define DEBUG_AREA_MEMORY 0
+#define DEBUG_AREA_SLOTS 1
+#define DEBUG_AREA_STACK 2
+#define DEBUG_AREA_CALL_STACK 3
+#define DEBUG_AREA_CODE 4
+
+// Why the application stopped, same as `backend::common::StopReason`
+#define DEBUG_STOP_STARTED 1
+#define DEBUG_STOP_ATTACHED 2
+#define DEBUG_STOP_BREAKPOINT 3
+#define DEBUG_STOP_STEP 4
+#define DEBUG_STOP_INTERRUPTED 5
+#define DEBUG_STOP_EXITED 6
+
+// Statuses passed to lapi_env_finish_debug_request, same as `debug::STATUS_*`
+#define DEBUG_STATUS_OK 0
+#define DEBUG_STATUS_INVALID -1
+
+#define DEBUG_MAX_READ_LEN 65536
+
+// Where a stopped application is. Must match `backend::common::DebugStop`.
+struct debug_stop {
+    long long reason;
+
+    // Offset of the next opcode in the code, or the exit status if exited
+    long long ip;
+
+    // Numbers of values on the stacks and in the slots, and sizes in bytes
+    long long stack_len;
+    long long call_stack_len;
+    long long n_slots;
+    long long memory_len;
+    long long code_len;
+};
+
+// A request as taken by the application. Must match `debug::RawRequest`.
+struct debug_request_args {
+    unsigned int cmd;
+    unsigned int area;
+    unsigned long long arg;
+    unsigned long long len;
+};
+
+// Makes the application running with `kctx` in the current task available
+// to debugger requests until it is unregistered. `status` is its exit status.
+void cv_debug_register(struct kernel_context *kctx);
+void cv_debug_unregister(struct kernel_context *kctx, int status);
+
+// Passes a request to the application running in debug mode in task `pid`
+// and waits until it is served. DEBUG_CMD_CONTINUE and DEBUG_CMD_STEP are
+// served when the application stops again, and DEBUG_CMD_INTERRUPT returns
+// right away.
+//
+// Returns the number of bytes read to `buf` or a negative errno. `stop`
+// receives where the application is.
+int cv_debug_request(
+    pid_t pid,
+    const struct debug_request_args *args,
+    void __user *buf,
+    struct debug_stop *stop
+);
+
+#endif
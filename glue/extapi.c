@@ -0,0 +1,61 @@
+// Exported interface for other kernel modules, declared in `cervus.h`.
+
+#include <linux/module.h>
+#include <linux/string.h>
+#include <linux/errno.h>
+
+#include "cervus.h"
+
+// Implemented in `api::external` and `schemes::external`
+extern int cervus_ext_register_native_invoke(const struct cervus_native_invoke *ni, size_t name_len);
+extern int cervus_ext_unregister_native_invoke(int id);
+extern int cervus_ext_register_scheme(const struct cervus_scheme *scheme, size_t name_len);
+extern int cervus_ext_unregister_scheme(const char *name, size_t name_len);
+
+extern void lapi_poll_wait_queue(struct cervus_poll *poll, wait_queue_head_t *wq);
+
+static int ext_errno(int ret) {
+    switch(ret) {
+        case -1: return -EEXIST;
+        case -2: return -EINVAL;
+        case -3: return -EINTR;
+        default: return ret;
+    }
+}
+
+int cervus_register_native_invoke(const struct cervus_native_invoke *ni) {
+    if(!ni || !ni -> name) {
+        return -EINVAL;
+    }
+
+    return ext_errno(cervus_ext_register_native_invoke(ni, strlen(ni -> name)));
+}
+EXPORT_SYMBOL_GPL(cervus_register_native_invoke);
+
+int cervus_unregister_native_invoke(int id) {
+    return ext_errno(cervus_ext_unregister_native_invoke(id));
+}
+EXPORT_SYMBOL_GPL(cervus_unregister_native_invoke);
+
+int cervus_register_scheme(const struct cervus_scheme *scheme) {
+    if(!scheme || !scheme -> name) {
+        return -EINVAL;
+    }
+
+    return ext_errno(cervus_ext_register_scheme(scheme, strlen(scheme -> name)));
+}
+EXPORT_SYMBOL_GPL(cervus_register_scheme);
+
+int cervus_unregister_scheme(const char *name) {
+    if(!name) {
+        return -EINVAL;
+    }
+
+    return ext_errno(cervus_ext_unregister_scheme(name, strlen(name)));
+}
+EXPORT_SYMBOL_GPL(cervus_unregister_scheme);
+
+void cervus_poll_wait_queue(struct cervus_poll *poll, wait_queue_head_t *wq) {
+    lapi_poll_wait_queue(poll, wq);
+}
+EXPORT_SYMBOL_GPL(cervus_poll_wait_queue);
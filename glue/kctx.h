@@ -2,21 +2,71 @@
 #define _KCTX_H_
 
 #include <linux/fs.h>
+#include <linux/spinlock.h>
+#include <linux/list.h>
+#include <linux/sched.h>
+#include <linux/wait.h>
+#include <linux/atomic.h>
 
 #define MAX_N_ARGS 256
 #define MAX_ARG_LEN 1024
+#define MAX_N_ENTRY_ARGS 64
 
 struct kernel_string {
     unsigned long len;
     char *data;
 };
 
+struct checkpoint_request;
+struct debug_request;
+struct cv_trace;
+
 struct kernel_context {
     uid_t euid;
     struct file *stdin;
     struct file *stdout;
     struct file *stderr;
 
+    // Receives the profile if profiling is enabled. May be NULL.
+    struct file *profile;
+
+    // Receives a core dump if the application traps. May be NULL.
+    struct file *core;
+
+    // Native invoke results are logged to `record`, or taken from `replay`
+    // instead of calling them. At most one of them is set.
+    struct file *record;
+    struct file *replay;
+
+    // Lists the native invokes and URLs the application may use. If NULL,
+    // everything is allowed.
+    struct file *manifest;
+
+    // Receives a line for every native invoke, if not NULL. See trace.c.
+    struct cv_trace *trace;
+
+    // Checkpoint to continue from instead of starting the code, or NULL
+    struct file *restore;
+
+    // Checkpoint requests of other processes, see checkpoint.c. `pending`
+    // waits for the next branch and `active` is being written.
+    spinlock_t checkpoint_lock;
+    struct checkpoint_request *checkpoint_pending;
+    struct checkpoint_request *checkpoint_active;
+    struct list_head checkpoint_entry;
+    struct task_struct *task;
+
+    // Debugger requests, see debug.c. Only set up if `debug` is set, which
+    // makes the application wait for a debugger before it starts. The
+    // application waits on `debug_wait` while it is stopped.
+    int debug;
+    spinlock_t debug_lock;
+    wait_queue_head_t debug_wait;
+    atomic_t debug_interrupt;
+    struct debug_request *debug_pending;
+    struct debug_request *debug_active;
+    struct list_head debug_entry;
+
     int n_args;
     struct kernel_string *args;
 };
@@ -0,0 +1,203 @@
+#include <linux/module.h>
+#include <linux/slab.h>
+#include <linux/vmalloc.h>
+#include <linux/sched.h>
+#include <linux/sched/signal.h>
+#include <linux/sched/task.h>
+#include <linux/spinlock.h>
+#include <linux/wait.h>
+#include <linux/completion.h>
+#include <linux/kthread.h>
+#include <linux/fs.h>
+#include <linux/file.h>
+
+#include "kctx.h"
+#include "trace.h"
+
+// Output that doesn't fit is dropped, and the tracer reports how much.
+#define TRACE_RING_LEN 65536
+#define TRACE_CHUNK_LEN 4096
+
+// How long the writer may take for the rest of the output once the
+// application exits, before a write the reader never takes is interrupted
+#define TRACE_DRAIN_TIMEOUT (HZ)
+
+struct cv_trace {
+    struct file *file;
+    struct task_struct *writer;
+    struct completion done;
+    wait_queue_head_t wait;
+
+    spinlock_t lock;
+    char *ring;
+    size_t head; // Next byte to write to the file
+    size_t len; // Bytes waiting to be written
+    bool stopping;
+    bool failed; // Writing to the file failed, nothing is queued anymore
+};
+
+static bool writer_should_wake(struct cv_trace *trace) {
+    bool ret;
+
+    spin_lock(&trace -> lock);
+    ret = trace -> len > 0 || trace -> stopping;
+    spin_unlock(&trace -> lock);
+
+    return ret;
+}
+
+// Takes up to `max_len` bytes from the ring. Returns 0 once the ring is
+// empty and the trace is stopping.
+static size_t take_chunk(struct cv_trace *trace, char *out, size_t max_len) {
+    size_t n, first;
+
+    spin_lock(&trace -> lock);
+    n = min(trace -> len, max_len);
+    first = min(n, (size_t) TRACE_RING_LEN - trace -> head);
+    memcpy(out, trace -> ring + trace -> head, first);
+    memcpy(out + first, trace -> ring, n - first);
+    trace -> head = (trace -> head + n) % TRACE_RING_LEN;
+    trace -> len -= n;
+    spin_unlock(&trace -> lock);
+
+    return n;
+}
+
+static int write_chunk(struct cv_trace *trace, const char *data, size_t len, loff_t *pos) {
+    ssize_t ret;
+
+    while(len > 0) {
+        ret = kernel_write(trace -> file, data, len, *pos);
+        if(ret <= 0) {
+            return -EIO;
+        }
+        *pos += ret;
+        data += ret;
+        len -= ret;
+    }
+
+    return 0;
+}
+
+static int trace_writer(void *arg) {
+    struct cv_trace *trace = arg;
+    char *chunk;
+    size_t n;
+    loff_t pos = 0;
+    int ret = 0;
+
+    // Lets cv_trace_stop() interrupt a write to a reader that stopped reading.
+    allow_signal(SIGKILL);
+
+    chunk = kmalloc(TRACE_CHUNK_LEN, GFP_KERNEL);
+    if(!chunk) {
+        ret = -ENOMEM;
+    }
+
+    while(ret == 0) {
+        if(wait_event_interruptible(trace -> wait, writer_should_wake(trace)) < 0) {
+            ret = -EINTR;
+            break;
+        }
+
+        n = take_chunk(trace, chunk, TRACE_CHUNK_LEN);
+        if(n == 0) {
+            // Stopping with nothing left to write
+            break;
+        }
+
+        ret = write_chunk(trace, chunk, n, &pos);
+    }
+
+    if(ret < 0) {
+        spin_lock(&trace -> lock);
+        trace -> failed = true;
+        trace -> len = 0;
+        spin_unlock(&trace -> lock);
+    }
+
+    kfree(chunk);
+    complete_and_exit(&trace -> done, 0);
+}
+
+struct cv_trace * cv_trace_start(struct file *file) {
+    struct cv_trace *trace;
+
+    trace = kzalloc(sizeof(*trace), GFP_KERNEL);
+    if(!trace) {
+        return NULL;
+    }
+
+    trace -> ring = vmalloc(TRACE_RING_LEN);
+    if(!trace -> ring) {
+        kfree(trace);
+        return NULL;
+    }
+
+    trace -> file = get_file(file);
+    init_completion(&trace -> done);
+    init_waitqueue_head(&trace -> wait);
+    spin_lock_init(&trace -> lock);
+
+    trace -> writer = kthread_run(trace_writer, trace, "cervus-trace");
+    if(IS_ERR(trace -> writer)) {
+        fput(trace -> file);
+        vfree(trace -> ring);
+        kfree(trace);
+        return NULL;
+    }
+
+    // Kept until the writer is known to have exited, so that it can be signaled.
+    get_task_struct(trace -> writer);
+
+    return trace;
+}
+
+void cv_trace_stop(struct cv_trace *trace) {
+    spin_lock(&trace -> lock);
+    trace -> stopping = true;
+    spin_unlock(&trace -> lock);
+    wake_up(&trace -> wait);
+
+    if(!wait_for_completion_timeout(&trace -> done, TRACE_DRAIN_TIMEOUT)) {
+        send_sig(SIGKILL, trace -> writer, 1);
+        wait_for_completion(&trace -> done);
+    }
+
+    put_task_struct(trace -> writer);
+    fput(trace -> file);
+    vfree(trace -> ring);
+    kfree(trace);
+}
+
+struct cv_trace * lapi_env_get_trace(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> trace;
+}
+
+// Queues `len` bytes of output, all of them or none. Returns `len`,
+// -EAGAIN if they don't fit, or -EIO once writing to the file failed.
+ssize_t lapi_env_write_trace(struct cv_trace *trace, const char *data, size_t len) {
+    size_t tail, first;
+    ssize_t ret = len;
+
+    spin_lock(&trace -> lock);
+    if(trace -> failed) {
+        ret = -EIO;
+    } else if(len > TRACE_RING_LEN - trace -> len) {
+        ret = -EAGAIN;
+    } else {
+        tail = (trace -> head + trace -> len) % TRACE_RING_LEN;
+        first = min(len, (size_t) TRACE_RING_LEN - tail);
+        memcpy(trace -> ring + tail, data, first);
+        memcpy(trace -> ring, data + first, len - first);
+        trace -> len += len;
+    }
+    spin_unlock(&trace -> lock);
+
+    if(ret > 0) {
+        wake_up(&trace -> wait);
+    }
+
+    return ret;
+}
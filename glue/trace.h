@@ -0,0 +1,18 @@
+#ifndef _CV_TRACE_H_
+#define _CV_TRACE_H_
+
+#include <linux/fs.h>
+
+struct cv_trace;
+
+// Starts a thread writing trace output to `file`, which is left as it is:
+// the application queues output in a private ring buffer instead of
+// writing to the file, and never waits for the reader.
+//
+// Returns NULL if the thread can't be started.
+struct cv_trace * cv_trace_start(struct file *file);
+
+// Gives the thread a moment to write what is left and frees `trace`.
+void cv_trace_stop(struct cv_trace *trace);
+
+#endif
@@ -2,6 +2,7 @@
 #include <linux/slab.h>
 #include <linux/sched.h>
 #include <linux/sched/signal.h>
+#include <linux/sched/prio.h>
 #include <linux/device.h>
 #include <linux/kernel.h>
 #include <linux/kfifo.h>
@@ -12,14 +13,53 @@
 #include <linux/cred.h>
 #include <linux/security.h>
 #include <linux/kthread.h>
+#include <linux/capability.h>
 
 #include "vmm.h"
 #include "kctx.h"
+#include "checkpoint.h"
+#include "debug.h"
+#include "trace.h"
 
 #define CERVUS_LOAD_CODE 0x1001
 #define CERVUS_RUN_CODE 0x1002
 #define CERVUS_MAP_CWA_API 0x1003
+#define CERVUS_MAP_CWA_API_BATCH 0x1004
+#define CERVUS_LIST_NATIVE_INVOKES 0x1005
+#define CERVUS_RUN_CODE_SIZED 0x1006
+#define CERVUS_CHECKPOINT 0x1007
+#define CERVUS_DEBUG 0x1008
 #define EXEC_HEXAGON_E 0x01
+#define EXEC_JIT 0x02
+
+#define DEFAULT_MEM_DEFAULT_LEN (1048576 * 4)
+#define DEFAULT_MEM_MAX_LEN (1048576 * 16)
+#define DEFAULT_MAX_SLOTS 16384
+#define DEFAULT_STACK_LEN 1024
+#define DEFAULT_CALL_STACK_LEN 1024
+
+#define MAX_BATCH_NAMES_LEN (1048576)
+#define MAX_BATCH_N_NAMES 65536
+#define MAX_NATIVE_INVOKE_LIST_LEN 65536
+#define MAX_WASM_ENTRY_LEN 256
+
+// Ceilings for what applications may request, adjustable by the administrator
+// through /sys/module/cervus/parameters.
+static unsigned long max_mem_len = DEFAULT_MEM_MAX_LEN;
+module_param(max_mem_len, ulong, 0644);
+MODULE_PARM_DESC(max_mem_len, "Maximum linear memory size of an application in bytes");
+
+static unsigned long max_slots = DEFAULT_MAX_SLOTS;
+module_param(max_slots, ulong, 0644);
+MODULE_PARM_DESC(max_slots, "Maximum number of slots of an application");
+
+static unsigned long max_stack_len = DEFAULT_STACK_LEN;
+module_param(max_stack_len, ulong, 0644);
+MODULE_PARM_DESC(max_stack_len, "Maximum operand stack length of an application");
+
+static unsigned long max_call_stack_len = DEFAULT_CALL_STACK_LEN;
+module_param(max_call_stack_len, ulong, 0644);
+MODULE_PARM_DESC(max_call_stack_len, "Maximum call stack length of an application");
 
 const char *CLASS_NAME = "cervus";
 const char *DEVICE_NAME = "cvctl";
@@ -37,15 +77,61 @@ static ssize_t wd_read(struct file *, char *, size_t, loff_t *);
 static ssize_t wd_write(struct file *, const char *, size_t, loff_t *);
 static ssize_t wd_ioctl(struct file *, unsigned int cmd, unsigned long arg);
 
+#define TERMINATION_EXITED 0
+#define TERMINATION_TRAPPED 1
+#define TERMINATION_RESOURCE_EXHAUSTED 2
+#define TERMINATION_KILLED 3
+#define TERMINATION_CHECKPOINTED 4
+
+// Must match `backend::common::TerminationReport`
+struct termination_report {
+    int kind;
+    int code;
+
+    // Value returned by the entry function, if has_value is not 0
+    int has_value;
+    long long value; // exit code if exited, or BackendError otherwise
+};
+
 extern int run_code_in_hexagon_e(
     const unsigned char *code_base,
     size_t code_len,
+    const long long *entry_args_base,
+    size_t n_entry_args,
+    const char *wasm_entry_base,
+    size_t wasm_entry_len,
     size_t mem_default_len,
     size_t mem_max_len,
     size_t max_slots,
     size_t stack_len,
     size_t call_stack_len,
-    void *kctx
+    unsigned long long fuel,
+    unsigned long long timeout_ms,
+    unsigned long long cpu_time_ms,
+    unsigned int profile_interval,
+    void *kctx,
+    struct termination_report *report
+);
+
+// Same arguments as `run_code_in_hexagon_e`
+extern int run_code_in_jit(
+    const unsigned char *code_base,
+    size_t code_len,
+    const long long *entry_args_base,
+    size_t n_entry_args,
+    const char *wasm_entry_base,
+    size_t wasm_entry_len,
+    size_t mem_default_len,
+    size_t mem_max_len,
+    size_t max_slots,
+    size_t stack_len,
+    size_t call_stack_len,
+    unsigned long long fuel,
+    unsigned long long timeout_ms,
+    unsigned long long cpu_time_ms,
+    unsigned int profile_interval,
+    void *kctx,
+    struct termination_report *report
 );
 
 extern int map_cwa_api(
@@ -53,11 +139,46 @@ extern int map_cwa_api(
     size_t name_len
 );
 
+extern int map_cwa_api_batch(
+    const char *names_base,
+    size_t names_len,
+    int *ids_base,
+    size_t n_ids
+);
+
+extern int list_native_invokes(
+    char *out_base,
+    size_t out_len
+);
+
 struct execution_info {
     int executor;
     uid_t euid;
     int n_args;
     struct kernel_string args[MAX_N_ARGS];
+    size_t mem_default_len;
+    size_t mem_max_len;
+    size_t max_slots;
+    size_t stack_len;
+    size_t call_stack_len;
+    unsigned long long fuel;
+    unsigned long long timeout_ms;
+    unsigned long long cpu_time_ms;
+    int report_fd;
+    int profile_fd;
+    unsigned int profile_interval;
+    int nice;
+    int core_fd;
+    int record_fd;
+    int replay_fd;
+    int manifest_fd;
+    int trace_fd;
+    int n_entry_args;
+    long long entry_args[MAX_N_ENTRY_ARGS];
+    int restore_fd;
+    int debug;
+    size_t wasm_entry_len;
+    char wasm_entry[MAX_WASM_ENTRY_LEN];
     size_t len;
     char code[0];
 };
@@ -81,13 +202,28 @@ static inline void init_kctx(struct kernel_context *kctx, struct execution_info
     kctx -> stdin = NULL;
     kctx -> stdout = NULL;
     kctx -> stderr = NULL;
+    kctx -> profile = NULL;
+    kctx -> core = NULL;
+    kctx -> record = NULL;
+    kctx -> replay = NULL;
+    kctx -> manifest = NULL;
+    kctx -> trace = NULL;
+    kctx -> restore = NULL;
+    kctx -> debug = einfo -> debug;
     kctx -> n_args = einfo -> n_args;
     kctx -> args = einfo -> args;
 }
 
-static int do_execution(struct execution_info *einfo, struct kernel_context *kctx) {
+static int do_execution(
+    struct execution_info *einfo,
+    struct kernel_context *kctx,
+    struct termination_report *report
+) {
     int ret;
 
+    report -> kind = TERMINATION_TRAPPED;
+    report -> code = 1; // BackendError::Generic
+
     printk(KERN_INFO "cervus: starting application for user %d\n", einfo -> euid);
 
     switch(einfo -> executor) {
@@ -95,12 +231,43 @@ static int do_execution(struct execution_info *einfo, struct kernel_context *kct
             ret = run_code_in_hexagon_e(
                 einfo -> code,
                 einfo -> len,
-                1048576 * 4,
-                1048576 * 16,
-                16384,
-                1024,
-                1024,
-                kctx
+                einfo -> entry_args,
+                einfo -> n_entry_args,
+                einfo -> wasm_entry,
+                einfo -> wasm_entry_len,
+                einfo -> mem_default_len,
+                einfo -> mem_max_len,
+                einfo -> max_slots,
+                einfo -> stack_len,
+                einfo -> call_stack_len,
+                einfo -> fuel,
+                einfo -> timeout_ms,
+                einfo -> cpu_time_ms,
+                einfo -> profile_interval,
+                kctx,
+                report
+            );
+            break;
+
+        case EXEC_JIT:
+            ret = run_code_in_jit(
+                einfo -> code,
+                einfo -> len,
+                einfo -> entry_args,
+                einfo -> n_entry_args,
+                einfo -> wasm_entry,
+                einfo -> wasm_entry_len,
+                einfo -> mem_default_len,
+                einfo -> mem_max_len,
+                einfo -> max_slots,
+                einfo -> stack_len,
+                einfo -> call_stack_len,
+                einfo -> fuel,
+                einfo -> timeout_ms,
+                einfo -> cpu_time_ms,
+                einfo -> profile_interval,
+                kctx,
+                report
             );
             break;
 
@@ -116,11 +283,15 @@ static int execution_worker(void *data) {
     int ret;
     struct execution_info *einfo = data;
     struct kernel_context kctx;
+    struct termination_report report;
 
     init_kctx(&kctx, einfo);
     allow_signal(SIGKILL);
 
-    ret = do_execution(einfo, &kctx);
+    // Nobody owns a standalone application, so it can't be debugged.
+    kctx.debug = 0;
+
+    ret = do_execution(einfo, &kctx, &report);
     einfo_free(einfo);
 
     printk(KERN_INFO "cervus: (%d) WebAssembly application exited with code %d\n", task_pid_nr(current), ret);
@@ -197,7 +368,24 @@ static ssize_t wd_write(struct file *_file, const char *data, size_t len, loff_t
     return -EINVAL;
 }
 
+// The argument of CERVUS_RUN_CODE, which predates the execution options.
+struct load_code_info_v0 {
+    int executor;
+
+    int n_args;
+    const struct kernel_string __user *args;
+
+    unsigned long len;
+    void *addr;
+};
+
+// The argument of CERVUS_RUN_CODE_SIZED. New fields are only ever appended,
+// so that callers built against an older version keep working.
 struct load_code_info {
+    // sizeof(struct load_code_info) as known to the caller. Fields past it
+    // take their default values.
+    unsigned int size;
+
     int executor;
 
     int n_args;
@@ -205,17 +393,178 @@ struct load_code_info {
 
     unsigned long len;
     void *addr;
+
+    // Branch budget, 0 for unlimited
+    unsigned long long fuel;
+
+    // Wall-clock and CPU time limits in milliseconds, 0 for unlimited
+    unsigned long long timeout_ms;
+    unsigned long long cpu_time_ms;
+
+    // Resource limits, 0 for the default value
+    unsigned long mem_default_len;
+    unsigned long mem_max_len;
+    unsigned long max_slots;
+    unsigned long stack_len;
+    unsigned long call_stack_len;
+
+    // File descriptor that receives a `struct termination_report`, or -1
+    int report_fd;
+
+    // File descriptor that receives the profile, or -1
+    int profile_fd;
+
+    // Branches between two profiler samples, 0 to disable profiling
+    unsigned int profile_interval;
+
+    // Added to the niceness of the calling process like nice(2), 0 to keep it
+    int nice;
+
+    // File descriptor that receives a core dump if the application traps, or -1
+    int core_fd;
+
+    // File descriptors to record native invoke results to or replay them from,
+    // or -1. Only one of them may be set.
+    int record_fd;
+    int replay_fd;
+
+    // File descriptor of the capability manifest, or -1 to allow everything
+    int manifest_fd;
+
+    // File descriptor to trace native invokes to, or -1
+    int trace_fd;
+
+    // Integer arguments of the entry function, at most MAX_N_ENTRY_ARGS
+    unsigned int n_entry_args;
+    const long long __user *entry_args;
+
+    // File descriptor of a checkpoint to continue from, or -1. The checkpoint
+    // holds the code, so `len` and `n_entry_args` must be 0.
+    int restore_fd;
+
+    // Export to call if the code is a WebAssembly binary for the kernel to
+    // translate, at most MAX_WASM_ENTRY_LEN bytes. Empty for translated code.
+    struct kernel_string wasm_entry;
+
+    // Non-zero to stop before the first opcode and serve CERVUS_DEBUG
+    // requests, see debug.c. Only the interpreter supports it.
+    int debug;
 };
 
-static struct execution_info * load_execution_info_from_user(void *lci_user) {
-    int i, j;
+// The caller must know at least the fields of struct load_code_info_v0.
+#define LOAD_CODE_INFO_MIN_SIZE offsetofend(struct load_code_info, addr)
+
+static int copy_load_code_info(struct load_code_info *lci, void __user *lci_user, bool sized) {
+    struct load_code_info_v0 v0;
+    unsigned int size;
+
+    memset(lci, 0, sizeof(*lci));
+    lci -> report_fd = -1;
+    lci -> profile_fd = -1;
+    lci -> core_fd = -1;
+    lci -> record_fd = -1;
+    lci -> replay_fd = -1;
+    lci -> manifest_fd = -1;
+    lci -> trace_fd = -1;
+    lci -> restore_fd = -1;
+
+    if(!sized) {
+        if(copy_from_user(&v0, lci_user, sizeof(v0))) {
+            return -EFAULT;
+        }
+        lci -> size = sizeof(*lci);
+        lci -> executor = v0.executor;
+        lci -> n_args = v0.n_args;
+        lci -> args = v0.args;
+        lci -> len = v0.len;
+        lci -> addr = v0.addr;
+        return 0;
+    }
+
+    if(get_user(size, (unsigned int __user *) lci_user)) {
+        return -EFAULT;
+    }
+    if(size < LOAD_CODE_INFO_MIN_SIZE) {
+        return -EINVAL;
+    }
+    if(size > sizeof(*lci)) {
+        return -E2BIG;
+    }
+    if(copy_from_user(lci, lci_user, size)) {
+        return -EFAULT;
+    }
+
+    return 0;
+}
+
+static inline unsigned long value_or_default(unsigned long value, unsigned long def) {
+    return value ? value : def;
+}
+
+static int resolve_resource_limits(struct load_code_info *lci) {
+    // When only one of the two memory limits is given, derive the other one
+    // from it so that the pair is always consistent.
+    if(lci -> mem_default_len == 0) {
+        lci -> mem_default_len = lci -> mem_max_len && lci -> mem_max_len < DEFAULT_MEM_DEFAULT_LEN
+            ? lci -> mem_max_len
+            : DEFAULT_MEM_DEFAULT_LEN;
+    }
+    lci -> mem_max_len = value_or_default(
+        lci -> mem_max_len,
+        lci -> mem_default_len > DEFAULT_MEM_MAX_LEN ? lci -> mem_default_len : DEFAULT_MEM_MAX_LEN
+    );
+    lci -> max_slots = value_or_default(lci -> max_slots, DEFAULT_MAX_SLOTS);
+    lci -> stack_len = value_or_default(lci -> stack_len, DEFAULT_STACK_LEN);
+    lci -> call_stack_len = value_or_default(lci -> call_stack_len, DEFAULT_CALL_STACK_LEN);
+
+    if(lci -> mem_default_len > lci -> mem_max_len) {
+        return -EINVAL;
+    }
+
+    if(
+        lci -> mem_max_len > max_mem_len
+        || lci -> max_slots > max_slots
+        || lci -> stack_len > max_stack_len
+        || lci -> call_stack_len > max_call_stack_len
+    ) {
+        printk(KERN_INFO "cervus: requested resource limits exceed the configured maximum\n");
+        return -EPERM;
+    }
+
+    return 0;
+}
+
+static struct execution_info * load_execution_info_from_user(void *lci_user, bool sized) {
+    int i, j, ret;
     struct load_code_info lci;
     struct execution_info *einfo;
     char *buf;
     const struct cred *cred;
 
-    if(copy_from_user(&lci, lci_user, sizeof(struct load_code_info))) {
-        return ERR_PTR(-EFAULT);
+    ret = copy_load_code_info(&lci, lci_user, sized);
+    if(ret < 0) {
+        return ERR_PTR(ret);
+    }
+
+    ret = resolve_resource_limits(&lci);
+    if(ret < 0) {
+        return ERR_PTR(ret);
+    }
+
+    if(lci.record_fd >= 0 && lci.replay_fd >= 0) {
+        return ERR_PTR(-EINVAL);
+    }
+
+    if(lci.restore_fd >= 0 && (lci.len != 0 || lci.n_entry_args != 0 || lci.wasm_entry.len != 0)) {
+        return ERR_PTR(-EINVAL);
+    }
+
+    if(lci.wasm_entry.len > MAX_WASM_ENTRY_LEN) {
+        return ERR_PTR(-EINVAL);
+    }
+
+    if(lci.debug && lci.executor != EXEC_HEXAGON_E) {
+        return ERR_PTR(-EINVAL);
     }
 
     einfo = einfo_alloc(lci.len);
@@ -227,12 +576,49 @@ static struct execution_info * load_execution_info_from_user(void *lci_user) {
 
     einfo -> executor = lci.executor;
     einfo -> euid = cred -> euid.val;
+    einfo -> fuel = lci.fuel;
+    einfo -> timeout_ms = lci.timeout_ms;
+    einfo -> cpu_time_ms = lci.cpu_time_ms;
+    einfo -> mem_default_len = lci.mem_default_len;
+    einfo -> mem_max_len = lci.mem_max_len;
+    einfo -> max_slots = lci.max_slots;
+    einfo -> stack_len = lci.stack_len;
+    einfo -> call_stack_len = lci.call_stack_len;
+    einfo -> report_fd = lci.report_fd;
+    einfo -> profile_fd = lci.profile_fd;
+    einfo -> profile_interval = lci.profile_fd >= 0 ? lci.profile_interval : 0;
+    einfo -> nice = lci.nice;
+    einfo -> core_fd = lci.core_fd;
+    einfo -> record_fd = lci.record_fd;
+    einfo -> replay_fd = lci.replay_fd;
+    einfo -> manifest_fd = lci.manifest_fd;
+    einfo -> trace_fd = lci.trace_fd;
+    einfo -> restore_fd = lci.restore_fd;
+    einfo -> debug = lci.debug != 0;
+    einfo -> wasm_entry_len = lci.wasm_entry.len;
+    einfo -> n_args = 0; // Nothing for einfo_free to free yet
+    einfo -> n_entry_args = 0;
     einfo -> len = lci.len;
     if(copy_from_user(einfo -> code, lci.addr, lci.len)) {
         einfo_free(einfo);
         return ERR_PTR(-EFAULT);
     }
 
+    if(lci.n_entry_args > MAX_N_ENTRY_ARGS) {
+        einfo_free(einfo);
+        return ERR_PTR(-EINVAL);
+    }
+    if(copy_from_user(einfo -> entry_args, lci.entry_args, sizeof(long long) * lci.n_entry_args)) {
+        einfo_free(einfo);
+        return ERR_PTR(-EFAULT);
+    }
+    einfo -> n_entry_args = lci.n_entry_args;
+
+    if(copy_from_user(einfo -> wasm_entry, lci.wasm_entry.data, lci.wasm_entry.len)) {
+        einfo_free(einfo);
+        return ERR_PTR(-EFAULT);
+    }
+
     einfo -> n_args = lci.n_args;
     if(einfo -> n_args > MAX_N_ARGS) {
         einfo_free(einfo);
@@ -283,7 +669,7 @@ static ssize_t handle_load_code(struct file *_file, void *arg) {
         return -EPERM;
     }
 
-    einfo = load_execution_info_from_user(arg);
+    einfo = load_execution_info_from_user(arg, false);
     if(IS_ERR(einfo)) {
         return PTR_ERR(einfo);
     }
@@ -303,29 +689,122 @@ static ssize_t handle_load_code(struct file *_file, void *arg) {
     */
 }
 
+// Same rules as nice(2): only privileged users may raise the priority.
+static int apply_nice(int inc) {
+    long nice;
+
+    if(inc == 0) {
+        return 0;
+    }
+
+    // can_nice() is not exported to modules, so its check is open-coded here.
+    nice = clamp_val((long) task_nice(current) + inc, MIN_NICE, MAX_NICE);
+    if(
+        nice < task_nice(current)
+        && !capable(CAP_SYS_NICE)
+        && nice_to_rlimit(nice) > rlimit(RLIMIT_NICE)
+    ) {
+        return -EPERM;
+    }
+
+    set_user_nice(current, nice);
+    return 0;
+}
+
 static int release_user_mappings(void) {
     return vm_munmap(0, TASK_SIZE);
 }
 
-static ssize_t handle_run_code(struct file *_file, void *arg) {
+// Files passed by descriptor to RUN_CODE. Any of them may be NULL.
+struct run_files {
+    struct file *report;
+    struct file *profile;
+    struct file *core;
+    struct file *record;
+    struct file *replay;
+    struct file *manifest;
+    struct file *trace;
+    struct file *restore;
+};
+
+static void put_run_files(struct run_files *files) {
+    if(files -> report) fput(files -> report);
+    if(files -> profile) fput(files -> profile);
+    if(files -> core) fput(files -> core);
+    if(files -> record) fput(files -> record);
+    if(files -> replay) fput(files -> replay);
+    if(files -> manifest) fput(files -> manifest);
+    if(files -> trace) fput(files -> trace);
+    if(files -> restore) fput(files -> restore);
+}
+
+static int get_run_file(int fd, struct file **out) {
+    *out = NULL;
+
+    if(fd < 0) {
+        return 0;
+    }
+
+    *out = fget(fd);
+    return *out ? 0 : -EBADF;
+}
+
+// Takes references to the files while the descriptors are still valid.
+static int get_run_files(struct execution_info *einfo, struct run_files *files) {
+    memset(files, 0, sizeof(struct run_files));
+
+    if(
+        get_run_file(einfo -> report_fd, &files -> report) < 0
+        || get_run_file(einfo -> profile_fd, &files -> profile) < 0
+        || get_run_file(einfo -> core_fd, &files -> core) < 0
+        || get_run_file(einfo -> record_fd, &files -> record) < 0
+        || get_run_file(einfo -> replay_fd, &files -> replay) < 0
+        || get_run_file(einfo -> manifest_fd, &files -> manifest) < 0
+        || get_run_file(einfo -> trace_fd, &files -> trace) < 0
+        || get_run_file(einfo -> restore_fd, &files -> restore) < 0
+    ) {
+        put_run_files(files);
+        return -EBADF;
+    }
+
+    return 0;
+}
+
+static ssize_t run_code(void *arg, bool sized) {
     int ret;
     struct execution_info *einfo;
     struct kernel_context kctx;
+    struct termination_report report;
+    struct run_files files;
 
     if(atomic_read(&current -> mm -> mm_count) != 1) {
         printk(KERN_INFO "cervus: unique ownership is required on process memory\n");
         return -EINVAL;
     }
 
-    einfo = load_execution_info_from_user(arg);
+    einfo = load_execution_info_from_user(arg, sized);
     if(IS_ERR(einfo)) {
         return PTR_ERR(einfo);
     }
 
+    ret = get_run_files(einfo, &files);
+    if(ret < 0) {
+        einfo_free(einfo);
+        return ret;
+    }
+
+    ret = apply_nice(einfo -> nice);
+    if(ret < 0) {
+        einfo_free(einfo);
+        put_run_files(&files);
+        return ret;
+    }
+
     ret = release_user_mappings();
     if(ret < 0) {
         printk(KERN_INFO "cervus: unable to unmap user memory: %d\n", ret);
         einfo_free(einfo);
+        put_run_files(&files);
         do_exit(1 << 8);
     }
 
@@ -333,11 +812,31 @@ static ssize_t handle_run_code(struct file *_file, void *arg) {
     if(ret < 0) {
         printk(KERN_INFO "cervus: vmm initialization failed with code %d\n", ret);
         einfo_free(einfo);
+        put_run_files(&files);
         do_exit(1 << 8);
     }
 
     init_kctx(&kctx, einfo);
 
+    // Tracing must never block the application, so trace output goes through
+    // a writer thread and is dropped when the reader falls behind.
+    if(files.trace) {
+        kctx.trace = cv_trace_start(files.trace);
+        if(!kctx.trace) {
+            printk(KERN_INFO "cervus: unable to start tracing\n");
+            einfo_free(einfo);
+            put_run_files(&files);
+            do_exit(1 << 8);
+        }
+    }
+
+    kctx.profile = files.profile;
+    kctx.core = files.core;
+    kctx.record = files.record;
+    kctx.replay = files.replay;
+    kctx.manifest = files.manifest;
+    kctx.restore = files.restore;
+
     kctx.stdin = fget_raw(0);
     if(IS_ERR(kctx.stdin)) kctx.stdin = NULL;
 
@@ -357,9 +856,26 @@ static ssize_t handle_run_code(struct file *_file, void *arg) {
     }
     task_unlock(current);
 
-    ret = do_execution(einfo, &kctx);
+    cv_checkpoint_register(&kctx);
+    if(kctx.debug) {
+        cv_debug_register(&kctx);
+    }
+    ret = do_execution(einfo, &kctx, &report);
+    if(kctx.debug) {
+        cv_debug_unregister(&kctx, ret);
+    }
+    cv_checkpoint_unregister(&kctx);
     module_put(THIS_MODULE);
 
+    if(kctx.trace) {
+        cv_trace_stop(kctx.trace);
+    }
+
+    if(files.report) {
+        kernel_write(files.report, (const char *) &report, sizeof(report), 0);
+    }
+    put_run_files(&files);
+
     einfo_free(einfo);
 
     if(kctx.stdin) fput(kctx.stdin);
@@ -369,6 +885,14 @@ static ssize_t handle_run_code(struct file *_file, void *arg) {
     do_exit((ret & 0xff) << 8);
 }
 
+static ssize_t handle_run_code(struct file *_file, void *arg) {
+    return run_code(arg, false);
+}
+
+static ssize_t handle_run_code_sized(struct file *_file, void *arg) {
+    return run_code(arg, true);
+}
+
 struct map_cwa_api_request {
     const char __user *name;
     size_t len;
@@ -403,13 +927,225 @@ static ssize_t handle_map_cwa_api(struct file *_file, void *arg) {
     return ret;
 }
 
+// `names` holds `n_names` NUL-terminated names back to back. `ids` receives
+// one native invoke id per name, or -1 for names that are not registered.
+struct map_cwa_api_batch_request {
+    const char __user *names;
+    size_t names_len;
+    int __user *ids;
+    size_t n_names;
+};
+
+static ssize_t handle_map_cwa_api_batch(struct file *_file, void *arg) {
+    struct map_cwa_api_batch_request req;
+    char *names_buf;
+    int *ids_buf;
+    int ret;
+
+    if(copy_from_user(&req, arg, sizeof(req))) {
+        return -EFAULT;
+    }
+
+    if(req.names_len == 0 || req.names_len > MAX_BATCH_NAMES_LEN) {
+        return -EINVAL;
+    }
+
+    if(req.n_names == 0 || req.n_names > MAX_BATCH_N_NAMES) {
+        return -EINVAL;
+    }
+
+    names_buf = vmalloc(req.names_len);
+    if(!names_buf) {
+        return -ENOMEM;
+    }
+
+    ids_buf = vmalloc(req.n_names * sizeof(int));
+    if(!ids_buf) {
+        vfree(names_buf);
+        return -ENOMEM;
+    }
+
+    if(copy_from_user(names_buf, req.names, req.names_len)) {
+        ret = -EFAULT;
+        goto out;
+    }
+
+    ret = map_cwa_api_batch(names_buf, req.names_len, ids_buf, req.n_names);
+    if(ret < 0) {
+        ret = -EINVAL;
+        goto out;
+    }
+
+    if(copy_to_user(req.ids, ids_buf, req.n_names * sizeof(int))) {
+        ret = -EFAULT;
+        goto out;
+    }
+
+out:
+    vfree(ids_buf);
+    vfree(names_buf);
+    return ret;
+}
+
+// Fills `buf` with one `<id> <n_args> <name>` line per native invoke and
+// returns the full length of the list, which may exceed `len`.
+struct list_native_invokes_request {
+    char __user *buf;
+    size_t len;
+};
+
+static ssize_t handle_list_native_invokes(struct file *_file, void *arg) {
+    struct list_native_invokes_request req;
+    char *list_buf;
+    size_t buf_len;
+    int ret;
+
+    if(copy_from_user(&req, arg, sizeof(req))) {
+        return -EFAULT;
+    }
+
+    buf_len = min_t(size_t, req.len, MAX_NATIVE_INVOKE_LIST_LEN);
+    list_buf = NULL;
+
+    if(buf_len > 0) {
+        list_buf = vmalloc(buf_len);
+        if(!list_buf) {
+            return -ENOMEM;
+        }
+    }
+
+    ret = list_native_invokes(list_buf, buf_len);
+    if(ret < 0) {
+        ret = -ENOMEM;
+    } else if(ret > MAX_NATIVE_INVOKE_LIST_LEN) {
+        // Never report a length that a retry could not fetch in full.
+        ret = -E2BIG;
+    } else if(copy_to_user(req.buf, list_buf, min_t(size_t, ret, buf_len))) {
+        ret = -EFAULT;
+    }
+
+    if(list_buf) {
+        vfree(list_buf);
+    }
+    return ret;
+}
+
+// Asks the application running in process `pid` to write a checkpoint to
+// `fd` and waits until it is written. Fails with -EOPNOTSUPP if a resource
+// can't be checkpointed, which `resource` and `resource_kind` then name.
+struct checkpoint_request_info {
+    int pid;
+    int fd;
+
+    // CHECKPOINT_STOP to stop the application afterwards
+    unsigned int flags;
+
+    long long resource;
+    char resource_kind[CHECKPOINT_KIND_LEN];
+};
+
+static ssize_t handle_checkpoint(struct file *_file, void *arg) {
+    struct checkpoint_request_info req;
+    struct file *file;
+    int ret;
+
+    if(copy_from_user(&req, arg, sizeof(req))) {
+        return -EFAULT;
+    }
+
+    if(req.flags & ~CHECKPOINT_STOP) {
+        return -EINVAL;
+    }
+
+    file = fget(req.fd);
+    if(!file) {
+        return -EBADF;
+    }
+    if(!(file -> f_mode & FMODE_WRITE)) {
+        fput(file);
+        return -EBADF;
+    }
+
+    req.resource = -1;
+    memset(req.resource_kind, 0, sizeof(req.resource_kind));
+
+    ret = cv_checkpoint_request(req.pid, file, req.flags, &req.resource, req.resource_kind);
+    fput(file);
+
+    if(ret == -EOPNOTSUPP && copy_to_user(arg, &req, sizeof(req))) {
+        return -EFAULT;
+    }
+
+    return ret;
+}
+
+// Serves a request of a debugger to the application running in debug mode
+// in process `pid`, see debug.h. Returns the number of bytes read to `buf`.
+struct debug_request_info {
+    int pid;
+
+    // DEBUG_CMD_*, and DEBUG_AREA_* for DEBUG_CMD_READ
+    unsigned int cmd;
+    unsigned int area;
+
+    // Breakpoint, or offset of the first byte to read
+    unsigned long long arg;
+
+    // Receives at most `len` bytes, at most DEBUG_MAX_READ_LEN
+    void __user *buf;
+    unsigned long long len;
+
+    // Where the application is once the request is served
+    struct debug_stop stop;
+};
+
+static ssize_t handle_debug(struct file *_file, void *arg) {
+    struct debug_request_info req;
+    struct debug_request_args args;
+    int ret;
+
+    if(copy_from_user(&req, arg, sizeof(req))) {
+        return -EFAULT;
+    }
+
+    if(req.cmd < DEBUG_CMD_ATTACH || req.cmd > DEBUG_CMD_READ) {
+        return -EINVAL;
+    }
+
+    if(req.cmd == DEBUG_CMD_READ && (req.area > DEBUG_AREA_CODE || req.len > DEBUG_MAX_READ_LEN)) {
+        return -EINVAL;
+    }
+
+    memset(&args, 0, sizeof(args));
+    args.cmd = req.cmd;
+    args.arg = req.arg;
+    if(req.cmd == DEBUG_CMD_READ) {
+        args.area = req.area;
+        args.len = req.len;
+    }
+
+    memset(&req.stop, 0, sizeof(req.stop));
+    ret = cv_debug_request(req.pid, &args, req.buf, &req.stop);
+
+    if(ret >= 0 && copy_to_user(arg, &req, sizeof(req))) {
+        return -EFAULT;
+    }
+
+    return ret;
+}
+
 #define DISPATCH_CMD(cmd, f) case cmd: return (f)(file, (void *) arg);
 
 static ssize_t wd_ioctl(struct file *file, unsigned int cmd, unsigned long arg) {
     switch(cmd) {
         //DISPATCH_CMD(CERVUS_LOAD_CODE, handle_load_code)
         DISPATCH_CMD(CERVUS_RUN_CODE, handle_run_code)
+        DISPATCH_CMD(CERVUS_RUN_CODE_SIZED, handle_run_code_sized)
         DISPATCH_CMD(CERVUS_MAP_CWA_API, handle_map_cwa_api)
+        DISPATCH_CMD(CERVUS_MAP_CWA_API_BATCH, handle_map_cwa_api_batch)
+        DISPATCH_CMD(CERVUS_LIST_NATIVE_INVOKES, handle_list_native_invokes)
+        DISPATCH_CMD(CERVUS_CHECKPOINT, handle_checkpoint)
+        DISPATCH_CMD(CERVUS_DEBUG, handle_debug)
         default:
             return -EINVAL;
     }
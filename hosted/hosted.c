@@ -0,0 +1,1469 @@
+// Userspace implementation of the `lapi_*` interface, so that the Rust
+// part of cervus can run inside an ordinary process.
+
+#define _GNU_SOURCE
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <limits.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <signal.h>
+#include <unistd.h>
+#include <time.h>
+#include <sched.h>
+#include <semaphore.h>
+#include <poll.h>
+#include <pthread.h>
+#include <sys/types.h>
+#include <sys/stat.h>
+#include <sys/mman.h>
+
+#define MAX_N_ARGS 256
+#define MAX_ARG_LEN 1024
+#define MAX_N_ENTRY_ARGS 64
+#define MAX_NAME_LEN 255
+
+#define DEFAULT_MEM_DEFAULT_LEN (1048576 * 4)
+#define DEFAULT_MEM_MAX_LEN (1048576 * 16)
+#define DEFAULT_MAX_SLOTS 16384
+#define DEFAULT_STACK_LEN 1024
+#define DEFAULT_CALL_STACK_LEN 1024
+#define DEFAULT_PROFILE_INTERVAL 64
+
+// Same as glue/trace.c
+#define TRACE_RING_LEN 65536
+#define TRACE_CHUNK_LEN 4096
+#define TRACE_DRAIN_TIMEOUT_SEC 1
+
+// Same as `checkpoint::FLAG_STOP` and `checkpoint::STATUS_*`
+#define CHECKPOINT_STOP 1
+#define CHECKPOINT_OK 0
+#define CHECKPOINT_UNSUPPORTED -2
+
+#define CHK_FATAL_SIGNAL() \
+    if(fatal_signal_pending()) { \
+        fprintf(stderr, "cervus-hosted: (%d) Terminating execution due to signal\n", (int) getpid()); \
+        return -1; \
+    }
+
+struct hosted_file {
+    int fd;
+};
+
+// Trace output waits here until the writer thread gets it to `fd`, like
+// glue/trace.c does, so that the application never waits for the reader.
+struct hosted_trace {
+    int fd;
+    pthread_t writer;
+    pthread_mutex_t lock;
+
+    // Signaled when output is queued, the trace is stopping or the writer exits
+    pthread_cond_t cond;
+
+    char ring[TRACE_RING_LEN];
+    size_t head; // Next byte to write to the file
+    size_t len; // Bytes waiting to be written
+    int stopping;
+    int failed; // Writing to the file failed, nothing is queued anymore
+    int done;
+};
+
+struct kernel_string {
+    unsigned long len;
+    char *data;
+};
+
+struct kernel_context {
+    uid_t euid;
+    struct hosted_file *stdin;
+    struct hosted_file *stdout;
+    struct hosted_file *stderr;
+    struct hosted_file *profile;
+    struct hosted_file *core;
+    struct hosted_file *record;
+    struct hosted_file *replay;
+    struct hosted_file *manifest;
+    struct hosted_trace *trace;
+    struct hosted_file *restore;
+
+    // Receives a checkpoint on SIGUSR1, or SIGUSR2 to stop afterwards
+    struct hosted_file *checkpoint;
+
+    int n_args;
+    struct kernel_string *args;
+};
+
+extern int cervus_global_init(void);
+extern void cervus_global_cleanup(void);
+
+extern int map_cwa_api(
+    const char *name_base,
+    size_t name_len
+);
+
+extern int list_native_invokes(
+    char *out_base,
+    size_t out_len
+);
+
+// Must match `backend::common::TerminationReport`
+struct termination_report {
+    int kind;
+    int code;
+
+    // Value returned by the entry function, if has_value is not 0
+    int has_value;
+    long long value;
+};
+
+extern int run_code_in_hexagon_e(
+    const unsigned char *code_base,
+    size_t code_len,
+    const long long *entry_args_base,
+    size_t n_entry_args,
+    const char *wasm_entry_base,
+    size_t wasm_entry_len,
+    size_t mem_default_len,
+    size_t mem_max_len,
+    size_t max_slots,
+    size_t stack_len,
+    size_t call_stack_len,
+    unsigned long long fuel,
+    unsigned long long timeout_ms,
+    unsigned long long cpu_time_ms,
+    unsigned int profile_interval,
+    void *kctx,
+    struct termination_report *report
+);
+
+// Same arguments as `run_code_in_hexagon_e`
+extern int run_code_in_jit(
+    const unsigned char *code_base,
+    size_t code_len,
+    const long long *entry_args_base,
+    size_t n_entry_args,
+    const char *wasm_entry_base,
+    size_t wasm_entry_len,
+    size_t mem_default_len,
+    size_t mem_max_len,
+    size_t max_slots,
+    size_t stack_len,
+    size_t call_stack_len,
+    unsigned long long fuel,
+    unsigned long long timeout_ms,
+    unsigned long long cpu_time_ms,
+    unsigned int profile_interval,
+    void *kctx,
+    struct termination_report *report
+);
+
+static volatile sig_atomic_t fatal_signal = 0;
+
+// The signal that requested a checkpoint, or 0
+static volatile sig_atomic_t checkpoint_signal = 0;
+
+static struct hosted_file hosted_stdin = { 0 };
+static struct hosted_file hosted_stdout = { 1 };
+static struct hosted_file hosted_stderr = { 2 };
+
+// Spawned instances run on threads of their own, which are killed separately.
+struct hosted_thread {
+    pthread_t thread;
+    void (*entry)(void *);
+    void *arg;
+    volatile sig_atomic_t killed;
+};
+
+// Interrupts blocking calls of a killed thread
+#define THREAD_KILL_SIGNAL SIGURG
+
+// NULL on the main thread
+static __thread struct hosted_thread *current_thread = NULL;
+
+static int fatal_signal_pending(void) {
+    return fatal_signal || (current_thread && current_thread -> killed);
+}
+
+static void handle_fatal_signal(int sig) {
+    fatal_signal = 1;
+}
+
+static void handle_thread_kill_signal(int sig) {
+}
+
+static void handle_checkpoint_signal(int sig) {
+    checkpoint_signal = sig;
+}
+
+void lapi_printk(const char *base, size_t len) {
+    fprintf(stderr, "cervus: %.*s\n", (int) len, base);
+}
+
+unsigned char * lapi_kmalloc(size_t len) {
+    unsigned char *mem = malloc(len);
+    if(mem == NULL) {
+        fprintf(stderr, "cervus-hosted: out of memory\n");
+        abort();
+    }
+
+    return mem;
+}
+
+void lapi_kfree(unsigned char *ptr) {
+    free(ptr);
+}
+
+unsigned char * lapi_exec_map(const unsigned char *code, size_t len) {
+    unsigned char *mem = mmap(NULL, len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if(mem == MAP_FAILED) {
+        return NULL;
+    }
+
+    memcpy(mem, code, len);
+    if(mprotect(mem, len, PROT_READ | PROT_EXEC) < 0) {
+        munmap(mem, len);
+        return NULL;
+    }
+
+    return mem;
+}
+
+void lapi_exec_unmap(unsigned char *mem, size_t len) {
+    munmap(mem, len);
+}
+
+void lapi_bug(void) {
+    fprintf(stderr, "Cervus has panicked unexpectedly. This is a bug.\n");
+    abort();
+}
+
+// There are no other modules to provide native invokes or schemes.
+int lapi_module_get(void *module) {
+    return 1;
+}
+
+void lapi_module_put(void *module) {
+}
+
+unsigned long lapi_get_total_ram_bytes(void) {
+    return (unsigned long) sysconf(_SC_PHYS_PAGES) * (unsigned long) sysconf(_SC_PAGESIZE);
+}
+
+static unsigned long long clock_ns(clockid_t clock) {
+    struct timespec ts;
+
+    clock_gettime(clock, &ts);
+    return (unsigned long long) ts.tv_sec * 1000000000ULL + ts.tv_nsec;
+}
+
+unsigned long long lapi_get_monotonic_ns(void) {
+    return clock_ns(CLOCK_MONOTONIC);
+}
+
+unsigned long long lapi_get_cpu_time_ns(void) {
+    // Per thread, like the runtime of a task in the kernel
+    return clock_ns(CLOCK_THREAD_CPUTIME_ID);
+}
+
+void lapi_oom_score_adj_current(short score) {
+    FILE *f = fopen("/proc/self/oom_score_adj", "w");
+
+    // Best effort. Lowering the score requires privileges we usually don't have.
+    if(f) {
+        fprintf(f, "%d", (int) score);
+        fclose(f);
+    }
+}
+
+static const char * get_log_prefix_for_level(int level) {
+    switch(level) {
+        case 1:
+            return "[ERROR]";
+
+        case 3:
+            return "[WARNING]";
+
+        case 6:
+            return "[INFO]";
+
+        default:
+            return "";
+    }
+}
+
+int lapi_env_get_uid(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> euid;
+}
+
+unsigned int lapi_env_get_n_args(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> n_args;
+}
+
+ssize_t lapi_env_read_arg(void *raw_kctx, unsigned int id, char *out, size_t max_len) {
+    size_t copy_len;
+    struct kernel_context *kctx = raw_kctx;
+
+    if(id >= kctx -> n_args) {
+        return -1;
+    }
+
+    copy_len = kctx -> args[id].len < max_len ? kctx -> args[id].len : max_len;
+    memcpy(out, kctx -> args[id].data, copy_len);
+
+    return copy_len;
+}
+
+struct hosted_file * lapi_env_get_stdin(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> stdin;
+}
+
+struct hosted_file * lapi_env_get_stdout(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> stdout;
+}
+
+struct hosted_file * lapi_env_get_stderr(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> stderr;
+}
+
+struct hosted_file * lapi_env_get_profile_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> profile;
+}
+
+struct hosted_file * lapi_env_get_core_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> core;
+}
+
+struct hosted_file * lapi_env_get_record_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> record;
+}
+
+struct hosted_file * lapi_env_get_replay_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> replay;
+}
+
+struct hosted_file * lapi_env_get_manifest_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> manifest;
+}
+
+struct hosted_trace * lapi_env_get_trace(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> trace;
+}
+
+// Queues `len` bytes of output, all of them or none. Returns `len`,
+// -EAGAIN if they don't fit, or -EIO once writing to the file failed.
+ssize_t lapi_env_write_trace(struct hosted_trace *trace, const char *data, size_t len) {
+    size_t tail, first;
+    ssize_t ret = len;
+
+    pthread_mutex_lock(&trace -> lock);
+    if(trace -> failed) {
+        ret = -EIO;
+    } else if(len > TRACE_RING_LEN - trace -> len) {
+        ret = -EAGAIN;
+    } else {
+        tail = (trace -> head + trace -> len) % TRACE_RING_LEN;
+        first = len < TRACE_RING_LEN - tail ? len : TRACE_RING_LEN - tail;
+        memcpy(trace -> ring + tail, data, first);
+        memcpy(trace -> ring, data + first, len - first);
+        trace -> len += len;
+        pthread_cond_broadcast(&trace -> cond);
+    }
+    pthread_mutex_unlock(&trace -> lock);
+
+    return ret;
+}
+
+// Only cancelable while writing, so that cancellation never happens with
+// the lock held.
+static void * trace_writer(void *arg) {
+    struct hosted_trace *trace = arg;
+    char chunk[TRACE_CHUNK_LEN];
+    size_t n, first, done;
+    ssize_t ret;
+    int failed = 0;
+    sigset_t all;
+
+    // Signals are for the application, and would interrupt writes here.
+    sigfillset(&all);
+    pthread_sigmask(SIG_BLOCK, &all, NULL);
+
+    pthread_setcancelstate(PTHREAD_CANCEL_DISABLE, NULL);
+
+    pthread_mutex_lock(&trace -> lock);
+    while(!failed) {
+        while(trace -> len == 0 && !trace -> stopping) {
+            pthread_cond_wait(&trace -> cond, &trace -> lock);
+        }
+        if(trace -> len == 0) {
+            break;
+        }
+
+        n = trace -> len < TRACE_CHUNK_LEN ? trace -> len : TRACE_CHUNK_LEN;
+        first = n < TRACE_RING_LEN - trace -> head ? n : TRACE_RING_LEN - trace -> head;
+        memcpy(chunk, trace -> ring + trace -> head, first);
+        memcpy(chunk + first, trace -> ring, n - first);
+        trace -> head = (trace -> head + n) % TRACE_RING_LEN;
+        trace -> len -= n;
+        pthread_mutex_unlock(&trace -> lock);
+
+        pthread_setcancelstate(PTHREAD_CANCEL_ENABLE, NULL);
+        for(done = 0; done < n; done += ret) {
+            ret = write(trace -> fd, chunk + done, n - done);
+            if(ret <= 0) {
+                failed = 1;
+                break;
+            }
+        }
+        pthread_setcancelstate(PTHREAD_CANCEL_DISABLE, NULL);
+
+        pthread_mutex_lock(&trace -> lock);
+    }
+
+    if(failed) {
+        trace -> failed = 1;
+        trace -> len = 0;
+    }
+    trace -> done = 1;
+    pthread_cond_broadcast(&trace -> cond);
+    pthread_mutex_unlock(&trace -> lock);
+
+    return NULL;
+}
+
+static struct hosted_trace * start_trace(int fd) {
+    struct hosted_trace *trace;
+
+    trace = calloc(1, sizeof(struct hosted_trace));
+    if(!trace) {
+        return NULL;
+    }
+
+    trace -> fd = fd;
+    pthread_mutex_init(&trace -> lock, NULL);
+    pthread_cond_init(&trace -> cond, NULL);
+
+    if(pthread_create(&trace -> writer, NULL, trace_writer, trace) != 0) {
+        free(trace);
+        return NULL;
+    }
+
+    return trace;
+}
+
+// Gives the writer a moment to write what is left. A write the reader never
+// takes is canceled.
+static void stop_trace(struct hosted_trace *trace) {
+    struct timespec deadline;
+
+    clock_gettime(CLOCK_REALTIME, &deadline);
+    deadline.tv_sec += TRACE_DRAIN_TIMEOUT_SEC;
+
+    pthread_mutex_lock(&trace -> lock);
+    trace -> stopping = 1;
+    pthread_cond_broadcast(&trace -> cond);
+    while(!trace -> done) {
+        if(pthread_cond_timedwait(&trace -> cond, &trace -> lock, &deadline) != 0) {
+            break;
+        }
+    }
+    pthread_mutex_unlock(&trace -> lock);
+
+    pthread_cancel(trace -> writer);
+    pthread_join(trace -> writer, NULL);
+    free(trace);
+}
+
+struct hosted_file * lapi_env_get_restore_file(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> restore;
+}
+
+int lapi_env_checkpoint_requested(void *raw_kctx) {
+    struct kernel_context *kctx = raw_kctx;
+    return kctx -> checkpoint && checkpoint_signal;
+}
+
+struct hosted_file * lapi_env_take_checkpoint_request(void *raw_kctx, unsigned int *flags) {
+    struct kernel_context *kctx = raw_kctx;
+
+    if(!kctx -> checkpoint || !checkpoint_signal) {
+        return NULL;
+    }
+
+    *flags = checkpoint_signal == SIGUSR2 ? CHECKPOINT_STOP : 0;
+    checkpoint_signal = 0;
+
+    // A later checkpoint replaces the previous one.
+    if(ftruncate(kctx -> checkpoint -> fd, 0) && errno != EINVAL) {
+        fprintf(stderr, "cervus-hosted: unable to truncate checkpoint file: %s\n", strerror(errno));
+    }
+
+    return kctx -> checkpoint;
+}
+
+// There is no requester waiting, so the result only goes to stderr.
+void lapi_env_finish_checkpoint(
+    void *raw_kctx,
+    int status,
+    long long resource,
+    const char *kind_base,
+    size_t kind_len
+) {
+    switch(status) {
+        case CHECKPOINT_OK:
+            fprintf(stderr, "cervus-hosted: (%d) checkpoint written\n", (int) getpid());
+            break;
+
+        case CHECKPOINT_UNSUPPORTED:
+            fprintf(stderr, "cervus-hosted: (%d) resource %lld (%.*s) can't be checkpointed\n",
+                (int) getpid(),
+                resource,
+                (int) kind_len,
+                kind_len ? kind_base : ""
+            );
+            break;
+
+        default:
+            fprintf(stderr, "cervus-hosted: (%d) unable to write checkpoint\n", (int) getpid());
+    }
+}
+
+// Debuggers attach through the kernel module, so hosted applications never
+// run in debug mode.
+int lapi_env_debug_enabled(void *raw_kctx) {
+    return 0;
+}
+
+int lapi_env_debug_pending(void *raw_kctx) {
+    return 0;
+}
+
+int lapi_env_take_debug_request(void *raw_kctx, int wait, void *out) {
+    return 0;
+}
+
+void lapi_env_finish_debug_request(
+    void *raw_kctx,
+    int status,
+    const void *stop,
+    const unsigned char *data_base,
+    size_t data_len
+) {
+}
+
+struct hosted_file * lapi_env_open_file(
+    void *kctx,
+    const char *name_base,
+    size_t name_len,
+    const char *flags_base,
+    size_t flags_len
+) {
+    size_t i;
+    int fd;
+    int open_flags = 0;
+    unsigned char allow_read = 0, allow_write = 0;
+    char name_buf[MAX_NAME_LEN + 1];
+    struct hosted_file *file;
+
+    if(name_len > MAX_NAME_LEN) {
+        return NULL;
+    }
+
+    for(i = 0; i < flags_len; i++) {
+        switch(flags_base[i]) {
+            case 'r': allow_read = 1; break;
+            case 'w': allow_write = 1; break;
+            default: break;
+        }
+    }
+
+    if(allow_read && allow_write) {
+        open_flags |= O_RDWR;
+    } else if(allow_read) {
+        open_flags |= O_RDONLY;
+    } else if(allow_write) {
+        open_flags |= O_WRONLY;
+    }
+
+    memcpy(name_buf, name_base, name_len);
+    name_buf[name_len] = 0;
+
+    fd = open(name_buf, open_flags | O_CLOEXEC);
+    if(fd < 0) {
+        return NULL;
+    }
+
+    file = malloc(sizeof(struct hosted_file));
+    if(!file) {
+        close(fd);
+        return NULL;
+    }
+
+    file -> fd = fd;
+    return file;
+}
+
+void lapi_env_close_file(struct hosted_file *file) {
+    close(file -> fd);
+    free(file);
+}
+
+ssize_t lapi_env_get_file_path(struct hosted_file *file, char *out, size_t len) {
+    char link[64];
+    ssize_t ret;
+
+    snprintf(link, sizeof(link), "/proc/self/fd/%d", file -> fd);
+    ret = readlink(link, out, len);
+    if(ret < 0 || (size_t) ret >= len) {
+        return -1;
+    }
+    return ret;
+}
+
+ssize_t lapi_env_write_file(
+    void *kctx,
+    struct hosted_file *file,
+    const char *data,
+    size_t len,
+    long long offset
+) {
+    ssize_t ret;
+
+    CHK_FATAL_SIGNAL();
+    ret = pwrite(file -> fd, data, len, offset);
+
+    // Pipes and terminals don't have a position, just like in the kernel.
+    if(ret < 0 && errno == ESPIPE) {
+        ret = write(file -> fd, data, len);
+    }
+    if(ret < 0) {
+        ret = -errno;
+    }
+    CHK_FATAL_SIGNAL();
+
+    return ret;
+}
+
+ssize_t lapi_env_read_file(
+    void *kctx,
+    struct hosted_file *file,
+    char *data_out,
+    size_t len,
+    long long offset
+) {
+    ssize_t ret;
+
+    CHK_FATAL_SIGNAL();
+    ret = pread(file -> fd, data_out, len, offset);
+    if(ret < 0 && errno == ESPIPE) {
+        ret = read(file -> fd, data_out, len);
+    }
+    if(ret < 0) {
+        ret = -errno;
+    }
+    CHK_FATAL_SIGNAL();
+
+    return ret;
+}
+
+void lapi_env_log(void *raw_kctx, int level, const char *text_base, size_t text_len) {
+    // Unlike the kernel module, everything goes to stderr of the current process
+    // so there is no need to restrict logging to root.
+    fprintf(stderr, "cervus: (%d) %s %.*s\n",
+        (int) getpid(),
+        get_log_prefix_for_level(level),
+        (int) text_len,
+        text_base
+    );
+}
+
+int lapi_env_yield(void *kctx) {
+    CHK_FATAL_SIGNAL();
+    sched_yield();
+
+    return 0;
+}
+
+int lapi_env_msleep(void *kctx, unsigned int ms) {
+    struct timespec ts;
+
+    CHK_FATAL_SIGNAL();
+    ts.tv_sec = ms / 1000;
+    ts.tv_nsec = (long) (ms % 1000) * 1000000;
+    nanosleep(&ts, NULL);
+    CHK_FATAL_SIGNAL();
+
+    return 0;
+}
+
+// The host kernel preempts us as needed, so there is nothing to do but
+// checking for signals.
+int lapi_env_reschedule(void *kctx) {
+    CHK_FATAL_SIGNAL();
+    return 0;
+}
+
+int lapi_env_need_resched(void) {
+    return fatal_signal_pending();
+}
+
+static void * hosted_thread_main(void *raw) {
+    struct hosted_thread *t = raw;
+
+    current_thread = t;
+    t -> entry(t -> arg);
+    return NULL;
+}
+
+// Runs `entry(arg)` on a new thread. Returns NULL on failure.
+struct hosted_thread * lapi_thread_spawn(void (*entry)(void *), void *arg) {
+    struct hosted_thread *t = calloc(1, sizeof(struct hosted_thread));
+    if(!t) return NULL;
+
+    t -> entry = entry;
+    t -> arg = arg;
+
+    if(pthread_create(&t -> thread, NULL, hosted_thread_main, t) != 0) {
+        free(t);
+        return NULL;
+    }
+
+    return t;
+}
+
+void lapi_thread_kill(struct hosted_thread *t) {
+    t -> killed = 1;
+    pthread_kill(t -> thread, THREAD_KILL_SIGNAL);
+}
+
+// Waits for the thread to return from `entry` and frees it.
+void lapi_thread_join(struct hosted_thread *t) {
+    pthread_join(t -> thread, NULL);
+    free(t);
+}
+
+sem_t * lapi_semaphore_new(void) {
+    sem_t *sem = malloc(sizeof(sem_t));
+    if(!sem) return NULL;
+
+    if(sem_init(sem, 0, 0)) {
+        free(sem);
+        return NULL;
+    }
+
+    return sem;
+}
+
+void lapi_semaphore_destroy(sem_t *sem) {
+    sem_destroy(sem);
+    free(sem);
+}
+
+void lapi_semaphore_up(sem_t *sem) {
+    sem_post(sem);
+}
+
+int lapi_semaphore_down(sem_t *sem) {
+    CHK_FATAL_SIGNAL();
+    while(sem_wait(sem)) {
+        if(errno != EINTR) {
+            return -1;
+        }
+        CHK_FATAL_SIGNAL();
+    }
+    CHK_FATAL_SIGNAL();
+
+    return 0;
+}
+
+struct hosted_poll;
+
+// Instances on other threads wake up a wait queue through the wake pipe of
+// every poller registered on it.
+struct hosted_wait_queue {
+    pthread_mutex_t lock;
+    struct hosted_poll **pollers;
+    size_t n_pollers;
+    size_t cap;
+};
+
+struct hosted_wait_queue * lapi_wait_queue_new(void) {
+    struct hosted_wait_queue *wq = calloc(1, sizeof(struct hosted_wait_queue));
+    if(!wq) return NULL;
+
+    pthread_mutex_init(&wq -> lock, NULL);
+    return wq;
+}
+
+void lapi_wait_queue_destroy(struct hosted_wait_queue *wq) {
+    pthread_mutex_destroy(&wq -> lock);
+    free(wq -> pollers);
+    free(wq);
+}
+
+static void wake_poller(struct hosted_poll *poll);
+
+void lapi_wait_queue_wake(struct hosted_wait_queue *wq) {
+    size_t i;
+
+    pthread_mutex_lock(&wq -> lock);
+    for(i = 0; i < wq -> n_pollers; i++) {
+        wake_poller(wq -> pollers[i]);
+    }
+    pthread_mutex_unlock(&wq -> lock);
+}
+
+// Must match `resource::POLL_*`
+#define CERVUS_POLL_READ 1
+#define CERVUS_POLL_WRITE 2
+#define CERVUS_POLL_ERROR 4
+
+static int poll_fds(struct pollfd *fds, size_t n_fds, int timeout) {
+    return poll(fds, n_fds, timeout);
+}
+
+struct hosted_poll {
+    struct pollfd *fds;
+    size_t n_fds;
+    size_t cap;
+    int registering;
+
+    // Written to by the wait queues in `wqs` to wake us up. Created when the
+    // first wait queue is registered.
+    int wake_fds[2];
+    struct hosted_wait_queue **wqs;
+    size_t n_wqs;
+    size_t wqs_cap;
+};
+
+// Grows `*items` with `*cap` elements of `size` bytes to hold one more than `len`.
+static int reserve_one(void **items, size_t *cap, size_t len, size_t size) {
+    void *new_items;
+
+    if(len < *cap) {
+        return 0;
+    }
+
+    new_items = realloc(*items, size * (*cap * 2 + 4));
+    if(!new_items) {
+        return -1;
+    }
+    *items = new_items;
+    *cap = *cap * 2 + 4;
+    return 0;
+}
+
+static void wake_poller(struct hosted_poll *poll) {
+    char c = 0;
+
+    // The pipe is non-blocking, and a full one is woken up already.
+    if(write(poll -> wake_fds[1], &c, 1) < 0) {
+        return;
+    }
+}
+
+static int add_pollfd(struct hosted_poll *poll, struct pollfd pfd) {
+    if(reserve_one((void **) &poll -> fds, &poll -> cap, poll -> n_fds, sizeof(struct pollfd))) {
+        return -1;
+    }
+    poll -> fds[poll -> n_fds++] = pfd;
+    return 0;
+}
+
+struct hosted_poll * lapi_poll_new(void) {
+    struct hosted_poll *poll = calloc(1, sizeof(struct hosted_poll));
+    if(!poll) return NULL;
+
+    poll -> registering = 1;
+    poll -> wake_fds[0] = -1;
+    poll -> wake_fds[1] = -1;
+    return poll;
+}
+
+void lapi_poll_destroy(struct hosted_poll *poll) {
+    size_t i, j;
+    struct hosted_wait_queue *wq;
+
+    for(i = 0; i < poll -> n_wqs; i++) {
+        wq = poll -> wqs[i];
+        pthread_mutex_lock(&wq -> lock);
+        for(j = 0; j < wq -> n_pollers; j++) {
+            if(wq -> pollers[j] == poll) {
+                wq -> pollers[j] = wq -> pollers[--wq -> n_pollers];
+                break;
+            }
+        }
+        pthread_mutex_unlock(&wq -> lock);
+    }
+
+    if(poll -> wake_fds[0] >= 0) {
+        close(poll -> wake_fds[0]);
+        close(poll -> wake_fds[1]);
+    }
+
+    free(poll -> wqs);
+    free(poll -> fds);
+    free(poll);
+}
+
+unsigned int lapi_poll_file(struct hosted_poll *poll, struct hosted_file *file, unsigned int interest) {
+    struct pollfd pfd;
+    unsigned int ready = 0;
+
+    pfd.fd = file -> fd;
+    pfd.events = (interest & CERVUS_POLL_READ ? POLLIN : 0) | (interest & CERVUS_POLL_WRITE ? POLLOUT : 0);
+    pfd.revents = 0;
+
+    if(poll -> registering && add_pollfd(poll, pfd)) {
+        return CERVUS_POLL_ERROR;
+    }
+
+    if(poll_fds(&pfd, 1, 0) < 0) {
+        return CERVUS_POLL_ERROR;
+    }
+
+    if(pfd.revents & (POLLIN | POLLHUP)) ready |= CERVUS_POLL_READ;
+    if(pfd.revents & POLLOUT) ready |= CERVUS_POLL_WRITE;
+
+    return (ready & interest) | (pfd.revents & (POLLERR | POLLNVAL) ? CERVUS_POLL_ERROR : 0);
+}
+
+// Registration failures are ignored like in the kernel module, where it
+// can't fail. The poller then relies on the timeout.
+void lapi_poll_wait_queue(struct hosted_poll *poll, struct hosted_wait_queue *wq) {
+    struct pollfd pfd;
+
+    if(!poll -> registering) {
+        return;
+    }
+
+    if(poll -> wake_fds[0] < 0) {
+        if(pipe2(poll -> wake_fds, O_NONBLOCK | O_CLOEXEC)) {
+            poll -> wake_fds[0] = -1;
+            return;
+        }
+
+        pfd.fd = poll -> wake_fds[0];
+        pfd.events = POLLIN;
+        pfd.revents = 0;
+        if(add_pollfd(poll, pfd)) {
+            return;
+        }
+    }
+
+    if(reserve_one((void **) &poll -> wqs, &poll -> wqs_cap, poll -> n_wqs, sizeof(struct hosted_wait_queue *))) {
+        return;
+    }
+
+    pthread_mutex_lock(&wq -> lock);
+    if(reserve_one((void **) &wq -> pollers, &wq -> cap, wq -> n_pollers, sizeof(struct hosted_poll *)) == 0) {
+        wq -> pollers[wq -> n_pollers++] = poll;
+        poll -> wqs[poll -> n_wqs++] = wq;
+    }
+    pthread_mutex_unlock(&wq -> lock);
+}
+
+int lapi_poll_wait(struct hosted_poll *poll, long long timeout_ms) {
+    int timeout = timeout_ms < 0 || timeout_ms > INT_MAX ? -1 : (int) timeout_ms;
+    char buf[64];
+
+    poll -> registering = 0;
+
+    CHK_FATAL_SIGNAL();
+    if(poll_fds(poll -> fds, poll -> n_fds, timeout) < 0 && errno != EINTR) {
+        return -1;
+    }
+    CHK_FATAL_SIGNAL();
+
+    if(poll -> wake_fds[0] >= 0) {
+        while(read(poll -> wake_fds[0], buf, sizeof(buf)) > 0);
+    }
+
+    return 0;
+}
+
+static int install_signal_handlers(void) {
+    struct sigaction sa;
+
+    memset(&sa, 0, sizeof(sa));
+    sa.sa_handler = handle_fatal_signal;
+    sigemptyset(&sa.sa_mask);
+
+    // No SA_RESTART: blocking calls have to return so that we can check the flag.
+    if(sigaction(SIGINT, &sa, NULL)) return -1;
+    if(sigaction(SIGTERM, &sa, NULL)) return -1;
+    if(sigaction(SIGHUP, &sa, NULL)) return -1;
+
+    sa.sa_handler = handle_thread_kill_signal;
+    if(sigaction(THREAD_KILL_SIGNAL, &sa, NULL)) return -1;
+
+    // Checkpoints are taken at the next branch, so blocking calls just continue.
+    sa.sa_handler = handle_checkpoint_signal;
+    sa.sa_flags = SA_RESTART;
+    if(sigaction(SIGUSR1, &sa, NULL)) return -1;
+    if(sigaction(SIGUSR2, &sa, NULL)) return -1;
+
+    return 0;
+}
+
+static unsigned char * read_code(const char *path, size_t *len_out) {
+    int fd;
+    struct stat st;
+    unsigned char *code;
+    size_t done = 0;
+    ssize_t ret;
+
+    fd = open(path, O_RDONLY | O_CLOEXEC);
+    if(fd < 0) {
+        return NULL;
+    }
+
+    if(fstat(fd, &st) || st.st_size <= 0) {
+        close(fd);
+        return NULL;
+    }
+
+    code = malloc(st.st_size);
+    if(!code) {
+        close(fd);
+        return NULL;
+    }
+
+    while(done < (size_t) st.st_size) {
+        ret = read(fd, code + done, st.st_size - done);
+        if(ret <= 0) {
+            free(code);
+            close(fd);
+            return NULL;
+        }
+        done += ret;
+    }
+
+    close(fd);
+    *len_out = done;
+    return code;
+}
+
+// Reads API names line by line from stdin and answers each of them
+// with the native invoke id (or -1) on stdout.
+static int serve_map_api(void) {
+    char line[MAX_NAME_LEN + 2];
+    size_t len;
+
+    while(fgets(line, sizeof(line), stdin)) {
+        len = strlen(line);
+        if(len > 0 && line[len - 1] == '\n') {
+            len--;
+        }
+
+        printf("%d\n", len == 0 ? -1 : map_cwa_api(line, len));
+        fflush(stdout);
+    }
+
+    return 0;
+}
+
+// Prints one `<id> <n_args> <name>` line per registered native invoke.
+static int list_api(void) {
+    char *buf;
+    int len, ret;
+
+    len = list_native_invokes(NULL, 0);
+    if(len < 0) {
+        return 1;
+    }
+
+    buf = malloc(len + 1);
+    if(!buf) {
+        return 1;
+    }
+
+    ret = list_native_invokes(buf, len);
+    if(ret < 0) {
+        free(buf);
+        return 1;
+    }
+
+    // Nothing is registered in between in the hosted runner, but don't rely on it.
+    fwrite(buf, 1, ret < len ? ret : len, stdout);
+    free(buf);
+    return 0;
+}
+
+struct run_options {
+    unsigned long long fuel;
+    unsigned long long timeout_ms;
+    unsigned long long cpu_time_ms;
+    unsigned long long mem_default_len;
+    unsigned long long mem_max_len;
+    unsigned long long max_slots;
+    unsigned long long stack_len;
+    unsigned long long call_stack_len;
+    unsigned long long report_fd; // ULLONG_MAX for none
+    unsigned long long profile_fd; // ULLONG_MAX for none
+    unsigned long long profile_interval;
+    unsigned long long core_fd; // ULLONG_MAX for none
+    unsigned long long record_fd; // ULLONG_MAX for none
+    unsigned long long replay_fd; // ULLONG_MAX for none
+    unsigned long long manifest_fd; // ULLONG_MAX for none
+    unsigned long long trace_fd; // ULLONG_MAX for none
+    unsigned long long restore_fd; // ULLONG_MAX for none
+    unsigned long long checkpoint_fd; // ULLONG_MAX for none
+    int nice;
+    int n_entry_args;
+    long long entry_args[MAX_N_ENTRY_ARGS];
+    const char *wasm_entry; // NULL if the code is translated already
+    int jit; // Run with the JIT backend instead of the interpreter
+};
+
+// `code_path` is NULL when restoring a checkpoint, which holds the code.
+static int run_code(const char *code_path, const struct run_options *opts, int n_args, char **args) {
+    int i, ret;
+    unsigned char *code = NULL;
+    size_t code_len = 0;
+    struct kernel_context kctx;
+    struct kernel_string kargs[MAX_N_ARGS];
+    struct termination_report report;
+    struct hosted_file profile_file;
+    struct hosted_file core_file;
+    struct hosted_file record_file;
+    struct hosted_file replay_file;
+    struct hosted_file manifest_file;
+    struct hosted_file restore_file;
+    struct hosted_file checkpoint_file;
+
+    if(n_args > MAX_N_ARGS) {
+        fprintf(stderr, "cervus-hosted: too many arguments\n");
+        return 1;
+    }
+
+    if(opts -> record_fd != ULLONG_MAX && opts -> replay_fd != ULLONG_MAX) {
+        fprintf(stderr, "cervus-hosted: cannot record and replay at the same time\n");
+        return 1;
+    }
+
+    for(i = 0; i < n_args; i++) {
+        kargs[i].len = strlen(args[i]);
+        if(kargs[i].len > MAX_ARG_LEN) {
+            fprintf(stderr, "cervus-hosted: argument %d is too long\n", i);
+            return 1;
+        }
+        kargs[i].data = args[i];
+    }
+
+    errno = 0;
+    if(opts -> nice && nice(opts -> nice) == -1 && errno) {
+        fprintf(stderr, "cervus-hosted: unable to change niceness: %s\n", strerror(errno));
+        return 1;
+    }
+
+    if(code_path) {
+        code = read_code(code_path, &code_len);
+        if(!code) {
+            fprintf(stderr, "cervus-hosted: unable to read code from %s\n", code_path);
+            return 1;
+        }
+    }
+
+    kctx.euid = geteuid();
+    kctx.stdin = &hosted_stdin;
+    kctx.stdout = &hosted_stdout;
+    kctx.stderr = &hosted_stderr;
+    kctx.profile = NULL;
+    kctx.core = NULL;
+    kctx.record = NULL;
+    kctx.replay = NULL;
+    kctx.manifest = NULL;
+    kctx.trace = NULL;
+    kctx.restore = NULL;
+    kctx.checkpoint = NULL;
+    kctx.n_args = n_args;
+
+    if(opts -> profile_fd != ULLONG_MAX) {
+        profile_file.fd = (int) opts -> profile_fd;
+        kctx.profile = &profile_file;
+    }
+
+    if(opts -> core_fd != ULLONG_MAX) {
+        core_file.fd = (int) opts -> core_fd;
+        kctx.core = &core_file;
+    }
+
+    if(opts -> record_fd != ULLONG_MAX) {
+        record_file.fd = (int) opts -> record_fd;
+        kctx.record = &record_file;
+    }
+
+    if(opts -> replay_fd != ULLONG_MAX) {
+        replay_file.fd = (int) opts -> replay_fd;
+        kctx.replay = &replay_file;
+    }
+
+    if(opts -> manifest_fd != ULLONG_MAX) {
+        manifest_file.fd = (int) opts -> manifest_fd;
+        kctx.manifest = &manifest_file;
+    }
+
+    if(opts -> trace_fd != ULLONG_MAX) {
+        kctx.trace = start_trace((int) opts -> trace_fd);
+        if(!kctx.trace) {
+            fprintf(stderr, "cervus-hosted: unable to start tracing\n");
+            free(code);
+            return 1;
+        }
+    }
+
+    if(opts -> restore_fd != ULLONG_MAX) {
+        restore_file.fd = (int) opts -> restore_fd;
+        kctx.restore = &restore_file;
+    }
+
+    if(opts -> checkpoint_fd != ULLONG_MAX) {
+        checkpoint_file.fd = (int) opts -> checkpoint_fd;
+        kctx.checkpoint = &checkpoint_file;
+    }
+    kctx.args = kargs;
+
+    ret = (opts -> jit ? run_code_in_jit : run_code_in_hexagon_e)(
+        code,
+        code_len,
+        opts -> entry_args,
+        (size_t) opts -> n_entry_args,
+        opts -> wasm_entry,
+        opts -> wasm_entry ? strlen(opts -> wasm_entry) : 0,
+        opts -> mem_default_len,
+        opts -> mem_max_len,
+        opts -> max_slots,
+        opts -> stack_len,
+        opts -> call_stack_len,
+        opts -> fuel,
+        opts -> timeout_ms,
+        opts -> cpu_time_ms,
+        kctx.profile ? (unsigned int) opts -> profile_interval : 0,
+        &kctx,
+        &report
+    );
+    free(code);
+
+    if(kctx.trace) {
+        stop_trace(kctx.trace);
+    }
+
+    if(opts -> report_fd != ULLONG_MAX) {
+        if(write((int) opts -> report_fd, &report, sizeof(report)) != sizeof(report)) {
+            fprintf(stderr, "cervus-hosted: unable to write termination report\n");
+        }
+    }
+
+    return ret & 0xff;
+}
+
+static void print_usage(void) {
+    fprintf(stderr, "Usage:\n");
+    fprintf(stderr, "    cervus-hosted --map-api\n");
+    fprintf(stderr, "    cervus-hosted --list-api\n");
+    fprintf(stderr, "    cervus-hosted --run [options] <code> [args...]\n");
+    fprintf(stderr, "    cervus-hosted --run --restore-fd <fd> [options] [args...]\n");
+    fprintf(stderr, "\n");
+    fprintf(stderr, "Options:\n");
+    fprintf(stderr, "    --fuel <n>\n");
+    fprintf(stderr, "    --timeout <ms>\n");
+    fprintf(stderr, "    --cpu-time <ms>\n");
+    fprintf(stderr, "    --memory <bytes>\n");
+    fprintf(stderr, "    --max-memory <bytes>\n");
+    fprintf(stderr, "    --max-slots <n>\n");
+    fprintf(stderr, "    --stack-len <n>\n");
+    fprintf(stderr, "    --call-stack-len <n>\n");
+    fprintf(stderr, "    --report-fd <fd>\n");
+    fprintf(stderr, "    --profile-fd <fd>\n");
+    fprintf(stderr, "    --profile-interval <branches>\n");
+    fprintf(stderr, "    --nice <increment>\n");
+    fprintf(stderr, "    --core-fd <fd>\n");
+    fprintf(stderr, "    --record-fd <fd>\n");
+    fprintf(stderr, "    --replay-fd <fd>\n");
+    fprintf(stderr, "    --manifest-fd <fd>\n");
+    fprintf(stderr, "    --trace-fd <fd>\n");
+    fprintf(stderr, "    --entry-arg <integer>  (repeatable)\n");
+    fprintf(stderr, "    --wasm-entry <export>  (the code is a WebAssembly binary to translate)\n");
+    fprintf(stderr, "    --checkpoint-fd <fd>  (written on SIGUSR1, or SIGUSR2 to stop afterwards)\n");
+    fprintf(stderr, "    --backend <hexagon-e|jit>\n");
+}
+
+static int parse_run_options(int argc, char **argv, struct run_options *opts) {
+    int i = 2;
+    char *end;
+    unsigned long long *target;
+
+    while(i + 1 < argc && strncmp(argv[i], "--", 2) == 0) {
+        // The signed options
+        if(strcmp(argv[i], "--nice") == 0) {
+            opts -> nice = (int) strtol(argv[i + 1], &end, 10);
+            if(*end) return -1;
+            i += 2;
+            continue;
+        }
+
+        if(strcmp(argv[i], "--entry-arg") == 0) {
+            if(opts -> n_entry_args >= MAX_N_ENTRY_ARGS) return -1;
+            opts -> entry_args[opts -> n_entry_args++] = strtoll(argv[i + 1], &end, 10);
+            if(*end) return -1;
+            i += 2;
+            continue;
+        }
+
+        if(strcmp(argv[i], "--wasm-entry") == 0) {
+            if(!*argv[i + 1]) return -1;
+            opts -> wasm_entry = argv[i + 1];
+            i += 2;
+            continue;
+        }
+
+        if(strcmp(argv[i], "--backend") == 0) {
+            if(strcmp(argv[i + 1], "jit") == 0) {
+                opts -> jit = 1;
+            } else if(strcmp(argv[i + 1], "hexagon-e") == 0) {
+                opts -> jit = 0;
+            } else {
+                return -1;
+            }
+            i += 2;
+            continue;
+        }
+
+        if(strcmp(argv[i], "--fuel") == 0) {
+            target = &opts -> fuel;
+        } else if(strcmp(argv[i], "--timeout") == 0) {
+            target = &opts -> timeout_ms;
+        } else if(strcmp(argv[i], "--cpu-time") == 0) {
+            target = &opts -> cpu_time_ms;
+        } else if(strcmp(argv[i], "--memory") == 0) {
+            target = &opts -> mem_default_len;
+        } else if(strcmp(argv[i], "--max-memory") == 0) {
+            target = &opts -> mem_max_len;
+        } else if(strcmp(argv[i], "--max-slots") == 0) {
+            target = &opts -> max_slots;
+        } else if(strcmp(argv[i], "--stack-len") == 0) {
+            target = &opts -> stack_len;
+        } else if(strcmp(argv[i], "--call-stack-len") == 0) {
+            target = &opts -> call_stack_len;
+        } else if(strcmp(argv[i], "--report-fd") == 0) {
+            target = &opts -> report_fd;
+        } else if(strcmp(argv[i], "--profile-fd") == 0) {
+            target = &opts -> profile_fd;
+        } else if(strcmp(argv[i], "--profile-interval") == 0) {
+            target = &opts -> profile_interval;
+        } else if(strcmp(argv[i], "--core-fd") == 0) {
+            target = &opts -> core_fd;
+        } else if(strcmp(argv[i], "--record-fd") == 0) {
+            target = &opts -> record_fd;
+        } else if(strcmp(argv[i], "--replay-fd") == 0) {
+            target = &opts -> replay_fd;
+        } else if(strcmp(argv[i], "--manifest-fd") == 0) {
+            target = &opts -> manifest_fd;
+        } else if(strcmp(argv[i], "--trace-fd") == 0) {
+            target = &opts -> trace_fd;
+        } else if(strcmp(argv[i], "--restore-fd") == 0) {
+            target = &opts -> restore_fd;
+        } else if(strcmp(argv[i], "--checkpoint-fd") == 0) {
+            target = &opts -> checkpoint_fd;
+        } else {
+            return -1;
+        }
+
+        *target = strtoull(argv[i + 1], &end, 10);
+        if(*end) return -1;
+
+        // 0 would mean unlimited.
+        if(target == &opts -> fuel && *target == 0) return -1;
+        i += 2;
+    }
+
+    // A checkpoint brings its code, so arguments start right away.
+    if(opts -> restore_fd != ULLONG_MAX) {
+        if(opts -> n_entry_args || opts -> wasm_entry) return -1;
+    } else if(i >= argc) {
+        return -1;
+    }
+
+    // Same defaults as resolve_resource_limits in the kernel module.
+    if(opts -> mem_default_len == 0) {
+        opts -> mem_default_len = opts -> mem_max_len && opts -> mem_max_len < DEFAULT_MEM_DEFAULT_LEN
+            ? opts -> mem_max_len
+            : DEFAULT_MEM_DEFAULT_LEN;
+    }
+    if(opts -> mem_max_len == 0) {
+        opts -> mem_max_len = opts -> mem_default_len > DEFAULT_MEM_MAX_LEN
+            ? opts -> mem_default_len
+            : DEFAULT_MEM_MAX_LEN;
+    }
+    if(opts -> mem_default_len > opts -> mem_max_len) return -1;
+
+    return i;
+}
+
+int main(int argc, char **argv) {
+    int ret, code_arg;
+    struct run_options opts;
+
+    memset(&opts, 0, sizeof(opts));
+    opts.max_slots = DEFAULT_MAX_SLOTS;
+    opts.stack_len = DEFAULT_STACK_LEN;
+    opts.call_stack_len = DEFAULT_CALL_STACK_LEN;
+    opts.report_fd = ULLONG_MAX;
+    opts.profile_fd = ULLONG_MAX;
+    opts.core_fd = ULLONG_MAX;
+    opts.record_fd = ULLONG_MAX;
+    opts.replay_fd = ULLONG_MAX;
+    opts.manifest_fd = ULLONG_MAX;
+    opts.trace_fd = ULLONG_MAX;
+    opts.restore_fd = ULLONG_MAX;
+    opts.checkpoint_fd = ULLONG_MAX;
+    opts.profile_interval = DEFAULT_PROFILE_INTERVAL;
+
+    if(argc < 2) {
+        print_usage();
+        return 1;
+    }
+
+    if(install_signal_handlers()) {
+        fprintf(stderr, "cervus-hosted: unable to install signal handlers\n");
+        return 1;
+    }
+
+    ret = cervus_global_init();
+    if(ret) {
+        fprintf(stderr, "cervus-hosted: global initialization failed with code %d\n", ret);
+        return 1;
+    }
+
+    if(strcmp(argv[1], "--map-api") == 0) {
+        ret = serve_map_api();
+    } else if(strcmp(argv[1], "--list-api") == 0) {
+        ret = list_api();
+    } else if(strcmp(argv[1], "--run") == 0 && (code_arg = parse_run_options(argc, argv, &opts)) > 0) {
+        if(opts.restore_fd != ULLONG_MAX) {
+            ret = run_code(NULL, &opts, argc - code_arg, argv + code_arg);
+        } else {
+            ret = run_code(argv[code_arg], &opts, argc - code_arg - 1, argv + code_arg + 1);
+        }
+    } else {
+        print_usage();
+        ret = 1;
+    }
+
+    cervus_global_cleanup();
+    return ret;
+}
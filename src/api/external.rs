@@ -0,0 +1,182 @@
+//! Native invokes registered at runtime by other kernel modules, through
+//! `cervus_register_native_invoke` in the glue code.
+
+use alloc::String;
+use alloc::arc::Arc;
+use backend::common::*;
+use env::UsermodeContext;
+use linux;
+
+use super::{NativeInvoke, check_len};
+
+/// Must match `struct cervus_native_invoke` in `glue/cervus.h`.
+#[repr(C)]
+pub struct RawNativeInvoke {
+    name: *const u8,
+    n_args: u32,
+    output_base_arg: i32,
+    output_len_arg: i32,
+    call: Option<unsafe extern "C" fn(
+        priv_data: *mut u8,
+        args: *const i64,
+        mem: *mut u8,
+        mem_len: usize
+    ) -> i64>,
+    priv_data: *mut u8,
+    owner: *mut u8
+}
+
+// Returned to the glue code, which turns them into errno values.
+pub const REGISTER_EXISTS: i32 = -1;
+pub const REGISTER_INVALID: i32 = -2;
+pub const REGISTER_FAILED: i32 = -3;
+
+pub struct ExternalInvoke {
+    name: String,
+    n_args: usize,
+    output: Option<(usize, usize)>,
+    call: unsafe extern "C" fn(*mut u8, *const i64, *mut u8, usize) -> i64,
+    priv_data: *mut u8,
+
+    /// `struct module *` of the provider, pinned by each `InvokeRef` to this
+    owner: *mut u8
+}
+
+/// Keeps the module providing an external native invoke or scheme loaded.
+pub struct ModuleRef {
+    module: *mut u8
+}
+
+impl ModuleRef {
+    /// Returns `None` if the module is being unloaded.
+    ///
+    /// The registry lock must be held while calling this. Providers unregister
+    /// before they unload, so the module can't be gone yet.
+    pub unsafe fn get(module: *mut u8) -> Option<ModuleRef> {
+        if linux::lapi_module_get(module) == 0 {
+            None
+        } else {
+            Some(ModuleRef {
+                module: module
+            })
+        }
+    }
+}
+
+impl Drop for ModuleRef {
+    fn drop(&mut self) {
+        unsafe { linux::lapi_module_put(self.module) };
+    }
+}
+
+// The provider is required to make `call` safe to use from any task.
+unsafe impl Send for ExternalInvoke {}
+unsafe impl Sync for ExternalInvoke {}
+
+impl ExternalInvoke {
+    unsafe fn from_raw(raw: &RawNativeInvoke, name_len: usize) -> Option<ExternalInvoke> {
+        if raw.name.is_null() || name_len == 0 {
+            return None;
+        }
+
+        let name = ::core::slice::from_raw_parts(raw.name, name_len);
+        let name = ::core::str::from_utf8(name).ok()?;
+
+        // Names are listed one per line and separated by NUL in batch lookups.
+        if name.bytes().any(|b| b <= b' ') {
+            return None;
+        }
+
+        Some(ExternalInvoke {
+            name: name.into(),
+            n_args: raw.n_args as usize,
+            output: if raw.output_base_arg >= 0 && raw.output_len_arg >= 0 {
+                Some((raw.output_base_arg as usize, raw.output_len_arg as usize))
+            } else {
+                None
+            },
+            call: raw.call?,
+            priv_data: raw.priv_data,
+            owner: raw.owner
+        })
+    }
+
+    /// See `ModuleRef::get`.
+    pub unsafe fn pin(&self) -> Option<ModuleRef> {
+        ModuleRef::get(self.owner)
+    }
+}
+
+impl NativeInvoke for ExternalInvoke {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn policy(&self) -> NativeInvokePolicy {
+        NativeInvokePolicy {
+            n_args: self.n_args
+        }
+    }
+
+    fn output_args(&self) -> Option<(usize, usize)> {
+        self.output
+    }
+
+    fn returns_output_len(&self) -> bool {
+        false
+    }
+
+    // Without a declared output region the call may write anywhere.
+    fn replayable(&self) -> bool {
+        self.output.is_some()
+    }
+
+    fn string_args(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    fn call(&self, _ctx: &mut UsermodeContext, args: &[i64], mem: &mut [u8]) -> BackendResult<Option<i64>> {
+        check_len(args, self.n_args)?;
+
+        // The caller's `InvokeRef` keeps the provider loaded.
+        let ret = unsafe {
+            (self.call)(
+                self.priv_data,
+                if args.len() == 0 { ::core::ptr::null() } else { &args[0] },
+                if mem.len() == 0 { ::core::ptr::null_mut() } else { &mut mem[0] },
+                mem.len()
+            )
+        };
+
+        Ok(Some(ret))
+    }
+}
+
+/// Registers a native invoke, returning its id or a `REGISTER_*` error.
+#[no_mangle]
+pub unsafe extern "C" fn cervus_ext_register_native_invoke(raw: *const RawNativeInvoke, name_len: usize) -> i32 {
+    let ni = match ExternalInvoke::from_raw(&*raw, name_len) {
+        Some(v) => v,
+        None => return REGISTER_INVALID
+    };
+
+    match ::global::get_global().native_invoke_registry.register_external(Arc::new(ni)) {
+        Ok(Some(id)) => id as i32,
+        Ok(None) => REGISTER_EXISTS,
+        Err(_) => REGISTER_FAILED
+    }
+}
+
+/// Unregisters a native invoke. Applications calling it from now on get `InvalidNativeInvoke`.
+#[no_mangle]
+pub unsafe extern "C" fn cervus_ext_unregister_native_invoke(id: i32) -> i32 {
+    if id < 0 {
+        return REGISTER_INVALID;
+    }
+
+    match ::global::get_global().native_invoke_registry.unregister_external(id as usize) {
+        Ok(true) => 0,
+        Ok(false) => REGISTER_INVALID,
+        Err(_) => REGISTER_FAILED
+    }
+}
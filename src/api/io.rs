@@ -1,13 +1,8 @@
-use linux;
-
 impl_ni_common!(
     io_get_stdin,
     n_args = 0,
     (ctx, _args, _mem) => {
-        let kctx = ctx.kctx;
-        Ok(Some(unsafe {
-            ctx.add_raw_linux_file(linux::lapi_env_get_stdin(kctx), false)
-        } as i64))
+        Ok(Some(ctx.open_stdio(0) as i64))
     }
 );
 
@@ -15,10 +10,7 @@ impl_ni_common!(
     io_get_stdout,
     n_args = 0,
     (ctx, _args, _mem) => {
-        let kctx = ctx.kctx;
-        Ok(Some(unsafe {
-            ctx.add_raw_linux_file(linux::lapi_env_get_stdout(kctx), false)
-        } as i64))
+        Ok(Some(ctx.open_stdio(1) as i64))
     }
 );
 
@@ -26,9 +18,6 @@ impl_ni_common!(
     io_get_stderr,
     n_args = 0,
     (ctx, _args, _mem) => {
-        let kctx = ctx.kctx;
-        Ok(Some(unsafe {
-            ctx.add_raw_linux_file(linux::lapi_env_get_stderr(kctx), false)
-        } as i64))
+        Ok(Some(ctx.open_stdio(2) as i64))
     }
 );
@@ -5,6 +5,7 @@ use ipc::broadcast;
 impl_ni_common!(
     ipc_create_broadcast,
     n_args = 2,
+    string = (0, 1),
     (ctx, args, mem) => {
         let name_begin = args[0] as u32 as usize;
         let name_len = args[1] as u32 as usize;
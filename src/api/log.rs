@@ -3,6 +3,7 @@ use super::*;
 impl_ni_common!(
     log_write,
     n_args = 3,
+    string = (1, 2),
     (ctx, args, mem) => {
         let level = args[0] as i32;
         let text_base = args[1] as u32 as usize;
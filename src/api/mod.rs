@@ -1,13 +1,29 @@
 macro_rules! impl_ni_common {
     ($name:ident, n_args = $n_args:expr, ($ctx_ident:ident, $args_ident:ident, $mem_ident:ident) => $call_blk:block) => {
+        impl_ni_common!(@impl $name, $n_args, None, false, None, ($ctx_ident, $args_ident, $mem_ident) => $call_blk);
+    };
+    ($name:ident, n_args = $n_args:expr, output = ($base:expr, $len:expr), ($ctx_ident:ident, $args_ident:ident, $mem_ident:ident) => $call_blk:block) => {
+        impl_ni_common!(@impl $name, $n_args, Some(($base, $len)), false, None, ($ctx_ident, $args_ident, $mem_ident) => $call_blk);
+    };
+    ($name:ident, n_args = $n_args:expr, output = ($base:expr, $len:expr), returns_len, ($ctx_ident:ident, $args_ident:ident, $mem_ident:ident) => $call_blk:block) => {
+        impl_ni_common!(@impl $name, $n_args, Some(($base, $len)), true, None, ($ctx_ident, $args_ident, $mem_ident) => $call_blk);
+    };
+    ($name:ident, n_args = $n_args:expr, string = ($sbase:expr, $slen:expr), ($ctx_ident:ident, $args_ident:ident, $mem_ident:ident) => $call_blk:block) => {
+        impl_ni_common!(@impl $name, $n_args, None, false, Some(($sbase, $slen)), ($ctx_ident, $args_ident, $mem_ident) => $call_blk);
+    };
+    (@impl $name:ident, $n_args:expr, $output:expr, $returns_len:expr, $string:expr, ($ctx_ident:ident, $args_ident:ident, $mem_ident:ident) => $call_blk:block) => {
         #[allow(non_camel_case_types)]
         pub struct $name;
 
         impl ::api::NativeInvoke for $name {
-            fn name(&self) -> &'static str { stringify!($name) }
+            fn name(&self) -> &str { stringify!($name) }
             fn policy(&self) -> ::backend::common::NativeInvokePolicy { ::backend::common::NativeInvokePolicy {
                 n_args: $n_args
             } }
+            fn output_args(&self) -> Option<(usize, usize)> { $output }
+            fn returns_output_len(&self) -> bool { $returns_len }
+            fn replayable(&self) -> bool { true }
+            fn string_args(&self) -> Option<(usize, usize)> { $string }
             fn call(
                 &self,
                 $ctx_ident: &mut ::env::UsermodeContext,
@@ -28,28 +44,76 @@ mod startup;
 mod resource;
 mod io;
 mod ipc;
+mod process;
+pub mod external;
 
-use alloc::BTreeMap;
+use alloc::{BTreeMap, String};
 use alloc::boxed::Box;
+use alloc::arc::Arc;
 use alloc::Vec;
+use core::ops::Deref;
 use backend::common::*;
 use env::UsermodeContext;
+use mutex::Mutex;
+use error::*;
+use self::external::{ExternalInvoke, ModuleRef};
 
 pub trait NativeInvoke: Send + Sync + 'static {
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
     fn policy(&self) -> NativeInvokePolicy;
+
+    /// Indices of the arguments holding the base and length of the linear
+    /// memory region the call writes to, if any.
+    fn output_args(&self) -> Option<(usize, usize)>;
+
+    /// Whether a non-negative return value is the number of bytes written
+    /// to the start of the output region, rather than the whole region.
+    fn returns_output_len(&self) -> bool;
+
+    /// Whether everything the call writes to linear memory is known, so
+    /// that it can be replayed from an invoke log.
+    fn replayable(&self) -> bool;
+
+    /// Indices of the arguments holding the base and length of a string
+    /// the call reads from linear memory, if any. Only used for tracing.
+    fn string_args(&self) -> Option<(usize, usize)>;
     fn call(&self, context: &mut UsermodeContext, args: &[i64], mem: &mut [u8]) -> BackendResult<Option<i64>>;
 }
 
-#[derive(Default)]
 pub struct Registry {
     targets: Vec<Box<NativeInvoke>>,
-    name_mappings: BTreeMap<&'static str, u32>,
+    name_mappings: BTreeMap<String, u32>,
+
+    /// Registered by other kernel modules, with ids following the built-in ones.
+    /// Unregistered slots are not reused, so that ids in loaded code stay valid.
+    external: Mutex<Vec<Option<Arc<ExternalInvoke>>>>
+}
+
+/// A registered native invoke. External ones and their providers are kept
+/// alive while referenced.
+pub enum InvokeRef {
+    Builtin(&'static NativeInvoke),
+    External(Arc<ExternalInvoke>, ModuleRef)
+}
+
+impl Deref for InvokeRef {
+    type Target = NativeInvoke;
+
+    fn deref(&self) -> &NativeInvoke {
+        match *self {
+            InvokeRef::Builtin(v) => v,
+            InvokeRef::External(ref v, _) => &**v
+        }
+    }
 }
 
 impl Registry {
-    pub fn new() -> Registry {
-        let mut reg = Registry::default();
+    pub fn new() -> KernelResult<Registry> {
+        let mut reg = Registry {
+            targets: Vec::new(),
+            name_mappings: BTreeMap::new(),
+            external: Mutex::new(Vec::new())?
+        };
 
         reg.register(runtime::runtime_spec_major);
         reg.register(runtime::runtime_spec_minor);
@@ -67,28 +131,101 @@ impl Registry {
         reg.register(io::io_get_stdout);
         reg.register(io::io_get_stderr);
 
-        reg
+        // Ids are stored in translated code and invoke logs, so new built-in
+        // native invokes are only ever appended.
+        reg.register(runtime::runtime_exit);
+        reg.register(runtime::runtime_yield);
+        reg.register(runtime::runtime_memory_usage);
+        reg.register(process::process_spawn);
+        reg.register(resource::resource_poll);
+        reg.register(resource::resource_set_options);
+        reg.register(process::process_wait);
+        reg.register(resource::resource_pipe);
+
+        Ok(reg)
     }
 
     pub fn map_name_to_id(&self, name: &str) -> Option<u32> {
-        self.name_mappings.get(name).map(|v| *v)
+        if let Some(id) = self.name_mappings.get(name) {
+            return Some(*id);
+        }
+
+        let external = self.external.lock().ok()?;
+        external.iter()
+            .position(|v| v.as_ref().map(|v| v.name() == name).unwrap_or(false))
+            .map(|i| (self.targets.len() + i) as u32)
     }
 
-    pub fn get(&self, id: usize) -> BackendResult<&NativeInvoke> {
-        if id >= self.targets.len() {
-            Err(BackendError::InvalidNativeInvoke)
-        } else {
-            Ok(&*self.targets[id])
+    pub fn get(&'static self, id: usize) -> BackendResult<InvokeRef> {
+        if id < self.targets.len() {
+            return Ok(InvokeRef::Builtin(&*self.targets[id]));
+        }
+
+        match self.external.lock()?.get(id - self.targets.len()) {
+            Some(&Some(ref v)) => match unsafe { v.pin() } {
+                Some(m) => Ok(InvokeRef::External(v.clone(), m)),
+
+                // The provider is being unloaded.
+                None => Err(BackendError::InvalidNativeInvoke)
+            },
+            _ => Err(BackendError::InvalidNativeInvoke)
         }
     }
 
+    /// Returns every registered native invoke with its id, in id order.
+    pub fn list(&'static self) -> KernelResult<Vec<(u32, InvokeRef)>> {
+        let mut ret: Vec<(u32, InvokeRef)> = self.targets.iter()
+            .enumerate()
+            .map(|(i, v)| (i as u32, InvokeRef::Builtin(&**v)))
+            .collect();
+
+        let external = self.external.lock()?;
+        for (i, v) in external.iter().enumerate() {
+            if let Some(ref v) = *v {
+                if let Some(m) = unsafe { v.pin() } {
+                    ret.push(((self.targets.len() + i) as u32, InvokeRef::External(v.clone(), m)));
+                }
+            }
+        }
+
+        Ok(ret)
+    }
+
     fn register<T: NativeInvoke>(&mut self, ni: T) {
-        let name = ni.name();
+        let name: String = ni.name().into();
         let id = self.targets.len() as u32;
 
         self.targets.push(Box::new(ni));
         self.name_mappings.insert(name, id);
     }
+
+    /// Returns the id of the new native invoke, or `None` if the name is taken.
+    pub fn register_external(&self, ni: Arc<ExternalInvoke>) -> KernelResult<Option<u32>> {
+        if self.name_mappings.contains_key(ni.name()) {
+            return Ok(None);
+        }
+
+        let mut external = self.external.lock()?;
+        if external.iter().any(|v| v.as_ref().map(|v| v.name() == ni.name()).unwrap_or(false)) {
+            return Ok(None);
+        }
+
+        external.push(Some(ni));
+        Ok(Some((self.targets.len() + external.len() - 1) as u32))
+    }
+
+    /// Returns whether `id` was a registered external native invoke.
+    pub fn unregister_external(&self, id: usize) -> KernelResult<bool> {
+        if id < self.targets.len() {
+            return Ok(false);
+        }
+
+        let mut external = self.external.lock()?;
+        Ok(match external.get_mut(id - self.targets.len()) {
+            Some(slot) => slot.take().is_some(),
+            None => false
+        })
+    }
 }
 
 fn check_len<T>(a: &[T], expected: usize) -> BackendResult<()> {
@@ -0,0 +1,109 @@
+use super::*;
+use alloc::Vec;
+use error::CwaError;
+use resource::{Resource, SharedResource};
+
+/// Largest module that `process_spawn` will load.
+const MAX_CODE_LEN: usize = 16 * 1048576;
+
+fn read_to_end(res: &mut Resource) -> BackendResult<Result<Vec<u8>, CwaError>> {
+    let mut code: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let n = match res.read(&mut buf)? {
+            Ok(n) => n,
+            Err(_) => return Ok(Err(CwaError::Unknown))
+        };
+        if n == 0 {
+            return Ok(Ok(code));
+        }
+        if code.len() + n > MAX_CODE_LEN {
+            return Ok(Err(CwaError::InvalidArgument));
+        }
+        code.extend_from_slice(&buf[0..n]);
+    }
+}
+
+// Starts the WebAssembly module at a URL as a child instance, which runs
+// concurrently on a thread of its own from its `__app_main` export.
+//
+// Arguments are NUL-separated, optionally with a NUL after the last one.
+// Each stdio argument is the id of a resource to pass to the child, or -1 to
+// pass on our own. Passed resources stay open in the parent as well.
+//
+// Returns the process id of the child, or a negative error status if it
+// could not be started.
+impl_ni_common!(
+    process_spawn,
+    n_args = 7,
+    string = (0, 1),
+    (ctx, args, mem) => {
+        let url_base = args[0] as u32 as usize;
+        let url_len = args[1] as u32 as usize;
+        let args_base = args[2] as u32 as usize;
+        let args_len = args[3] as u32 as usize;
+
+        let child_args: Vec<Vec<u8>> = if args_len == 0 {
+            Vec::new()
+        } else {
+            let data = mem.checked_slice(args_base, args_base + args_len)?;
+
+            // A terminating NUL doesn't start another argument.
+            let data = match data.split_last() {
+                Some((&0, rest)) => rest,
+                _ => data
+            };
+
+            data.split(|c| *c == 0)
+                .map(|v| v.to_vec())
+                .collect()
+        };
+
+        let code = {
+            let u = mem.extract_str(url_base, url_len)?;
+            let u = match ::url::Url::parse(u) {
+                Ok(v) => v,
+                Err(e) => return Ok(Some(e.status() as i64))
+            };
+            let mut res = match u.open(ctx.kctx, ctx.manifest())? {
+                Ok(v) => v,
+                Err(e) => return Ok(Some(e.status() as i64))
+            };
+            match read_to_end(&mut *res)? {
+                Ok(v) => v,
+                Err(e) => return Ok(Some(e.status() as i64))
+            }
+        };
+
+        let mut stdio: [Option<SharedResource>; 3] = [None, None, None];
+        for i in 0..3 {
+            let id = args[4 + i] as i32;
+            if id >= 0 {
+                stdio[i] = Some(SharedResource::share(ctx.resources.get_mut(id as usize)?)?);
+            }
+        }
+
+        Ok(Some(match ctx.spawn(&code, child_args, stdio)? {
+            Ok(pid) => pid as i64,
+            Err(e) => e.status() as i64
+        }))
+    }
+);
+
+// Waits for child `pid` to terminate.
+//
+// Returns `(kind << 32) | code` as in the termination report. A child can
+// only be waited for once.
+impl_ni_common!(
+    process_wait,
+    n_args = 1,
+    (ctx, args, _mem) => {
+        let pid = args[0] as u32 as usize;
+
+        Ok(Some(match ctx.wait_child(pid)? {
+            Some(report) => ((report.kind as i64) << 32) | (report.code as u32 as i64),
+            None => CwaError::InvalidArgument.status() as i64
+        }))
+    }
+);
@@ -1,18 +1,50 @@
 use super::*;
+use alloc::boxed::Box;
 use error::CwaError;
+use resource::{IoError, IoOptions, POLL_READ, POLL_WRITE, POLL_INVALID};
+use sync::poll_until;
+
+/// `{ id: u32, interest: u32, ready: u32 }`
+const POLL_ENTRY_LEN: usize = 12;
+const MAX_POLL_ENTRIES: usize = 256;
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
+
+fn write_u32(out: &mut [u8], v: u32) {
+    for i in 0..4 {
+        out[i] = (v >> (i * 8)) as u8;
+    }
+}
+
+/// Non-blocking mode for `resource_set_options`
+const OPTION_NONBLOCK: u32 = 1;
+
+fn io_error_status(e: IoError) -> i64 {
+    match e {
+        IoError::WouldBlock => CwaError::WouldBlock.status() as i64,
+        IoError::TimedOut => CwaError::TimedOut.status() as i64,
+        _ => CwaError::Unknown.status() as i64
+    }
+}
 
 impl_ni_common!(
     resource_read,
     n_args = 3,
+    output = (1, 2), returns_len,
     (ctx, args, mem) => {
         let id = args[0] as u32 as usize;
         let mem_begin = args[1] as u32 as usize;
         let len = args[2] as u32 as usize;
 
         let out = mem.checked_slice_mut(mem_begin, mem_begin + len)?;
+        if let Err(e) = ctx.wait_io_ready(id, POLL_READ)? {
+            return Ok(Some(io_error_status(e)));
+        }
         ctx.resources.get_mut(id)?.read(out)?
             .map(|n| Some(n as i64))
-            .or_else(|_| Ok(Some(CwaError::Unknown.status() as i64)))
+            .or_else(|e| Ok(Some(io_error_status(e))))
     }
 );
 
@@ -25,15 +57,19 @@ impl_ni_common!(
         let len = args[2] as u32 as usize;
 
         let data = mem.checked_slice(mem_begin, mem_begin + len)?;
+        if let Err(e) = ctx.wait_io_ready(id, POLL_WRITE)? {
+            return Ok(Some(io_error_status(e)));
+        }
         ctx.resources.get_mut(id)?.write(data)?
             .map(|n| Some(n as i64))
-            .or_else(|_| Ok(Some(CwaError::Unknown.status() as i64)))
+            .or_else(|e| Ok(Some(io_error_status(e))))
     }
 );
 
 impl_ni_common!(
     resource_open,
     n_args = 2,
+    string = (0, 1),
     (ctx, args, mem) => {
         let url_base = args[0] as u32 as usize;
         let url_len = args[1] as u32 as usize;
@@ -42,8 +78,16 @@ impl_ni_common!(
 
         Ok(Some(match ::url::Url::parse(u) {
             Ok(u) => {
-                match u.open(ctx.kctx)? {
-                    Ok(f) => ctx.add_resource(f) as i64,
+                let options = match u.io_options() {
+                    Ok(v) => v,
+                    Err(e) => return Ok(Some(e.status() as i64))
+                };
+                match u.open(ctx.kctx, ctx.manifest())? {
+                    Ok(f) => {
+                        let id = ctx.add_resource(f);
+                        ctx.set_io_options(id, options)?;
+                        id as i64
+                    },
                     Err(e) => e.status() as i64
                 }
             },
@@ -62,3 +106,96 @@ impl_ni_common!(
         Ok(None)
     }
 );
+
+// Sets the options of a resource: `flags` may contain `OPTION_NONBLOCK`, and
+// a non-negative `timeout_ms` bounds how long reads and writes wait.
+impl_ni_common!(
+    resource_set_options,
+    n_args = 3,
+    (ctx, args, _mem) => {
+        let id = args[0] as u32 as usize;
+        let flags = args[1] as u32;
+        let timeout_ms = args[2] as i32;
+
+        if flags & !OPTION_NONBLOCK != 0 {
+            return Ok(Some(CwaError::InvalidArgument.status() as i64));
+        }
+
+        ctx.set_io_options(id, IoOptions {
+            nonblock: flags & OPTION_NONBLOCK != 0,
+            timeout_ms: if timeout_ms >= 0 { Some(timeout_ms as u64) } else { None }
+        })?;
+
+        Ok(Some(0))
+    }
+);
+
+// Takes an array of poll entries (`id`, `interest` and `ready`, all u32) and
+// waits until at least one of the resources is ready or `timeout_ms` passes.
+// A negative timeout waits forever.
+//
+// Writes the ready flags of each entry and returns the number of ready
+// entries, 0 on timeout.
+impl_ni_common!(
+    resource_poll,
+    n_args = 3,
+    output = (0, 1),
+    (ctx, args, mem) => {
+        let mem_begin = args[0] as u32 as usize;
+        let len = args[1] as u32 as usize;
+        let timeout_ms = args[2] as i32;
+
+        if len % POLL_ENTRY_LEN != 0 || len / POLL_ENTRY_LEN > MAX_POLL_ENTRIES {
+            return Ok(Some(CwaError::InvalidArgument.status() as i64));
+        }
+        let entries = mem.checked_slice_mut(mem_begin, mem_begin + len)?;
+        let wait_ms = ctx.clamp_wait_ms(if timeout_ms >= 0 { Some(timeout_ms as u64) } else { None });
+        let mut n_ready: i64 = 0;
+
+        {
+            let resources = &mut ctx.resources;
+            poll_until(wait_ms, |poller| {
+                n_ready = 0;
+
+                for entry in entries.chunks_mut(POLL_ENTRY_LEN) {
+                    let id = read_u32(&entry[0..4]) as usize;
+                    let interest = read_u32(&entry[4..8]);
+
+                    let ready = match resources.get_mut(id) {
+                        Ok(res) => res.poll_ready(poller, interest)?,
+                        Err(_) => POLL_INVALID
+                    };
+                    write_u32(&mut entry[8..12], ready);
+
+                    if ready != 0 {
+                        n_ready += 1;
+                    }
+                }
+
+                Ok(n_ready > 0)
+            })?;
+        }
+
+        if n_ready == 0 {
+            ctx.check_limits()?;
+        }
+
+        Ok(Some(n_ready))
+    }
+);
+
+// Creates a pipe, usually passed to `process_spawn` to connect instances.
+// Reads return end of file once every copy of the write end is closed.
+//
+// Returns `(read_id << 32) | write_id`.
+impl_ni_common!(
+    resource_pipe,
+    n_args = 0,
+    (ctx, _args, _mem) => {
+        let (reader, writer) = ::ipc::pipe::new()?;
+        let read_id = ctx.add_resource(Box::new(reader));
+        let write_id = ctx.add_resource(Box::new(writer));
+
+        Ok(Some(((read_id as i64) << 32) | (write_id as u32 as i64)))
+    }
+);
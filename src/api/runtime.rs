@@ -21,6 +21,7 @@ impl_ni_common!(
 impl_ni_common!(
     runtime_name,
     n_args = 2,
+    output = (0, 1), returns_len,
     (_ctx, args, mem) => {
         static RT_NAME: &'static [u8] = b"Cervus";
 
@@ -43,13 +44,46 @@ impl_ni_common!(
     runtime_msleep,
     n_args = 1,
     (ctx, args, _mem) => {
-        let ms = args[0] as u32;
+        // Sleeping past the deadline would only delay the timeout.
+        let ms = ctx.clamp_wait_ms(Some(args[0] as u32 as u64)).unwrap_or(0) as u32;
         let ret = unsafe { linux::lapi_env_msleep(ctx.kctx, ms) };
 
         if ret < 0 {
             Err(BackendError::FatalSignal)
         } else {
+            ctx.check_limits()?;
             Ok(None)
         }
     }
 );
+
+impl_ni_common!(
+    runtime_exit,
+    n_args = 1,
+    (ctx, args, _mem) => {
+        ctx.set_exit_code(args[0] as i32);
+        Err(BackendError::Exit)
+    }
+);
+
+impl_ni_common!(
+    runtime_yield,
+    n_args = 0,
+    (ctx, _args, _mem) => {
+        let ret = unsafe { linux::lapi_env_yield(ctx.kctx) };
+
+        if ret < 0 {
+            Err(BackendError::FatalSignal)
+        } else {
+            Ok(None)
+        }
+    }
+);
+
+impl_ni_common!(
+    runtime_memory_usage,
+    n_args = 0,
+    (ctx, _args, _mem) => {
+        Ok(Some(ctx.memory_usage() as i64))
+    }
+);
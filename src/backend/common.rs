@@ -1,5 +1,7 @@
 use error::KernelError;
 
+pub use cervus_common::checkpoint::CheckpointState;
+
 #[derive(Copy, Clone, Debug)]
 #[repr(u8)]
 pub enum BackendError {
@@ -8,7 +10,20 @@ pub enum BackendError {
     InvalidNativeInvoke,
     NotFound,
     InvalidInput,
-    FatalSignal
+    FatalSignal,
+    Unsupported,
+    OutOfFuel,
+    Timeout,
+    CpuTimeout,
+
+    /// A replayed application made a native invoke that doesn't match the log.
+    ReplayMismatch,
+
+    /// Not an error: raised by `runtime_exit` to unwind the interpreter.
+    Exit,
+
+    /// Not an error: the application was stopped after writing a checkpoint.
+    Checkpointed
 }
 
 impl From<KernelError> for BackendError {
@@ -26,6 +41,96 @@ impl BackendError {
     pub fn status(&self) -> i32 {
         -(*self as u8 as i32)
     }
+
+    pub fn from_code(code: i64) -> Option<BackendError> {
+        Some(match code {
+            1 => BackendError::Generic,
+            2 => BackendError::Bounds,
+            3 => BackendError::InvalidNativeInvoke,
+            4 => BackendError::NotFound,
+            5 => BackendError::InvalidInput,
+            6 => BackendError::FatalSignal,
+            7 => BackendError::Unsupported,
+            8 => BackendError::OutOfFuel,
+            9 => BackendError::Timeout,
+            10 => BackendError::CpuTimeout,
+            11 => BackendError::ReplayMismatch,
+            12 => BackendError::Exit,
+            13 => BackendError::Checkpointed,
+            _ => return None
+        })
+    }
+
+    pub fn termination_kind(&self) -> TerminationKind {
+        match *self {
+            BackendError::Exit => TerminationKind::Exited,
+            BackendError::OutOfFuel | BackendError::Timeout | BackendError::CpuTimeout
+                => TerminationKind::ResourceExhausted,
+            BackendError::FatalSignal => TerminationKind::Killed,
+            BackendError::Checkpointed => TerminationKind::Checkpointed,
+            _ => TerminationKind::Trapped
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(i32)]
+pub enum TerminationKind {
+    Exited = 0,
+    Trapped = 1,
+    ResourceExhausted = 2,
+    Killed = 3,
+    Checkpointed = 4
+}
+
+/// How an application terminated, as reported to the loader.
+///
+/// `code` is the exit code for `Exited` and the `BackendError` discriminant otherwise.
+/// `value` is what the entry function returned, if `has_value` is not 0.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TerminationReport {
+    pub kind: TerminationKind,
+    pub code: i32,
+    pub has_value: i32,
+    pub value: i64
+}
+
+impl TerminationReport {
+    pub fn exited(code: i32) -> TerminationReport {
+        TerminationReport {
+            kind: TerminationKind::Exited,
+            code: code,
+            has_value: 0,
+            value: 0
+        }
+    }
+
+    /// The entry function returned `value`, or nothing if it is `None`.
+    pub fn returned(value: Option<i64>) -> TerminationReport {
+        TerminationReport {
+            has_value: value.is_some() as i32,
+            value: value.unwrap_or(0),
+            ..TerminationReport::exited(0)
+        }
+    }
+
+    pub fn from_error(e: BackendError) -> TerminationReport {
+        TerminationReport {
+            kind: e.termination_kind(),
+            code: e as u8 as i32,
+            has_value: 0,
+            value: 0
+        }
+    }
+
+    /// Exit status of the process running the application.
+    pub fn status(&self) -> i32 {
+        match self.kind {
+            TerminationKind::Exited => self.code,
+            _ => -self.code
+        }
+    }
 }
 
 pub type BackendResult<T> = Result<T, BackendError>;
@@ -34,11 +139,102 @@ pub trait Backend: Sized {
     type Config;
 
     fn new(config: Self::Config) -> BackendResult<Self>;
+
+    /// Runs `code`, passing `args` to its entry function, and returns the
+    /// value the entry function returned, if any.
     fn run<C: Context>(
         &mut self,
         code: &[u8],
+        args: &[i64],
+        context: &mut C
+    ) -> BackendResult<Option<i64>>;
+
+    /// Like `run`, but continues where `state` was checkpointed.
+    fn resume<C: Context>(
+        &mut self,
+        state: &CheckpointState,
         context: &mut C
-    ) -> BackendResult<()>;
+    ) -> BackendResult<Option<i64>>;
+}
+
+/// State of a trapped application, for core dumps.
+pub struct GuestState<'a> {
+    pub error: BackendError,
+
+    /// Offset of the opcode that trapped in the code
+    pub ip: usize,
+
+    pub memory: &'a [u8],
+    pub slots: &'a [i64],
+
+    /// The live parts of the stacks, bottom first
+    pub stack: &'a [i64],
+    pub call_stack: &'a [i64]
+}
+
+/// A request of the debugger attached to an application running in debug mode.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DebugRequest {
+    /// Stop and report where. Served with the current stop if stopped already.
+    Attach,
+
+    /// Remove the breakpoints and continue without a debugger
+    Detach,
+
+    /// Continue until the next breakpoint or interrupt, which is reported
+    /// when this request is finished
+    Continue,
+
+    /// Run one opcode, which is reported when this request is finished
+    Step,
+
+    /// Stop a running application. Not finished like the other requests.
+    Interrupt,
+
+    SetBreakpoint(usize),
+    ClearBreakpoint(usize),
+
+    /// Read at most `len` bytes at `offset` of `area`
+    Read { area: DebugArea, offset: usize, len: usize }
+}
+
+/// What `DebugRequest::Read` reads. Slots and stacks are read as
+/// little-endian 64-bit values.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DebugArea {
+    Memory,
+    Slots,
+    Stack,
+    CallStack,
+    Code
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(i64)]
+pub enum StopReason {
+    /// Waiting for a debugger before the first opcode
+    Started = 1,
+    Attached = 2,
+    Breakpoint = 3,
+    Step = 4,
+    Interrupted = 5
+}
+
+/// Where a stopped application is, reported with every finished debugger
+/// request. Must match `struct debug_stop` in the kernel module.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct DebugStop {
+    pub reason: StopReason,
+
+    /// Offset of the next opcode in the code
+    pub ip: i64,
+
+    pub stack_len: i64,
+    pub call_stack_len: i64,
+    pub n_slots: i64,
+    pub memory_len: i64,
+    pub code_len: i64
 }
 
 #[derive(Debug)]
@@ -50,4 +246,41 @@ pub trait Context {
     fn get_native_invoke_policy(&self, id: usize) -> BackendResult<NativeInvokePolicy>;
     fn do_native_invoke(&mut self, id: usize, args: &[i64], mem: &mut [u8]) -> BackendResult<Option<i64>>;
     fn tick(&self) -> BackendResult<()>;
+
+    /// Whether `tick` should be called without waiting for the next tick
+    /// interval, e.g. because the scheduler wants the CPU back.
+    fn tick_pending(&self) -> bool;
+
+    /// Reports the bytes currently allocated for linear memory, slots and stacks.
+    fn set_guest_memory_usage(&self, bytes: usize);
+
+    /// Number of branches between two profiler samples, or `None` if profiling is off.
+    fn profile_interval(&self) -> Option<u32>;
+    fn sample_branch(&self, target: usize);
+
+    /// Whether `write_core_dump` should be called when the application traps.
+    fn wants_core_dump(&self) -> bool;
+    fn write_core_dump(&self, state: &GuestState);
+
+    /// Whether a checkpoint has been requested. Checked on ticks.
+    fn checkpoint_pending(&self) -> bool;
+
+    /// Writes a checkpoint of `state` and the open resources. Fails with
+    /// `Checkpointed` if the application should stop after it.
+    fn write_checkpoint(&self, state: &CheckpointState) -> BackendResult<()>;
+
+    /// Whether the application runs under a debugger. Backends that support
+    /// it stop before the first opcode and check `debug_pending` before
+    /// every one after it.
+    fn debug_enabled(&self) -> bool;
+
+    /// Whether a debugger request is waiting to be taken.
+    fn debug_pending(&self) -> bool;
+
+    /// Takes the next debugger request, waiting for one if `wait` is set.
+    /// Fails with `FatalSignal` if the application is killed while waiting.
+    fn take_debug_request(&self, wait: bool) -> BackendResult<Option<DebugRequest>>;
+
+    /// Finishes the request taken last with `data`, or fails it with `None`.
+    fn finish_debug_request(&self, data: Option<&[u8]>, stop: &DebugStop);
 }
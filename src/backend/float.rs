@@ -0,0 +1,444 @@
+//! Floating point operations for the interpreter.
+//!
+//! hexagon-e has no floating point opcodes, so `cvctl` lowers them to native
+//! invokes with ids starting at `FLOAT_OP_BASE`. The backend executes those
+//! itself instead of passing them to the context. Values are passed by their
+//! bit patterns, in the low 32 bits for `f32`.
+//!
+//! Only the four basic arithmetic operations go through the soft-float
+//! routines; everything else works on the bit representation.
+
+use core::cmp::Ordering;
+use backend::common::*;
+
+pub use cervus_common::float_ops::{FLOAT_OP_BASE, n_args};
+
+/// Raised for conversions to integers that are out of range or NaN.
+const CONVERSION_TRAP: BackendError = BackendError::InvalidInput;
+
+#[derive(Copy, Clone)]
+struct Format {
+    mant_bits: u32,
+    exp_bits: u32
+}
+
+const F32: Format = Format { mant_bits: 23, exp_bits: 8 };
+const F64: Format = Format { mant_bits: 52, exp_bits: 11 };
+
+impl Format {
+    fn sign_bit(&self) -> u64 {
+        1 << (self.mant_bits + self.exp_bits)
+    }
+
+    fn exp_max(&self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    fn mant_mask(&self) -> u64 {
+        (1 << self.mant_bits) - 1
+    }
+
+    fn bias(&self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    fn quiet_bit(&self) -> u64 {
+        1 << (self.mant_bits - 1)
+    }
+
+    fn canonical_nan(&self) -> u64 {
+        (self.exp_max() << self.mant_bits) | self.quiet_bit()
+    }
+
+    fn infinity(&self, negative: bool) -> u64 {
+        (if negative { self.sign_bit() } else { 0 }) | (self.exp_max() << self.mant_bits)
+    }
+}
+
+enum Class {
+    Nan,
+    Infinite,
+    Zero,
+
+    /// The value is `sig * 2^exp`.
+    Finite(u64, i32)
+}
+
+fn is_negative(bits: u64, fmt: Format) -> bool {
+    bits & fmt.sign_bit() != 0
+}
+
+fn classify(bits: u64, fmt: Format) -> Class {
+    let exp = (bits >> fmt.mant_bits) & fmt.exp_max();
+    let mant = bits & fmt.mant_mask();
+
+    if exp == fmt.exp_max() {
+        if mant != 0 { Class::Nan } else { Class::Infinite }
+    } else if exp == 0 {
+        if mant == 0 {
+            Class::Zero
+        } else {
+            Class::Finite(mant, 1 - fmt.bias() - fmt.mant_bits as i32)
+        }
+    } else {
+        Class::Finite(mant | (1 << fmt.mant_bits), exp as i32 - fmt.bias() - fmt.mant_bits as i32)
+    }
+}
+
+/// Rounds `sig * 2^exp` to nearest, ties to even. `sticky` tells that the
+/// exact value is slightly above that; callers that set it must provide at
+/// least two bits more than the format keeps.
+fn compose(negative: bool, sig: u128, exp: i32, sticky: bool, fmt: Format) -> u64 {
+    let sign = if negative { fmt.sign_bit() } else { 0 };
+    if sig == 0 {
+        return sign;
+    }
+
+    let mant_bits = fmt.mant_bits as i32;
+    let min_exp = 1 - fmt.bias();
+    let top = 127 - sig.leading_zeros() as i32 + exp;
+
+    // Exponent of the lowest bit that is kept, fixed for subnormal results.
+    let mut unit = if top > min_exp { top } else { min_exp } - mant_bits;
+    let shift = unit - exp;
+
+    let mut q = if shift <= 0 {
+        sig << (-shift) as u32
+    } else if shift > 128 {
+        0
+    } else {
+        let (q, rem) = if shift == 128 {
+            (0, sig)
+        } else {
+            (sig >> shift as u32, sig & ((1u128 << shift as u32) - 1))
+        };
+        let half = 1u128 << (shift - 1) as u32;
+
+        if rem > half || (rem == half && (sticky || q & 1 == 1)) {
+            q + 1
+        } else {
+            q
+        }
+    };
+
+    // Rounding up may carry into a new bit.
+    if q >> (mant_bits + 1) as u32 != 0 {
+        q >>= 1;
+        unit += 1;
+    }
+
+    if q == 0 {
+        return sign;
+    }
+
+    let biased = if q >> mant_bits as u32 != 0 {
+        (unit + mant_bits + fmt.bias()) as u64
+    } else {
+        0
+    };
+    if biased >= fmt.exp_max() {
+        return fmt.infinity(negative);
+    }
+
+    sign | (biased << fmt.mant_bits) | (q as u64 & fmt.mant_mask())
+}
+
+fn compare(a: u64, b: u64, fmt: Format) -> Option<Ordering> {
+    match (classify(a, fmt), classify(b, fmt)) {
+        (Class::Nan, _) | (_, Class::Nan) => return None,
+        (Class::Zero, Class::Zero) => return Some(Ordering::Equal),
+        _ => {}
+    }
+
+    let (neg_a, neg_b) = (is_negative(a, fmt), is_negative(b, fmt));
+    let (mag_a, mag_b) = (a & !fmt.sign_bit(), b & !fmt.sign_bit());
+
+    Some(match (neg_a, neg_b) {
+        (false, true) => Ordering::Greater,
+        (true, false) => Ordering::Less,
+        (false, false) => mag_a.cmp(&mag_b),
+        (true, true) => mag_b.cmp(&mag_a)
+    })
+}
+
+fn min_max(a: u64, b: u64, fmt: Format, max: bool) -> u64 {
+    match compare(a, b, fmt) {
+        None => fmt.canonical_nan(),
+
+        // -0.0 is less than +0.0
+        Some(Ordering::Equal) => if max { a & b } else { a | b },
+        Some(Ordering::Greater) => if max { a } else { b },
+        Some(Ordering::Less) => if max { b } else { a }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum RoundMode {
+    Trunc,
+    Floor,
+    Ceil,
+    Nearest
+}
+
+fn round(bits: u64, fmt: Format, mode: RoundMode) -> u64 {
+    let negative = is_negative(bits, fmt);
+    let mag = bits & !fmt.sign_bit();
+    let e = ((bits >> fmt.mant_bits) & fmt.exp_max()) as i32 - fmt.bias();
+
+    if e >= fmt.mant_bits as i32 {
+        // Already integral, infinite or NaN
+        return match classify(bits, fmt) {
+            Class::Nan => bits | fmt.quiet_bit(),
+            _ => bits
+        };
+    }
+
+    if e < 0 {
+        if mag == 0 {
+            return bits;
+        }
+
+        let zero = bits & fmt.sign_bit();
+        let one = zero | ((fmt.bias() as u64) << fmt.mant_bits);
+
+        let away = match mode {
+            RoundMode::Trunc => false,
+            RoundMode::Floor => negative,
+            RoundMode::Ceil => !negative,
+
+            // Only values in (0.5, 1) round to 1.
+            RoundMode::Nearest => e == -1 && mag & fmt.mant_mask() != 0
+        };
+        return if away { one } else { zero };
+    }
+
+    let frac_mask = (1u64 << (fmt.mant_bits - e as u32)) - 1;
+    let frac = bits & frac_mask;
+    if frac == 0 {
+        return bits;
+    }
+
+    let truncated = bits & !frac_mask;
+    let away = match mode {
+        RoundMode::Trunc => false,
+        RoundMode::Floor => negative,
+        RoundMode::Ceil => !negative,
+        RoundMode::Nearest => {
+            let half = (frac_mask + 1) >> 1;
+            frac > half || (frac == half && truncated & (frac_mask + 1) != 0)
+        }
+    };
+
+    // Incrementing the magnitude may carry into the exponent, which is
+    // exactly the next power of two.
+    if away { truncated + frac_mask + 1 } else { truncated }
+}
+
+fn sqrt(bits: u64, fmt: Format) -> u64 {
+    let (sig, exp) = match classify(bits, fmt) {
+        Class::Nan => return bits | fmt.quiet_bit(),
+        Class::Zero => return bits,
+        _ if is_negative(bits, fmt) => return fmt.canonical_nan(),
+        Class::Infinite => return bits,
+        Class::Finite(sig, exp) => (sig, exp)
+    };
+
+    // Normalize subnormals and make the exponent even, so that the integer
+    // square root below has plenty of bits.
+    let lz = sig.leading_zeros() - (63 - fmt.mant_bits);
+    let (mut sig, mut exp) = ((sig << lz) as u128, exp - lz as i32);
+    if exp & 1 != 0 {
+        sig <<= 1;
+        exp -= 1;
+    }
+
+    let mut rem = sig << 64;
+    let mut root: u128 = 0;
+    let mut bit: u128 = 1 << 126;
+    while bit > rem {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+
+    compose(false, root, (exp - 64) / 2, rem != 0, fmt)
+}
+
+fn convert_format(bits: u64, from: Format, to: Format) -> u64 {
+    let negative = is_negative(bits, from);
+    let sign = if negative { to.sign_bit() } else { 0 };
+
+    match classify(bits, from) {
+        Class::Nan => {
+            let payload = bits & from.mant_mask();
+            let payload = if to.mant_bits > from.mant_bits {
+                payload << (to.mant_bits - from.mant_bits)
+            } else {
+                payload >> (from.mant_bits - to.mant_bits)
+            };
+            sign | to.canonical_nan() | payload
+        },
+        Class::Infinite => to.infinity(negative),
+        Class::Zero => sign,
+        Class::Finite(sig, exp) => compose(negative, sig as u128, exp, false, to)
+    }
+}
+
+/// Truncates towards zero to an integer of `width` bits, returned sign-extended
+/// from that width.
+fn to_int(bits: u64, fmt: Format, signed: bool, width: u32) -> BackendResult<i64> {
+    let negative = is_negative(bits, fmt);
+    let mag: u128 = match classify(bits, fmt) {
+        Class::Nan | Class::Infinite => return Err(CONVERSION_TRAP),
+        Class::Zero => 0,
+        Class::Finite(_, exp) if exp >= 64 => return Err(CONVERSION_TRAP),
+        Class::Finite(sig, exp) => if exp >= 0 {
+            (sig as u128) << exp as u32
+        } else if exp > -64 {
+            (sig >> (-exp) as u32) as u128
+        } else {
+            0
+        }
+    };
+
+    let value: i128 = if signed {
+        let limit = 1u128 << (width - 1);
+        if negative {
+            if mag > limit {
+                return Err(CONVERSION_TRAP);
+            }
+            -(mag as i128)
+        } else {
+            if mag >= limit {
+                return Err(CONVERSION_TRAP);
+            }
+            mag as i128
+        }
+    } else {
+        if (negative && mag != 0) || mag >= 1u128 << width {
+            return Err(CONVERSION_TRAP);
+        }
+        mag as i128
+    };
+
+    Ok(if width == 32 { value as i32 as i64 } else { value as i64 })
+}
+
+fn from_int(v: i64, signed: bool, width: u32, fmt: Format) -> u64 {
+    let (negative, mag) = match (signed, width) {
+        (true, 32) => ((v as i32) < 0, (v as i32 as i64).wrapping_abs() as u64),
+        (false, 32) => (false, v as u32 as u64),
+        (true, _) => (v < 0, v.wrapping_abs() as u64),
+        (false, _) => (false, v as u64)
+    };
+
+    compose(negative, mag as u128, 0, false, fmt)
+}
+
+fn arith32(a: u64, b: u64, op: u32) -> u64 {
+    let (a, b) = (f32::from_bits(a as u32), f32::from_bits(b as u32));
+    (match op {
+        0 => a + b,
+        1 => a - b,
+        2 => a * b,
+        _ => a / b
+    }).to_bits() as u64
+}
+
+fn arith64(a: u64, b: u64, op: u32) -> u64 {
+    let (a, b) = (f64::from_bits(a), f64::from_bits(b));
+    (match op {
+        0 => a + b,
+        1 => a - b,
+        2 => a * b,
+        _ => a / b
+    }).to_bits()
+}
+
+fn unary(v: u64, fmt: Format, op: usize) -> u64 {
+    match op {
+        0 => v & !fmt.sign_bit(),
+        1 => v ^ fmt.sign_bit(),
+        2 => round(v, fmt, RoundMode::Ceil),
+        3 => round(v, fmt, RoundMode::Floor),
+        4 => round(v, fmt, RoundMode::Trunc),
+        5 => round(v, fmt, RoundMode::Nearest),
+        _ => sqrt(v, fmt)
+    }
+}
+
+fn binary(a: u64, b: u64, fmt: Format, op: usize) -> u64 {
+    match op {
+        0..=3 => if fmt.exp_bits == F32.exp_bits {
+            arith32(a, b, op as u32)
+        } else {
+            arith64(a, b, op as u32)
+        },
+        4 => min_max(a, b, fmt, false),
+        5 => min_max(a, b, fmt, true),
+        _ => (a & !fmt.sign_bit()) | (b & fmt.sign_bit())
+    }
+}
+
+fn relation(a: u64, b: u64, fmt: Format, op: usize) -> bool {
+    let ord = compare(a, b, fmt);
+    match op {
+        0 => ord == Some(Ordering::Equal),
+        1 => ord != Some(Ordering::Equal),
+        2 => ord == Some(Ordering::Less),
+        3 => ord == Some(Ordering::Greater),
+        4 => ord == Some(Ordering::Less) || ord == Some(Ordering::Equal),
+        _ => ord == Some(Ordering::Greater) || ord == Some(Ordering::Equal)
+    }
+}
+
+/// Number of arguments taken by operation `id`, or `None` if `id` isn't one.
+///
+/// Runs operation `id` on `args`, which hold at least `n_args(id)` values.
+pub fn call(id: usize, args: &[i64]) -> BackendResult<i64> {
+    let op = id - FLOAT_OP_BASE;
+    let a = args[0] as u64;
+    let b = args.get(1).map(|v| *v as u64).unwrap_or(0);
+
+    let (fmt, sub) = if op < 20 { (F32, op) } else { (F64, op - 20) };
+    let a_narrow = if op < 20 { a as u32 as u64 } else { a };
+    let b_narrow = if op < 20 { b as u32 as u64 } else { b };
+
+    // 32-bit results are sign-extended, like other i32 values.
+    let float_result = |bits: u64| if fmt.exp_bits == F32.exp_bits {
+        bits as u32 as i32 as i64
+    } else {
+        bits as i64
+    };
+
+    Ok(match op {
+        0..=39 => match sub {
+            0..=6 => float_result(unary(a_narrow, fmt, sub)),
+            7..=13 => float_result(binary(a_narrow, b_narrow, fmt, sub - 7)),
+            _ => relation(a_narrow, b_narrow, fmt, sub - 14) as i64
+        },
+        40..=47 => {
+            let n = op - 40;
+            let (src, bits) = if n & 2 == 0 { (F32, a as u32 as u64) } else { (F64, a) };
+            to_int(bits, src, n & 1 == 0, if n < 4 { 32 } else { 64 })?
+        },
+        48..=55 => {
+            let n = op - 48;
+            let (signed, width) = (n & 1 == 0, if n & 2 == 0 { 32 } else { 64 });
+            if n < 4 {
+                from_int(args[0], signed, width, F32) as u32 as i32 as i64
+            } else {
+                from_int(args[0], signed, width, F64) as i64
+            }
+        },
+        56 => convert_format(a, F64, F32) as u32 as i32 as i64,
+        _ => convert_format(a as u32 as u64, F32, F64) as i64
+    })
+}
@@ -0,0 +1,104 @@
+//! Fuel accounting shared by the backends.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::arc::Arc;
+
+use backend::common::*;
+
+/// Fuel consumed by a native invoke, on top of the branch that led to it.
+pub const NATIVE_INVOKE_FUEL_COST: u64 = 100;
+
+/// Fuel taken from the shared budget at a time, so that instances running on
+/// other threads don't contend for it on every branch.
+const FUEL_BATCH: u64 = 10000;
+
+/// Fuel of one instance, taken in batches from a budget that may be shared
+/// with other instances.
+pub struct FuelMeter {
+    fuel: Option<Arc<AtomicUsize>>,
+
+    // Taken from `fuel` but not consumed yet
+    local: Cell<u64>
+}
+
+impl FuelMeter {
+    /// A meter for `fuel`, or an unlimited one if it is `None`.
+    pub fn new(fuel: Option<Arc<AtomicUsize>>) -> FuelMeter {
+        FuelMeter {
+            fuel: fuel,
+            local: Cell::new(0)
+        }
+    }
+
+    #[inline]
+    pub fn consume(&self, n: u64) -> BackendResult<()> {
+        if self.fuel.is_none() {
+            return Ok(());
+        }
+
+        let local = self.local.get();
+        if local >= n {
+            self.local.set(local - n);
+            Ok(())
+        } else {
+            self.refill(n)
+        }
+    }
+
+    /// Takes another batch from the shared budget and consumes `n` of it.
+    #[inline(never)]
+    fn refill(&self, n: u64) -> BackendResult<()> {
+        let fuel = match self.fuel {
+            Some(ref v) => v,
+            None => return Ok(())
+        };
+
+        let local = self.local.get();
+        let want = ::core::cmp::max(n - local, FUEL_BATCH) as usize;
+        let mut left = fuel.load(Ordering::SeqCst);
+        let taken = loop {
+            let taken = ::core::cmp::min(left, want);
+            match fuel.compare_exchange(left, left - taken, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break taken as u64,
+                Err(v) => left = v
+            }
+        };
+
+        if local + taken < n {
+            self.local.set(0);
+            return Err(BackendError::OutOfFuel);
+        }
+        self.local.set(local + taken - n);
+        Ok(())
+    }
+
+    /// Consumes up to `n` of the fuel taken already, without going to the
+    /// shared budget, and returns how much. Always `n` if unlimited.
+    pub fn consume_local(&self, n: u64) -> u64 {
+        if self.fuel.is_none() {
+            return n;
+        }
+
+        let local = self.local.get();
+        let taken = ::core::cmp::min(local, n);
+        self.local.set(local - taken);
+        taken
+    }
+
+    /// Returns `n` that was consumed but not used.
+    pub fn give_back(&self, n: u64) {
+        if self.fuel.is_some() {
+            self.local.set(self.local.get() + n);
+        }
+    }
+}
+
+impl Drop for FuelMeter {
+    fn drop(&mut self) {
+        // Leaves the rest of the batch to other instances.
+        if let Some(ref fuel) = self.fuel {
+            fuel.fetch_add(self.local.get() as usize, Ordering::SeqCst);
+        }
+    }
+}
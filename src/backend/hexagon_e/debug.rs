@@ -0,0 +1,206 @@
+//! Debugging support of the interpreter.
+//!
+//! Before every opcode, the application stops at breakpoints, after a step
+//! and when the debugger interrupts it. While stopped, it serves debugger
+//! requests until one of them continues it.
+
+use core::cell::{Cell, RefCell};
+use alloc::Vec;
+
+use backend::common::*;
+use super::verify::OpcodeMap;
+
+/// What a stopped application shows the debugger.
+pub struct Target<'a> {
+    pub code: &'a [u8],
+    pub opcodes: &'a OpcodeMap,
+    pub memory: &'a [u8],
+    pub slots: &'a [i64],
+
+    /// The live parts of the stacks, bottom first
+    pub stack: &'a [i64],
+    pub call_stack: &'a [i64]
+}
+
+impl<'a> Target<'a> {
+    fn stop(&self, reason: StopReason, ip: usize) -> DebugStop {
+        DebugStop {
+            reason: reason,
+            ip: ip as i64,
+            stack_len: self.stack.len() as i64,
+            call_stack_len: self.call_stack.len() as i64,
+            n_slots: self.slots.len() as i64,
+            memory_len: self.memory.len() as i64,
+            code_len: self.code.len() as i64
+        }
+    }
+
+    /// Reads at most `len` bytes at `offset` of `area`, or `None` if
+    /// `offset` is past its end.
+    fn read(&self, area: DebugArea, offset: usize, len: usize) -> Option<Vec<u8>> {
+        let values = match area {
+            DebugArea::Memory => return read_bytes(self.memory, offset, len),
+            DebugArea::Code => return read_bytes(self.code, offset, len),
+            DebugArea::Slots => self.slots,
+            DebugArea::Stack => self.stack,
+            DebugArea::CallStack => self.call_stack
+        };
+
+        let size = ::core::mem::size_of::<i64>();
+        if offset > values.len() * size {
+            return None;
+        }
+
+        let first = offset / size;
+        let end = ::core::cmp::min(values.len(), offset.saturating_add(len).saturating_add(size - 1) / size);
+        let mut bytes: Vec<u8> = Vec::with_capacity((end - first) * size);
+        for v in &values[first..end] {
+            let b: [u8; 8] = unsafe { ::core::mem::transmute(v.to_le()) };
+            bytes.extend_from_slice(&b);
+        }
+
+        read_bytes(&bytes, offset % size, len)
+    }
+}
+
+fn read_bytes(data: &[u8], offset: usize, len: usize) -> Option<Vec<u8>> {
+    if offset > data.len() {
+        return None;
+    }
+
+    let end = ::core::cmp::min(data.len(), offset.saturating_add(len));
+    Some(data[offset..end].to_vec())
+}
+
+pub struct Debugger {
+    // Offsets of opcodes, sorted
+    breakpoints: RefCell<Vec<usize>>,
+    stepping: Cell<bool>,
+
+    // Makes the application stop before the next opcode. It waits for a
+    // debugger before the first one.
+    stop_next: Cell<Option<StopReason>>,
+
+    // A continue or step waits for the next stop.
+    resumed: Cell<bool>
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: RefCell::new(Vec::new()),
+            stepping: Cell::new(false),
+            stop_next: Cell::new(Some(StopReason::Started)),
+            resumed: Cell::new(false)
+        }
+    }
+
+    /// Called before the opcode at `ip`, which runs once this returns.
+    #[inline]
+    pub fn before_opcode<'b, C: Context, F: Fn() -> Target<'b>>(
+        &self,
+        ip: usize,
+        context: &C,
+        target: F
+    ) -> BackendResult<()> {
+        let mut reason = self.stop_next.replace(None);
+        if reason.is_none() {
+            if self.stepping.get() {
+                reason = Some(StopReason::Step);
+            } else if self.is_breakpoint(ip) {
+                reason = Some(StopReason::Breakpoint);
+            }
+        }
+
+        if reason.is_none() && !context.debug_pending() {
+            return Ok(());
+        }
+
+        self.serve(ip, reason, context, &target())
+    }
+
+    fn is_breakpoint(&self, ip: usize) -> bool {
+        let breakpoints = self.breakpoints.borrow();
+        breakpoints.len() > 0 && breakpoints.binary_search(&ip).is_ok()
+    }
+
+    fn serve<C: Context>(
+        &self,
+        ip: usize,
+        reason: Option<StopReason>,
+        context: &C,
+        target: &Target
+    ) -> BackendResult<()> {
+        // A request that comes in while running stops the application, as
+        // if the debugger attached first, and is then served like any other.
+        let mut next: Option<DebugRequest> = None;
+        let reason = match reason {
+            Some(v) => v,
+            None => match context.take_debug_request(false)? {
+                Some(DebugRequest::Interrupt) => StopReason::Interrupted,
+                Some(req) => {
+                    next = Some(req);
+                    StopReason::Attached
+                },
+                None => return Ok(())
+            }
+        };
+        let stop = target.stop(reason, ip);
+
+        if self.resumed.replace(false) {
+            context.finish_debug_request(Some(&[][..]), &stop);
+        }
+
+        loop {
+            let req = match next.take() {
+                Some(v) => v,
+                None => match context.take_debug_request(true)? {
+                    Some(v) => v,
+                    None => continue
+                }
+            };
+
+            match req {
+                DebugRequest::Continue | DebugRequest::Step => {
+                    self.stepping.set(req == DebugRequest::Step);
+                    self.resumed.set(true);
+                    return Ok(());
+                },
+                DebugRequest::Detach => {
+                    self.breakpoints.borrow_mut().clear();
+                    self.stepping.set(false);
+                    context.finish_debug_request(Some(&[][..]), &stop);
+                    return Ok(());
+                },
+
+                // Stopped already
+                DebugRequest::Interrupt => {},
+
+                DebugRequest::Attach => context.finish_debug_request(Some(&[][..]), &stop),
+                DebugRequest::SetBreakpoint(at) => {
+                    if !target.opcodes.contains(at) {
+                        context.finish_debug_request(None, &stop);
+                        continue;
+                    }
+
+                    let mut breakpoints = self.breakpoints.borrow_mut();
+                    if let Err(i) = breakpoints.binary_search(&at) {
+                        breakpoints.insert(i, at);
+                    }
+                    context.finish_debug_request(Some(&[][..]), &stop);
+                },
+                DebugRequest::ClearBreakpoint(at) => {
+                    let mut breakpoints = self.breakpoints.borrow_mut();
+                    if let Ok(i) = breakpoints.binary_search(&at) {
+                        breakpoints.remove(i);
+                    }
+                    context.finish_debug_request(Some(&[][..]), &stop);
+                },
+                DebugRequest::Read { area, offset, len } => {
+                    let data = target.read(area, offset, len);
+                    context.finish_debug_request(data.as_ref().map(|v| &v[..]), &stop);
+                }
+            }
+        }
+    }
+}
@@ -1,13 +1,26 @@
+pub use cervus_common::{verify, wasm, trans};
+
+pub mod debug;
+
 use backend::common::*;
+use backend::float;
+use backend::fuel::{FuelMeter, NATIVE_INVOKE_FUEL_COST};
 
 use core::cell::Cell;
+use core::sync::atomic::AtomicUsize;
 use alloc::Vec;
+use alloc::arc::Arc;
 
 use hexagon_e;
 use hexagon_e::environment::Environment;
+use hexagon_e::module::Opcode;
 use hexagon_e::tape::Tape;
 use hexagon_e::error::*;
 
+use self::verify::{OpcodeMap, ResumePoint, VerifyError};
+use self::trans::TransError;
+use self::debug::Debugger;
+
 impl From<ExecuteError> for BackendError {
     fn from(other: ExecuteError) -> BackendError {
         match other {
@@ -30,23 +43,86 @@ impl From<BackendError> for ExecuteError {
     }
 }
 
+impl From<VerifyError> for BackendError {
+    fn from(other: VerifyError) -> BackendError {
+        match other {
+            VerifyError::NativeInvoke { .. } => BackendError::InvalidNativeInvoke,
+            _ => BackendError::InvalidInput
+        }
+    }
+}
+
+impl From<TransError> for BackendError {
+    fn from(other: TransError) -> BackendError {
+        match other {
+            TransError::UnresolvedImports(_) => BackendError::InvalidNativeInvoke,
+            _ => BackendError::InvalidInput
+        }
+    }
+}
+
+pub fn cells_as_values(cells: &[Cell<i64>]) -> &[i64] {
+    assert_eq!(::core::mem::size_of::<Cell<i64>>(), ::core::mem::size_of::<i64>());
+    unsafe {
+        ::core::mem::transmute::<&[Cell<i64>], &[i64]>(cells)
+    }
+}
+
+/// Memory, slots and stacks of a run, shared by the backends.
 pub struct ResourceHolder {
-    max_mem: usize,
-    max_slots: usize,
-    mem: Vec<u8>,
-    slots: Vec<i64>,
-    stack: Vec<Cell<i64>>,
-    call_stack: Vec<Cell<i64>>
+    pub max_mem: usize,
+    pub max_slots: usize,
+    pub fuel: Option<Arc<AtomicUsize>>,
+    pub mem: Vec<u8>,
+    pub slots: Vec<i64>,
+    pub stack: Vec<Cell<i64>>,
+    pub call_stack: Vec<Cell<i64>>
 }
 
+/// Branches between two calls to `Context::tick` when the context doesn't ask
+/// for one earlier with `tick_pending`. This bounds how late time limits are
+/// noticed; preemption is driven by `tick_pending`.
+pub const TICK_INTERVAL: usize = 1000;
+
 pub struct ExecutionEnv<'a, C: Context + 'a> {
     max_mem: usize,
     max_slots: usize,
 
     resched_counter: Cell<usize>,
+    fuel: FuelMeter,
+
+    // The interpreter doesn't expose its instruction pointer, so for core
+    // dumps and the debugger it is followed from the opcodes and branches
+    // it reports.
+    code: &'a [u8],
+    opcodes: &'a OpcodeMap,
+    track_ip: bool,
+    ip: Cell<usize>,
+    next_ip: Cell<usize>,
+
+    // Set if the application runs under a debugger
+    debug: Option<Debugger>,
+
+    // Checkpoints are taken at a branch, where the state is complete. The
+    // branch of a call comes before its frame is pushed, so `in_call` makes
+    // a pending checkpoint wait for the next one.
+    image: &'a [u8],
+    n_entry_args: usize,
+    checkpoint_due: Cell<bool>,
+    in_call: Cell<bool>,
+
+    profile_interval: Option<u32>,
+    profile_countdown: Cell<u32>,
+
+    // Errors lose their details when passing through hexagon-e, so the
+    // original one is kept here.
+    termination: &'a Cell<Option<BackendError>>,
 
     mem: &'a mut Vec<u8>,
     slots: &'a mut Vec<i64>,
+
+    // Operand and call stacks have a fixed size
+    stack_bytes: usize,
     stack: Tape<'a, Cell<i64>>,
     call_stack: Tape<'a, Cell<i64>>,
 
@@ -54,21 +130,108 @@ pub struct ExecutionEnv<'a, C: Context + 'a> {
 }
 
 impl<'a, C: Context + 'a> ExecutionEnv<'a, C> {
-    pub fn new(rh: &'a mut ResourceHolder, ctx: &'a mut C) -> ExecutionEnv<'a, C> {
+    pub fn new(
+        rh: &'a mut ResourceHolder,
+        ctx: &'a mut C,
+        termination: &'a Cell<Option<BackendError>>,
+        image: &'a [u8],
+        n_entry_args: usize,
+        code: &'a [u8],
+        opcodes: &'a OpcodeMap
+    ) -> ExecutionEnv<'a, C> {
+        let profile_interval = ctx.profile_interval();
+        let debug = if ctx.debug_enabled() { Some(Debugger::new()) } else { None };
+        let track_ip = ctx.wants_core_dump() || debug.is_some();
+
         ExecutionEnv {
             max_mem: rh.max_mem,
             max_slots: rh.max_slots,
 
             resched_counter: Cell::new(0),
+            fuel: FuelMeter::new(rh.fuel.clone()),
+
+            code: code,
+            opcodes: opcodes,
+            track_ip: track_ip,
+            ip: Cell::new(0),
+            next_ip: Cell::new(0),
+            debug: debug,
+
+            image: image,
+            n_entry_args: n_entry_args,
+            checkpoint_due: Cell::new(false),
+            in_call: Cell::new(false),
+
+            profile_interval: profile_interval,
+            profile_countdown: Cell::new(profile_interval.unwrap_or(0)),
+
+            termination: termination,
 
             mem: &mut rh.mem,
             slots: &mut rh.slots,
+            stack_bytes: (rh.stack.len() + rh.call_stack.len()) * ::core::mem::size_of::<i64>(),
             stack: Tape::from(&*rh.stack),
             call_stack: Tape::from(&*rh.call_stack),
 
             context: ctx
         }
     }
+
+    fn fail(&self, e: BackendError) -> ExecuteError {
+        self.termination.set(Some(e));
+        e.into()
+    }
+
+    fn report_memory_usage(&self) {
+        self.context.set_guest_memory_usage(
+            self.mem.len()
+                + self.slots.len() * ::core::mem::size_of::<i64>()
+                + self.stack_bytes
+        );
+    }
+
+    #[inline]
+    fn consume_fuel(&self, n: u64) -> ExecuteResult<()> {
+        match self.fuel.consume(n) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.fail(e))
+        }
+    }
+
+    fn debug_target(&self) -> debug::Target {
+        debug::Target {
+            code: self.code,
+            opcodes: self.opcodes,
+            memory: &self.mem,
+            slots: &self.slots,
+            stack: cells_as_values(self.stack.tail_many(self.stack.get_pos()).unwrap_or(&[])),
+            call_stack: cells_as_values(self.call_stack.tail_many(self.call_stack.get_pos()).unwrap_or(&[]))
+        }
+    }
+
+    /// Writes a checkpoint that continues at `ip`.
+    fn checkpoint(&self, ip: usize) -> ExecuteResult<()> {
+        // Float operations are part of the backend and don't need to be looked up.
+        let native_invokes: Vec<usize> = self.opcodes.native_invokes().iter()
+            .cloned()
+            .filter(|id| float::n_args(*id).is_none())
+            .collect();
+        let state = CheckpointState {
+            code: self.image,
+            n_entry_args: self.n_entry_args,
+            ip: ip,
+            memory: &self.mem,
+            slots: &self.slots,
+            stack: cells_as_values(self.stack.tail_many(self.stack.get_pos())?),
+            call_stack: cells_as_values(self.call_stack.tail_many(self.call_stack.get_pos())?),
+            native_invokes: &native_invokes
+        };
+
+        match self.context.write_checkpoint(&state) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.fail(e))
+        }
+    }
 }
 
 impl<'a, C: Context + 'a> Environment for ExecutionEnv<'a, C> {
@@ -87,6 +250,7 @@ impl<'a, C: Context + 'a> Environment for ExecutionEnv<'a, C> {
         }
 
         self.mem.resize(new_len, 0);
+        self.report_memory_usage();
         Ok(())
     }
 
@@ -104,6 +268,7 @@ impl<'a, C: Context + 'a> Environment for ExecutionEnv<'a, C> {
         }
 
         *self.slots = vec! [ 0; len ];
+        self.report_memory_usage();
         Ok(())
     }
 
@@ -116,29 +281,95 @@ impl<'a, C: Context + 'a> Environment for ExecutionEnv<'a, C> {
     }
 
     fn do_native_invoke(&mut self, id: usize) -> ExecuteResult<Option<i64>> {
-        let policy = self.context.get_native_invoke_policy(id)?;
+        // Lowered floating point opcodes cost no fuel, like other opcodes.
+        if let Some(n_args) = float::n_args(id) {
+            let args = cells_as_values(self.stack.prev_many(n_args)?);
+            return match float::call(id, args) {
+                Ok(v) => Ok(Some(v)),
+                Err(e) => Err(self.fail(e))
+            };
+        }
 
-        let args: &[Cell<i64>] = self.stack.prev_many(policy.n_args)?;
+        self.consume_fuel(NATIVE_INVOKE_FUEL_COST)?;
 
-        assert_eq!(::core::mem::size_of::<Cell<i64>>(), ::core::mem::size_of::<i64>());
-        let args = unsafe {
-            ::core::mem::transmute::<&[Cell<i64>], &[i64]>(args)
+        let policy = match self.context.get_native_invoke_policy(id) {
+            Ok(v) => v,
+            Err(e) => return Err(self.fail(e))
         };
 
-        Ok(self.context.do_native_invoke(id, args, &mut self.mem)?)
+        let args = cells_as_values(self.stack.prev_many(policy.n_args)?);
+
+        match self.context.do_native_invoke(id, args, &mut self.mem) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(self.fail(e))
+        }
+    }
+
+    #[inline]
+    fn trace_opcode(&self, op: &Opcode) -> ExecuteResult<()> {
+        if self.track_ip {
+            let ip = self.next_ip.get();
+            let imm = if ip < self.code.len() { &self.code[ip + 1..] } else { &[] };
+            self.ip.set(ip);
+            self.next_ip.set(ip + 1 + verify::immediate_len(op, imm));
+        }
+
+        if let Some(ref d) = self.debug {
+            if let Err(e) = d.before_opcode(self.ip.get(), &*self.context, || self.debug_target()) {
+                return Err(self.fail(e));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn trace_call(&self, _target: usize, _n_locals: usize) {
+        self.in_call.set(true);
     }
 
     #[inline]
-    fn trace_branch(&self, _target: usize) -> ExecuteResult<()> {
+    fn trace_branch(&self, target: usize) -> ExecuteResult<()> {
+        // Static targets are verified, but calls through the table go
+        // wherever the slot points.
+        if !self.opcodes.contains(target) {
+            return Err(self.fail(BackendError::Bounds));
+        }
+        if self.track_ip {
+            self.next_ip.set(target);
+        }
+        self.consume_fuel(1)?;
+
+        if let Some(interval) = self.profile_interval {
+            let n = self.profile_countdown.get();
+            if n <= 1 {
+                self.profile_countdown.set(interval);
+                self.context.sample_branch(target);
+            } else {
+                self.profile_countdown.set(n - 1);
+            }
+        }
+
         let count = self.resched_counter.get();
 
-        if count >= 10000 {
+        if count >= TICK_INTERVAL || self.context.tick_pending() {
             self.resched_counter.set(0);
-            self.context.tick()?;
+            if let Err(e) = self.context.tick() {
+                return Err(self.fail(e));
+            }
+            if self.context.checkpoint_pending() {
+                self.checkpoint_due.set(true);
+            }
         } else {
             self.resched_counter.set(count + 1);
         }
 
+        let in_call = self.in_call.replace(false);
+        if self.checkpoint_due.get() && !in_call {
+            self.checkpoint_due.set(false);
+            self.checkpoint(target)?;
+        }
+
         Ok(())
     }
 }
@@ -149,14 +380,22 @@ pub struct EnvConfig {
     pub memory_max_len: usize,
     pub max_slots: usize,
     pub stack_len: usize,
-    pub call_stack_len: usize
+    pub call_stack_len: usize,
+
+    /// Number of branches the app may still take. Native invokes count as
+    /// `NATIVE_INVOKE_FUEL_COST` branches. `None` for unlimited.
+    ///
+    /// Shared by every instance run with a clone of this configuration, so
+    /// that spawned instances draw from the budget of their parent.
+    pub fuel: Option<Arc<AtomicUsize>>
 }
 
 impl EnvConfig {
     pub fn is_valid(&self) -> bool {
         if self.memory_default_len == 0 || self.memory_max_len == 0
             || self.max_slots == 0
-            || self.stack_len == 0 || self.call_stack_len == 0 {
+            || self.stack_len == 0 || self.call_stack_len == 0
+            || self.memory_default_len > self.memory_max_len {
             false
         } else {
             true
@@ -172,6 +411,7 @@ impl ResourceHolder {
             Some(ResourceHolder {
                 max_mem: config.memory_max_len,
                 max_slots: config.max_slots,
+                fuel: config.fuel,
                 mem: vec! [ 0; config.memory_default_len ],
                 slots: vec! [],
                 stack: vec! [ Cell::new(0); config.stack_len ],
@@ -179,6 +419,75 @@ impl ResourceHolder {
             })
         }
     }
+
+    /// Loads the state of a checkpoint into the resources.
+    pub fn restore(&mut self, state: &CheckpointState) -> BackendResult<()> {
+        if state.memory.len() > self.max_mem
+            || state.slots.len() > self.max_slots
+            || state.stack.len() > self.stack.len()
+            || state.call_stack.len() > self.call_stack.len() {
+            println!("checkpoint exceeds the resource limits");
+            return Err(BackendError::InvalidInput);
+        }
+
+        self.mem = state.memory.to_vec();
+        self.slots = state.slots.to_vec();
+        for (cell, v) in self.stack.iter().zip(state.stack) {
+            cell.set(*v);
+        }
+        for (cell, v) in self.call_stack.iter().zip(state.call_stack) {
+            cell.set(*v);
+        }
+
+        Ok(())
+    }
+
+    /// Verifies `m` for a run from `start`, with the state of a checkpoint
+    /// restored already.
+    pub fn verify<C: Context>(
+        &self,
+        m: &hexagon_e::module::Module,
+        n_entry_args: usize,
+        start: Start,
+        context: &C
+    ) -> BackendResult<OpcodeMap> {
+        let native_invoke_args = |id| float::n_args(id)
+            .or_else(|| context.get_native_invoke_policy(id).ok().map(|p| p.n_args));
+        let verified = match start {
+            Start::Entry(_) => verify::verify(
+                m,
+                n_entry_args,
+                self.stack.len(),
+                self.mem.len(),
+                self.max_slots,
+                native_invoke_args
+            ),
+
+            // The stacks come from the checkpoint file, so they are checked
+            // against the code like the code itself.
+            Start::Resume(state) => verify::verify_resume(
+                m,
+                n_entry_args,
+                self.stack.len(),
+                self.mem.len(),
+                self.max_slots,
+                native_invoke_args,
+                &ResumePoint {
+                    ip: state.ip,
+                    n_slots: state.slots.len(),
+                    stack_len: state.stack.len(),
+                    call_stack: state.call_stack
+                }
+            )
+        };
+        match verified {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                println!("rejected code: {:?}", e);
+                Err(e.into())
+            }
+        }
+    }
 }
 
 pub struct HexagonEBackend {
@@ -197,14 +506,108 @@ impl Backend for HexagonEBackend {
         })
     }
 
-    fn run<C: Context>(&mut self, code: &[u8], context: &mut C) -> BackendResult<()> {
-        let m = hexagon_e::module::Module::from_raw(code)?;
-        let env = ExecutionEnv::new(&mut self.rh, context);
+    fn run<C: Context>(&mut self, code: &[u8], args: &[i64], context: &mut C) -> BackendResult<Option<i64>> {
+        self.execute(code, Start::Entry(args), context)
+    }
 
-        let mut vm = hexagon_e::vm::VirtualMachine::new(&m, env);
-        vm.run_memory_initializers()?;
-        vm.run()?;
+    fn resume<C: Context>(&mut self, state: &CheckpointState, context: &mut C) -> BackendResult<Option<i64>> {
+        self.execute(state.code, Start::Resume(state), context)
+    }
+}
 
-        Ok(())
+/// Where a run starts.
+#[derive(Copy, Clone)]
+pub enum Start<'a> {
+    /// At the startup code, which calls the entry function with these arguments
+    Entry(&'a [i64]),
+
+    /// Where a checkpoint was taken
+    Resume(&'a CheckpointState<'a>)
+}
+
+impl HexagonEBackend {
+    fn execute<C: Context>(&mut self, code: &[u8], start: Start, context: &mut C) -> BackendResult<Option<i64>> {
+        let m = match hexagon_e::module::Module::from_raw(code) {
+            Ok(v) => v,
+            Err(_) => return Err(BackendError::InvalidInput)
+        };
+        let n_entry_args = match start {
+            Start::Entry(args) => args.len(),
+            Start::Resume(state) => {
+                self.rh.restore(state)?;
+                state.n_entry_args
+            }
+        };
+        let opcodes = self.rh.verify(&m, n_entry_args, start, context)?;
+
+        // The interpreter always starts at the beginning of the code, so a
+        // resumed run starts with a jump to where the checkpoint was taken.
+        // It replaces the `ResetSlots` there, which has the same length, as
+        // the slots are restored already.
+        let mut resume_code: Vec<u8> = Vec::new();
+        let m = match start {
+            Start::Entry(_) => m,
+            Start::Resume(state) => {
+                // `verify_resume` checked that `ip` starts an opcode.
+                let ip: [u8; 4] = unsafe { ::core::mem::transmute((state.ip as u32).to_le()) };
+
+                resume_code.extend_from_slice(m.code);
+                resume_code[0] = Opcode::Jmp as u8;
+                resume_code[1..5].copy_from_slice(&ip);
+
+                hexagon_e::module::Module {
+                    memory_initializers: m.memory_initializers,
+                    code: &resume_code
+                }
+            }
+        };
+        let termination: Cell<Option<BackendError>> = Cell::new(None);
+
+        // Instruction pointer and stack positions when the run ended.
+        let (result, ip, stack_len, call_stack_len) = {
+            let env = ExecutionEnv::new(&mut self.rh, context, &termination, code, n_entry_args, m.code, &opcodes);
+            env.report_memory_usage();
+
+            match start {
+                // The startup code calls the entry function with the arguments it
+                // finds on the stack, and leaves its return value there.
+                Start::Entry(args) => for arg in args {
+                    match env.stack.next() {
+                        Ok(v) => v.set(*arg),
+                        Err(_) => return Err(BackendError::InvalidInput)
+                    }
+                },
+                Start::Resume(state) => {
+                    env.stack.set_pos(state.stack.len())?;
+                    env.call_stack.set_pos(state.call_stack.len())?;
+                }
+            }
+
+            let mut vm = hexagon_e::vm::VirtualMachine::new(&m, env);
+            if let Start::Entry(_) = start {
+                vm.run_memory_initializers()?;
+            }
+
+            let result = match vm.run() {
+                Ok(_) => Ok(vm.env.stack.tail_many(1).ok().map(|v| v[0].get())),
+                Err(e) => Err(termination.get().unwrap_or(e.into()))
+            };
+            (result, vm.env.ip.get(), vm.env.stack.get_pos(), vm.env.call_stack.get_pos())
+        };
+
+        if let Err(e) = result {
+            if e.termination_kind() == TerminationKind::Trapped && context.wants_core_dump() {
+                context.write_core_dump(&GuestState {
+                    error: e,
+                    ip: ip,
+                    memory: &self.rh.mem,
+                    slots: &self.rh.slots,
+                    stack: cells_as_values(&self.rh.stack[..stack_len]),
+                    call_stack: cells_as_values(&self.rh.call_stack[..call_stack_len])
+                });
+            }
+        }
+
+        result
     }
 }
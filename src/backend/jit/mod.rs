@@ -0,0 +1,497 @@
+//! Runs hexagon-e code compiled to x86-64 machine code.
+//!
+//! The code is compiled with `cervus_common::jit` and works on the same
+//! resources as the interpreter, so it is verified, checkpointed, resumed
+//! and dumped the same way. The helpers here do what the interpreter's
+//! environment does: fuel, ticks, profiling, checkpoints and native invokes.
+//!
+//! Branches are counted by the code, which only calls the `branch` helper
+//! when its budget runs out. The budget never covers more branches than are
+//! left until the next tick interval, profiler sample or batch of fuel, so
+//! those happen on the same branch as in the interpreter. `tick_pending` is
+//! only checked there, which makes preemption wait up to `TICK_INTERVAL`
+//! branches.
+
+use backend::common::*;
+use backend::float;
+use backend::fuel::{FuelMeter, NATIVE_INVOKE_FUEL_COST};
+use backend::hexagon_e::{EnvConfig, ResourceHolder, Start, TICK_INTERVAL, cells_as_values};
+use backend::hexagon_e::verify::OpcodeMap;
+
+use alloc::Vec;
+
+use cervus_common::jit::{self, JitState, Entry, Trap, EXIT_HALT};
+use hexagon_e;
+
+use linux;
+
+pub struct JitBackend {
+    rh: ResourceHolder
+}
+
+impl Backend for JitBackend {
+    type Config = EnvConfig;
+
+    fn new(config: EnvConfig) -> BackendResult<JitBackend> {
+        if cfg!(not(target_arch = "x86_64")) {
+            return Err(BackendError::Unsupported);
+        }
+
+        Ok(JitBackend {
+            rh: match ResourceHolder::new(config) {
+                Some(v) => v,
+                None => return Err(BackendError::InvalidInput)
+            }
+        })
+    }
+
+    fn run<C: Context>(&mut self, code: &[u8], args: &[i64], context: &mut C) -> BackendResult<Option<i64>> {
+        self.execute(code, Start::Entry(args), context)
+    }
+
+    fn resume<C: Context>(&mut self, state: &CheckpointState, context: &mut C) -> BackendResult<Option<i64>> {
+        self.execute(state.code, Start::Resume(state), context)
+    }
+}
+
+/// Machine code in executable memory.
+struct ExecMemory {
+    base: *mut u8,
+    len: usize
+}
+
+impl ExecMemory {
+    fn new(code: &[u8]) -> BackendResult<ExecMemory> {
+        let base = unsafe { linux::lapi_exec_map(code.as_ptr(), code.len()) };
+        if base.is_null() {
+            println!("unable to map compiled code");
+            return Err(BackendError::Generic);
+        }
+
+        Ok(ExecMemory {
+            base: base,
+            len: code.len()
+        })
+    }
+}
+
+impl Drop for ExecMemory {
+    fn drop(&mut self) {
+        unsafe { linux::lapi_exec_unmap(self.base, self.len); }
+    }
+}
+
+/// Copies the data of `initializers` into memory, like
+/// `VirtualMachine::run_memory_initializers`.
+fn run_memory_initializers(initializers: &[u8], mem: &mut [u8]) -> BackendResult<()> {
+    fn read_u32(data: &[u8]) -> BackendResult<usize> {
+        if data.len() < 4 {
+            return Err(BackendError::Bounds);
+        }
+        Ok(((data[0] as u32) | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24) as usize)
+    }
+
+    let mut rest = initializers;
+    while rest.len() >= 4 {
+        let addr = read_u32(rest)?;
+        let data_len = read_u32(&rest[4..])?;
+        rest = &rest[8..];
+
+        if data_len > rest.len() {
+            return Err(BackendError::Bounds);
+        }
+        if addr >= mem.len() || data_len > mem.len() - addr {
+            return Err(BackendError::Bounds);
+        }
+
+        mem[addr..addr + data_len].copy_from_slice(&rest[..data_len]);
+        rest = &rest[data_len..];
+    }
+
+    Ok(())
+}
+
+/// The values from `base` to `top`.
+unsafe fn values<'a>(base: *const i64, top: *const i64) -> &'a [i64] {
+    ::core::slice::from_raw_parts(base, (top as usize - base as usize) / ::core::mem::size_of::<i64>())
+}
+
+/// What the helpers work with, in `JitState::runtime`.
+struct Runtime<'a, C: Context + 'a> {
+    max_mem: usize,
+    max_slots: usize,
+
+    resched_counter: usize,
+    fuel: FuelMeter,
+
+    // Budget given to the code on the last call to `branch`
+    last_grant: u64,
+
+    image: &'a [u8],
+    n_entry_args: usize,
+    opcodes: &'a OpcodeMap,
+
+    profile_interval: Option<u32>,
+    profile_countdown: u32,
+
+    // `ResetSlots` may only run once.
+    slots_reset: bool,
+
+    // Why a helper stopped the code
+    termination: Option<BackendError>,
+
+    mem: &'a mut Vec<u8>,
+    slots: &'a mut Vec<i64>,
+    stack_bytes: usize,
+
+    context: &'a mut C
+}
+
+impl<'a, C: Context + 'a> Runtime<'a, C> {
+    fn report_memory_usage(&self) {
+        self.context.set_guest_memory_usage(
+            self.mem.len()
+                + self.slots.len() * ::core::mem::size_of::<i64>()
+                + self.stack_bytes
+        );
+    }
+
+    /// Accounts for the branches since the last call, the last of them to
+    /// `target`, and returns the next budget.
+    fn branch(&mut self, state: &JitState, target: usize) -> BackendResult<u64> {
+        let counted = self.last_grant;
+        self.last_grant = 0;
+
+        // The others were paid for with the budget.
+        self.fuel.consume(1)?;
+
+        if let Some(interval) = self.profile_interval {
+            let n = self.profile_countdown - counted as u32;
+            if n <= 1 {
+                self.profile_countdown = interval;
+                self.context.sample_branch(target);
+            } else {
+                self.profile_countdown = n - 1;
+            }
+        }
+
+        let count = self.resched_counter + counted as usize;
+        if count >= TICK_INTERVAL || self.context.tick_pending() {
+            self.resched_counter = 0;
+            self.context.tick()?;
+
+            // The code has pushed the frame of a call before branching, so
+            // the state is complete.
+            if self.context.checkpoint_pending() {
+                self.checkpoint(state, target)?;
+            }
+        } else {
+            self.resched_counter = count + 1;
+        }
+
+        let mut grant = (TICK_INTERVAL - self.resched_counter) as u64;
+        if self.profile_interval.is_some() {
+            grant = ::core::cmp::min(grant, (self.profile_countdown - 1) as u64);
+        }
+        self.last_grant = self.fuel.consume_local(grant);
+        Ok(self.last_grant)
+    }
+
+    /// Writes a checkpoint that continues at `ip`.
+    fn checkpoint(&self, state: &JitState, ip: usize) -> BackendResult<()> {
+        // Float operations are part of the backend and don't need to be looked up.
+        let native_invokes: Vec<usize> = self.opcodes.native_invokes().iter()
+            .cloned()
+            .filter(|id| float::n_args(*id).is_none())
+            .collect();
+        let cp = CheckpointState {
+            code: self.image,
+            n_entry_args: self.n_entry_args,
+            ip: ip,
+            memory: &self.mem,
+            slots: &self.slots,
+            stack: unsafe { values(state.stack_base, state.sp) },
+            call_stack: unsafe { values(state.call_stack_base, state.csp) },
+            native_invokes: &native_invokes
+        };
+
+        self.context.write_checkpoint(&cp)
+    }
+
+    fn native_invoke(&mut self, state: &mut JitState, id: usize) -> BackendResult<()> {
+        // Lowered floating point opcodes cost no fuel, like other opcodes.
+        let result = if let Some(n_args) = float::n_args(id) {
+            let args = pop(state, n_args)?;
+            Some(float::call(id, args)?)
+        } else {
+            // The fuel the code hasn't used yet may be what the invoke needs.
+            self.fuel.give_back(state.budget);
+            self.last_grant -= state.budget;
+            state.budget = 0;
+
+            self.fuel.consume(NATIVE_INVOKE_FUEL_COST)?;
+            let policy = self.context.get_native_invoke_policy(id)?;
+            let args = pop(state, policy.n_args)?;
+            self.context.do_native_invoke(id, args, &mut self.mem)?
+        };
+
+        if let Some(v) = result {
+            if state.sp == state.stack_end {
+                return Err(BackendError::Bounds);
+            }
+            unsafe {
+                *state.sp = v;
+                state.sp = state.sp.offset(1);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn grow_memory(&mut self, state: &mut JitState, len_inc: usize) -> BackendResult<()> {
+        let new_len = self.mem.len().wrapping_add(len_inc);
+        if new_len < self.mem.len() || new_len > self.max_mem {
+            return Err(BackendError::Generic);
+        }
+
+        self.mem.resize(new_len, 0);
+        state.mem_base = self.mem.as_mut_ptr();
+        state.mem_len = self.mem.len();
+        self.report_memory_usage();
+        Ok(())
+    }
+
+    fn reset_slots(&mut self, state: &mut JitState, len: usize) -> BackendResult<()> {
+        if self.slots_reset || len > self.max_slots {
+            return Err(BackendError::Generic);
+        }
+        self.slots_reset = true;
+
+        *self.slots = vec! [ 0; len ];
+        state.slots_base = self.slots.as_mut_ptr();
+        state.slots_len = self.slots.len();
+        self.report_memory_usage();
+        Ok(())
+    }
+}
+
+/// Pops `n` values off the stack of `state`, returning them bottom first.
+fn pop<'a>(state: &mut JitState, n: usize) -> BackendResult<&'a [i64]> {
+    let len = (state.sp as usize - state.stack_base as usize) / ::core::mem::size_of::<i64>();
+    if n > len {
+        return Err(BackendError::Bounds);
+    }
+
+    unsafe {
+        state.sp = state.sp.offset(-(n as isize));
+        Ok(values(state.sp, state.sp.offset(n as isize)))
+    }
+}
+
+/// Runs `f` on the runtime of `state`, stopping the code at `ip` if it fails.
+unsafe fn with_runtime<'a, C, F>(state: *mut JitState, ip: usize, f: F) -> u64
+    where C: Context + 'a, F: FnOnce(&mut Runtime<'a, C>, &mut JitState) -> BackendResult<()> {
+    let state = &mut *state;
+    let rt = &mut *(state.runtime as *mut Runtime<'a, C>);
+
+    match f(rt, state) {
+        Ok(()) => 0,
+        Err(e) => {
+            state.ip = ip;
+            rt.termination = Some(e);
+            1
+        }
+    }
+}
+
+unsafe extern "C" fn branch<C: Context>(state: *mut JitState, target: usize, _: u64) -> u64 {
+    // The budget has wrapped around below zero.
+    (*state).budget = 0;
+
+    with_runtime::<C, _>(state, target, |rt, state| {
+        state.budget = rt.branch(state, target)?;
+        Ok(())
+    })
+}
+
+unsafe extern "C" fn native_invoke<C: Context>(state: *mut JitState, ip: usize, id: u64) -> u64 {
+    with_runtime::<C, _>(state, ip, |rt, state| rt.native_invoke(state, id as usize))
+}
+
+unsafe extern "C" fn grow_memory<C: Context>(state: *mut JitState, ip: usize, len_inc: u64) -> u64 {
+    with_runtime::<C, _>(state, ip, |rt, state| rt.grow_memory(state, len_inc as usize))
+}
+
+unsafe extern "C" fn reset_slots<C: Context>(state: *mut JitState, ip: usize, len: u64) -> u64 {
+    with_runtime::<C, _>(state, ip, |rt, state| rt.reset_slots(state, len as usize))
+}
+
+impl JitBackend {
+    fn execute<C: Context>(&mut self, code: &[u8], start: Start, context: &mut C) -> BackendResult<Option<i64>> {
+        // Compiled code doesn't stop between opcodes.
+        if context.debug_enabled() {
+            println!("debugging is only supported by the interpreter");
+            return Err(BackendError::Unsupported);
+        }
+
+        let m = match hexagon_e::module::Module::from_raw(code) {
+            Ok(v) => v,
+            Err(_) => return Err(BackendError::InvalidInput)
+        };
+        let n_entry_args = match start {
+            Start::Entry(args) => args.len(),
+            Start::Resume(state) => {
+                self.rh.restore(state)?;
+                state.n_entry_args
+            }
+        };
+        let opcodes = self.rh.verify(&m, n_entry_args, start, context)?;
+
+        let compiled = match jit::compile(m.code) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("unable to compile code: {:?}", e);
+                return Err(BackendError::InvalidInput);
+            }
+        };
+        let exec = ExecMemory::new(&compiled.code)?;
+
+        // `verify_resume` checked that `ip` starts an opcode.
+        let start_ip = match start {
+            Start::Entry(_) => 0,
+            Start::Resume(state) => state.ip
+        };
+        let entry_offset = match compiled.start_of(start_ip) {
+            Some(v) => v,
+            None => return Err(BackendError::InvalidInput)
+        };
+
+        let (stack_len, call_stack_len) = match start {
+            // The startup code calls the entry function with the arguments it
+            // finds on the stack, and leaves its return value there.
+            Start::Entry(args) => {
+                if args.len() > self.rh.stack.len() {
+                    return Err(BackendError::InvalidInput);
+                }
+                for (cell, arg) in self.rh.stack.iter().zip(args) {
+                    cell.set(*arg);
+                }
+                run_memory_initializers(m.memory_initializers, &mut self.rh.mem)?;
+                (args.len(), 0)
+            },
+            Start::Resume(state) => (state.stack.len(), state.call_stack.len())
+        };
+
+        // The code writes to the stacks through these.
+        let stack_base = self.rh.stack.as_ptr() as *mut i64;
+        let stack_cap = self.rh.stack.len();
+        let call_stack_base = self.rh.call_stack.as_ptr() as *mut i64;
+        let call_stack_cap = self.rh.call_stack.len();
+
+        let profile_interval = context.profile_interval();
+
+        // Instruction pointer and stack positions when the run ended.
+        let (result, ip, stack_len, call_stack_len) = {
+            let mut rt = Runtime {
+                max_mem: self.rh.max_mem,
+                max_slots: self.rh.max_slots,
+
+                resched_counter: 0,
+                fuel: FuelMeter::new(self.rh.fuel.clone()),
+                last_grant: 0,
+
+                image: code,
+                n_entry_args: n_entry_args,
+                opcodes: &opcodes,
+
+                profile_interval: profile_interval,
+                profile_countdown: profile_interval.unwrap_or(0),
+
+                // The slots of a checkpoint are restored already.
+                slots_reset: match start {
+                    Start::Entry(_) => false,
+                    Start::Resume(_) => true
+                },
+
+                termination: None,
+
+                mem: &mut self.rh.mem,
+                slots: &mut self.rh.slots,
+                stack_bytes: (stack_cap + call_stack_cap) * ::core::mem::size_of::<i64>(),
+
+                context: &mut *context
+            };
+            rt.report_memory_usage();
+
+            let mut state = unsafe {
+                JitState {
+                    mem_base: rt.mem.as_mut_ptr(),
+                    mem_len: rt.mem.len(),
+                    slots_base: rt.slots.as_mut_ptr(),
+                    slots_len: rt.slots.len(),
+
+                    stack_base: stack_base,
+                    stack_end: stack_base.offset(stack_cap as isize),
+                    sp: stack_base.offset(stack_len as isize),
+                    call_stack_base: call_stack_base,
+                    call_stack_end: call_stack_base.offset(call_stack_cap as isize),
+                    csp: call_stack_base.offset(call_stack_len as isize),
+
+                    // The first branch calls `branch`, which sets the budget.
+                    budget: 0,
+
+                    code_base: exec.base,
+                    targets: compiled.targets.as_ptr(),
+                    n_targets: compiled.targets.len(),
+
+                    ip: start_ip,
+                    trap: 0,
+                    saved_rsp: 0,
+
+                    branch: branch::<C>,
+                    native_invoke: native_invoke::<C>,
+                    grow_memory: grow_memory::<C>,
+                    reset_slots: reset_slots::<C>,
+
+                    runtime: &mut rt as *mut Runtime<C> as *mut u8
+                }
+            };
+
+            let exit = unsafe {
+                let entry: Entry = ::core::mem::transmute(exec.base);
+                entry(&mut state, exec.base.offset(entry_offset as isize))
+            };
+
+            // What is left of the budget was never used.
+            rt.fuel.give_back(state.budget);
+
+            let stack_len = (state.sp as usize - stack_base as usize) / ::core::mem::size_of::<i64>();
+            let call_stack_len = (state.csp as usize - call_stack_base as usize) / ::core::mem::size_of::<i64>();
+
+            let result = if exit == EXIT_HALT {
+                Ok(if stack_len > 0 { Some(self.rh.stack[stack_len - 1].get()) } else { None })
+            } else {
+                Err(rt.termination.unwrap_or(match Trap::from_code(state.trap) {
+                    Some(Trap::Bounds) => BackendError::Bounds,
+                    _ => BackendError::Generic
+                }))
+            };
+            (result, state.ip, stack_len, call_stack_len)
+        };
+
+        if let Err(e) = result {
+            if e.termination_kind() == TerminationKind::Trapped && context.wants_core_dump() {
+                context.write_core_dump(&GuestState {
+                    error: e,
+                    ip: ip,
+                    memory: &self.rh.mem,
+                    slots: &self.rh.slots,
+                    stack: cells_as_values(&self.rh.stack[..stack_len]),
+                    call_stack: cells_as_values(&self.rh.call_stack[..call_stack_len])
+                });
+            }
+        }
+
+        result
+    }
+}
@@ -1,2 +1,34 @@
 pub mod common;
+pub mod float;
+pub mod fuel;
 pub mod hexagon_e;
+pub mod jit;
+
+use backend::common::*;
+use backend::hexagon_e::{EnvConfig, HexagonEBackend};
+use backend::jit::JitBackend;
+
+/// The backends a loader can choose from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Executor {
+    HexagonE,
+    Jit
+}
+
+impl Executor {
+    /// Runs `code` on a new backend of this kind, like `Backend::run`.
+    pub fn run<C: Context>(
+        &self,
+        config: EnvConfig,
+        code: &[u8],
+        args: &[i64],
+        context: &mut C
+    ) -> BackendResult<Option<i64>> {
+        match *self {
+            Executor::HexagonE => HexagonEBackend::new(config)
+                .and_then(|mut b| b.run(code, args, context)),
+            Executor::Jit => JitBackend::new(config)
+                .and_then(|mut b| b.run(code, args, context))
+        }
+    }
+}
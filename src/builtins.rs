@@ -0,0 +1,290 @@
+//! Implementations of the libm and compiler-rt routines that are referenced by
+//! `core` and `alloc` but not provided under the `+soft-float` kernel target.
+//!
+//! Everything here works on the bit representation where possible, so that no
+//! routine ends up calling itself through a lowered floating point operation.
+
+const F64_SIGN_MASK: u64 = 0x8000_0000_0000_0000;
+const F64_EXP_MASK: u64 = 0x7ff0_0000_0000_0000;
+const F64_MANT_BITS: u32 = 52;
+const F64_EXP_BIAS: i32 = 1023;
+
+const F32_SIGN_MASK: u32 = 0x8000_0000;
+const F32_EXP_MASK: u32 = 0x7f80_0000;
+const F32_MANT_BITS: u32 = 23;
+const F32_EXP_BIAS: i32 = 127;
+
+fn f64_is_nan(bits: u64) -> bool {
+    bits & !F64_SIGN_MASK > F64_EXP_MASK
+}
+
+fn f32_is_nan(bits: u32) -> bool {
+    bits & !F32_SIGN_MASK > F32_EXP_MASK
+}
+
+#[no_mangle]
+pub extern "C" fn fmax(x: f64, y: f64) -> f64 {
+    let (a, b) = (x.to_bits(), y.to_bits());
+
+    if f64_is_nan(a) {
+        return y;
+    }
+    if f64_is_nan(b) {
+        return x;
+    }
+
+    // +0.0 is greater than -0.0
+    let (sign_a, sign_b) = (a & F64_SIGN_MASK != 0, b & F64_SIGN_MASK != 0);
+    if sign_a != sign_b {
+        return if sign_a { y } else { x };
+    }
+
+    // Same sign: magnitudes compare like integers, reversed for negative numbers.
+    if (a < b) != sign_a { y } else { x }
+}
+
+#[no_mangle]
+pub extern "C" fn fmaxf(x: f32, y: f32) -> f32 {
+    let (a, b) = (x.to_bits(), y.to_bits());
+
+    if f32_is_nan(a) {
+        return y;
+    }
+    if f32_is_nan(b) {
+        return x;
+    }
+
+    let (sign_a, sign_b) = (a & F32_SIGN_MASK != 0, b & F32_SIGN_MASK != 0);
+    if sign_a != sign_b {
+        return if sign_a { y } else { x };
+    }
+
+    if (a < b) != sign_a { y } else { x }
+}
+
+#[no_mangle]
+pub extern "C" fn scalbn(x: f64, mut n: i32) -> f64 {
+    // Same approach as musl: scale in at most three steps so that intermediate
+    // results neither overflow nor get rounded twice in the subnormal range.
+    let p1023 = f64::from_bits(0x7fe0_0000_0000_0000); // 0x1p1023
+    let pm969 = f64::from_bits(0x0360_0000_0000_0000); // 0x1p-1022 * 0x1p53
+
+    let mut y = x;
+
+    if n > 1023 {
+        y *= p1023;
+        n -= 1023;
+        if n > 1023 {
+            y *= p1023;
+            n -= 1023;
+            if n > 1023 {
+                n = 1023;
+            }
+        }
+    } else if n < -1022 {
+        y *= pm969;
+        n += 1022 - 53;
+        if n < -1022 {
+            y *= pm969;
+            n += 1022 - 53;
+            if n < -1022 {
+                n = -1022;
+            }
+        }
+    }
+
+    y * f64::from_bits(((F64_EXP_BIAS + n) as u64) << F64_MANT_BITS)
+}
+
+#[no_mangle]
+pub extern "C" fn scalbnf(x: f32, mut n: i32) -> f32 {
+    let p127 = f32::from_bits(0x7f00_0000); // 0x1p127f
+    let pm102 = f32::from_bits(0x0c80_0000); // 0x1p-126f * 0x1p24f
+
+    let mut y = x;
+
+    if n > 127 {
+        y *= p127;
+        n -= 127;
+        if n > 127 {
+            y *= p127;
+            n -= 127;
+            if n > 127 {
+                n = 127;
+            }
+        }
+    } else if n < -126 {
+        y *= pm102;
+        n += 126 - 24;
+        if n < -126 {
+            y *= pm102;
+            n += 126 - 24;
+            if n < -126 {
+                n = -126;
+            }
+        }
+    }
+
+    y * f32::from_bits(((F32_EXP_BIAS + n) as u32) << F32_MANT_BITS)
+}
+
+#[no_mangle]
+pub extern "C" fn logb(x: f64) -> f64 {
+    let bits = x.to_bits();
+    let exp = ((bits & F64_EXP_MASK) >> F64_MANT_BITS) as i32;
+
+    if exp == 0x7ff {
+        // logb(NaN) = NaN, logb(+-inf) = +inf
+        return if f64_is_nan(bits) { x } else { f64::from_bits(F64_EXP_MASK) };
+    }
+
+    if exp == 0 {
+        let mant = bits << (64 - F64_MANT_BITS);
+        if mant == 0 {
+            // logb(+-0) = -inf
+            return f64::from_bits(F64_SIGN_MASK | F64_EXP_MASK);
+        }
+
+        // Subnormal
+        return (-F64_EXP_BIAS - mant.leading_zeros() as i32) as f64;
+    }
+
+    (exp - F64_EXP_BIAS) as f64
+}
+
+#[no_mangle]
+pub extern "C" fn logbf(x: f32) -> f32 {
+    let bits = x.to_bits();
+    let exp = ((bits & F32_EXP_MASK) >> F32_MANT_BITS) as i32;
+
+    if exp == 0xff {
+        return if f32_is_nan(bits) { x } else { f32::from_bits(F32_EXP_MASK) };
+    }
+
+    if exp == 0 {
+        let mant = bits << (32 - F32_MANT_BITS);
+        if mant == 0 {
+            return f32::from_bits(F32_SIGN_MASK | F32_EXP_MASK);
+        }
+
+        return (-F32_EXP_BIAS - mant.leading_zeros() as i32) as f32;
+    }
+
+    (exp - F32_EXP_BIAS) as f32
+}
+
+/// Rounds `a` (which has its highest set bit at `msb`) to `mant_bits + 1`
+/// significant bits, to nearest, ties to even.
+///
+/// The result includes the implicit leading bit and may carry over to `1 << (mant_bits + 1)`.
+fn round_significand(a: u64, msb: u32, mant_bits: u32) -> u64 {
+    if msb <= mant_bits {
+        return a << (mant_bits - msb);
+    }
+
+    let shift = msb - mant_bits;
+    let mut m = a >> shift;
+    let rem = a & ((1u64 << shift) - 1);
+    let half = 1u64 << (shift - 1);
+
+    if rem > half || (rem == half && m & 1 == 1) {
+        m += 1;
+    }
+
+    m
+}
+
+#[no_mangle]
+pub extern "C" fn __floatundidf(a: u64) -> f64 {
+    if a == 0 {
+        return f64::from_bits(0);
+    }
+
+    let msb = 63 - a.leading_zeros();
+    let m = round_significand(a, msb, F64_MANT_BITS);
+    let exp = (F64_EXP_BIAS as u64 + msb as u64) << F64_MANT_BITS;
+
+    // A carry out of the significand correctly bumps the exponent.
+    f64::from_bits(exp + (m - (1u64 << F64_MANT_BITS)))
+}
+
+#[no_mangle]
+pub extern "C" fn __floatundisf(a: u64) -> f32 {
+    if a == 0 {
+        return f32::from_bits(0);
+    }
+
+    let msb = 63 - a.leading_zeros();
+    let m = round_significand(a, msb, F32_MANT_BITS);
+    let exp = (F32_EXP_BIAS as u64 + msb as u64) << F32_MANT_BITS;
+
+    f32::from_bits((exp + (m - (1u64 << F32_MANT_BITS))) as u32)
+}
+
+#[no_mangle]
+pub extern "C" fn __udivti3(n: u128, d: u128) -> u128 {
+    if d == 0 {
+        ::linux::kernel_panic("__udivti3: division by zero");
+    }
+
+    if d > n {
+        return 0;
+    }
+
+    // Shift-subtract long division
+    let shift = d.leading_zeros() - n.leading_zeros();
+    let mut d = d << shift;
+    let mut r = n;
+    let mut q: u128 = 0;
+
+    for _ in 0..=shift {
+        q <<= 1;
+        if r >= d {
+            r -= d;
+            q |= 1;
+        }
+        d >>= 1;
+    }
+
+    q
+}
+
+/// Multiplies two unsigned 128-bit integers, returning the wrapped result and
+/// whether it overflowed.
+fn umul128_overflowing(a: u128, b: u128) -> (u128, bool) {
+    let (a_hi, a_lo) = ((a >> 64) as u64, a as u64);
+    let (b_hi, b_lo) = ((b >> 64) as u64, b as u64);
+
+    // Each partial product fits in 128 bits, but their sum may not when both
+    // high halves are set. That case overflows anyway, and the bits lost
+    // are above the wrapped result.
+    let lo = (a_lo as u128) * (b_lo as u128);
+    let (cross, cross_carry) = ((a_hi as u128) * (b_lo as u128))
+        .overflowing_add((a_lo as u128) * (b_hi as u128));
+    let (result, carry) = lo.overflowing_add(cross << 64);
+
+    let overflow = (a_hi != 0 && b_hi != 0) || cross_carry || (cross >> 64) != 0 || carry;
+    (result, overflow)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __muloti4(a: i128, b: i128, overflow: *mut i32) -> i128 {
+    let negative = (a < 0) != (b < 0);
+    let abs_a = if a < 0 { (a as u128).wrapping_neg() } else { a as u128 };
+    let abs_b = if b < 0 { (b as u128).wrapping_neg() } else { b as u128 };
+
+    let (abs_result, mut of) = umul128_overflowing(abs_a, abs_b);
+
+    let limit = if negative { 1u128 << 127 } else { (1u128 << 127) - 1 };
+    if abs_result > limit {
+        of = true;
+    }
+
+    *overflow = if of { 1 } else { 0 };
+
+    if negative {
+        abs_result.wrapping_neg() as i128
+    } else {
+        abs_result as i128
+    }
+}
@@ -0,0 +1,125 @@
+//! Checkpoints of running applications, which can be restored later,
+//! possibly on another machine.
+//!
+//! The format is implemented by `cervus_common::checkpoint`. This module
+//! writes and reads checkpoints through the files given by the loader.
+
+use alloc::String;
+
+use backend::common::*;
+use backend::hexagon_e::EnvConfig;
+use core_dump::CoreDumpWriter;
+use linux;
+use linux::RawFile;
+
+use cervus_common::checkpoint::{self, Limits, LoadError, Reader, MAGIC};
+pub use cervus_common::checkpoint::{Checkpoint, ResourceEntry};
+
+/// Results of a checkpoint request, passed to `lapi_env_finish_checkpoint`.
+pub const STATUS_OK: i32 = 0;
+pub const STATUS_IO_ERROR: i32 = -1;
+pub const STATUS_UNSUPPORTED: i32 = -2;
+
+/// Stops the application once the checkpoint is written.
+pub const FLAG_STOP: u32 = 1;
+
+/// Writes a checkpoint of `state` with the given resources, native invoke
+/// names and manifest. Returns whether everything was written.
+pub fn write(
+    kctx: *mut u8,
+    file: *mut RawFile,
+    state: &CheckpointState,
+    resources: &[ResourceEntry],
+    native_invokes: &[(usize, String)],
+    manifest: Option<&str>
+) -> bool {
+    let mut w = CoreDumpWriter::with_magic(kctx, file, MAGIC);
+    checkpoint::write_sections(&mut w, state, resources, native_invokes, manifest);
+    w.is_ok()
+}
+
+/// Reads a file from the start, like `CoreDumpWriter` writes it.
+struct FileReader {
+    kctx: *mut u8,
+    file: *mut RawFile,
+    offset: i64
+}
+
+impl Reader for FileReader {
+    type Error = BackendError;
+
+    fn read(&mut self, out: &mut [u8]) -> BackendResult<usize> {
+        let mut done: usize = 0;
+
+        while done < out.len() {
+            let ret = unsafe { linux::lapi_env_read_file(
+                self.kctx,
+                self.file,
+                &mut out[done],
+                out.len() - done,
+                self.offset
+            ) };
+            if ret < 0 {
+                return Err(BackendError::Generic);
+            }
+            if ret == 0 {
+                break;
+            }
+            done += ret as usize;
+            self.offset += ret as i64;
+        }
+
+        Ok(done)
+    }
+}
+
+/// Reads the checkpoint given by the loader to restore, if any.
+///
+/// Sections larger than `config` allows for the restored application
+/// are rejected before they are read.
+pub fn load(kctx: *mut u8, config: &EnvConfig) -> BackendResult<Option<Checkpoint>> {
+    let file = unsafe { linux::lapi_env_get_restore_file(kctx) };
+    if file.is_null() {
+        return Ok(None);
+    }
+
+    let mut r = FileReader {
+        kctx: kctx,
+        file: file,
+        offset: 0
+    };
+    let limits = Limits {
+        memory_max_len: config.memory_max_len,
+        max_slots: config.max_slots,
+        stack_len: config.stack_len,
+        call_stack_len: config.call_stack_len
+    };
+
+    match Checkpoint::read(&mut r, &limits) {
+        Ok(v) => Ok(Some(v)),
+        Err(LoadError::Io(e)) => Err(e),
+        Err(LoadError::SectionTooLarge { tag }) => {
+            println!("checkpoint section {} exceeds the resource limits", tag);
+            Err(BackendError::InvalidInput)
+        },
+        Err(LoadError::Malformed(what)) => {
+            println!("malformed checkpoint: {}", what);
+            Err(BackendError::InvalidInput)
+        }
+    }
+}
+
+/// Fails if a native invoke the code uses has another id here than
+/// where the checkpoint was taken.
+pub fn check_native_invokes(cp: &Checkpoint) -> BackendResult<()> {
+    let registry = &::global::get_global().native_invoke_registry;
+
+    for &(id, ref name) in &cp.native_invokes {
+        if registry.map_name_to_id(name) != Some(id as u32) {
+            println!("native invoke {} is not available with id {}", name, id);
+            return Err(BackendError::InvalidNativeInvoke);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! Core dumps of applications that trapped.
+//!
+//! A dump starts with `MAGIC`, followed by sections made of a little-endian
+//! `u32` tag, a little-endian `u64` length and that many bytes of data.
+//! `cvctl` has a reader for this format.
+
+use linux;
+use linux::RawFile;
+
+use cervus_common::checkpoint::SectionWriter;
+
+pub const MAGIC: &'static [u8; 8] = b"CVCORE02";
+
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Section {
+    /// `BackendError` discriminant as `u32`
+    Error = 1,
+
+    /// Offset of the opcode that trapped in the hexagon-e code, as `u64`
+    Ip = 2,
+
+    Memory = 3,
+
+    /// Arrays of `i64`. Only the live parts of the stacks are written.
+    Slots = 4,
+    Stack = 5,
+    CallStack = 6,
+
+    /// One `<id> <kind>` line per open resource
+    Resources = 7
+}
+
+/// Writes a core dump section by section, so that linear memory doesn't need to be copied.
+///
+/// Checkpoints use the same layout with their own magic and tags.
+pub struct CoreDumpWriter {
+    kctx: *mut u8,
+    file: *mut RawFile,
+    offset: i64,
+    failed: bool
+}
+
+impl CoreDumpWriter {
+    pub fn new(kctx: *mut u8, file: *mut RawFile) -> CoreDumpWriter {
+        CoreDumpWriter::with_magic(kctx, file, MAGIC)
+    }
+
+    pub fn with_magic(kctx: *mut u8, file: *mut RawFile, magic: &[u8]) -> CoreDumpWriter {
+        let mut w = CoreDumpWriter {
+            kctx: kctx,
+            file: file,
+            offset: 0,
+            failed: false
+        };
+        w.write_all(magic);
+        w
+    }
+
+    fn write_all(&mut self, data: &[u8]) {
+        let mut written: usize = 0;
+
+        while !self.failed && written < data.len() {
+            let ret = unsafe { linux::lapi_env_write_file(
+                self.kctx,
+                self.file,
+                &data[written],
+                data.len() - written,
+                self.offset
+            ) };
+            if ret <= 0 {
+                self.failed = true;
+            } else {
+                written += ret as usize;
+                self.offset += ret as i64;
+            }
+        }
+    }
+
+    pub fn write_section(&mut self, section: Section, data: &[u8]) {
+        self.write_tagged(section as u32, data);
+    }
+
+    pub fn write_i64_section(&mut self, section: Section, data: &[i64]) {
+        self.write_i64_tagged(section as u32, data);
+    }
+
+    pub fn write_tagged(&mut self, tag: u32, data: &[u8]) {
+        let tag: [u8; 4] = unsafe { ::core::mem::transmute(tag.to_le()) };
+        let len: [u8; 8] = unsafe { ::core::mem::transmute((data.len() as u64).to_le()) };
+
+        self.write_all(&tag);
+        self.write_all(&len);
+        self.write_all(data);
+    }
+
+    /// Only correct on little-endian targets, which is all we build for.
+    pub fn write_i64_tagged(&mut self, tag: u32, data: &[i64]) {
+        let data = unsafe { ::core::slice::from_raw_parts(
+            data.as_ptr() as *const u8,
+            data.len() * ::core::mem::size_of::<i64>()
+        ) };
+        self.write_tagged(tag, data);
+    }
+
+    /// Whether all sections so far were written completely.
+    pub fn is_ok(&self) -> bool {
+        !self.failed
+    }
+}
+
+impl SectionWriter for CoreDumpWriter {
+    fn write_tagged(&mut self, tag: u32, data: &[u8]) {
+        CoreDumpWriter::write_tagged(self, tag, data);
+    }
+
+    fn write_i64_tagged(&mut self, tag: u32, data: &[i64]) {
+        CoreDumpWriter::write_i64_tagged(self, tag, data);
+    }
+}
@@ -0,0 +1,64 @@
+//! Requests of debuggers attached to applications running in debug mode.
+//!
+//! The kernel module queues them in debug.c, and the backend serves them
+//! through `Context::take_debug_request` while the application is stopped.
+
+use backend::common::*;
+
+/// Commands of `CERVUS_DEBUG`, same as `DEBUG_CMD_*` in debug.h.
+pub const CMD_ATTACH: u32 = 1;
+pub const CMD_DETACH: u32 = 2;
+pub const CMD_CONTINUE: u32 = 3;
+pub const CMD_STEP: u32 = 4;
+pub const CMD_INTERRUPT: u32 = 5;
+pub const CMD_SET_BREAKPOINT: u32 = 6;
+pub const CMD_CLEAR_BREAKPOINT: u32 = 7;
+pub const CMD_READ: u32 = 8;
+
+/// What `CMD_READ` reads from, same as `DEBUG_AREA_*`.
+pub const AREA_MEMORY: u32 = 0;
+pub const AREA_SLOTS: u32 = 1;
+pub const AREA_STACK: u32 = 2;
+pub const AREA_CALL_STACK: u32 = 3;
+pub const AREA_CODE: u32 = 4;
+
+/// Results passed to `lapi_env_finish_debug_request`, same as `DEBUG_STATUS_*`.
+pub const STATUS_OK: i32 = 0;
+pub const STATUS_INVALID: i32 = -1;
+
+/// A request as taken from the queue. Must match `struct debug_request_args`.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct RawRequest {
+    pub cmd: u32,
+    pub area: u32,
+    pub arg: u64,
+    pub len: u64
+}
+
+impl RawRequest {
+    pub fn parse(&self) -> Option<DebugRequest> {
+        Some(match self.cmd {
+            CMD_ATTACH => DebugRequest::Attach,
+            CMD_DETACH => DebugRequest::Detach,
+            CMD_CONTINUE => DebugRequest::Continue,
+            CMD_STEP => DebugRequest::Step,
+            CMD_INTERRUPT => DebugRequest::Interrupt,
+            CMD_SET_BREAKPOINT => DebugRequest::SetBreakpoint(self.arg as usize),
+            CMD_CLEAR_BREAKPOINT => DebugRequest::ClearBreakpoint(self.arg as usize),
+            CMD_READ => DebugRequest::Read {
+                area: match self.area {
+                    AREA_MEMORY => DebugArea::Memory,
+                    AREA_SLOTS => DebugArea::Slots,
+                    AREA_STACK => DebugArea::Stack,
+                    AREA_CALL_STACK => DebugArea::CallStack,
+                    AREA_CODE => DebugArea::Code,
+                    _ => return None
+                },
+                offset: self.arg as usize,
+                len: self.len as usize
+            },
+            _ => return None
+        })
+    }
+}
@@ -1,19 +1,219 @@
 use alloc::boxed::Box;
+use alloc::{String, Vec, BTreeMap};
+use alloc::arc::Arc;
 use core::cell::Cell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use linux;
+use linux::RawThread;
 use backend::common::*;
 use slab::Slab;
-use resource::Resource;
-use resource::LinuxFile;
-use memory_pressure::MemoryPressure;
+use resource::{Resource, IoOptions, IoResult, IoError};
+use resource::{LinuxFile, SharedResource, ResourceSnapshot};
+use sync::{WaitQueue, poll_until};
+use mutex::Mutex;
+use backend::Executor;
+use backend::hexagon_e::EnvConfig;
+use backend::hexagon_e::trans::{self, Translated};
+use memory_pressure::{MemoryPressure, MemoryPressureHandle};
+use profile::Profile;
+use core_dump::{CoreDumpWriter, Section};
+use replay::{InvokeLog, Entry};
+use manifest::Manifest;
+use trace::SharedTracer;
+use checkpoint;
+use checkpoint::ResourceEntry;
+use debug;
+use url::Url;
+use api::NativeInvoke;
 use error::*;
 
 pub struct UsermodeContext {
     pub kctx: *mut u8,
     pub resources: Slab<Box<Resource>>,
+
+    // Only for resources that don't block normally
+    io_options: BTreeMap<usize, IoOptions>,
+    mp: MemoryPressure,
+
+    // Linear memory, slots and stacks of the backend
+    guest_mem: MemoryPressureHandle,
+    prev_oom_score_adj: Cell<i16>,
+    exit_code: Option<i32>,
+    profile: Option<Profile>,
+    invoke_log: Option<InvokeLog>,
+
+    // Everything is allowed if `None`
+    manifest: Option<Manifest>,
+
+    // Shared with spawned instances, which trace to the same file
+    trace: Option<SharedTracer>,
+
+    // Absolute values of `lapi_get_monotonic_ns` and `lapi_get_cpu_time_ns`
+    deadline: Option<u64>,
+    cpu_time_deadline: Option<u64>,
+
+    // 0 for the instance started by the loader, counting up for spawned ones
+    instance: usize,
+    instances: Arc<Instances>,
+
+    // Configuration and backend of spawned instances. Spawning is
+    // unavailable if `None`.
+    child_config: Option<(EnvConfig, Executor)>,
+
+    // Running or exited children that haven't been waited for, by process id
+    children: Slab<ChildProcess>,
+
+    // Set for spawned instances, overriding what the loader passed in `kctx`
+    args: Option<Vec<Vec<u8>>>,
+    stdio: [Option<SharedResource>; 3]
+}
+
+/// Every spawned instance has a thread and a kernel stack of its own, so
+/// their number is bounded for the whole application.
+const MAX_RUNNING_INSTANCES: usize = 16;
+
+/// Shared by all instances of an application.
+struct Instances {
+    next_id: AtomicUsize,
+    running: AtomicUsize
+}
+
+/// A spawned instance, which is killed if it is still running when this is dropped.
+struct ChildProcess {
+    thread: *mut RawThread,
+    shared: Arc<ChildShared>
+}
+
+struct ChildShared {
+    report: Mutex<Option<TerminationReport>>,
+    exited: AtomicBool,
+    exit_wq: WaitQueue
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.shared.exited.load(Ordering::SeqCst) {
+                linux::lapi_thread_kill(self.thread);
+            }
+            linux::lapi_thread_join(self.thread);
+        }
+    }
+}
+
+/// Everything a spawned instance starts with, moved to its thread.
+struct ChildStart {
+    kctx: *mut u8,
+    instance: usize,
+    instances: Arc<Instances>,
+    code: Translated,
+    config: EnvConfig,
+    executor: Executor,
+    args: Vec<Vec<u8>>,
+    stdio: [Option<SharedResource>; 3],
     mp: MemoryPressure,
-    prev_oom_score_adj: Cell<i16>
+    manifest: Option<Manifest>,
+    trace: Option<SharedTracer>,
+    deadline: Option<u64>,
+
+    // CPU time is counted per thread, so the child gets what the parent had left.
+    cpu_time_left_ns: Option<u64>,
+    shared: Arc<ChildShared>
+}
+
+impl ChildStart {
+    fn run(self) -> TerminationReport {
+        let code = self.code;
+        let config = self.config;
+
+        let mut ctx = UsermodeContext {
+            kctx: self.kctx,
+            resources: Slab::new(),
+            io_options: BTreeMap::new(),
+            mp: self.mp.clone(),
+            guest_mem: self.mp.handle(),
+            prev_oom_score_adj: Cell::new(0),
+            exit_code: None,
+            profile: None,
+            invoke_log: None,
+            manifest: self.manifest,
+            trace: self.trace,
+            deadline: self.deadline,
+            cpu_time_deadline: self.cpu_time_left_ns.map(|ns| {
+                unsafe { linux::lapi_get_cpu_time_ns() }.saturating_add(ns)
+            }),
+            instance: self.instance,
+            instances: self.instances,
+            child_config: Some((config.clone(), self.executor)),
+            children: Slab::new(),
+            args: Some(self.args),
+            stdio: self.stdio
+        };
+
+        let result = self.executor.run(config, &code.code, &code.entry_args, &mut ctx);
+
+        match result {
+            Ok(v) => TerminationReport::returned(v),
+            Err(BackendError::Exit) => TerminationReport::exited(ctx.exit_code.unwrap_or(0)),
+            Err(e) => TerminationReport::from_error(e)
+        }
+    }
+}
+
+extern "C" fn child_main(arg: *mut u8) {
+    let start = unsafe { Box::from_raw(arg as *mut ChildStart) };
+    let shared = start.shared.clone();
+    let instances = start.instances.clone();
+
+    // Resources of the child, including its own children, are released before it counts as exited.
+    let report = start.run();
+
+    if let Ok(mut v) = shared.report.lock() {
+        *v = Some(report);
+    }
+    instances.running.fetch_sub(1, Ordering::SeqCst);
+    shared.exited.store(true, Ordering::SeqCst);
+    shared.exit_wq.wake();
+}
+
+/// Translates the WebAssembly binary `code` to call its export `entry`,
+/// resolving imports through the native invoke registry.
+pub fn translate_wasm(
+    code: &[u8],
+    entry: &[u8],
+    args: &[i64],
+    max_slots: usize
+) -> BackendResult<Translated> {
+    let entry = match ::core::str::from_utf8(entry) {
+        Ok(v) => v,
+        Err(_) => return Err(BackendError::InvalidInput)
+    };
+
+    trans::translate(
+        code,
+        entry,
+        args,
+        max_slots,
+        UsermodeContext::map_cwa_api_to_native_invoke
+    ).map_err(|e| {
+        println!("unable to translate code: {:?}", e);
+        e.into()
+    })
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExecutionLimits {
+    /// Wall-clock time limit in milliseconds
+    pub timeout_ms: Option<u64>,
+
+    /// CPU time limit in milliseconds
+    pub cpu_time_ms: Option<u64>
+}
+
+fn ms_after(now_ns: u64, ms: u64) -> u64 {
+    now_ns.saturating_add(ms.saturating_mul(1000000))
 }
 
 fn calc_oom_score_adj(mem_pressure: usize) -> i16 {
@@ -26,21 +226,332 @@ fn calc_oom_score_adj(mem_pressure: usize) -> i16 {
     }
 }
 
+fn open_invoke_log(kctx: *mut u8) -> Option<InvokeLog> {
+    unsafe {
+        if let Ok(f) = LinuxFile::from_raw_checked(kctx, linux::lapi_env_get_replay_file(kctx), false) {
+            Some(InvokeLog::Replay(f))
+        } else if let Ok(f) = LinuxFile::from_raw_checked(kctx, linux::lapi_env_get_record_file(kctx), false) {
+            Some(InvokeLog::Record(f))
+        } else {
+            None
+        }
+    }
+}
+
+/// The region of `mem` that `ni` wrote to when called with `args` and
+/// returned `result`.
+fn output_region<'m>(
+    ni: &NativeInvoke,
+    args: &[i64],
+    result: &BackendResult<Option<i64>>,
+    mem: &'m mut [u8]
+) -> Option<&'m mut [u8]> {
+    let (base, len) = ni.output_args()?;
+    let base = *args.get(base)? as u32 as usize;
+    let mut len = *args.get(len)? as u32 as usize;
+
+    match *result {
+        Ok(ret) => if ni.returns_output_len() {
+            let written = match ret {
+                Some(n) if n > 0 => n as u64,
+                _ => 0
+            };
+            if written < len as u64 {
+                len = written as usize;
+            }
+        },
+        Err(_) => return None
+    }
+
+    mem.get_mut(base..base.checked_add(len)?)
+}
+
 impl UsermodeContext {
-    pub fn new(kctx: *mut u8) -> UsermodeContext {
+    pub fn new(kctx: *mut u8, limits: ExecutionLimits, profile: Option<Profile>) -> UsermodeContext {
+        let mp = MemoryPressure::new();
+        let guest_mem = mp.handle();
+
         UsermodeContext {
             kctx: kctx,
             resources: Slab::new(),
-            mp: MemoryPressure::new(),
-            prev_oom_score_adj: Cell::new(0)
+            io_options: BTreeMap::new(),
+            mp: mp,
+            guest_mem: guest_mem,
+            prev_oom_score_adj: Cell::new(0),
+            exit_code: None,
+            profile: profile,
+            invoke_log: open_invoke_log(kctx),
+            manifest: None,
+            trace: ::trace::open(kctx),
+            deadline: limits.timeout_ms.map(|ms| ms_after(
+                unsafe { linux::lapi_get_monotonic_ns() },
+                ms
+            )),
+            cpu_time_deadline: limits.cpu_time_ms.map(|ms| ms_after(
+                unsafe { linux::lapi_get_cpu_time_ns() },
+                ms
+            )),
+            instance: 0,
+            instances: Arc::new(Instances {
+                next_id: AtomicUsize::new(1),
+                running: AtomicUsize::new(0)
+            }),
+            child_config: None,
+            children: Slab::new(),
+            args: None,
+            stdio: [None, None, None]
         }
     }
 
+    /// Restricts the native invokes and URLs the application may use.
+    pub fn set_manifest(&mut self, manifest: Manifest) {
+        self.manifest = Some(manifest);
+    }
+
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Allows the application to spawn instances running with `config` on
+    /// `executor`.
+    pub fn set_child_config(&mut self, config: EnvConfig, executor: Executor) {
+        self.child_config = Some((config, executor));
+    }
+
+    /// Starts the WebAssembly binary `code` as a child instance on a thread
+    /// of its own, returning its process id.
+    ///
+    /// The child shares the memory pressure, fuel and wall-clock deadline of
+    /// this instance. Its stdio falls back to ours where `stdio` is `None`.
+    /// Fails with `WouldBlock` if too many instances are running.
+    pub fn spawn(
+        &mut self,
+        code: &[u8],
+        args: Vec<Vec<u8>>,
+        stdio: [Option<SharedResource>; 3]
+    ) -> BackendResult<Result<usize, CwaError>> {
+        let (config, executor) = match self.child_config {
+            Some((ref config, executor)) => (config.clone(), executor),
+            None => return Ok(Err(CwaError::PermissionDenied))
+        };
+
+        let code = match translate_wasm(code, b"__app_main", &[], config.max_slots) {
+            Ok(v) => v,
+            Err(_) => return Ok(Err(CwaError::InvalidArgument))
+        };
+
+        let mut stdio = stdio;
+        for (own, inherited) in stdio.iter_mut().zip(self.stdio.iter()) {
+            if own.is_none() {
+                *own = inherited.clone();
+            }
+        }
+
+        let shared = Arc::new(ChildShared {
+            report: Mutex::new(None)?,
+            exited: AtomicBool::new(false),
+            exit_wq: WaitQueue::new()?
+        });
+
+        if self.instances.running.fetch_add(1, Ordering::SeqCst) >= MAX_RUNNING_INSTANCES {
+            self.instances.running.fetch_sub(1, Ordering::SeqCst);
+            return Ok(Err(CwaError::WouldBlock));
+        }
+
+        let start = Box::new(ChildStart {
+            kctx: self.kctx,
+            instance: self.instances.next_id.fetch_add(1, Ordering::SeqCst),
+            instances: self.instances.clone(),
+            code: code,
+            config: config,
+            executor: executor,
+            args: args,
+            stdio: stdio,
+            mp: self.mp.clone(),
+            manifest: self.manifest.clone(),
+            trace: self.trace.clone(),
+            deadline: self.deadline,
+            cpu_time_left_ns: self.cpu_time_deadline.map(|deadline| {
+                deadline.saturating_sub(unsafe { linux::lapi_get_cpu_time_ns() })
+            }),
+            shared: shared.clone()
+        });
+        let start = Box::into_raw(start) as *mut u8;
+
+        let thread = unsafe { linux::lapi_thread_spawn(child_main, start) };
+        if thread.is_null() {
+            unsafe { ::core::mem::drop(Box::from_raw(start as *mut ChildStart)); }
+            self.instances.running.fetch_sub(1, Ordering::SeqCst);
+            return Err(BackendError::Generic);
+        }
+
+        Ok(Ok(self.children.insert(ChildProcess {
+            thread: thread,
+            shared: shared
+        })))
+    }
+
+    /// Waits for child `pid` to terminate and returns its report, or `None`
+    /// if there is no such child. The child can't be waited for again.
+    pub fn wait_child(&mut self, pid: usize) -> BackendResult<Option<TerminationReport>> {
+        let shared = match self.children.get(pid) {
+            Ok(v) => v.shared.clone(),
+            Err(_) => return Ok(None)
+        };
+
+        loop {
+            let exited = poll_until(self.clamp_wait_ms(None), |poller| {
+                poller.register(&shared.exit_wq);
+                Ok(shared.exited.load(Ordering::SeqCst))
+            })?;
+            if exited {
+                break;
+            }
+            self.check_limits()?;
+        }
+
+        let report = shared.report.lock()?.take();
+        self.children.remove(pid)?;
+
+        // A child that was unable to store its report was killed.
+        Ok(Some(report.unwrap_or(TerminationReport::from_error(BackendError::FatalSignal))))
+    }
+
+    pub fn arg_count(&self) -> usize {
+        match self.args {
+            Some(ref args) => args.len(),
+            None => unsafe { linux::lapi_env_get_n_args(self.kctx) as usize }
+        }
+    }
+
+    /// Copies as much of argument `id` as fits into `out`, returning the number of bytes copied.
+    pub fn read_arg(&self, id: usize, out: &mut [u8]) -> Option<usize> {
+        match self.args {
+            Some(ref args) => {
+                let arg = args.get(id)?;
+                let len = if arg.len() < out.len() { arg.len() } else { out.len() };
+                out[0..len].copy_from_slice(&arg[0..len]);
+                Some(len)
+            },
+            None => {
+                if out.len() == 0 || id > ::core::u32::MAX as usize {
+                    return None;
+                }
+
+                let ret = unsafe {
+                    linux::lapi_env_read_arg(self.kctx, id as u32, &mut out[0], out.len())
+                };
+                if ret < 0 {
+                    None
+                } else {
+                    Some(ret as usize)
+                }
+            }
+        }
+    }
+
+    /// Opens stdin (0), stdout (1) or stderr (2) as a resource.
+    pub fn open_stdio(&mut self, n: usize) -> i32 {
+        if let Some(res) = self.stdio.get(n).and_then(|v| v.clone()) {
+            return self.add_resource(Box::new(res)) as i32;
+        }
+
+        match self.loader_stdio(n) {
+            Some(f) => self.add_resource(Box::new(f)) as i32,
+            None => -1
+        }
+    }
+
+    /// stdin (0), stdout (1) or stderr (2) as given by the loader.
+    fn loader_stdio(&self, n: usize) -> Option<LinuxFile> {
+        let raw = unsafe {
+            match n {
+                0 => linux::lapi_env_get_stdin(self.kctx),
+                1 => linux::lapi_env_get_stdout(self.kctx),
+                2 => linux::lapi_env_get_stderr(self.kctx),
+                _ => return None
+            }
+        };
+
+        unsafe { LinuxFile::from_raw_checked(self.kctx, raw, false) }
+            .ok()
+            .map(|v| v.with_stdio(n))
+    }
+
+    /// Opens the resources of a restored checkpoint again, with the ids and
+    /// I/O options they had. Files are subject to the manifest, if any.
+    pub fn restore_resources(&mut self, entries: &[ResourceEntry]) -> BackendResult<()> {
+        for entry in entries {
+            let f = match entry.snapshot {
+                ResourceSnapshot::Stdio(n) => match self.loader_stdio(n) {
+                    Some(v) => v,
+                    None => {
+                        println!("checkpoint: stdio {} is not available", n);
+                        return Err(BackendError::InvalidInput);
+                    }
+                },
+                ResourceSnapshot::File { ref path, offset } => {
+                    let url = Url {
+                        scheme: "file",
+                        path: path.as_str(),
+                        unparsed_args: ""
+                    };
+                    if let Some(ref m) = self.manifest {
+                        if !m.allows_url(url.scheme, url.path) {
+                            println!("checkpoint: the manifest doesn't allow {}", path);
+                            return Err(BackendError::InvalidInput);
+                        }
+                    }
+
+                    let mut f = match ::schemes::file::open_path(self.kctx, path, self.manifest.as_ref()) {
+                        Ok(v) => v,
+                        Err(_) => {
+                            println!("checkpoint: unable to open {}", path);
+                            return Err(BackendError::InvalidInput);
+                        }
+                    };
+                    f.set_offset(offset);
+                    f
+                }
+            };
+
+            let mut res: Box<Resource> = Box::new(f);
+            res.init_mem_pressure(self.mp.handle());
+            self.resources.insert_at(entry.id, res)?;
+            self.set_io_options(entry.id, entry.options)?;
+        }
+
+        self.update_oom_score();
+        Ok(())
+    }
+
+    /// Reports the result of a checkpoint request to the requester.
+    fn finish_checkpoint(&self, status: i32, resource: Option<(usize, &str)>) {
+        let (id, kind) = match resource {
+            Some((id, kind)) => (id as i64, kind.as_bytes()),
+            None => (-1, &[][..])
+        };
+
+        unsafe { linux::lapi_env_finish_checkpoint(
+            self.kctx,
+            status,
+            id,
+            if kind.len() == 0 { ::core::ptr::null() } else { &kind[0] },
+            kind.len()
+        ); }
+    }
+
     pub fn map_cwa_api_to_native_invoke(name: &str) -> Option<u32> {
         ::global::get_global().native_invoke_registry.map_name_to_id(name)
     }
 
     fn update_oom_score(&self) {
+        // Spawned instances run on kernel threads, and their memory is
+        // accounted to the task of the loader through the shared pressure.
+        if self.instance != 0 {
+            return;
+        }
+
         let new_val = calc_oom_score_adj(self.mp.read());
         let old_val = self.prev_oom_score_adj.get();
 
@@ -59,11 +570,73 @@ impl UsermodeContext {
 
     pub fn remove_resource(&mut self, id: usize) -> KernelResult<()> {
         self.resources.remove(id)?;
+        self.io_options.remove(&id);
         self.update_oom_score();
 
         Ok(())
     }
 
+    pub fn set_io_options(&mut self, id: usize, options: IoOptions) -> KernelResult<()> {
+        self.resources.get_mut(id)?;
+
+        if options == IoOptions::default() {
+            self.io_options.remove(&id);
+        } else {
+            self.io_options.insert(id, options);
+        }
+
+        Ok(())
+    }
+
+    /// Waits as allowed by the I/O options of resource `id` until it is ready for `interest`.
+    ///
+    /// With a deadline set, resources that block normally are waited for here
+    /// as well, so that the read or write itself doesn't outlast the deadline.
+    pub fn wait_io_ready(&mut self, id: usize, interest: u32) -> BackendResult<IoResult<()>> {
+        let options = self.io_options.get(&id).cloned().unwrap_or_default();
+        if options == IoOptions::default() && self.deadline.is_none() {
+            return Ok(Ok(()));
+        }
+
+        let wait_ms = self.clamp_wait_ms(options.wait_ms());
+        let ready = {
+            let res = self.resources.get_mut(id)?;
+            poll_until(wait_ms, |poller| {
+                Ok(res.poll_ready(poller, interest)? != 0)
+            })?
+        };
+
+        if ready {
+            return Ok(Ok(()));
+        }
+
+        self.check_limits()?;
+
+        Ok(if options.nonblock {
+            Err(IoError::WouldBlock)
+        } else {
+            Err(IoError::TimedOut)
+        })
+    }
+
+    /// Shortens a wait of `timeout_ms` (`None` for no timeout) so that it
+    /// ends by the wall-clock deadline. Waiting doesn't use CPU time, so the
+    /// CPU time limit can't be reached while blocked.
+    pub fn clamp_wait_ms(&self, timeout_ms: Option<u64>) -> Option<u64> {
+        let remaining_ms = match self.deadline {
+            Some(deadline) => {
+                let now = unsafe { linux::lapi_get_monotonic_ns() };
+                Some(deadline.saturating_sub(now).saturating_add(999999) / 1000000)
+            },
+            None => return timeout_ms
+        };
+
+        match timeout_ms {
+            Some(v) => ::core::cmp::min(Some(v), remaining_ms),
+            None => remaining_ms
+        }
+    }
+
     pub unsafe fn add_raw_linux_file(&mut self, raw: *mut linux::RawFile, need_close: bool) -> i32 {
         match LinuxFile::from_raw_checked(
             self.kctx,
@@ -75,6 +648,132 @@ impl UsermodeContext {
         }
     }
 
+    /// Estimated memory used by the application in bytes, including its linear memory.
+    pub fn memory_usage(&self) -> usize {
+        self.mp.read()
+    }
+
+    /// Records the exit code of the application. The caller should unwind with `BackendError::Exit`.
+    pub fn set_exit_code(&mut self, code: i32) {
+        self.exit_code = Some(code);
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Writes the profile, if any, to the profile file given by the loader.
+    pub fn write_profile(&self) {
+        let profile = match self.profile {
+            Some(ref v) => v,
+            None => return
+        };
+
+        let file = unsafe { linux::lapi_env_get_profile_file(self.kctx) };
+        if file.is_null() {
+            return;
+        }
+
+        let registry = &::global::get_global().native_invoke_registry;
+        let text = profile.format(|id| registry.get(id).ok().map(|v| v.name().into()));
+        let text = text.as_bytes();
+
+        let mut written: usize = 0;
+        while written < text.len() {
+            let ret = unsafe { linux::lapi_env_write_file(
+                self.kctx,
+                file,
+                &text[written],
+                text.len() - written,
+                written as i64
+            ) };
+            if ret <= 0 {
+                println!("unable to write profile");
+                return;
+            }
+            written += ret as usize;
+        }
+    }
+
+    /// Calls a native invoke, or takes its result from the invoke log.
+    fn dispatch_native_invoke(
+        &mut self,
+        id: usize,
+        ni: &NativeInvoke,
+        args: &[i64],
+        mem: &mut [u8]
+    ) -> BackendResult<Option<i64>> {
+        if let Some(ref m) = self.manifest {
+            if !m.allows_invoke(id) {
+                return Ok(Some(CwaError::PermissionDenied.status() as i64));
+            }
+        }
+
+        let result = match self.invoke_log {
+            Some(InvokeLog::Replay(_)) => self.replay_native_invoke(id, ni, args, mem),
+            _ => ni.call(self, args, mem)
+        };
+        self.update_oom_score();
+
+        if let Some(ref mut log @ InvokeLog::Record(_)) = self.invoke_log {
+            log.record(&Entry {
+                id: id,
+                result: result,
+                exit_code: self.exit_code,
+                output: output_region(ni, args, &result, mem).map(|v| v.to_vec()).unwrap_or(vec! [])
+            })?;
+        }
+
+        result
+    }
+
+    /// Returns the recorded result of a native invoke instead of calling it.
+    fn replay_native_invoke(
+        &mut self,
+        id: usize,
+        ni: &NativeInvoke,
+        args: &[i64],
+        mem: &mut [u8]
+    ) -> BackendResult<Option<i64>> {
+        let entry = match self.invoke_log {
+            Some(ref mut log) => log.replay(mem.len())?,
+            None => return Err(BackendError::InvalidInput)
+        };
+
+        if entry.id != id || !ni.replayable() {
+            return Err(BackendError::ReplayMismatch);
+        }
+
+        match output_region(ni, args, &entry.result, mem) {
+            Some(ref mut out) if out.len() == entry.output.len() => out.copy_from_slice(&entry.output),
+            None if entry.output.len() == 0 => {},
+            _ => return Err(BackendError::ReplayMismatch)
+        }
+
+        if let Some(code) = entry.exit_code {
+            self.set_exit_code(code);
+        }
+
+        entry.result
+    }
+
+    /// Fails with `Timeout` or `CpuTimeout` once a time limit has been reached.
+    pub fn check_limits(&self) -> BackendResult<()> {
+        if let Some(deadline) = self.deadline {
+            if unsafe { linux::lapi_get_monotonic_ns() } >= deadline {
+                return Err(BackendError::Timeout);
+            }
+        }
+
+        if let Some(deadline) = self.cpu_time_deadline {
+            if unsafe { linux::lapi_get_cpu_time_ns() } >= deadline {
+                return Err(BackendError::CpuTimeout);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn log(&self, level: i32, text: &str) {
         let text = text.as_bytes();
 
@@ -93,17 +792,205 @@ impl Context for UsermodeContext {
         if ret < 0 {
             Err(BackendError::FatalSignal)
         } else {
-            Ok(())
+            self.check_limits()
         }
     }
 
+    fn tick_pending(&self) -> bool {
+        unsafe { linux::lapi_env_need_resched() != 0 }
+    }
+
     fn get_native_invoke_policy(&self, id: usize) -> BackendResult<NativeInvokePolicy> {
         Ok(::global::get_global().native_invoke_registry.get(id)?.policy())
     }
 
-    fn do_native_invoke(&mut self, id: usize, args: &[i64], mem: &mut [u8]) -> BackendResult<Option<i64>> {
-        let result = ::global::get_global().native_invoke_registry.get(id)?.call(self, args, mem);
+    fn set_guest_memory_usage(&self, bytes: usize) {
+        self.guest_mem.set(bytes);
         self.update_oom_score();
-        result
+    }
+
+    fn profile_interval(&self) -> Option<u32> {
+        self.profile.as_ref().map(|v| v.interval())
+    }
+
+    fn sample_branch(&self, target: usize) {
+        if let Some(ref p) = self.profile {
+            p.sample_branch(target);
+        }
+    }
+
+    fn wants_core_dump(&self) -> bool {
+        self.instance == 0 && !unsafe { linux::lapi_env_get_core_file(self.kctx) }.is_null()
+    }
+
+    fn write_core_dump(&self, state: &GuestState) {
+        let file = unsafe { linux::lapi_env_get_core_file(self.kctx) };
+        if file.is_null() {
+            return;
+        }
+
+        let mut resources = String::new();
+        let _ = self.resources.for_each_with_id(|id, res| {
+            writeln!(resources, "{} {}", id, res.kind())
+        });
+
+        let error: [u8; 4] = unsafe { ::core::mem::transmute((state.error as u32).to_le()) };
+        let ip: [u8; 8] = unsafe { ::core::mem::transmute((state.ip as u64).to_le()) };
+
+        let mut w = CoreDumpWriter::new(self.kctx, file);
+        w.write_section(Section::Error, &error);
+        w.write_section(Section::Ip, &ip);
+        w.write_section(Section::Memory, state.memory);
+        w.write_i64_section(Section::Slots, state.slots);
+        w.write_i64_section(Section::Stack, state.stack);
+        w.write_i64_section(Section::CallStack, state.call_stack);
+        w.write_section(Section::Resources, resources.as_bytes());
+
+        if !w.is_ok() {
+            println!("unable to write core dump");
+        }
+    }
+
+    fn checkpoint_pending(&self) -> bool {
+        self.instance == 0 && unsafe { linux::lapi_env_checkpoint_requested(self.kctx) } != 0
+    }
+
+    fn write_checkpoint(&self, state: &CheckpointState) -> BackendResult<()> {
+        let mut flags: u32 = 0;
+        let file = unsafe { linux::lapi_env_take_checkpoint_request(self.kctx, &mut flags) };
+        if file.is_null() {
+            // Withdrawn by the requester
+            return Ok(());
+        }
+
+        let mut resources: Vec<ResourceEntry> = Vec::new();
+        let mut unsupported: Option<(usize, &'static str)> = None;
+        let _: Result<(), ()> = self.resources.for_each_with_id(|id, res| {
+            match res.snapshot() {
+                Some(snapshot) => resources.push(ResourceEntry {
+                    id: id,
+                    options: self.io_options.get(&id).cloned().unwrap_or_default(),
+                    snapshot: snapshot
+                }),
+                None => if unsupported.is_none() {
+                    unsupported = Some((id, res.kind()));
+                }
+            }
+            Ok(())
+        });
+
+        if let Some((id, kind)) = unsupported {
+            println!("checkpoint: resource {} ({}) can't be checkpointed", id, kind);
+            self.finish_checkpoint(checkpoint::STATUS_UNSUPPORTED, Some((id, kind)));
+            return Ok(());
+        }
+
+        let registry = &::global::get_global().native_invoke_registry;
+        let mut native_invokes: Vec<(usize, String)> = Vec::new();
+        for &id in state.native_invokes {
+            native_invokes.push((id, registry.get(id)?.name().into()));
+        }
+        let manifest = self.manifest.as_ref()
+            .map(|m| m.format(|id| registry.get(id).ok().map(|v| v.name().into())));
+
+        if !checkpoint::write(
+            self.kctx,
+            file,
+            state,
+            &resources,
+            &native_invokes,
+            manifest.as_ref().map(|v| v.as_str())
+        ) {
+            println!("unable to write checkpoint");
+            self.finish_checkpoint(checkpoint::STATUS_IO_ERROR, None);
+            return Ok(());
+        }
+
+        self.finish_checkpoint(checkpoint::STATUS_OK, None);
+
+        if flags & checkpoint::FLAG_STOP != 0 {
+            Err(BackendError::Checkpointed)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Spawned instances are not debugged, like they don't write core dumps.
+    fn debug_enabled(&self) -> bool {
+        self.instance == 0 && unsafe { linux::lapi_env_debug_enabled(self.kctx) } != 0
+    }
+
+    fn debug_pending(&self) -> bool {
+        unsafe { linux::lapi_env_debug_pending(self.kctx) != 0 }
+    }
+
+    fn take_debug_request(&self, wait: bool) -> BackendResult<Option<DebugRequest>> {
+        loop {
+            let mut raw = debug::RawRequest::default();
+            let ret = unsafe { linux::lapi_env_take_debug_request(self.kctx, wait as i32, &mut raw) };
+            if ret < 0 {
+                return Err(BackendError::FatalSignal);
+            }
+            if ret == 0 {
+                return Ok(None);
+            }
+
+            // The kernel module only queues known requests.
+            match raw.parse() {
+                Some(v) => return Ok(Some(v)),
+                None => unsafe { linux::lapi_env_finish_debug_request(
+                    self.kctx,
+                    debug::STATUS_INVALID,
+                    ::core::ptr::null(),
+                    ::core::ptr::null(),
+                    0
+                ); }
+            }
+        }
+    }
+
+    fn finish_debug_request(&self, data: Option<&[u8]>, stop: &DebugStop) {
+        let (status, data) = match data {
+            Some(v) => (debug::STATUS_OK, v),
+            None => (debug::STATUS_INVALID, &[][..])
+        };
+
+        unsafe { linux::lapi_env_finish_debug_request(
+            self.kctx,
+            status,
+            stop,
+            if data.len() == 0 { ::core::ptr::null() } else { &data[0] },
+            data.len()
+        ); }
+    }
+
+    fn do_native_invoke(&mut self, id: usize, args: &[i64], mem: &mut [u8]) -> BackendResult<Option<i64>> {
+        if let Some(ref p) = self.profile {
+            p.count_native_invoke(id);
+        }
+
+        let ni = ::global::get_global().native_invoke_registry.get(id)?;
+
+        let trace_start = match self.trace {
+            Some(ref t) => {
+                t.lock()?.enter(self.instance, &*ni, args, mem)?;
+                Some(unsafe { linux::lapi_get_monotonic_ns() })
+            },
+            None => None
+        };
+
+        let result = self.dispatch_native_invoke(id, &*ni, args, mem);
+
+        if let (Some(t), Some(start)) = (self.trace.as_ref(), trace_start) {
+            let elapsed = unsafe { linux::lapi_get_monotonic_ns() }.saturating_sub(start);
+            t.lock()?.exit(self.instance, &*ni, &result, elapsed)?;
+        }
+
+        let ret = result?;
+
+        // Blocking calls don't tick, so limits are checked here as well.
+        self.check_limits()?;
+
+        Ok(ret)
     }
 }
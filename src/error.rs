@@ -13,7 +13,9 @@ pub enum CwaError {
     Unknown = -1,
     InvalidArgument = -2,
     PermissionDenied = -3,
-    NotFound = -4
+    NotFound = -4,
+    WouldBlock = -5,
+    TimedOut = -6
 }
 
 pub type CwaResult<T> = Result<T, CwaError>;
@@ -22,4 +24,16 @@ impl CwaError {
     pub fn status(&self) -> i32 {
         *self as i32
     }
+
+    pub fn from_status(status: i64) -> Option<CwaError> {
+        Some(match status {
+            -1 => CwaError::Unknown,
+            -2 => CwaError::InvalidArgument,
+            -3 => CwaError::PermissionDenied,
+            -4 => CwaError::NotFound,
+            -5 => CwaError::WouldBlock,
+            -6 => CwaError::TimedOut,
+            _ => return None
+        })
+    }
 }
@@ -15,8 +15,8 @@ impl GlobalContext {
         Ok(GlobalContext {
             total_memory: unsafe { ::linux::lapi_get_total_ram_bytes() },
             system_service_registry: ::system_service::Registry::new()?,
-            native_invoke_registry: ::api::Registry::new(),
-            scheme_registry: ::url::SchemeRegistry::new(),
+            native_invoke_registry: ::api::Registry::new()?,
+            scheme_registry: ::url::SchemeRegistry::new()?,
             broadcast_channel_registry: ::ipc::broadcast::Registry::new()?
         })
     }
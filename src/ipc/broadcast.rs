@@ -6,7 +6,7 @@ use alloc::BTreeMap;
 use alloc::String;
 
 use mutex::Mutex;
-use sync::Semaphore;
+use sync::{Semaphore, WaitQueue, Poller};
 use resource::*;
 use error::*;
 use slab::Slab;
@@ -107,6 +107,9 @@ pub struct SubscriberImpl {
     bc: Weak<BroadcastImpl>,
     messages: Mutex<VecDeque<Arc<[u8]>>>,
     notify: Semaphore,
+
+    // Woken up together with `notify`, for pollers
+    poll_wq: WaitQueue,
     mp: Mutex<Option<MemoryPressureHandle>>
 }
 
@@ -116,6 +119,15 @@ impl Resource for Owner {
         self.mp = Some(pressure);
     }
 
+    fn kind(&self) -> &'static str {
+        "broadcast-owner"
+    }
+
+    fn poll_ready(&mut self, _poller: &mut Poller, interest: u32) -> KernelResult<u32> {
+        // Messages to full subscribers are dropped, so writing never blocks.
+        Ok(interest & POLL_WRITE)
+    }
+
     fn write(&mut self, data: &[u8]) -> KernelResult<IoResult<usize>> {
         let subscribers = self.bc.inner.subscribers.lock()?.clone();
         let data: Arc<[u8]> = Arc::from(data.to_vec().into_boxed_slice());
@@ -126,6 +138,7 @@ impl Resource for Owner {
                 if messages.len() < MAX_PENDING_MESSAGES {
                     messages.push_back(data.clone());
                     sub.notify.up();
+                    sub.poll_wq.wake();
                     if let Some(ref mp) = &*sub.mp.lock()? {
                         mp.inc(data.len());
                     } else {
@@ -157,6 +170,21 @@ impl Resource for Subscriber {
         }
     }
 
+    fn kind(&self) -> &'static str {
+        "broadcast-subscriber"
+    }
+
+    fn poll_ready(&mut self, poller: &mut Poller, interest: u32) -> KernelResult<u32> {
+        poller.register(&self.inner.poll_wq);
+
+        // A closed channel is readable, returning end of file.
+        if self.inner.bc.upgrade().is_none() || self.inner.messages.lock()?.len() > 0 {
+            Ok(interest & POLL_READ)
+        } else {
+            Ok(0)
+        }
+    }
+
     fn write(&mut self, _data: &[u8]) -> KernelResult<IoResult<usize>> {
         Ok(Err(IoError::Invalid))
     }
@@ -217,6 +245,7 @@ impl BroadcastImpl {
                 bc: Arc::downgrade(&me),
                 messages: Mutex::new(VecDeque::new())?,
                 notify: Semaphore::new()?,
+                poll_wq: WaitQueue::new()?,
                 mp: Mutex::new(None)?
             })
         };
@@ -237,6 +266,7 @@ impl Drop for BroadcastImpl {
                 // One more notification to let subscribers know we are not valid any more
                 if let Some(sub) = sub.upgrade() {
                     sub.notify.up();
+                    sub.poll_wq.wake();
                 }
                 Ok(())
             }).unwrap_or_else(|_| unreachable!()); // This should never fail since we always return `Ok`
@@ -1 +1,2 @@
 pub mod broadcast;
+pub mod pipe;
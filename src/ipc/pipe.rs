@@ -0,0 +1,180 @@
+//! Pipes between application instances, like a parent and the children it
+//! spawns, which run on threads of their own.
+
+use alloc::VecDeque;
+use alloc::arc::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use mutex::Mutex;
+use sync::{WaitQueue, Poller, poll_until};
+use resource::*;
+use error::*;
+use memory_pressure::MemoryPressureHandle;
+
+/// Bytes a pipe holds before writes have to wait.
+pub const PIPE_CAPACITY: usize = 65536;
+
+struct PipeImpl {
+    data: Mutex<VecDeque<u8>>,
+
+    // Cleared when an end is dropped. Not behind the mutex, which can't be
+    // locked while a fatal signal is pending.
+    reader_open: AtomicBool,
+    writer_open: AtomicBool,
+
+    // Woken up whenever data is written or read, or an end is dropped
+    wq: WaitQueue
+}
+
+pub struct PipeReader {
+    inner: Arc<PipeImpl>,
+    mp: Option<MemoryPressureHandle>
+}
+
+pub struct PipeWriter {
+    inner: Arc<PipeImpl>,
+    mp: Option<MemoryPressureHandle>
+}
+
+pub fn new() -> KernelResult<(PipeReader, PipeWriter)> {
+    let inner = Arc::new(PipeImpl {
+        data: Mutex::new(VecDeque::new())?,
+        reader_open: AtomicBool::new(true),
+        writer_open: AtomicBool::new(true),
+        wq: WaitQueue::new()?
+    });
+
+    Ok((
+        PipeReader { inner: inner.clone(), mp: None },
+        PipeWriter { inner: inner, mp: None }
+    ))
+}
+
+impl PipeImpl {
+    /// Data to read, or end of file.
+    fn readable(&self) -> KernelResult<bool> {
+        Ok(self.data.lock()?.len() > 0 || !self.writer_open.load(Ordering::SeqCst))
+    }
+
+    /// Room to write, or an error to report.
+    fn writable(&self) -> KernelResult<bool> {
+        Ok(self.data.lock()?.len() < PIPE_CAPACITY || !self.reader_open.load(Ordering::SeqCst))
+    }
+
+    fn wait<F: Fn(&PipeImpl) -> KernelResult<bool>>(&self, ready: F) -> KernelResult<()> {
+        poll_until(None, |poller| {
+            poller.register(&self.wq);
+            ready(self)
+        })?;
+        Ok(())
+    }
+}
+
+impl Resource for PipeReader {
+    // The buffer is charged to the reader at its full size.
+    fn init_mem_pressure(&mut self, pressure: MemoryPressureHandle) {
+        pressure.inc(PIPE_CAPACITY);
+        self.mp = Some(pressure);
+    }
+
+    fn kind(&self) -> &'static str {
+        "pipe-reader"
+    }
+
+    fn poll_ready(&mut self, poller: &mut Poller, interest: u32) -> KernelResult<u32> {
+        poller.register(&self.inner.wq);
+
+        if self.inner.readable()? {
+            Ok(interest & POLL_READ)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn read(&mut self, out: &mut [u8]) -> KernelResult<IoResult<usize>> {
+        if out.len() == 0 {
+            return Ok(Ok(0));
+        }
+
+        self.inner.wait(PipeImpl::readable)?;
+
+        let n = {
+            let mut data = self.inner.data.lock()?;
+            let n = if data.len() < out.len() { data.len() } else { out.len() };
+            for (dst, src) in out.iter_mut().zip(data.drain(..n)) {
+                *dst = src;
+            }
+            n
+        };
+        self.inner.wq.wake();
+
+        Ok(Ok(n))
+    }
+
+    fn write(&mut self, _data: &[u8]) -> KernelResult<IoResult<usize>> {
+        Ok(Err(IoError::Invalid))
+    }
+}
+
+impl Resource for PipeWriter {
+    fn init_mem_pressure(&mut self, pressure: MemoryPressureHandle) {
+        pressure.inc(16);
+        self.mp = Some(pressure);
+    }
+
+    fn kind(&self) -> &'static str {
+        "pipe-writer"
+    }
+
+    fn poll_ready(&mut self, poller: &mut Poller, interest: u32) -> KernelResult<u32> {
+        poller.register(&self.inner.wq);
+
+        if self.inner.writable()? {
+            Ok(interest & POLL_WRITE)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn read(&mut self, _out: &mut [u8]) -> KernelResult<IoResult<usize>> {
+        Ok(Err(IoError::Invalid))
+    }
+
+    fn write(&mut self, data: &[u8]) -> KernelResult<IoResult<usize>> {
+        if data.len() == 0 {
+            return Ok(Ok(0));
+        }
+
+        self.inner.wait(PipeImpl::writable)?;
+
+        // Nobody will read what we write.
+        if !self.inner.reader_open.load(Ordering::SeqCst) {
+            return Ok(Err(IoError::Generic));
+        }
+
+        let n = {
+            let mut buf = self.inner.data.lock()?;
+            let room = PIPE_CAPACITY - buf.len();
+            let n = if data.len() < room { data.len() } else { room };
+            buf.extend(data[..n].iter().cloned());
+            n
+        };
+        self.inner.wq.wake();
+
+        Ok(Ok(n))
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.inner.reader_open.store(false, Ordering::SeqCst);
+        self.inner.wq.wake();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.inner.writer_open.store(false, Ordering::SeqCst);
+        self.inner.wq.wake();
+    }
+}
@@ -7,6 +7,7 @@
 #![no_std]
 
 extern crate hexagon_e;
+extern crate cervus_common;
 
 #[macro_use]
 extern crate alloc;
@@ -15,7 +16,10 @@ extern crate alloc;
 pub mod linux;
 
 pub mod env;
+#[cfg(not(feature = "hosted"))]
 pub mod stub;
+#[cfg(not(feature = "hosted"))]
+pub mod builtins;
 pub mod allocator;
 pub mod uapi;
 pub mod backend;
@@ -31,7 +35,17 @@ pub mod api;
 pub mod ipc;
 pub mod schemes;
 pub mod memory_pressure;
+pub mod profile;
+pub mod core_dump;
+pub mod replay;
+pub mod manifest;
+pub mod trace;
+pub mod checkpoint;
+pub mod debug;
 
+use alloc::Vec;
+use alloc::arc::Arc;
+use core::sync::atomic::AtomicUsize;
 use allocator::KernelAllocator;
 
 #[global_allocator]
@@ -53,13 +67,66 @@ pub extern "C" fn __cv_oom() -> ! {
 
 fn run_in_usermode_context<B: Backend<Config = G>, G>(
     code: &[u8],
+    args: &[i64],
+    restore: Option<&checkpoint::Checkpoint>,
     config: G,
+    limits: env::ExecutionLimits,
+    profile: Option<profile::Profile>,
+    child_config: Option<(backend::hexagon_e::EnvConfig, backend::Executor)>,
     kctx: *mut u8
-) -> BackendResult<()> {
-    let mut executor = B::new(config)?;
-    let mut context = env::UsermodeContext::new(kctx);
-    executor.run(code, &mut context)?;
-    Ok(())
+) -> TerminationReport {
+    let mut executor = match B::new(config) {
+        Ok(v) => v,
+        Err(e) => return TerminationReport::from_error(e)
+    };
+    let mut context = env::UsermodeContext::new(kctx, limits, profile);
+    if let Some((config, executor)) = child_config {
+        context.set_child_config(config, executor);
+    }
+
+    match manifest::load(kctx) {
+        Ok(Some(m)) => context.set_manifest(m),
+        // A restored application keeps its manifest unless the loader gives another one.
+        Ok(None) => if let Some(text) = restore.and_then(|v| v.manifest.as_ref()) {
+            match manifest::parse(text) {
+                Some(m) => context.set_manifest(m),
+                None => {
+                    println!("unable to load manifest of checkpoint");
+                    return TerminationReport::from_error(BackendError::InvalidInput);
+                }
+            }
+        },
+        Err(e) => {
+            println!("unable to load manifest");
+            return TerminationReport::from_error(e);
+        }
+    }
+
+    let result = match restore {
+        Some(cp) => {
+            if let Err(e) = checkpoint::check_native_invokes(cp)
+                .and_then(|_| context.restore_resources(&cp.resources)) {
+                println!("unable to restore checkpoint");
+                return TerminationReport::from_error(e);
+            }
+            executor.resume(&cp.state(), &mut context)
+        },
+        None => executor.run(code, args, &mut context)
+    };
+    context.write_profile();
+
+    match result {
+        Ok(v) => TerminationReport::returned(v),
+        Err(BackendError::Exit) => TerminationReport::exited(context.exit_code().unwrap_or(0)),
+        Err(BackendError::Checkpointed) => {
+            println!("execution stopped after checkpoint");
+            TerminationReport::from_error(BackendError::Checkpointed)
+        },
+        Err(e) => {
+            println!("execution terminated with error: {:?}", e);
+            TerminationReport::from_error(e)
+        }
+    }
 }
 
 #[no_mangle]
@@ -75,39 +142,261 @@ pub extern "C" fn map_cwa_api(name_base: *const u8, name_len: usize) -> i32 {
         .unwrap_or(-1)
 }
 
+/// Resolves `n_ids` NUL-terminated names laid out back to back in `names_base`,
+/// writing -1 for the ones that are not registered.
+///
+/// Returns the number of unresolved names, or -1 if the name table is malformed.
+#[no_mangle]
+pub extern "C" fn map_cwa_api_batch(
+    names_base: *const u8,
+    names_len: usize,
+    ids_base: *mut i32,
+    n_ids: usize
+) -> i32 {
+    if n_ids == 0 || names_len == 0 {
+        return if n_ids == names_len { 0 } else { -1 };
+    }
+
+    let names = unsafe { ::core::slice::from_raw_parts(names_base, names_len) };
+    let ids = unsafe { ::core::slice::from_raw_parts_mut(ids_base, n_ids) };
+
+    if names[names_len - 1] != 0 || names.iter().filter(|v| **v == 0).count() != n_ids {
+        return -1;
+    }
+
+    let mut unresolved = 0;
+    for (name, id) in names[..names_len - 1].split(|v| *v == 0).zip(ids.iter_mut()) {
+        *id = ::core::str::from_utf8(name).ok()
+            .and_then(|v| env::UsermodeContext::map_cwa_api_to_native_invoke(v))
+            .map(|v| v as i32)
+            .unwrap_or(-1);
+        if *id < 0 {
+            unresolved += 1;
+        }
+    }
+
+    unresolved
+}
+
+/// Writes one `<id> <n_args> <name>` line for each registered native invoke
+/// to `out_base`, truncated to `out_len` bytes.
+///
+/// Returns the full length of the list, or -1 on failure.
+#[no_mangle]
+pub extern "C" fn list_native_invokes(out_base: *mut u8, out_len: usize) -> i32 {
+    let invokes = match global::get_global().native_invoke_registry.list() {
+        Ok(v) => v,
+        Err(_) => return -1
+    };
+
+    let mut list: Vec<u8> = Vec::new();
+    for (id, ni) in invokes {
+        list.extend_from_slice(format!("{} {} {}\n", id, ni.policy().n_args, ni.name()).as_bytes());
+    }
+
+    let n = ::core::cmp::min(list.len(), out_len);
+    if n > 0 {
+        let out = unsafe { ::core::slice::from_raw_parts_mut(out_base, n) };
+        out.copy_from_slice(&list[..n]);
+    }
+
+    list.len() as i32
+}
+
+/// Runs `code`, which is translated hexagon-e code, or a WebAssembly binary
+/// that is translated first if `wasm_entry_len` is not 0. The binary is then
+/// called at the export named by `wasm_entry_base`.
 #[no_mangle]
 pub extern "C" fn run_code_in_hexagon_e(
     code_base: *const u8,
     code_len: usize,
+    entry_args_base: *const i64,
+    n_entry_args: usize,
+    wasm_entry_base: *const u8,
+    wasm_entry_len: usize,
     mem_default_len: usize,
     mem_max_len: usize,
     max_slots: usize,
     stack_len: usize,
     call_stack_len: usize,
-    kctx: *mut u8
+    fuel: u64,
+    timeout_ms: u64,
+    cpu_time_ms: u64,
+    profile_interval: u32,
+    kctx: *mut u8,
+    report: *mut TerminationReport
+) -> i32 {
+    run_code(
+        backend::Executor::HexagonE,
+        code_base,
+        code_len,
+        entry_args_base,
+        n_entry_args,
+        wasm_entry_base,
+        wasm_entry_len,
+        mem_default_len,
+        mem_max_len,
+        max_slots,
+        stack_len,
+        call_stack_len,
+        fuel,
+        timeout_ms,
+        cpu_time_ms,
+        profile_interval,
+        kctx,
+        report
+    )
+}
+
+/// Like `run_code_in_hexagon_e`, but compiles the code to machine code first.
+#[no_mangle]
+pub extern "C" fn run_code_in_jit(
+    code_base: *const u8,
+    code_len: usize,
+    entry_args_base: *const i64,
+    n_entry_args: usize,
+    wasm_entry_base: *const u8,
+    wasm_entry_len: usize,
+    mem_default_len: usize,
+    mem_max_len: usize,
+    max_slots: usize,
+    stack_len: usize,
+    call_stack_len: usize,
+    fuel: u64,
+    timeout_ms: u64,
+    cpu_time_ms: u64,
+    profile_interval: u32,
+    kctx: *mut u8,
+    report: *mut TerminationReport
+) -> i32 {
+    run_code(
+        backend::Executor::Jit,
+        code_base,
+        code_len,
+        entry_args_base,
+        n_entry_args,
+        wasm_entry_base,
+        wasm_entry_len,
+        mem_default_len,
+        mem_max_len,
+        max_slots,
+        stack_len,
+        call_stack_len,
+        fuel,
+        timeout_ms,
+        cpu_time_ms,
+        profile_interval,
+        kctx,
+        report
+    )
+}
+
+fn run_code(
+    executor: backend::Executor,
+    code_base: *const u8,
+    code_len: usize,
+    entry_args_base: *const i64,
+    n_entry_args: usize,
+    wasm_entry_base: *const u8,
+    wasm_entry_len: usize,
+    mem_default_len: usize,
+    mem_max_len: usize,
+    max_slots: usize,
+    stack_len: usize,
+    call_stack_len: usize,
+    fuel: u64,
+    timeout_ms: u64,
+    cpu_time_ms: u64,
+    profile_interval: u32,
+    kctx: *mut u8,
+    report: *mut TerminationReport
 ) -> i32 {
-    let code = unsafe { ::core::slice::from_raw_parts(code_base, code_len) };
+    let code: &[u8] = if code_len == 0 {
+        &[]
+    } else {
+        unsafe { ::core::slice::from_raw_parts(code_base, code_len) }
+    };
+    let entry_args: &[i64] = if n_entry_args == 0 {
+        &[]
+    } else {
+        unsafe { ::core::slice::from_raw_parts(entry_args_base, n_entry_args) }
+    };
     let config = backend::hexagon_e::EnvConfig {
         memory_default_len: mem_default_len,
         memory_max_len: mem_max_len,
         max_slots: max_slots,
         stack_len: stack_len,
-        call_stack_len: call_stack_len
+        call_stack_len: call_stack_len,
+        fuel: if fuel == 0 { None } else { Some(Arc::new(AtomicUsize::new(fuel as usize))) }
     };
 
-    println!("loading code with configuration {:?}", config);
+    let limits = env::ExecutionLimits {
+        timeout_ms: if timeout_ms == 0 { None } else { Some(timeout_ms) },
+        cpu_time_ms: if cpu_time_ms == 0 { None } else { Some(cpu_time_ms) }
+    };
 
-    let result = run_in_usermode_context::<backend::hexagon_e::HexagonEBackend, _>(
-        code,
-        config,
-        kctx
-    );
+    println!("loading code for {:?} with configuration {:?} and limits {:?}", executor, config, limits);
 
-    match result {
-        Ok(_) => 0,
-        Err(e) => {
-            println!("execution terminated with error: {:?}", e);
-            e.status()
-        }
+    let profile = if profile_interval == 0 {
+        None
+    } else {
+        Some(profile::Profile::new(profile_interval))
+    };
+
+    // Spawned instances run with the same configuration and backend, and
+    // share the fuel.
+    let child_config = (config.clone(), executor);
+
+    let translated = if wasm_entry_len == 0 {
+        Ok(None)
+    } else {
+        let entry = unsafe { ::core::slice::from_raw_parts(wasm_entry_base, wasm_entry_len) };
+        env::translate_wasm(code, entry, entry_args, max_slots).map(Some)
+    };
+
+    let result = match translated {
+        Ok(translated) => {
+            let (code, entry_args) = match translated {
+                Some(ref t) => (&t.code[..], &t.entry_args[..]),
+                None => (code, entry_args)
+            };
+
+            // A checkpoint brings its own code, so the loader doesn't pass any.
+            match checkpoint::load(kctx, &config) {
+                Ok(restore) => match executor {
+                    backend::Executor::HexagonE => run_in_usermode_context::<backend::hexagon_e::HexagonEBackend, _>(
+                        code,
+                        entry_args,
+                        restore.as_ref(),
+                        config,
+                        limits,
+                        profile,
+                        Some(child_config),
+                        kctx
+                    ),
+                    backend::Executor::Jit => run_in_usermode_context::<backend::jit::JitBackend, _>(
+                        code,
+                        entry_args,
+                        restore.as_ref(),
+                        config,
+                        limits,
+                        profile,
+                        Some(child_config),
+                        kctx
+                    )
+                },
+                Err(e) => {
+                    println!("unable to load checkpoint");
+                    TerminationReport::from_error(e)
+                }
+            }
+        },
+        Err(e) => TerminationReport::from_error(e)
+    };
+
+    if !report.is_null() {
+        unsafe { *report = result; }
     }
+
+    result.status()
 }
@@ -2,20 +2,43 @@ extern "C" {
     fn lapi_printk(s: *const u8, len: usize);
     pub fn lapi_kmalloc(len: usize) -> *mut u8;
     pub fn lapi_kfree(ptr: *mut u8);
+    pub fn lapi_exec_map(code: *const u8, len: usize) -> *mut u8;
+    pub fn lapi_exec_unmap(ptr: *mut u8, len: usize);
     fn lapi_bug() -> !;
 
     pub fn lapi_env_reschedule(kctx: *mut u8) -> i32;
+    pub fn lapi_env_need_resched() -> i32;
+
+    pub fn lapi_module_get(module: *mut u8) -> i32;
+    pub fn lapi_module_put(module: *mut u8);
 
     pub fn lapi_env_get_uid(kctx: *mut u8) -> i32;
 
+    pub fn lapi_thread_spawn(entry: extern "C" fn(*mut u8), arg: *mut u8) -> *mut RawThread;
+    pub fn lapi_thread_kill(t: *mut RawThread);
+    pub fn lapi_thread_join(t: *mut RawThread);
+
     pub fn lapi_semaphore_new() -> *mut RawSemaphore;
     pub fn lapi_semaphore_destroy(sem: *mut RawSemaphore);
     pub fn lapi_semaphore_up(sem: *mut RawSemaphore);
     pub fn lapi_semaphore_down(sem: *mut RawSemaphore) -> i32;
 
+    pub fn lapi_wait_queue_new() -> *mut RawWaitQueue;
+    pub fn lapi_wait_queue_destroy(wq: *mut RawWaitQueue);
+    pub fn lapi_wait_queue_wake(wq: *mut RawWaitQueue);
+
+    pub fn lapi_poll_new() -> *mut RawPoll;
+    pub fn lapi_poll_destroy(poll: *mut RawPoll);
+    pub fn lapi_poll_file(poll: *mut RawPoll, file: *mut RawFile, interest: u32) -> u32;
+    pub fn lapi_poll_wait_queue(poll: *mut RawPoll, wq: *mut RawWaitQueue);
+    pub fn lapi_poll_wait(poll: *mut RawPoll, timeout_ms: i64) -> i32;
+
     pub fn lapi_oom_score_adj_current(score: i16);
     pub fn lapi_get_total_ram_bytes() -> usize;
 
+    pub fn lapi_get_monotonic_ns() -> u64;
+    pub fn lapi_get_cpu_time_ns() -> u64;
+
     pub fn lapi_env_get_n_args(kctx: *mut u8) -> u32;
     pub fn lapi_env_read_arg(kctx: *mut u8, id: u32, out: *mut u8, max_len: usize) -> isize;
 
@@ -28,6 +51,7 @@ extern "C" {
     ) -> *mut RawFile;
 
     pub fn lapi_env_close_file(file: *mut RawFile);
+    pub fn lapi_env_get_file_path(file: *mut RawFile, out: *mut u8, len: usize) -> isize;
 
     pub fn lapi_env_write_file(
         kctx: *mut u8,
@@ -57,6 +81,70 @@ extern "C" {
         kctx: *mut u8
     ) -> *mut RawFile;
 
+    pub fn lapi_env_get_profile_file(
+        kctx: *mut u8
+    ) -> *mut RawFile;
+
+    pub fn lapi_env_get_core_file(
+        kctx: *mut u8
+    ) -> *mut RawFile;
+
+    pub fn lapi_env_get_record_file(
+        kctx: *mut u8
+    ) -> *mut RawFile;
+
+    pub fn lapi_env_get_replay_file(
+        kctx: *mut u8
+    ) -> *mut RawFile;
+
+    pub fn lapi_env_get_manifest_file(
+        kctx: *mut u8
+    ) -> *mut RawFile;
+
+    pub fn lapi_env_get_trace(
+        kctx: *mut u8
+    ) -> *mut RawTrace;
+
+    pub fn lapi_env_write_trace(trace: *mut RawTrace, data: *const u8, len: usize) -> isize;
+
+    pub fn lapi_env_get_restore_file(
+        kctx: *mut u8
+    ) -> *mut RawFile;
+
+    pub fn lapi_env_checkpoint_requested(
+        kctx: *mut u8
+    ) -> i32;
+
+    pub fn lapi_env_take_checkpoint_request(
+        kctx: *mut u8,
+        flags: *mut u32
+    ) -> *mut RawFile;
+
+    pub fn lapi_env_finish_checkpoint(
+        kctx: *mut u8,
+        status: i32,
+        resource: i64,
+        kind_base: *const u8,
+        kind_len: usize
+    );
+
+    pub fn lapi_env_debug_enabled(kctx: *mut u8) -> i32;
+    pub fn lapi_env_debug_pending(kctx: *mut u8) -> i32;
+
+    pub fn lapi_env_take_debug_request(
+        kctx: *mut u8,
+        wait: i32,
+        out: *mut ::debug::RawRequest
+    ) -> i32;
+
+    pub fn lapi_env_finish_debug_request(
+        kctx: *mut u8,
+        status: i32,
+        stop: *const ::backend::common::DebugStop,
+        data_base: *const u8,
+        data_len: usize
+    );
+
     pub fn lapi_env_log(kctx: *mut u8, level: i32, text_base: *const u8, text_len: usize);
     pub fn lapi_env_yield(kctx: *mut u8) -> i32;
     pub fn lapi_env_msleep(kctx: *mut u8, ms: u32) -> i32;
@@ -67,11 +155,31 @@ pub struct RawFile {
     _opaque: usize
 }
 
+#[repr(C)]
+pub struct RawTrace {
+    _opaque: usize
+}
+
+#[repr(C)]
+pub struct RawThread {
+    _opaque: usize
+}
+
 #[repr(C)]
 pub struct RawSemaphore {
     _opaque: usize
 }
 
+#[repr(C)]
+pub struct RawWaitQueue {
+    _opaque: usize
+}
+
+#[repr(C)]
+pub struct RawPoll {
+    _opaque: usize
+}
+
 macro_rules! println {
     ($fmt:expr) => (::linux::printk(
         &format!($fmt)
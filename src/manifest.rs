@@ -0,0 +1,65 @@
+//! Loads manifests, which are parsed by `cervus_common::manifest`.
+
+use alloc::Vec;
+
+use backend::common::*;
+use resource::{Resource, LinuxFile};
+use linux;
+
+pub use cervus_common::manifest::{Manifest, ParseError};
+
+/// Manifests are small, so anything larger is probably not one.
+const MAX_MANIFEST_LEN: usize = 65536;
+
+/// Reads the manifest given by the loader, if any.
+pub fn load(kctx: *mut u8) -> BackendResult<Option<Manifest>> {
+    let mut f = match unsafe {
+        LinuxFile::from_raw_checked(kctx, linux::lapi_env_get_manifest_file(kctx), false)
+    } {
+        Ok(v) => v,
+        Err(_) => return Ok(None)
+    };
+
+    let mut text: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let n = match f.read(&mut buf)? {
+            Ok(n) => n,
+            Err(_) => return Err(BackendError::InvalidInput)
+        };
+        if n == 0 {
+            break;
+        }
+        if text.len() + n > MAX_MANIFEST_LEN {
+            return Err(BackendError::InvalidInput);
+        }
+        text.extend_from_slice(&buf[0..n]);
+    }
+
+    match ::core::str::from_utf8(&text).ok().and_then(parse) {
+        Some(v) => Ok(Some(v)),
+        None => Err(BackendError::InvalidInput)
+    }
+}
+
+/// Returns `None` if a line is malformed or names an unknown native invoke.
+pub fn parse(text: &str) -> Option<Manifest> {
+    let registry = &::global::get_global().native_invoke_registry;
+
+    match Manifest::parse_with(text, |name| registry.map_name_to_id(name)) {
+        Ok(v) => Some(v),
+        Err(ParseError::UnknownInvoke(name)) => {
+            println!("manifest: unknown native invoke: {}", name);
+            None
+        },
+        Err(ParseError::MalformedUrl(prefix)) => {
+            println!("manifest: malformed url prefix: {}", prefix);
+            None
+        },
+        Err(ParseError::MalformedLine(line)) => {
+            println!("manifest: malformed line: {}", line);
+            None
+        }
+    }
+}
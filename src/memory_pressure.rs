@@ -30,6 +30,15 @@ impl MemoryPressure {
     }
 }
 
+/// Clones refer to the same counter.
+impl Clone for MemoryPressure {
+    fn clone(&self) -> MemoryPressure {
+        MemoryPressure {
+            value: self.value.clone()
+        }
+    }
+}
+
 impl Clone for MemoryPressureHandle {
     fn clone(&self) -> MemoryPressureHandle {
         MemoryPressureHandle {
@@ -56,4 +65,14 @@ impl MemoryPressureHandle {
         self.contrib.fetch_sub(n, Ordering::SeqCst);
         self.value.fetch_sub(n, Ordering::SeqCst);
     }
+
+    /// Replaces the contribution of this handle with `n`.
+    pub fn set(&self, n: usize) {
+        let prev = self.contrib.swap(n, Ordering::SeqCst);
+        if n >= prev {
+            self.value.fetch_add(n - prev, Ordering::SeqCst);
+        } else {
+            self.value.fetch_sub(prev - n, Ordering::SeqCst);
+        }
+    }
 }
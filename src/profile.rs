@@ -0,0 +1,79 @@
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use alloc::{BTreeMap, String, Vec};
+
+/// Upper bound on distinct branch targets kept, so that a profile can't grow without limit.
+const MAX_BRANCH_TARGETS: usize = 65536;
+
+/// Samples of hot branch targets and counts of native invokes of one application.
+pub struct Profile {
+    interval: u32,
+    branches: RefCell<BTreeMap<usize, u64>>,
+    dropped: Cell<u64>,
+    invokes: RefCell<Vec<u64>>
+}
+
+impl Profile {
+    /// Creates a profile that takes one sample every `interval` branches.
+    pub fn new(interval: u32) -> Profile {
+        Profile {
+            interval: if interval == 0 { 1 } else { interval },
+            branches: RefCell::new(BTreeMap::new()),
+            dropped: Cell::new(0),
+            invokes: RefCell::new(Vec::new())
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn sample_branch(&self, target: usize) {
+        let mut branches = self.branches.borrow_mut();
+
+        if let Some(count) = branches.get_mut(&target) {
+            *count += 1;
+            return;
+        }
+
+        if branches.len() >= MAX_BRANCH_TARGETS {
+            self.dropped.set(self.dropped.get() + 1);
+        } else {
+            branches.insert(target, 1);
+        }
+    }
+
+    pub fn count_native_invoke(&self, id: usize) {
+        let mut invokes = self.invokes.borrow_mut();
+
+        if id >= invokes.len() {
+            invokes.resize(id + 1, 0);
+        }
+        invokes[id] += 1;
+    }
+
+    /// Formats the profile in the text format read by `cvctl`.
+    ///
+    /// `name_of` resolves native invoke ids to their names.
+    pub fn format<F: Fn(usize) -> Option<String>>(&self, name_of: F) -> String {
+        let mut out = String::new();
+
+        // Writing to a `String` can't fail.
+        let _ = writeln!(out, "cervus-profile 1");
+        let _ = writeln!(out, "interval {}", self.interval);
+        let _ = writeln!(out, "dropped {}", self.dropped.get());
+
+        for (target, count) in self.branches.borrow().iter() {
+            let _ = writeln!(out, "branch {} {}", target, count);
+        }
+
+        for (id, count) in self.invokes.borrow().iter().enumerate() {
+            if *count > 0 {
+                let name = name_of(id);
+                let _ = writeln!(out, "invoke {} {}", name.as_ref().map(|v| v.as_str()).unwrap_or("unknown"), count);
+            }
+        }
+
+        out
+    }
+}
@@ -0,0 +1,137 @@
+//! Recording and replaying the results of native invokes.
+//!
+//! The log is a sequence of entries, each made of an `EntryHeader` followed by
+//! `output_len` bytes that the invoke wrote to linear memory.
+
+use alloc::Vec;
+
+use backend::common::*;
+use resource::{Resource, LinuxFile};
+
+const KIND_NONE: u32 = 0;
+const KIND_VALUE: u32 = 1;
+const KIND_ERROR: u32 = 2;
+const KIND_EXIT: u32 = 3;
+
+/// In-file representation of an entry. We only build for little-endian targets.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct EntryHeader {
+    id: u32,
+    kind: u32,
+
+    /// Return value, `BackendError` discriminant or exit code
+    value: i64,
+    output_len: u64
+}
+
+const HEADER_LEN: usize = 24;
+
+pub struct Entry {
+    pub id: usize,
+    pub result: BackendResult<Option<i64>>,
+
+    /// Set for `BackendError::Exit`
+    pub exit_code: Option<i32>,
+
+    pub output: Vec<u8>
+}
+
+fn write_all(f: &mut LinuxFile, mut data: &[u8]) -> BackendResult<()> {
+    while data.len() > 0 {
+        match f.write(data)? {
+            Ok(n) if n > 0 => data = &data[n..],
+            _ => return Err(BackendError::Generic)
+        }
+    }
+
+    Ok(())
+}
+
+fn read_exact(f: &mut LinuxFile, mut out: &mut [u8]) -> BackendResult<()> {
+    while out.len() > 0 {
+        let n = match f.read(out)? {
+            Ok(n) if n > 0 => n,
+
+            // The application is doing more than it did when recorded.
+            _ => return Err(BackendError::ReplayMismatch)
+        };
+        let rest = out;
+        out = &mut rest[n..];
+    }
+
+    Ok(())
+}
+
+pub enum InvokeLog {
+    Record(LinuxFile),
+    Replay(LinuxFile)
+}
+
+impl InvokeLog {
+    pub fn record(&mut self, entry: &Entry) -> BackendResult<()> {
+        let f = match *self {
+            InvokeLog::Record(ref mut f) => f,
+            InvokeLog::Replay(_) => return Err(BackendError::InvalidInput)
+        };
+
+        let (kind, value) = match entry.result {
+            Ok(None) => (KIND_NONE, 0),
+            Ok(Some(v)) => (KIND_VALUE, v),
+            Err(BackendError::Exit) => (KIND_EXIT, entry.exit_code.unwrap_or(0) as i64),
+            Err(e) => (KIND_ERROR, e as u8 as i64)
+        };
+
+        let header = EntryHeader {
+            id: entry.id as u32,
+            kind: kind,
+            value: value,
+            output_len: entry.output.len() as u64
+        };
+        let header: [u8; HEADER_LEN] = unsafe { ::core::mem::transmute(header) };
+
+        write_all(f, &header)?;
+        write_all(f, &entry.output)
+    }
+
+    /// Reads the next entry. `max_output_len` bounds the allocation for its output.
+    pub fn replay(&mut self, max_output_len: usize) -> BackendResult<Entry> {
+        let f = match *self {
+            InvokeLog::Replay(ref mut f) => f,
+            InvokeLog::Record(_) => return Err(BackendError::InvalidInput)
+        };
+
+        let mut raw = [0u8; HEADER_LEN];
+        read_exact(f, &mut raw)?;
+        let header: EntryHeader = unsafe { ::core::mem::transmute(raw) };
+
+        if header.output_len > max_output_len as u64 {
+            return Err(BackendError::ReplayMismatch);
+        }
+
+        let mut output = vec! [ 0; header.output_len as usize ];
+        read_exact(f, &mut output)?;
+
+        let mut exit_code = None;
+        let result = match header.kind {
+            KIND_NONE => Ok(None),
+            KIND_VALUE => Ok(Some(header.value)),
+            KIND_ERROR => Err(match BackendError::from_code(header.value) {
+                Some(e) => e,
+                None => return Err(BackendError::ReplayMismatch)
+            }),
+            KIND_EXIT => {
+                exit_code = Some(header.value as i32);
+                Err(BackendError::Exit)
+            },
+            _ => return Err(BackendError::ReplayMismatch)
+        };
+
+        Ok(Entry {
+            id: header.id as usize,
+            result: result,
+            exit_code: exit_code,
+            output: output
+        })
+    }
+}
@@ -2,38 +2,102 @@ use linux;
 use linux::RawFile;
 use error::*;
 use memory_pressure::MemoryPressureHandle;
+use sync::Poller;
+use alloc::boxed::Box;
+use alloc::arc::Arc;
+use alloc::{String, Vec};
+use mutex::Mutex;
+
+pub use cervus_common::resource::{IoOptions, ResourceSnapshot};
+
+/// Returned by files opened with `O_NONBLOCK` that aren't ready.
+const EAGAIN: isize = 11;
 
 #[derive(Copy, Clone, Debug)]
 #[repr(i32)]
 pub enum IoError {
     Generic = -1,
-    Invalid = -2
+    Invalid = -2,
+
+    // Same values as in `CwaError`
+    PermissionDenied = -3,
+    WouldBlock = -5,
+    TimedOut = -6
 }
 
 impl IoError {
     pub fn status(&self) -> i32 {
         *self as i32
     }
+
+    /// Maps a negative errno returned by a file operation.
+    pub fn from_errno(ret: isize) -> IoError {
+        match -ret {
+            EAGAIN => IoError::WouldBlock,
+            _ => IoError::Generic
+        }
+    }
 }
 
 pub type IoResult<T> = Result<T, IoError>;
 
-pub trait Resource {
+/// Readiness flags for `Resource::poll_ready`, shared with the glue code.
+pub const POLL_READ: u32 = 1;
+pub const POLL_WRITE: u32 = 2;
+
+/// Reported whether requested or not.
+pub const POLL_ERROR: u32 = 4;
+
+/// Set by `resource_poll` for ids that aren't open resources.
+pub const POLL_INVALID: u32 = 8;
+
+/// Resources may be shared with spawned instances, which run on other threads.
+pub trait Resource: Send {
     /// Memory pressure (should be equivalent to the estimated size in bytes)
     fn init_mem_pressure(&mut self, _pressure: MemoryPressureHandle) {}
 
+    /// Short name of the resource type, for diagnostics
+    fn kind(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Returns which of the `POLL_*` flags in `interest` are ready, and
+    /// registers with `poller` to be woken up when that may change.
+    ///
+    /// Resources that can't tell report everything as ready.
+    fn poll_ready(&mut self, _poller: &mut Poller, interest: u32) -> KernelResult<u32> {
+        Ok(interest)
+    }
+
+    /// How to open the resource again after a checkpoint, or `None` if it
+    /// can't be checkpointed.
+    fn snapshot(&self) -> Option<ResourceSnapshot> {
+        None
+    }
+
     fn read(&mut self, out: &mut [u8]) -> KernelResult<IoResult<usize>>;
     fn write(&mut self, data: &[u8]) -> KernelResult<IoResult<usize>>;
 }
 
+/// Where a `LinuxFile` came from, for checkpoints.
+enum FileOrigin {
+    Unknown,
+    Stdio(usize),
+    Path(String)
+}
+
 pub struct LinuxFile {
     kctx: *mut u8,
     handle: *mut RawFile,
     need_close: bool,
     offset: i64,
+    origin: FileOrigin,
     pressure: Option<MemoryPressureHandle>
 }
 
+// Kernel files may be used from any task.
+unsafe impl Send for LinuxFile {}
+
 impl Drop for LinuxFile {
     fn drop(&mut self) {
         if self.need_close {
@@ -54,10 +118,39 @@ impl LinuxFile {
                 handle: f,
                 need_close: need_close,
                 offset: 0,
+                origin: FileOrigin::Unknown,
                 pressure: None
             })
         }
     }
+
+    /// Marks the file as opened from `path`, so that checkpoints can open it again.
+    pub fn with_path(mut self, path: String) -> LinuxFile {
+        self.origin = FileOrigin::Path(path);
+        self
+    }
+
+    /// Marks the file as stdin (0), stdout (1) or stderr (2) of the instance.
+    pub fn with_stdio(mut self, n: usize) -> LinuxFile {
+        self.origin = FileOrigin::Stdio(n);
+        self
+    }
+
+    /// Sets the offset of the next read or write.
+    pub fn set_offset(&mut self, offset: i64) {
+        self.offset = offset;
+    }
+
+    /// The absolute path of the file with symlinks resolved, if it has one.
+    pub fn resolved_path(&self) -> Option<String> {
+        let mut buf: Vec<u8> = vec! [ 0; 4096 ];
+        let len = unsafe { linux::lapi_env_get_file_path(self.handle, &mut buf[0], buf.len()) };
+        if len < 0 {
+            return None;
+        }
+        buf.truncate(len as usize);
+        String::from_utf8(buf).ok()
+    }
 }
 
 impl Resource for LinuxFile {
@@ -66,6 +159,25 @@ impl Resource for LinuxFile {
         self.pressure = Some(p);
     }
 
+    fn kind(&self) -> &'static str {
+        "file"
+    }
+
+    fn poll_ready(&mut self, poller: &mut Poller, interest: u32) -> KernelResult<u32> {
+        Ok(unsafe { poller.poll_file(self.handle, interest) })
+    }
+
+    fn snapshot(&self) -> Option<ResourceSnapshot> {
+        match self.origin {
+            FileOrigin::Unknown => None,
+            FileOrigin::Stdio(n) => Some(ResourceSnapshot::Stdio(n)),
+            FileOrigin::Path(ref path) => Some(ResourceSnapshot::File {
+                path: path.clone(),
+                offset: self.offset
+            })
+        }
+    }
+
     fn read(&mut self, out: &mut [u8]) -> KernelResult<IoResult<usize>> {
         let len = out.len();
 
@@ -83,7 +195,7 @@ impl Resource for LinuxFile {
             )
         };
         Ok(if ret < 0 {
-            Err(IoError::Generic)
+            Err(IoError::from_errno(ret))
         } else {
             self.offset += ret as i64;
             Ok(ret as usize)
@@ -107,10 +219,71 @@ impl Resource for LinuxFile {
             )
         };
         Ok(if ret < 0 {
-            Err(IoError::Generic)
+            Err(IoError::from_errno(ret))
         } else {
             self.offset += ret as i64;
             Ok(ret as usize)
         })
     }
 }
+
+/// A resource used by more than one application instance, like the stdio of a spawned child.
+///
+/// Calls are serialized, so a blocking read holds up the other users.
+#[derive(Clone)]
+pub struct SharedResource {
+    inner: Arc<Mutex<Box<Resource>>>
+}
+
+/// Takes the place of a resource while it is being moved.
+struct Detached;
+
+impl Resource for Detached {
+    fn read(&mut self, _out: &mut [u8]) -> KernelResult<IoResult<usize>> {
+        Ok(Err(IoError::Invalid))
+    }
+
+    fn write(&mut self, _data: &[u8]) -> KernelResult<IoResult<usize>> {
+        Ok(Err(IoError::Invalid))
+    }
+}
+
+impl SharedResource {
+    /// Replaces the resource in `slot` with a shared handle to it and returns another handle.
+    pub fn share(slot: &mut Box<Resource>) -> KernelResult<SharedResource> {
+        let res = ::core::mem::replace(slot, Box::new(Detached));
+        let shared = SharedResource {
+            inner: Arc::new(Mutex::new(res)?)
+        };
+        *slot = Box::new(shared.clone());
+
+        Ok(shared)
+    }
+}
+
+impl Resource for SharedResource {
+    // Memory pressure stays with the instance that opened the resource.
+
+    fn kind(&self) -> &'static str {
+        match self.inner.lock() {
+            Ok(v) => v.kind(),
+            Err(_) => "shared"
+        }
+    }
+
+    fn poll_ready(&mut self, poller: &mut Poller, interest: u32) -> KernelResult<u32> {
+        self.inner.lock()?.poll_ready(poller, interest)
+    }
+
+    fn snapshot(&self) -> Option<ResourceSnapshot> {
+        self.inner.lock().ok()?.snapshot()
+    }
+
+    fn read(&mut self, out: &mut [u8]) -> KernelResult<IoResult<usize>> {
+        self.inner.lock()?.read(out)
+    }
+
+    fn write(&mut self, data: &[u8]) -> KernelResult<IoResult<usize>> {
+        self.inner.lock()?.write(data)
+    }
+}
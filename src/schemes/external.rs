@@ -0,0 +1,199 @@
+//! URL schemes registered at runtime by other kernel modules, through
+//! `cervus_register_scheme` in the glue code.
+
+use url::Url;
+use error::*;
+use resource::*;
+use sync::Poller;
+use linux::RawPoll;
+use alloc::boxed::Box;
+use alloc::arc::Arc;
+use alloc::String;
+use api::external::{ModuleRef, REGISTER_EXISTS, REGISTER_INVALID, REGISTER_FAILED};
+
+/// Must match `struct cervus_resource_ops` in `glue/cervus.h`.
+#[repr(C)]
+pub struct RawResourceOps {
+    read: Option<unsafe extern "C" fn(res: *mut u8, out: *mut u8, len: usize) -> i64>,
+    write: Option<unsafe extern "C" fn(res: *mut u8, data: *const u8, len: usize) -> i64>,
+    poll: Option<unsafe extern "C" fn(res: *mut u8, poll: *mut RawPoll, interest: u32) -> u32>,
+    release: Option<unsafe extern "C" fn(res: *mut u8)>
+}
+
+/// Must match `struct cervus_scheme` in `glue/cervus.h`.
+#[repr(C)]
+pub struct RawScheme {
+    name: *const u8,
+    open: Option<unsafe extern "C" fn(
+        priv_data: *mut u8,
+        path: *const u8,
+        path_len: usize,
+        args: *const u8,
+        args_len: usize,
+        err: *mut i32
+    ) -> *mut u8>,
+    ops: *const RawResourceOps,
+    priv_data: *mut u8,
+    owner: *mut u8
+}
+
+pub struct ExternalScheme {
+    raw: RawScheme
+}
+
+// The provider is required to make its callbacks safe to use from any task.
+unsafe impl Send for ExternalScheme {}
+unsafe impl Sync for ExternalScheme {}
+
+/// A resource opened through an external scheme. Keeps the provider loaded until closed.
+pub struct ExternalResource {
+    scheme: Arc<ExternalScheme>,
+    handle: *mut u8,
+
+    // Dropped after the handle is released
+    _module: ModuleRef
+}
+
+// Covered by the same requirement as the scheme.
+unsafe impl Send for ExternalResource {}
+
+fn io_error_from_status(status: i64) -> IoError {
+    match status {
+        -2 => IoError::Invalid,
+        -3 => IoError::PermissionDenied,
+        -5 => IoError::WouldBlock,
+        -6 => IoError::TimedOut,
+        _ => IoError::Generic
+    }
+}
+
+fn io_result(ret: i64) -> IoResult<usize> {
+    if ret < 0 {
+        Err(io_error_from_status(ret))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+impl ExternalScheme {
+    fn ops(&self) -> &RawResourceOps {
+        unsafe { &*self.raw.ops }
+    }
+
+    /// See `ModuleRef::get`.
+    pub unsafe fn pin(&self) -> Option<ModuleRef> {
+        ModuleRef::get(self.raw.owner)
+    }
+
+    /// Opens `url` with a scheme pinned by `module`.
+    pub fn open(scheme: &Arc<ExternalScheme>, module: ModuleRef, url: &Url) -> KernelResult<IoResult<Box<Resource>>> {
+        let path = url.path.as_bytes();
+        let args = url.unparsed_args.as_bytes();
+        let mut err: i32 = -1;
+
+        let handle = unsafe {
+            (scheme.raw.open.unwrap())(
+                scheme.raw.priv_data,
+                path.as_ptr(),
+                path.len(),
+                args.as_ptr(),
+                args.len(),
+                &mut err
+            )
+        };
+
+        if handle.is_null() {
+            return Ok(Err(io_error_from_status(err as i64)));
+        }
+
+        Ok(Ok(Box::new(ExternalResource {
+            scheme: scheme.clone(),
+            handle: handle,
+            _module: module
+        })))
+    }
+}
+
+impl Resource for ExternalResource {
+    fn kind(&self) -> &'static str {
+        "external"
+    }
+
+    fn poll_ready(&mut self, poller: &mut Poller, interest: u32) -> KernelResult<u32> {
+        Ok(match self.scheme.ops().poll {
+            Some(f) => unsafe { f(self.handle, poller.as_raw(), interest) },
+            None => interest
+        })
+    }
+
+    fn read(&mut self, out: &mut [u8]) -> KernelResult<IoResult<usize>> {
+        let ret = unsafe {
+            (self.scheme.ops().read.unwrap())(self.handle, out.as_mut_ptr(), out.len())
+        };
+        Ok(io_result(ret))
+    }
+
+    fn write(&mut self, data: &[u8]) -> KernelResult<IoResult<usize>> {
+        let ret = unsafe {
+            (self.scheme.ops().write.unwrap())(self.handle, data.as_ptr(), data.len())
+        };
+        Ok(io_result(ret))
+    }
+}
+
+impl Drop for ExternalResource {
+    fn drop(&mut self) {
+        unsafe { (self.scheme.ops().release.unwrap())(self.handle) };
+    }
+}
+
+/// Registers a scheme, returning 0 or a `REGISTER_*` error.
+#[no_mangle]
+pub unsafe extern "C" fn cervus_ext_register_scheme(raw: *const RawScheme, name_len: usize) -> i32 {
+    let raw = &*raw;
+
+    if raw.name.is_null() || name_len == 0 || raw.open.is_none() || raw.ops.is_null() {
+        return REGISTER_INVALID;
+    }
+
+    let ops = &*raw.ops;
+    if ops.read.is_none() || ops.write.is_none() || ops.release.is_none() {
+        return REGISTER_INVALID;
+    }
+
+    let name = match ::core::str::from_utf8(::core::slice::from_raw_parts(raw.name, name_len)) {
+        Ok(v) => v,
+        Err(_) => return REGISTER_INVALID
+    };
+
+    let scheme = ExternalScheme {
+        raw: RawScheme {
+            name: ::core::ptr::null(),
+            open: raw.open,
+            ops: raw.ops,
+            priv_data: raw.priv_data,
+            owner: raw.owner
+        }
+    };
+
+    match ::global::get_global().scheme_registry.register_external(String::from(name), Arc::new(scheme)) {
+        Ok(true) => 0,
+        Ok(false) => REGISTER_EXISTS,
+        Err(_) => REGISTER_FAILED
+    }
+}
+
+/// Unregisters a scheme. Resources opened through it stay usable until closed.
+#[no_mangle]
+pub unsafe extern "C" fn cervus_ext_unregister_scheme(name: *const u8, name_len: usize) -> i32 {
+    let name = match ::core::str::from_utf8(::core::slice::from_raw_parts(name, name_len)) {
+        Ok(v) => v,
+        Err(_) => return REGISTER_INVALID
+    };
+
+    match ::global::get_global().scheme_registry.unregister_external(name) {
+        Ok(true) => 0,
+        Ok(false) => REGISTER_INVALID,
+        Err(_) => REGISTER_FAILED
+    }
+}
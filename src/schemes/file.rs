@@ -2,34 +2,52 @@ use url::{SchemeProvider, Url};
 use error::*;
 use resource::*;
 use linux;
+use manifest::Manifest;
 use alloc::boxed::Box;
 
 pub struct File;
 
 impl SchemeProvider for File {
-    fn open(&self, url: &Url, kctx: *mut u8) -> KernelResult<IoResult<Box<Resource>>> {
-        // TODO: parse args (flags, etc.)
-        static FLAGS: &'static [u8] = b"r";
+    fn open(&self, url: &Url, kctx: *mut u8, manifest: Option<&Manifest>) -> KernelResult<IoResult<Box<Resource>>> {
+        Ok(open_path(kctx, url.path, manifest).map(|v| Box::new(v) as Box<Resource>))
+    }
+}
 
-        let path = url.path.as_bytes();
-        if path.len() == 0 {
-            return Ok(Err(IoError::Invalid));
-        }
+/// Opens the file at `path`, also used to open files again when a checkpoint is restored.
+///
+/// With a `manifest`, the file is only kept if the manifest allows both
+/// `path` and where it resolved to, so that symlinks can't lead out of an
+/// allowed prefix.
+pub fn open_path(kctx: *mut u8, path: &str, manifest: Option<&Manifest>) -> IoResult<LinuxFile> {
+    // TODO: parse args (flags, etc.)
+    static FLAGS: &'static [u8] = b"r";
+
+    let path_bytes = path.as_bytes();
+    if path_bytes.len() == 0 {
+        return Err(IoError::Invalid);
+    }
 
-        let file = unsafe { linux::lapi_env_open_file(
-            kctx,
-            &path[0],
-            path.len(),
-            &FLAGS[0],
-            FLAGS.len()
-        ) };
-
-        if file.is_null() {
-            // TODO: accurate error code
-            Ok(Err(IoError::Generic))
-        } else {
-            Ok(unsafe { LinuxFile::from_raw_checked(kctx, file, true) }
-                .map(|v| Box::new(v) as Box<Resource>))
+    let file = unsafe { linux::lapi_env_open_file(
+        kctx,
+        &path_bytes[0],
+        path_bytes.len(),
+        &FLAGS[0],
+        FLAGS.len()
+    ) };
+
+    if file.is_null() {
+        // TODO: accurate error code
+        return Err(IoError::Generic);
+    }
+
+    let file = unsafe { LinuxFile::from_raw_checked(kctx, file, true) }?;
+
+    if let Some(m) = manifest {
+        match file.resolved_path() {
+            Some(ref resolved) if m.allows_file(path, resolved) => {},
+            _ => return Err(IoError::PermissionDenied)
         }
     }
+
+    Ok(file.with_path(path.into()))
 }
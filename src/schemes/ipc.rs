@@ -4,11 +4,12 @@ use resource::*;
 use linux;
 use alloc::boxed::Box;
 use ipc::broadcast;
+use manifest::Manifest;
 
 pub struct Broadcast;
 
 impl SchemeProvider for Broadcast {
-    fn open(&self, url: &Url, _kctx: *mut u8) -> KernelResult<IoResult<Box<Resource>>> {
+    fn open(&self, url: &Url, _kctx: *mut u8, _manifest: Option<&Manifest>) -> KernelResult<IoResult<Box<Resource>>> {
         let path = url.path;
         let options = url.parse_args();
 
@@ -62,6 +62,26 @@ impl<T> Slab<T> {
         }
     }
 
+    /// Inserts `val` with a given id, as when restoring a checkpoint.
+    /// Fails if the id is taken.
+    pub fn insert_at(&mut self, id: usize, val: T) -> KernelResult<()> {
+        while self.storage.len() <= id {
+            let free = self.storage.len();
+            self.storage.push(None);
+            self.release_pool.push_back(free);
+        }
+
+        if self.storage[id].is_some() {
+            return Err(KernelError::InvalidResource);
+        }
+
+        self.release_pool.retain(|v| *v != id);
+        self.storage[id] = Some(val);
+        self.len += 1;
+
+        Ok(())
+    }
+
     pub fn remove(&mut self, id: usize) -> KernelResult<T> {
         if id >= self.storage.len() {
             Err(KernelError::InvalidResource)
@@ -89,4 +109,14 @@ impl<T> Slab<T> {
 
         Ok(())
     }
+
+    pub fn for_each_with_id<E, F: FnMut(usize, &T) -> Result<(), E>>(&self, mut f: F) -> Result<(), E> {
+        for (id, elem) in self.storage.iter().enumerate() {
+            if let Some(ref v) = *elem {
+                f(id, v)?;
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -9,16 +9,8 @@ macro_rules! declare_stub {
 
 declare_stub!(abort);
 
-declare_stub!(logbf);
-declare_stub!(fmax);
-declare_stub!(scalbn);
-declare_stub!(fmaxf);
-declare_stub!(scalbnf);
+// The x87 `long double` routines return through st(0). Kernel code must not
+// touch the x87 state, so these are never implemented.
 declare_stub!(logbl);
 declare_stub!(scalbnl);
 declare_stub!(fmaxl);
-declare_stub!(logb);
-declare_stub!(__muloti4);
-declare_stub!(__floatundidf);
-declare_stub!(__floatundisf);
-declare_stub!(__udivti3);
@@ -1,5 +1,5 @@
 use linux;
-use linux::RawSemaphore;
+use linux::{RawSemaphore, RawWaitQueue, RawPoll, RawFile};
 use error::*;
 
 pub struct Semaphore {
@@ -56,3 +56,145 @@ impl Drop for Semaphore {
         }
     }
 }
+
+/// Something a `Poller` can wait on, for resources that are not backed by a file.
+pub struct WaitQueue {
+    holder: RawWaitQueueHolder
+}
+
+struct RawWaitQueueHolder {
+    raw: *mut RawWaitQueue
+}
+
+unsafe impl Send for RawWaitQueueHolder {}
+unsafe impl Sync for RawWaitQueueHolder {}
+
+impl WaitQueue {
+    pub fn new() -> KernelResult<WaitQueue> {
+        let raw = unsafe {
+            linux::lapi_wait_queue_new()
+        };
+
+        if raw.is_null() {
+            Err(KernelError::NoMem)
+        } else {
+            Ok(WaitQueue {
+                holder: RawWaitQueueHolder {
+                    raw: raw
+                }
+            })
+        }
+    }
+
+    /// Wakes up all pollers registered on this queue.
+    pub fn wake(&self) {
+        unsafe {
+            linux::lapi_wait_queue_wake(self.holder.raw);
+        }
+    }
+}
+
+impl Drop for WaitQueue {
+    fn drop(&mut self) {
+        unsafe {
+            linux::lapi_wait_queue_destroy(self.holder.raw);
+        }
+    }
+}
+
+/// Waits for any of several files and wait queues.
+///
+/// Everything polled before the first `wait` is registered, and the
+/// following rounds only check readiness again.
+pub struct Poller {
+    raw: *mut RawPoll
+}
+
+impl Poller {
+    pub fn new() -> KernelResult<Poller> {
+        let raw = unsafe {
+            linux::lapi_poll_new()
+        };
+
+        if raw.is_null() {
+            Err(KernelError::NoMem)
+        } else {
+            Ok(Poller {
+                raw: raw
+            })
+        }
+    }
+
+    /// For other kernel modules polling their own wait queues.
+    pub fn as_raw(&mut self) -> *mut RawPoll {
+        self.raw
+    }
+
+    /// Returns which of `interest` (`resource::POLL_*`) are ready on `file`.
+    pub unsafe fn poll_file(&mut self, file: *mut RawFile, interest: u32) -> u32 {
+        linux::lapi_poll_file(self.raw, file, interest)
+    }
+
+    pub fn register(&mut self, wq: &WaitQueue) {
+        unsafe {
+            linux::lapi_poll_wait_queue(self.raw, wq.holder.raw);
+        }
+    }
+
+    /// Sleeps until something registered is woken up or `timeout_ms` passes.
+    pub fn wait(&mut self, timeout_ms: Option<u64>) -> KernelResult<()> {
+        let timeout_ms = match timeout_ms {
+            Some(v) if v < ::core::i64::MAX as u64 => v as i64,
+            _ => -1
+        };
+
+        let ret = unsafe {
+            linux::lapi_poll_wait(self.raw, timeout_ms)
+        };
+
+        if ret != 0 {
+            Err(KernelError::FatalSignal)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        unsafe {
+            linux::lapi_poll_destroy(self.raw);
+        }
+    }
+}
+
+/// Calls `poll` until it returns `true` or `timeout_ms` passes, waiting on
+/// whatever it registers in between. Returns the last result of `poll`.
+pub fn poll_until<F>(timeout_ms: Option<u64>, mut poll: F) -> KernelResult<bool>
+    where F: FnMut(&mut Poller) -> KernelResult<bool>
+{
+    let deadline = timeout_ms.map(|ms| unsafe {
+        linux::lapi_get_monotonic_ns()
+    }.saturating_add(ms.saturating_mul(1000000)));
+
+    let mut poller = Poller::new()?;
+
+    loop {
+        if poll(&mut poller)? {
+            return Ok(true);
+        }
+
+        let remaining_ms = match deadline {
+            Some(deadline) => {
+                let now = unsafe { linux::lapi_get_monotonic_ns() };
+                if now >= deadline {
+                    return Ok(false);
+                }
+                Some((deadline - now + 999999) / 1000000)
+            },
+            None => None
+        };
+
+        poller.wait(remaining_ms)?;
+    }
+}
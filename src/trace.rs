@@ -0,0 +1,225 @@
+//! strace-style tracing of native invokes.
+//!
+//! Each call is written as `[<instance>] <name>(<args>) = <result> <<seconds>>`,
+//! where the instance is 0 for the application and counts up for the
+//! instances it spawns. The part up to the closing parenthesis is written
+//! before the call is made, so that the trace shows which call an application
+//! is blocked in. Lines interrupted by calls of other instances are split
+//! into `<unfinished ...>` and `<... name resumed>` like `strace` does.
+//!
+//! Entries are queued in a ring buffer that a thread of the loader writes to
+//! the trace file, so that the application never waits for the reader.
+//! Entries that don't fit are dropped, and the next one that does is
+//! preceded by a count of what was lost.
+
+use alloc::String;
+use alloc::arc::Arc;
+use core::fmt::Write;
+
+use backend::common::*;
+use api::NativeInvoke;
+use error::*;
+use linux;
+use linux::RawTrace;
+use mutex::Mutex;
+
+/// Longest prefix of a string argument that is shown.
+const MAX_STRING_LEN: usize = 96;
+
+/// Returned by `lapi_env_write_trace` when the ring buffer is full.
+const EAGAIN: isize = 11;
+
+/// Shared by an instance and the instances it spawns.
+pub type SharedTracer = Arc<Mutex<Tracer>>;
+
+pub struct Tracer {
+    // `None` once the trace file stopped accepting writes
+    trace: Option<*mut RawTrace>,
+    buf: String,
+
+    // Instance whose call has been written without its result
+    unfinished: Option<usize>,
+
+    // Writes lost since the last one that went through
+    dropped: usize,
+
+    // Whether the last byte written wasn't the end of a line
+    line_open: bool
+}
+
+fn write_string(out: &mut String, mem: &[u8], base: i64, len: i64) {
+    let base = base as u32 as usize;
+    let len = len as u32 as usize;
+
+    let data = match mem.get(base..base.saturating_add(len)) {
+        Some(v) => v,
+        None => {
+            let _ = write!(out, "0x{:x} <out of bounds>, {}", base, len);
+            return;
+        }
+    };
+
+    out.push('"');
+    for &b in data.iter().take(MAX_STRING_LEN) {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(b as char),
+            _ => { let _ = write!(out, "\\x{:02x}", b); }
+        }
+    }
+    out.push('"');
+
+    if data.len() > MAX_STRING_LEN {
+        out.push_str("...");
+    }
+}
+
+// The ring buffer behind `RawTrace` does its own locking.
+unsafe impl Send for Tracer {}
+
+impl Tracer {
+    pub fn new(trace: *mut RawTrace) -> Tracer {
+        Tracer {
+            trace: Some(trace),
+            buf: String::new(),
+            unfinished: None,
+            dropped: 0,
+            line_open: false
+        }
+    }
+
+    /// Writes the name and arguments of a call that is about to be made.
+    pub fn enter(&mut self, instance: usize, ni: &NativeInvoke, args: &[i64], mem: &[u8]) -> KernelResult<()> {
+        self.interrupt();
+
+        let _ = write!(self.buf, "[{}] {}(", instance, ni.name());
+
+        let string = ni.string_args();
+        let output_base = ni.output_args().map(|v| v.0);
+
+        let mut i = 0;
+        while i < args.len() {
+            if i > 0 {
+                self.buf.push_str(", ");
+            }
+
+            match string {
+                Some((base, len)) if base == i && len == i + 1 && len < args.len() => {
+                    write_string(&mut self.buf, mem, args[base], args[len]);
+                    i += 2;
+                    continue;
+                },
+                _ => {}
+            }
+
+            if output_base == Some(i) {
+                let _ = write!(self.buf, "0x{:x}", args[i] as u32);
+            } else {
+                let _ = write!(self.buf, "{}", args[i]);
+            }
+            i += 1;
+        }
+
+        self.buf.push(')');
+        self.unfinished = Some(instance);
+
+        self.flush()
+    }
+
+    /// Writes the result of a call and how long it took.
+    pub fn exit(
+        &mut self,
+        instance: usize,
+        ni: &NativeInvoke,
+        result: &BackendResult<Option<i64>>,
+        elapsed_ns: u64
+    ) -> KernelResult<()> {
+        if self.unfinished != Some(instance) {
+            self.interrupt();
+            let _ = write!(self.buf, "[{}] <... {} resumed>", instance, ni.name());
+        }
+        self.unfinished = None;
+
+        let _ = match *result {
+            Ok(Some(v)) => match CwaError::from_status(v) {
+                Some(e) => write!(self.buf, " = {} ({:?})", v, e),
+                None => write!(self.buf, " = {}", v)
+            },
+            Ok(None) => write!(self.buf, " = ?"),
+            Err(e) => write!(self.buf, " = ! {:?}", e)
+        };
+        let _ = writeln!(
+            self.buf,
+            " <{}.{:06}>",
+            elapsed_ns / 1000000000,
+            elapsed_ns % 1000000000 / 1000
+        );
+
+        self.flush()
+    }
+
+    /// Terminates the line of a call that other calls are written in the middle of.
+    fn interrupt(&mut self) {
+        if self.unfinished.take().is_some() {
+            self.buf.push_str(" <unfinished ...>\n");
+        }
+    }
+
+    fn flush(&mut self) -> KernelResult<()> {
+        let mut text = ::core::mem::replace(&mut self.buf, String::new());
+
+        let trace = match self.trace {
+            Some(v) => v,
+            None => return Ok(())
+        };
+
+        if self.dropped > 0 {
+            text = format!(
+                "{}<{} trace writes dropped>\n{}",
+                if self.line_open { " <unfinished ...>\n" } else { "" },
+                self.dropped,
+                text
+            );
+        }
+
+        if text.len() == 0 {
+            return Ok(());
+        }
+
+        // Entries are queued whole or not at all.
+        let ret = unsafe { linux::lapi_env_write_trace(trace, &text.as_bytes()[0], text.len()) };
+
+        if ret >= 0 {
+            self.line_open = !text.ends_with("\n");
+            self.dropped = 0;
+        } else if ret == -EAGAIN {
+            // The reader fell behind and loses entries instead of stalling the application.
+            self.dropped += 1;
+            self.unfinished = None;
+        } else {
+            // Tracing is a debugging aid and shouldn't take the application down with it.
+            println!("unable to write trace, tracing stopped");
+            self.trace = None;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn open(kctx: *mut u8) -> Option<SharedTracer> {
+    let trace = unsafe { linux::lapi_env_get_trace(kctx) };
+    if trace.is_null() {
+        return None;
+    }
+
+    match Mutex::new(Tracer::new(trace)) {
+        Ok(v) => Some(Arc::new(v)),
+        Err(_) => {
+            println!("unable to set up tracing");
+            None
+        }
+    }
+}
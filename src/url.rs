@@ -4,6 +4,11 @@ use resource::*;
 use alloc::boxed::Box;
 use alloc::BTreeMap;
 use alloc::String;
+use alloc::arc::Arc;
+use manifest::Manifest;
+use mutex::Mutex;
+use schemes::external::ExternalScheme;
+use api::external::ModuleRef;
 
 #[derive(Debug)]
 pub struct Url<'a> {
@@ -13,41 +18,103 @@ pub struct Url<'a> {
 }
 
 pub struct SchemeRegistry {
-    schemes: BTreeMap<String, Box<SchemeProvider>>
+    schemes: BTreeMap<String, Box<SchemeProvider>>,
+
+    /// Registered by other kernel modules. Built-in schemes can't be overridden.
+    external: Mutex<BTreeMap<String, Arc<ExternalScheme>>>
 }
 
 impl SchemeRegistry {
-    pub fn new() -> SchemeRegistry {
+    pub fn new() -> KernelResult<SchemeRegistry> {
         let mut reg = SchemeRegistry {
-            schemes: BTreeMap::new()
+            schemes: BTreeMap::new(),
+            external: Mutex::new(BTreeMap::new())?
         };
 
         reg.register("file", ::schemes::file::File);
         reg.register("ipc-broadcast", ::schemes::ipc::Broadcast);
 
-        reg
+        Ok(reg)
     }
 
     fn register<S: Into<String>, T: SchemeProvider>(&mut self, key: S, provider: T) {
         self.schemes.insert(key.into(), Box::new(provider));
     }
+
+    /// Returns `false` if the name is taken.
+    pub fn register_external(&self, key: String, scheme: Arc<ExternalScheme>) -> KernelResult<bool> {
+        if self.schemes.contains_key(&key) {
+            return Ok(false);
+        }
+
+        let mut external = self.external.lock()?;
+        if external.contains_key(&key) {
+            return Ok(false);
+        }
+
+        external.insert(key, scheme);
+        Ok(true)
+    }
+
+    /// Returns whether `key` was a registered external scheme.
+    pub fn unregister_external(&self, key: &str) -> KernelResult<bool> {
+        Ok(self.external.lock()?.remove(key).is_some())
+    }
+
+    /// Returns `None` if there is no such scheme or its provider is being unloaded.
+    fn get_external(&self, key: &str) -> KernelResult<Option<(Arc<ExternalScheme>, ModuleRef)>> {
+        let external = self.external.lock()?;
+
+        Ok(match external.get(key) {
+            Some(v) => unsafe { v.pin() }.map(|m| (v.clone(), m)),
+            None => None
+        })
+    }
 }
 
 pub trait SchemeProvider: Send + Sync + 'static {
-    fn open(&self, url: &Url, kctx: *mut u8) -> KernelResult<IoResult<Box<Resource>>>;
+    /// Opens the resource at `url`, which `manifest` allows if given.
+    ///
+    /// Providers check the manifest again against what they opened where
+    /// that can differ from the URL, like files behind symlinks.
+    fn open(&self, url: &Url, kctx: *mut u8, manifest: Option<&Manifest>) -> KernelResult<IoResult<Box<Resource>>>;
 }
 
 impl<'a> Url<'a> {
-    // TODO: scheme registry
-    pub fn open(&self, kctx: *mut u8) -> KernelResult<IoResult<Box<Resource>>> {
-        match ::global::get_global().scheme_registry.schemes.get(self.scheme) {
+    /// Opens the resource if `manifest` is `None` or allows it.
+    pub fn open(&self, kctx: *mut u8, manifest: Option<&Manifest>) -> KernelResult<IoResult<Box<Resource>>> {
+        if let Some(m) = manifest {
+            if !m.allows_url(self.scheme, self.path) {
+                return Ok(Err(IoError::PermissionDenied));
+            }
+        }
+
+        let registry = &::global::get_global().scheme_registry;
+
+        match registry.schemes.get(self.scheme) {
             Some(provider) => {
-                provider.open(self, kctx)
+                provider.open(self, kctx, manifest)
             },
-            None => Ok(Err(IoError::Invalid))
+            None => match registry.get_external(self.scheme)? {
+                Some((provider, module)) => ExternalScheme::open(&provider, module, self),
+                None => Ok(Err(IoError::Invalid))
+            }
         }
     }
 
+    /// Options given by the `nonblock` and `timeout=<ms>` arguments.
+    pub fn io_options(&'a self) -> CwaResult<IoOptions> {
+        let args = self.parse_args();
+
+        Ok(IoOptions {
+            nonblock: args.get("nonblock").is_some(),
+            timeout_ms: match args.get("timeout") {
+                Some(v) => Some(v.parse().map_err(|_| CwaError::InvalidArgument)?),
+                None => None
+            }
+        })
+    }
+
     pub fn parse_args(&'a self) -> BTreeMap<&'a str, &'a str> {
         let mut map: BTreeMap<&'a str, &'a str> = BTreeMap::new();
         for mut pair in self.unparsed_args.split("&").map(|v| v.splitn(2, "=")) {